@@ -0,0 +1,69 @@
+extern crate flow_rs;
+
+use flow_rs::simulation::experiment::Experiment;
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+// Runs num_trials trials of every market type in parallel and saves the results to results/<exp_name>/
+// usage: cargo run --bin experiment <exp_name> <num_trials> <dists.csv path> <consts.csv path> [y/n log] [seed]
+fn main() {
+	let mut args = env::args();
+	args.next(); // consume file name arg[0]
+
+	let exp_name = match args.next() {
+		Some(arg) => arg,
+		None => {
+			println!("Supply experiment name!");
+			std::process::exit(1);
+		}
+	};
+
+	let num_trials: u64 = match args.next().map(|arg| arg.parse()) {
+		Some(Ok(n)) => n,
+		_ => {
+			println!("Supply number of trials!");
+			std::process::exit(1);
+		}
+	};
+
+	let dists_path = match args.next() {
+		Some(arg) => PathBuf::from(arg),
+		None => {
+			println!("Supply distributions csv file!");
+			std::process::exit(1);
+		}
+	};
+
+	let consts_path = match args.next() {
+		Some(arg) => PathBuf::from(arg),
+		None => {
+			println!("Supply consts csv file!");
+			std::process::exit(1);
+		}
+	};
+
+	let enable_log: bool = match args.next() {
+		Some(arg) => arg.to_lowercase() != "n",
+		None => true,	// logging enabled by default
+	};
+
+	// An optional master seed overrides the seed in the consts config
+	let seed: Option<u64> = match args.next() {
+		Some(arg) => match arg.parse() {
+			Ok(seed) => Some(seed),
+			Err(_) => {
+				println!("Seed must be an unsigned integer!");
+				std::process::exit(1);
+			}
+		},
+		None => None,
+	};
+
+	let experiment = Experiment::new(exp_name, num_trials, dists_path, consts_path, enable_log, seed)
+		.expect("Couldn't load experiment configs");
+	println!("Running {} trials of {} with seed {}", num_trials, experiment.name, experiment.seed);
+
+	let exp_dir = experiment.run(Path::new("results")).expect("Couldn't run experiment");
+	println!("Saved results to {}", exp_dir.join("results.csv").display());
+}
@@ -0,0 +1,91 @@
+extern crate flow_rs;
+
+use flow_rs::simulation::experiment::Experiment;
+use flow_rs::simulation::sweep::{Sweep, SweepMode};
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+// Runs num_trials trials of every market type at every point of the sweep spec and saves the results to results/<sweep_name>/
+// usage: cargo run --bin sweep <sweep_name> <sweep.csv path> <grid|lhs:n> <num_trials> <dists.csv path> <consts.csv path> [y/n log] [seed]
+fn main() {
+	let mut args = env::args();
+	args.next(); // consume file name arg[0]
+
+	let sweep_name = match args.next() {
+		Some(arg) => arg,
+		None => {
+			println!("Supply sweep name!");
+			std::process::exit(1);
+		}
+	};
+
+	let spec_path = match args.next() {
+		Some(arg) => PathBuf::from(arg),
+		None => {
+			println!("Supply sweep spec csv file!");
+			std::process::exit(1);
+		}
+	};
+
+	let mode = match args.next().map(|arg| SweepMode::parse(&arg)) {
+		Some(Ok(mode)) => mode,
+		Some(Err(e)) => {
+			println!("{}", e);
+			std::process::exit(1);
+		},
+		None => {
+			println!("Supply sweep mode: grid or lhs:<num_points>!");
+			std::process::exit(1);
+		}
+	};
+
+	let num_trials: u64 = match args.next().map(|arg| arg.parse()) {
+		Some(Ok(n)) => n,
+		_ => {
+			println!("Supply number of trials!");
+			std::process::exit(1);
+		}
+	};
+
+	let dists_path = match args.next() {
+		Some(arg) => PathBuf::from(arg),
+		None => {
+			println!("Supply distributions csv file!");
+			std::process::exit(1);
+		}
+	};
+
+	let consts_path = match args.next() {
+		Some(arg) => PathBuf::from(arg),
+		None => {
+			println!("Supply consts csv file!");
+			std::process::exit(1);
+		}
+	};
+
+	let enable_log: bool = match args.next() {
+		Some(arg) => arg.to_lowercase() != "n",
+		None => true,	// logging enabled by default
+	};
+
+	// An optional master seed overrides the seed in the consts config
+	let seed: Option<u64> = match args.next() {
+		Some(arg) => match arg.parse() {
+			Ok(seed) => Some(seed),
+			Err(_) => {
+				println!("Seed must be an unsigned integer!");
+				std::process::exit(1);
+			}
+		},
+		None => None,
+	};
+
+	let base = Experiment::new(sweep_name, num_trials, dists_path, consts_path, enable_log, seed)
+		.expect("Couldn't load experiment configs");
+	let sweep = Sweep::new(base, spec_path, mode).expect("Couldn't load sweep spec");
+	println!("Running {} points of {} with seed {}", sweep.points().len(), sweep.base.name, sweep.base.seed);
+
+	let exp_dir = sweep.run(Path::new("results")).expect("Couldn't run sweep");
+	println!("Saved results to {}", exp_dir.join("results.csv").display());
+}
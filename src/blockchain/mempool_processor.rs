@@ -3,7 +3,9 @@ use crate::blockchain::mem_pool::MemPool;
 use crate::order::order_book::Book;
 use crate::controller::{Task, State};
 use crate::exchange::exchange_logic::{Auction, TradeResults, PlayerUpdate};
-use crate::exchange::MarketType;	
+use crate::exchange::{MarketType, SelfTradePrevention};
+use crate::exchange::mechanism::AuctionMechanism;
+use crate::error::SimError;
 
 use std::thread;
 use std::thread::JoinHandle;
@@ -15,17 +17,18 @@ impl MemPoolProcessor {
 	// Concurrently process orders in the pool. Each order is
 	// either of OrderType::{Enter, Update, Cancel}. Each order will
 	// modify the state of either the Bids or Asks Book, but must
-	// first acquire a lock on the respective book. 
+	// first acquire a lock on the respective book. Joining a
+	// handle gives the errors of its order.
 	pub fn conc_process_mem_pool(pool: Arc<MemPool>, 
 									bids: Arc<Book>, 
 									asks: Arc<Book>) 
-									-> Vec<JoinHandle<()>>{
+									-> Vec<JoinHandle<Vec<SimError>>>{
 		// Acquire lock of MemPool
 		// Pop off contents of MemPool
 		// match over the OrderType
 		// process each order based on OrderType
 		
-		let mut handles = Vec::<JoinHandle<()>>::new();
+		let mut handles = Vec::<JoinHandle<Vec<SimError>>>::new();
 		for order in pool.pop_all() {
 			let m_t = MarketType::CDA;		// CHANGE LATERRRRRRRRRRR
 			let handle = match order.order_type {
@@ -42,181 +45,96 @@ impl MemPoolProcessor {
 	// either of OrderType::{Enter, Update, Cancel}. Each order will
 	// modify the state of either the Bids or Asks Book, but must
 	// first acquire a lock on the respective book. 
-	pub fn seq_process_orders(frame: &mut Vec<Order>, bids: Arc<Book>, asks: Arc<Book>, _m_t: MarketType) -> Option<Vec<TradeResults>> {
+	// Orders entered are accepted by the auction mechanism.
+	// An order that fails is skipped and its error returned with the results.
+	pub fn seq_process_orders(frame: &mut Vec<Order>, bids: Arc<Book>, asks: Arc<Book>, mechanism: &dyn AuctionMechanism) -> (Option<Vec<TradeResults>>, Vec<SimError>) {
 		// Create vec to return results of all the crossings
 		let mut results: Vec<TradeResults> = Vec::new();
+		let mut errors: Vec<SimError> = Vec::new();
 		for order in frame.drain(..) {
-			// println!("Processing order:{:?}", order);
-			match order.order_type {
-				OrderType::Enter => {
-					if let Some(result) = MemPoolProcessor::seq_process_enter(Arc::clone(&bids), Arc::clone(&asks), order, _m_t.clone()) {
-						results.push(result);
-					}
-				}
-				OrderType::Update => MemPoolProcessor::seq_process_update(Arc::clone(&bids), Arc::clone(&asks), order, _m_t.clone()),
-				OrderType::Cancel => {
-					if let Some(result) = MemPoolProcessor::seq_process_cancel(Arc::clone(&bids), Arc::clone(&asks), order, _m_t.clone()) {
-						results.push(result);
-					}
-				}
-			};
+			MemPoolProcessor::seq_process_order(order, Arc::clone(&bids), Arc::clone(&asks), mechanism, &mut results, &mut errors);
 		}
 		if results.len() == 0 {
-			return None;
+			return (None, errors);
 		}
-		Some(results)
+		(Some(results), errors)
 	}
 
-
-	// Checks if the new order crosses. Modifies orders in book then calculates new max price
-	fn seq_process_enter(bids: Arc<Book>, asks: Arc<Book>, order: Order, m_t: MarketType) -> Option<TradeResults> {
-		// Spawn a new thread to process the order
-    	match m_t {
-    		MarketType::FBA|MarketType::KLF => {
-				// KLF and FBA are processed the same way by the order book
-				match order.trade_type {
-					TradeType::Ask => {
-						asks.add_order(order).expect("Failed to add order");
-						return None;
-					},
-					TradeType::Bid => {
-						bids.add_order(order).expect("Failed to add order...");
-						return None
-					}
-				}
-			},
-			MarketType::CDA => {
-				// Since CDA we will check if the order transacts here:
-				match order.trade_type {
-					TradeType::Ask => {
-						// Only check for cross if this ask price is lower than best ask
-						if order.price < asks.get_min_price() {
-							// This will add the new ask to the book if it doesn't fully transact
-							if let Some(results) = Auction::calc_ask_crossing(bids, asks, order) {
-								// We have some trade results return them to apply updates to the clearing house
-								return Some(results);
-							} 
-						} else {
-							// We need to add the ask to the book, best price will be updated in add_order
-							asks.add_order(order).expect("Failed to add order");
-							return None
-						}
-					},
-					TradeType::Bid => {
-						// Only check for cross if this bid price is higher than best bid
-						if order.price > bids.get_max_price() {
-							// This will add the new bid to the book if it doesn't fully transact
-							if let Some(results) = Auction::calc_bid_crossing(bids, asks, order) {
-								// We have some trade results return them to apply updates to the clearing house
-								return Some(results);
-							}
-						} else {
-							// We need to add the ask to the book, best price will be updated in add_order
-							bids.add_order(order).expect("Failed to add order...");
-							return None;
-						}
-					}
+	// Processes a single order from the frame, pushing its results or error
+	pub fn seq_process_order(order: Order, bids: Arc<Book>, asks: Arc<Book>, mechanism: &dyn AuctionMechanism, 
+							 results: &mut Vec<TradeResults>, errors: &mut Vec<SimError>) {
+		// println!("Processing order:{:?}", order);
+		match order.order_type {
+			OrderType::Enter => {
+				match mechanism.accept_order(bids, asks, order) {
+					Ok(Some(result)) => results.push(result),
+					Ok(None) => {},
+					Err(e) => errors.push(e),
 				}
 			}
-    	}
-    	None
-		
+			OrderType::Update => MemPoolProcessor::seq_process_update(bids, asks, order, mechanism, results, errors),
+			OrderType::Cancel => {
+				let result = MemPoolProcessor::seq_process_cancel(bids, asks, order, mechanism, errors);
+				results.push(result);
+			}
+		};
 	}
 
-	// Cancels the previous order and then enters this as a new one
-	// Updates an order in the Bids or Asks Book in it's own thread
-	fn seq_process_update(bids: Arc<Book>, asks: Arc<Book>, order: Order, _m_t: MarketType) {
-		// update books min/max price if this overwrites current min/max OR this order contains new min/max
-		match order.trade_type {
-			TradeType::Ask => {
-				// Cancel the orginal order:
-				println!("Cancelling!");
-				match asks.cancel_order_by_id(order.order_id) {
-					Ok(()) => {},
-					Err(e) => println!("{:?}", e),
-				}
-				// Only check for cross if this ask price is lower than best ask
-				if order.price < asks.get_min_price() {
-					println!("Gonna auction!");
-					// This will add the new ask to the book if it doesn't fully transact
-					Auction::calc_ask_crossing(bids, asks, order);
-				} else {
-					println!("Adding to ask book");
-					// We need to add the ask to the book, best price will be updated in add_order
-					asks.add_order(order).expect("Failed to add order");
-				}
-			},
-			TradeType::Bid => {
-				// Cancel the orginal order:
-				println!("Cancelling!");
-				match bids.cancel_order_by_id(order.order_id) {
-					Ok(()) => {},
-					Err(e) => println!("{:?}", e),
-				}
-				// Only check for cross if this bid price is higher than best bid
-				if order.price > bids.get_max_price() {
-					println!("Gonna auction!");
-					// This will add the new bid to the book if it doesn't fully transact
-					Auction::calc_bid_crossing(bids, asks, order);
-				} else {
-					println!("Adding to ask book");
-					// We need to add the ask to the book, best price will be updated in add_order
-					bids.add_order(order).expect("Failed to add order...");
-				}
-			}
+
+	// Cancels the previous order and then accepts this one as a new order through the
+	// mechanism, so any trades it makes are settled like those of an entered order
+	fn seq_process_update(bids: Arc<Book>, asks: Arc<Book>, order: Order, mechanism: &dyn AuctionMechanism,
+						  results: &mut Vec<TradeResults>, errors: &mut Vec<SimError>) {
+		// Cancel the orginal order, still entering the new one if it wasn't found
+		if let Err(e) = mechanism.cancel_order(Arc::clone(&bids), Arc::clone(&asks), order.clone()) {
+			errors.push(e);
+		}
+		match mechanism.accept_order(bids, asks, order) {
+			Ok(Some(result)) => results.push(result),
+			Ok(None) => {},
+			Err(e) => errors.push(e),
 		}
 	}
 
 	// Cancels the order living in the Bids or Asks Book
-	fn seq_process_cancel(bids: Arc<Book>, asks: Arc<Book>, order: Order, m_t: MarketType) -> Option<TradeResults> {
-		// select bids or asks book 
-		let book = match order.trade_type {
-			TradeType::Ask => asks,
-			TradeType::Bid => bids,
-		};
-
+	fn seq_process_cancel(bids: Arc<Book>, asks: Arc<Book>, order: Order, mechanism: &dyn AuctionMechanism, errors: &mut Vec<SimError>) -> TradeResults {
 		let trader_id = order.trader_id.clone();
 		let order_id = order.order_id;
 
-		// If the cancel fails bubble error up.
-		match book.cancel_order(order) {
-    		Ok(()) => {},
-    		Err(e) => {
-    			println!("ERROR: {}", e);
-    			// TODO send an error response over TCP
-    		}
-    	}
+		// The order may have traded before the cancel was published, the player's
+		// order is still cancelled in the clearing house
+		if let Err(e) = mechanism.cancel_order(bids, asks, order) {
+			errors.push(e);
+		}
     	
     	// Once cancelled in order book, cancel in the clearing house 
     	// Store a PlayerUpdate with Cancel set to true, in vec form for TradeResults compatibility
-		let updates = vec![PlayerUpdate::new( 
-						trader_id.clone(),
-						trader_id,
-						order_id,
-						order_id,
-						-9.99,
-						-9.99,
-						true       // Cancel = true 
-					)];
+		let updates = vec![PlayerUpdate::cancel(trader_id, order_id)];
 
 
-    	// make TradeResult for compatible return type with seq_process_enter
-    	Some(TradeResults::new(m_t, None, 0.0, 0.0, Some(updates)))
+    	// make TradeResult for compatible return type with AuctionMechanism::accept_order
+    	TradeResults::new(None, 0.0, 0.0, Some(updates))
 	}
 
-	// Checks if the new order crosses. Modifies orders in book then calculates new max price
-	fn conc_process_enter(bids: Arc<Book>, asks: Arc<Book>, order: Order, m_t: MarketType) -> JoinHandle<()> {
+	// Checks if the new order crosses. Modifies orders in book then calculates new max price.
+	// The thread returns the errors of the order, like the sequential path.
+	fn conc_process_enter(bids: Arc<Book>, asks: Arc<Book>, order: Order, m_t: MarketType) -> JoinHandle<Vec<SimError>> {
 		// Spawn a new thread to process the order
 	    thread::spawn(move || {
+	    	let mut errors = Vec::<SimError>::new();
 	    	match m_t {
 	    		MarketType::FBA|MarketType::KLF => {
     				// KLF and FBA are processed the same way by the order book
 					match order.trade_type {
 						TradeType::Ask => {
-							asks.add_order(order).expect("Failed to add order");
+							if let Err(e) = asks.add_order(order) {
+								errors.push(e);
+							}
 						},
 						TradeType::Bid => {
-							bids.add_order(order).expect("Failed to add order...");
+							if let Err(e) = bids.add_order(order) {
+								errors.push(e);
+							}
 						}
 					}
     			},
@@ -227,91 +145,93 @@ impl MemPoolProcessor {
 							// Only check for cross if this ask price is lower than best ask
 							if order.price < asks.get_min_price() {
 								// This will add the new ask to the book if it doesn't fully transact
-								Auction::calc_ask_crossing(bids, asks, order);
+								if let Err(e) = Auction::calc_ask_crossing(bids, asks, order, SelfTradePrevention::None) {
+									errors.push(e);
+								}
 							} else {
 								// We need to add the ask to the book, best price will be updated in add_order
-								asks.add_order(order).expect("Failed to add order");
+								if let Err(e) = asks.add_order(order) {
+									errors.push(e);
+								}
 							}
 						},
 						TradeType::Bid => {
 							// Only check for cross if this bid price is higher than best bid
 							if order.price > bids.get_max_price() {
 								// This will add the new bid to the book if it doesn't fully transact
-								Auction::calc_bid_crossing(bids, asks, order);
+								if let Err(e) = Auction::calc_bid_crossing(bids, asks, order, SelfTradePrevention::None) {
+									errors.push(e);
+								}
 							} else {
 								// We need to add the ask to the book, best price will be updated in add_order
-								bids.add_order(order).expect("Failed to add order...");
+								if let Err(e) = bids.add_order(order) {
+									errors.push(e);
+								}
 							}
 						}
 					}
     			}
 	    	}
-			
+			errors
 	    })
 	}
 
 	// Cancels the previous order and then enters this as a new one
-	// Updates an order in the Bids or Asks Book in it's own thread
-	fn conc_process_update(bids: Arc<Book>, asks: Arc<Book>, order: Order, _m_t: MarketType) -> JoinHandle<()> {
+	// Updates an order in the Bids or Asks Book in it's own thread, returning its errors
+	fn conc_process_update(bids: Arc<Book>, asks: Arc<Book>, order: Order, _m_t: MarketType) -> JoinHandle<Vec<SimError>> {
 		// update books min/max price if this overwrites current min/max OR this order contains new min/max
 	    thread::spawn(move || {
+	    	let mut errors = Vec::<SimError>::new();
 			match order.trade_type {
 				TradeType::Ask => {
 					// Cancel the orginal order:
-					println!("Cancelling!");
-					match asks.cancel_order_by_id(order.order_id) {
-						Ok(()) => {},
-						Err(e) => println!("{:?}", e),
+					if let Err(e) = asks.cancel_order_by_id(order.order_id) {
+						errors.push(e);
 					}
 					// Only check for cross if this ask price is lower than best ask
 					if order.price < asks.get_min_price() {
-						println!("Gonna auction!");
 						// This will add the new ask to the book if it doesn't fully transact
-						Auction::calc_ask_crossing(bids, asks, order);
+						if let Err(e) = Auction::calc_ask_crossing(bids, asks, order, SelfTradePrevention::None) {
+							errors.push(e);
+						}
 					} else {
-						println!("Adding to ask book");
 						// We need to add the ask to the book, best price will be updated in add_order
-						asks.add_order(order).expect("Failed to add order");
+						if let Err(e) = asks.add_order(order) {
+							errors.push(e);
+						}
 					}
 				},
 				TradeType::Bid => {
 					// Cancel the orginal order:
-					println!("Cancelling!");
-					match bids.cancel_order_by_id(order.order_id) {
-						Ok(()) => {},
-						Err(e) => println!("{:?}", e),
+					if let Err(e) = bids.cancel_order_by_id(order.order_id) {
+						errors.push(e);
 					}
 					// Only check for cross if this bid price is higher than best bid
 					if order.price > bids.get_max_price() {
-						println!("Gonna auction!");
 						// This will add the new bid to the book if it doesn't fully transact
-						Auction::calc_bid_crossing(bids, asks, order);
+						if let Err(e) = Auction::calc_bid_crossing(bids, asks, order, SelfTradePrevention::None) {
+							errors.push(e);
+						}
 					} else {
-						println!("Adding to ask book");
-						// We need to add the ask to the book, best price will be updated in add_order
-						bids.add_order(order).expect("Failed to add order...");
+						// We need to add the bid to the book, best price will be updated in add_order
+						if let Err(e) = bids.add_order(order) {
+							errors.push(e);
+						}
 					}
 				}
 			}
+			errors
 	    })
 	}
 
-	// Cancels the order living in the Bids or Asks Book
-	fn conc_process_cancel(bids: Arc<Book>, asks: Arc<Book>, order: Order, _m_t: MarketType) -> JoinHandle<()> {
+	// Cancels the order living in the Bids or Asks Book, returning the error if it fails
+	fn conc_process_cancel(bids: Arc<Book>, asks: Arc<Book>, order: Order, _m_t: MarketType) -> JoinHandle<Vec<SimError>> {
 	    thread::spawn(move || {
 			let book = match order.trade_type {
 				TradeType::Ask => asks,
 				TradeType::Bid => bids,
 			};
-
-			// If the cancel fails bubble error up.
-			match book.cancel_order(order) {
-	    		Ok(()) => {},
-	    		Err(e) => {
-	    			println!("ERROR: {}", e);
-	    			// TODO send an error response over TCP
-	    		}
-	    	}
+			book.cancel_order(order).err().into_iter().collect()
 	    })
 	}
 
@@ -319,6 +239,7 @@ impl MemPoolProcessor {
 							bids: Arc<Book>, 
 							asks: Arc<Book>, 
 							state: Arc<Mutex<State>>, 
+							errors: Arc<Mutex<Vec<SimError>>>,
 							duration: u64) -> Task
 	{
 	    Task::rpt_task(move || {
@@ -328,8 +249,10 @@ impl MemPoolProcessor {
 								Arc::clone(&bids),
 								Arc::clone(&asks));
 
+					// The errors of the orders that failed go to the errors sink
 					for h in handles {
-						h.join().expect("Couldn't join queue tasks");
+						let failed = h.join().expect("Couldn't join queue tasks");
+						errors.lock().expect("Couldn't lock errors in queue task").extend(failed);
 					}
 					// println!("Processing order queue");
 				},
@@ -15,7 +15,6 @@ extern crate tokio_serde_json;
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use serde_json::Value;
 use tokio_serde_json::{ReadJson, WriteJson};
-use log::{log, Level};
 
 // Handles JSON serialization/deserialization functions and new message processing
 pub struct OrderProcessor {}
@@ -33,6 +32,13 @@ impl OrderProcessor {
 	    	pool.add(order);
 	    })
 	}
+
+	// Logs and appends the order to the MemPool on the calling thread so that
+	// the arrival order in the pool is deterministic.
+	pub fn seq_recv_order(order: Order, pool: &MemPool) {
+		log_mempool_data!(Order::order_to_csv(&order));
+		pool.add(order);
+	}
 }
 
 // Type alias for returning JSON stream
@@ -114,6 +120,7 @@ impl JsonOrder {
 		let et = match typed_json.ex_type.to_lowercase().as_ref() {
 			"floworder" => ExchangeType::FlowOrder,
 			"limitorder" => ExchangeType::LimitOrder,
+			"marketorder" => ExchangeType::MarketOrder,
 			_ => {
 				println!("Entered an invalid tradetype");
 				return None;
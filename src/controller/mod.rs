@@ -1,3 +1,5 @@
+pub mod scheduler;
+
 use tokio::runtime::Runtime;
 use std::time::{Duration, Instant};
 use tokio::prelude::*;
@@ -7,7 +9,10 @@ use futures::{Future};
 
 use futures::future;
 
-#[derive(Debug)]
+/// The trading state of the market. Process: orders trade by the main session's mechanism.
+/// PreAuction: a call auction collects orders and publishes an indicative price.
+/// Auction: the call auction uncrosses the books.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
 	Process,
 	PreAuction,
@@ -0,0 +1,160 @@
+use crate::utility::set_time;
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::time::Duration;
+
+
+/// The events that drive the players of the simulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+	InvestorArrival,	// A random investor may send an order to the mempool
+	MakerUpdate,		// Every maker may cancel and re-enter their orders
+	MinerPublish,		// The miner publishes its frame as the next block
+	MinerFrameForm,		// The miner forms its next frame from the mempool
+}
+
+// An event waiting in the queue. Ties in time are broken by the order
+// the events were scheduled in so the event sequence is always repeatable.
+#[derive(Debug)]
+struct Scheduled {
+	time: Duration,
+	seq: u64,
+	event: Event,
+}
+
+impl PartialEq for Scheduled {
+	fn eq(&self, other: &Scheduled) -> bool {
+		self.time == other.time && self.seq == other.seq
+	}
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+	fn partial_cmp(&self, other: &Scheduled) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Scheduled {
+	fn cmp(&self, other: &Scheduled) -> Ordering {
+		self.time.cmp(&other.time).then(self.seq.cmp(&other.seq))
+	}
+}
+
+
+/// A discrete-event scheduler running in simulated time. Events are popped
+/// in time order and the simulated clock (utility::get_time) jumps straight
+/// to each event, so a run is as fast as the CPU allows and exactly repeatable.
+pub struct Scheduler {
+	now: Duration,
+	seq: u64,
+	queue: BinaryHeap<Reverse<Scheduled>>,
+	prev_time: Duration,
+}
+
+impl Scheduler {
+	/// Creates a new scheduler starting at simulated time 0. The clock of the
+	/// calling thread is restored when the scheduler is dropped.
+	pub fn new() -> Scheduler {
+		let prev_time = set_time(Duration::from_millis(0));
+		Scheduler {
+			now: Duration::from_millis(0),
+			seq: 0,
+			queue: BinaryHeap::new(),
+			prev_time,
+		}
+	}
+
+	/// Schedules the event to fire after a delay in millis from the current simulated time
+	pub fn schedule(&mut self, event: Event, delay_millis: f64) {
+		// Negative delays can't move the clock backwards
+		let delay = Duration::from_secs_f64(delay_millis.max(0.0) / 1000.0);
+		let time = self.now + delay;
+		self.schedule_at(event, time);
+	}
+
+	/// Schedules the event to fire at an absolute simulated time
+	pub fn schedule_at(&mut self, event: Event, time: Duration) {
+		let time = if time < self.now {self.now} else {time};
+		self.queue.push(Reverse(Scheduled {
+			time,
+			seq: self.seq,
+			event,
+		}));
+		self.seq += 1;
+	}
+
+	/// Pops the next event and advances the simulated clock to its time
+	pub fn next_event(&mut self) -> Option<Event> {
+		match self.queue.pop() {
+			Some(Reverse(scheduled)) => {
+				self.now = scheduled.time;
+				set_time(self.now);
+				Some(scheduled.event)
+			},
+			None => None,
+		}
+	}
+
+	/// The current simulated time
+	pub fn now(&self) -> Duration {
+		self.now
+	}
+}
+
+impl Default for Scheduler {
+	fn default() -> Scheduler {
+		Scheduler::new()
+	}
+}
+
+impl Drop for Scheduler {
+	fn drop(&mut self) {
+		// A simulation can run nested inside another on the same thread (e.g. rayon
+		// work stealing), so hand the outer simulation its clock back.
+		set_time(self.prev_time);
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utility::get_time;
+
+	#[test]
+	fn test_event_order() {
+		let mut scheduler = Scheduler::new();
+		scheduler.schedule(Event::MinerPublish, 300.0);
+		scheduler.schedule(Event::InvestorArrival, 10.0);
+		scheduler.schedule(Event::MakerUpdate, 300.0);
+		scheduler.schedule(Event::InvestorArrival, 0.5);
+
+		assert_eq!(scheduler.next_event(), Some(Event::InvestorArrival));
+		assert_eq!(get_time(), Duration::from_micros(500));
+		assert_eq!(scheduler.next_event(), Some(Event::InvestorArrival));
+		assert_eq!(get_time(), Duration::from_millis(10));
+
+		// Ties are broken by the order they were scheduled
+		assert_eq!(scheduler.next_event(), Some(Event::MinerPublish));
+		assert_eq!(scheduler.next_event(), Some(Event::MakerUpdate));
+		assert_eq!(scheduler.now(), Duration::from_millis(300));
+		assert_eq!(scheduler.next_event(), None);
+	}
+
+	#[test]
+	fn test_clock_restored() {
+		let mut outer = Scheduler::new();
+		outer.schedule(Event::MinerPublish, 100.0);
+		outer.next_event();
+		{
+			let mut inner = Scheduler::new();
+			inner.schedule(Event::MinerPublish, 5.0);
+			inner.next_event();
+			assert_eq!(get_time(), Duration::from_millis(5));
+		}
+		assert_eq!(get_time(), Duration::from_millis(100));
+	}
+}
@@ -0,0 +1,46 @@
+// The errors returned while matching and clearing orders
+use crate::order::order::TradeType;
+
+use std::error::Error;
+use std::fmt;
+
+
+/// An inconsistency between the order books, the auction and the ClearingHouse.
+/// None of these stop a simulation, the order or update that caused it is skipped
+/// and the error is recorded in History::errors.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimError {
+	/// The order isn't in the book it was sent to
+	OrderNotInBook { book: TradeType, order_id: u64 },
+	/// An order with the same id is already resting in the book
+	DuplicateOrder { book: TradeType, order_id: u64 },
+	/// The trader isn't registered with the ClearingHouse
+	PlayerNotFound { trader_id: String },
+	/// The trader doesn't hold the order in the ClearingHouse
+	PlayerOrderNotFound { trader_id: String, order_id: u64 },
+	/// A cancel for the order has already been sent to the mempool
+	DoubleCancel { trader_id: String, order_id: u64 },
+	/// The order's price or quantity is NaN so it can't be matched
+	InvalidOrder { order_id: u64, reason: &'static str },
+	/// The order's price isn't on the tick grid or its quantity isn't a whole number of lots
+	OffGrid { order_id: u64, reason: &'static str },
+	/// The portfolio market's prices were still moving after max_sweeps passes, so nothing traded
+	NoCross { sweeps: usize },
+}
+
+impl fmt::Display for SimError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SimError::OrderNotInBook { book, order_id } => write!(f, "order {} not found in the {:?} book", order_id, book),
+			SimError::DuplicateOrder { book, order_id } => write!(f, "order {} is already in the {:?} book", order_id, book),
+			SimError::PlayerNotFound { trader_id } => write!(f, "trader {} not found in the clearing house", trader_id),
+			SimError::PlayerOrderNotFound { trader_id, order_id } => write!(f, "trader {} has no order {}", trader_id, order_id),
+			SimError::DoubleCancel { trader_id, order_id } => write!(f, "trader {} already sent a cancel for order {}", trader_id, order_id),
+			SimError::InvalidOrder { order_id, reason } => write!(f, "order {} is invalid: {}", order_id, reason),
+			SimError::OffGrid { order_id, reason } => write!(f, "order {} is off-grid: {}", order_id, reason),
+			SimError::NoCross { sweeps } => write!(f, "the portfolio market found no cross in {} sweeps", sweeps),
+		}
+	}
+}
+
+impl Error for SimError {}
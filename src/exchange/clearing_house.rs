@@ -1,31 +1,32 @@
 use crate::simulation::simulation_config::{Distributions, Constants};
 use crate::simulation::simulation_history::{PriorData, LikelihoodStats, UpdateReason};
-use crate::exchange::exchange_logic::TradeResults;
-use crate::exchange::MarketType;
-use crate::order::order::{Order};
+use crate::exchange::exchange_logic::{TradeResults, PlayerUpdate};
+use crate::order::order::{Order, ExchangeType};
 use crate::players::{Player, TraderT};
 use crate::players::investor::Investor;
 use crate::players::maker::{Maker, MakerT};
 use crate::players::miner::Miner;
+use crate::error::SimError;
+use crate::utility::with_rng;
 use crate::log_player_data;
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::sync::Mutex;
-use rand::{thread_rng};
 use rand::seq::SliceRandom;
 
 
-use log::{log, Level};
 
 
 
 /// The struct for keeping track of active players and their balances and inventories
-/// ClearingHouse is a HashMap indexed by each player's trader_id
+/// ClearingHouse is a BTreeMap indexed by each player's trader_id. Players are kept
+/// in id order so iterating over them is repeatable for a given seed.
 pub struct ClearingHouse {
-	pub players: Mutex<HashMap<String, Box<dyn Player + Send>>>,
+	pub players: Mutex<BTreeMap<String, Box<dyn Player + Send>>>,
 	pub gas_fees: Mutex<Vec<f64>>,
 	pub total_tax: Mutex<f64>,
 	pub maker_profits: Mutex<Vec<f64>>,
+	pub asset_inventories: Mutex<BTreeMap<String, BTreeMap<usize, f64>>>,	// Each player's shares of the assets other than asset 0 in a multi-asset market
 }
 
 
@@ -34,10 +35,11 @@ impl ClearingHouse {
 	/// Create a new ClearingHouse to store player data
 	pub fn new() -> Self {
 		ClearingHouse {
-			players: Mutex::new(HashMap::new()),
+			players: Mutex::new(BTreeMap::new()),
 			gas_fees: Mutex::new(Vec::<f64>::new()),	
 			total_tax: Mutex::new(0.0),
 			maker_profits: Mutex::new(vec![0.0, 0.0, 0.0]),
+			asset_inventories: Mutex::new(BTreeMap::new()),
 		}
 	}
 
@@ -87,13 +89,14 @@ impl ClearingHouse {
 	}
 
 	// Gets the maker and generates a pair of orders based on supplied parameters 
-	pub fn maker_new_orders(&self, id: String, data: &PriorData, inference: &LikelihoodStats, dists: &Distributions, consts: &Constants) -> Option<(Order, Order)>{
+	pub fn maker_new_orders(&self, id: String, data: &PriorData, inference: &LikelihoodStats, dists: &Distributions, consts: &Constants,
+							ex_type: ExchangeType) -> Option<(Order, Order)>{
 		let players = self.players.lock().unwrap();
 		match players.get(&id) {
 			Some(player) => {
 				if let Some(maker) = player.as_any().downcast_ref::<Maker>() {
 					// Was able to find the maker in the clearing house and cast Player object to Maker
-					let orders = maker.new_orders(data, inference, dists, consts);
+					let orders = maker.new_orders(data, inference, dists, consts, ex_type);
 					return orders
 				} else {
 					// Couldn't downcast to maker
@@ -111,7 +114,7 @@ impl ClearingHouse {
 	// Gets the maker and cancels all of their enter orders in the clearing house
 	// returns a vector of all of their orders with the update OrderType = Cancel
 	// to be submitted to the mempool -> order books
-	pub fn cancel_all_orders(&self, id: String) -> Result<Vec<Order>, ()> {
+	pub fn cancel_all_orders(&self, id: String) -> Result<Vec<Order>, SimError> {
 		let mut players = self.players.lock().unwrap();
 		let mut orders = Vec::new();
 		match players.get_mut(&id) {
@@ -130,35 +133,55 @@ impl ClearingHouse {
 				} 
 				Ok(orders)
 			},
-			None => {
-				println!("Couldn't get player to cancel orders: {}", id);
-				return Err(());
-			}
+			None => Err(SimError::PlayerNotFound { trader_id: id }),
+		}
+	}
+
+	/// Generates a cancel order for one of the player's enter orders, unless a cancel
+	/// for it has already been sent to the mempool
+	pub fn cancel_player_order_by_id(&self, id: &String, o_id: u64) -> Result<Order, SimError> {
+		let mut players = self.players.lock().unwrap();
+		match players.get_mut(id) {
+			Some(player) => {
+				if player.check_double_cancel(o_id) {
+					return Err(SimError::DoubleCancel { trader_id: id.clone(), order_id: o_id });
+				}
+				let cancel_order = player.gen_cancel_order(o_id)
+					.map_err(|_| SimError::PlayerOrderNotFound { trader_id: id.clone(), order_id: o_id })?;
+				// Record this in the player's history of sent orders to avoid double cancels.
+				player.add_to_sent(o_id, cancel_order.order_type.clone());
+				Ok(cancel_order)
+			},
+			None => Err(SimError::PlayerNotFound { trader_id: id.clone() }),
 		}
 	}
 
-	pub fn get_player_order_count(&self, id: &String) -> Result<usize, ()> {
+	pub fn get_player_orders(&self, id: &String) -> Option<Vec<Order>> {
+		let players = self.players.lock().unwrap();
+		players.get(id).map(|p| p.copy_orders())
+	}
+
+	pub fn get_player_order_count(&self, id: &String) -> Result<usize, SimError> {
 		let players = self.players.lock().unwrap();
 		match players.get(id) {
 			Some(p) => Ok(p.num_orders()),
-			None => Err(()),
+			None => Err(SimError::PlayerNotFound { trader_id: id.clone() }),
 		}
 	}
 
-	pub fn get_type(&self, id: &String) -> Result<TraderT, ()> {
+	pub fn get_type(&self, id: &String) -> Result<TraderT, SimError> {
 		let players = self.players.lock().unwrap();
 		match players.get(id) {
 			Some(p) => Ok(p.get_player_type()),
-			None => Err(()),
+			None => Err(SimError::PlayerNotFound { trader_id: id.clone() }),
 		}
 	}
 
 	// Shuffles through the players matching the player_type and returns their id
 	pub fn get_rand_player_id(&self, player_type: TraderT) -> Option<String> {
 		let players = self.players.lock().unwrap();
-		let mut rng = thread_rng();
-		let mut _filtered: Vec<(_, _)> = players.iter().filter(|(_k, v)| v.get_player_type() == player_type).collect();
-		if let Some((id, _value)) = _filtered.choose(&mut rng) {
+		let _filtered: Vec<(_, _)> = players.iter().filter(|(_k, v)| v.get_player_type() == player_type).collect();
+		if let Some((id, _value)) = with_rng(|rng| _filtered.choose(rng)) {
 			return Some(id.to_string());
 		} else {
 			return None
@@ -169,12 +192,11 @@ impl ClearingHouse {
 	pub fn get_filtered_ids(&self, player_type: TraderT) -> Vec<String> {
 		let mut ids = Vec::new();
 		let players = self.players.lock().unwrap();
-		let mut rng = thread_rng();
 		let filtered: Vec<(_, _)> = players.iter().filter(|(_k, v)| v.get_player_type() == player_type).collect();
 		for (id, _o) in filtered {
 			ids.push(id.clone());
 		}
-		ids.shuffle(&mut rng);
+		with_rng(|rng| ids.shuffle(rng));
 		ids
 	}
 
@@ -276,166 +298,208 @@ impl ClearingHouse {
 		}
 	}
 
-	/// Gets the TradeResults from an auction and updates each player
-	pub fn update_house(&self, results: TradeResults) {
-		match results.auction_type {
-			MarketType::CDA => self.cda_cross_update(results),
-			MarketType::FBA => self.fba_batch_update(results),
-			MarketType::KLF => self.flow_batch_update(results),
+	/// The player's shares of the asset at index asset of a multi-asset market. Asset 0 is
+	/// the inventory the player holds, the ClearingHouse keeps the shares of the others.
+	pub fn get_asset_inv(&self, id: &String, asset: usize) -> Option<f64> {
+		if asset == 0 {
+			return self.get_bal_inv(id.clone()).map(|(_bal, inv)| inv);
 		}
+		if !self.players.lock().unwrap().contains_key(id) {
+			return None;
+		}
+		let inventories = self.asset_inventories.lock().unwrap();
+		Some(inventories.get(id).and_then(|assets| assets.get(&asset)).cloned().unwrap_or(0.0))
 	}
 
-	/// Consumes the trade results from CDA limit order cross to update each player's state
-	pub fn cda_cross_update(&self, results: TradeResults) {
-		match results.cross_results {
-			None => return,
-			Some(player_updates) => {
-				for pu in player_updates {
-					if pu.cancel == true {
-						// Cancel the player's order in the clearing house
-						match self.cancel_player_order(pu.payer_id, pu.payer_order_id) {
-							Ok(()) => {},
-							Err(e) => println!("cda_cross_update: {:?}, {}", e, pu.payer_order_id),
-						}
-						continue;
-					}
+	/// The player's shares of every asset other than asset 0 they've traded
+	pub fn get_asset_invs(&self, id: &String) -> BTreeMap<usize, f64> {
+		let inventories = self.asset_inventories.lock().unwrap();
+		inventories.get(id).cloned().unwrap_or_default()
+	}
 
-					// Update bidder: -bal, +inv
-					let bidder_id = pu.payer_id;
-					let volume = pu.volume;
-					if volume == 0.0 {
-						// no need to update players if no volume is to be traded
-						continue;
-					}
-					let payment = pu.price * volume;
-					if let Some((new_bal, new_inv)) = self.update_player(bidder_id.clone(), -payment, volume, UpdateReason::Transact) {
-						println!("Updated {}. bal=>{}, inv=>{}", bidder_id.clone(), new_bal, new_inv);
-					} else {
-						self.report_player(bidder_id.clone());
-						panic!("failed to update {}'s balance/inventory", bidder_id);
-					}
+	// Settles one side of a trade: the player's balance and inventory, then the volume
+	// left on their order
+	fn settle_trade(&self, trader_id: String, order_id: u64, asset: usize, bal_to_add: f64, inv_to_add: f64) -> Result<(), SimError> {
+		self.settle_position(trader_id.clone(), asset, bal_to_add, inv_to_add)?;
+		self.update_player_order_vol(trader_id, order_id, -inv_to_add.abs())
+	}
 
-					// NOTE: in CDA, the order's volume in orderbook is implicitly modified during crossing
-					self.update_player_order_vol(bidder_id.clone(), pu.payer_order_id, -volume).expect("Failed to update");
+	// Adds to the player's balance and their inventory of the asset, without touching their orders
+	fn settle_position(&self, trader_id: String, asset: usize, bal_to_add: f64, inv_to_add: f64) -> Result<(), SimError> {
+		let player_inv = if asset == 0 { inv_to_add } else { 0.0 };
+		if self.update_player(trader_id.clone(), bal_to_add, player_inv, UpdateReason::Transact).is_none() {
+			return Err(SimError::PlayerNotFound { trader_id });
+		}
+		if asset != 0 {
+			let mut inventories = self.asset_inventories.lock().unwrap();
+			*inventories.entry(trader_id).or_default().entry(asset).or_insert(0.0) += inv_to_add;
+		}
+		Ok(())
+	}
 
-					// Update asker: +bal, -inv
-					let asker_id = pu.vol_filler_id;
-					if let Some((new_bal, new_inv)) = self.update_player(asker_id.clone(), payment, -volume, UpdateReason::Transact) {
-							println!("Updated {}. bal=>{}, inv=>{}", asker_id.clone(), new_bal, new_inv);
-					} else {
-						self.report_player(asker_id.clone());
-						panic!("failed to update {}'s balance/inventory", asker_id);
-					}
+	// Cancels the player's order in the clearing house once the cancel is published, or only
+	// reduces it if self-trade prevention decremented it
+	fn settle_cancel(&self, pu: &PlayerUpdate, errors: &mut Vec<SimError>) {
+		let result = match pu.is_decrement() {
+			true => self.update_player_order_vol(pu.payer_id.clone(), pu.payer_order_id, -pu.volume),
+			false => self.cancel_player_order(pu.payer_id.clone(), pu.payer_order_id),
+		};
+		if let Err(e) = result {
+			errors.push(e);
+		}
+	}
+
+	/// Removes the orders of the cancel updates from their players, whatever the market type.
+	/// Used for the orders that expired, returns an error for each that couldn't be cancelled.
+	pub fn settle_cancels(&self, updates: &[PlayerUpdate]) -> Vec<SimError> {
+		let mut errors = Vec::new();
+		for pu in updates.iter().filter(|pu| pu.cancel) {
+			self.settle_cancel(pu, &mut errors);
+		}
+		errors
+	}
+
+	/// Consumes the trade results from CDA limit order cross to update each player's state.
+	/// Returns an error for each player that couldn't be updated, the rest are still settled.
+	pub fn cda_cross_update(&self, results: TradeResults) -> Vec<SimError> {
+		let mut errors = Vec::new();
+		if let Some(player_updates) = results.cross_results {
+			for pu in player_updates {
+				if pu.cancel {
+					self.settle_cancel(&pu, &mut errors);
+					continue;
+				}
+
+				let volume = pu.volume;
+				if volume == 0.0 {
+					// no need to update players if no volume is to be traded
+					continue;
+				}
+				let payment = pu.price * volume;
+
+				// Update bidder: -bal, +inv
+				// NOTE: in CDA, the order's volume in orderbook is implicitly modified during crossing
+				if let Err(e) = self.settle_trade(pu.payer_id, pu.payer_order_id, pu.asset, -payment, volume) {
+					errors.push(e);
+				}
 
-					// NOTE: in CDA, the order's volume in orderbook is implicitly modified during crossing
-					self.update_player_order_vol(asker_id.clone(), pu.vol_filler_order_id, -volume).expect("Failed to update");
+				// Update asker: +bal, -inv
+				if let Err(e) = self.settle_trade(pu.vol_filler_id, pu.vol_filler_order_id, pu.asset, payment, -volume) {
+					errors.push(e);
 				}
 			}
 		}
+		errors
 	}
 
-	/// Consumes the trade results to update each player's state
-	pub fn fba_batch_update(&self, results: TradeResults) {
-		match results.cross_results {
-			None => return,
-			Some(player_updates) => {
-				for pu in player_updates {
-					if pu.cancel == true {
-						// Cancel the player's order in the clearing house
-						match self.cancel_player_order(pu.payer_id, pu.payer_order_id) {
-							Ok(()) => {},
-							Err(e) => println!("fba_batch_update: {:?}, {}", e, pu.payer_order_id),
-						}
-						continue;
-					}
-					// Update bidder: -bal, +inv
-					let bidder_id = pu.payer_id;
-					let volume = pu.volume;
-					if volume == 0.0 {
-						// no need to update players if no volume is to be traded
-						continue;
-					}
-					let payment = pu.price * volume;
-					if let Some((new_bal, new_inv)) = self.update_player(bidder_id.clone(), -payment, volume, UpdateReason::Transact) {
-						println!("Updated {}. bal=>{}, inv=>{}", bidder_id.clone(), new_bal, new_inv);
-					} else {
-						panic!("failed to update {}'s balance/inventory", bidder_id);
-					}
+	/// Consumes the trade results to update each player's state. Returns an error for
+	/// each player that couldn't be updated, the rest are still settled.
+	pub fn fba_batch_update(&self, results: TradeResults) -> Vec<SimError> {
+		let mut errors = Vec::new();
+		if let Some(player_updates) = results.cross_results {
+			for pu in player_updates {
+				if pu.cancel {
+					self.settle_cancel(&pu, &mut errors);
+					continue;
+				}
 
-					// Subtract interest from the bidder's order in the clearing house
-					self.update_player_order_vol(bidder_id.clone(), pu.payer_order_id, -volume).expect("Failed to update");
+				let volume = pu.volume;
+				if volume == 0.0 {
+					// no need to update players if no volume is to be traded
+					continue;
+				}
+				let payment = pu.price * volume;
 
-					// Update asker: +bal, -inv
-					let asker_id = pu.vol_filler_id;
-					if let Some((new_bal, new_inv)) = self.update_player(asker_id.clone(), payment, -volume, UpdateReason::Transact) {
-							println!("Updated {}. bal=>{}, inv=>{}", asker_id.clone(), new_bal, new_inv);
-					} else {
-						panic!("failed to update {}'s balance/inventory", bidder_id);
-					}
+				// Update bidder: -bal, +inv and subtract interest from their order
+				if let Err(e) = self.settle_trade(pu.payer_id, pu.payer_order_id, pu.asset, -payment, volume) {
+					errors.push(e);
+				}
 
-					// Subtract interest from the asker's order
-					self.update_player_order_vol(asker_id.clone(), pu.vol_filler_order_id, -volume).expect("Failed to update");
+				// Update asker: +bal, -inv and subtract interest from their order
+				if let Err(e) = self.settle_trade(pu.vol_filler_id, pu.vol_filler_order_id, pu.asset, payment, -volume) {
+					errors.push(e);
 				}
 			}
 		}
+		errors
 	}
 
 	/// Given the clearing price of the last batch, updates every involved player's state
 	// For every order that was in the order book at auction time, 
 	// Calculate player.demand(price) or player.supply(price)
-	pub fn flow_batch_update(&self, results: TradeResults) {
-		match results.uniform_price {
-			None => return,
-			Some(_clearing_price) => {
-				if let Some(player_updates) = results.cross_results {
-					let id_check = format!("N/A");
-					for pu in player_updates {
-						if pu.cancel == true {
-						// Cancel the player's order in the clearing house
-						match self.cancel_player_order(pu.payer_id, pu.payer_order_id) {
-							Ok(()) => {},
-							Err(e) => println!("flow_batch_update: {:?}, {}", e, pu.payer_order_id),
-						}
-						continue;
-					}
-						let volume = pu.volume;
-						let payment = pu.price * volume;
-
-						// This was an ask order, update accordingly
-						if pu.payer_id == id_check {
-							// Update asker: +bal, -inv
-							let asker_id = pu.vol_filler_id;
-							if let Some((_new_bal, _new_inv)) = self.update_player(asker_id.clone(), payment, -volume, UpdateReason::Transact) {
-								// println!("Updated {}. bal=>{}, inv=>{}", asker_id.clone(), _new_bal, _new_inv);
-							}
-							// Subtract vol from the trader's order
-							self.update_player_order_vol(asker_id.clone(), pu.vol_filler_order_id, -volume).expect("Failed to update");
-						} 
-						// This was a bid order, update accordingly
-						else {
-							// Update bidder: -bal, +inv
-							let bidder_id = pu.payer_id;
-							
-							if let Some((_new_bal, _new_inv)) = self.update_player(bidder_id.clone(), -payment, volume, UpdateReason::Transact) {
-								// println!("Updated {}. bal=>{}, inv=>{}", bidder_id.clone(), _new_bal, _new_inv);
-							}
-
-							// Subtract vol from the trader's order
-							self.update_player_order_vol(bidder_id.clone(), pu.payer_order_id, -volume).expect("Failed to update");
-						}
-					}
-						
+	pub fn flow_batch_update(&self, results: TradeResults) -> Vec<SimError> {
+		let mut errors = Vec::new();
+		if results.uniform_price.is_none() {
+			return errors;
+		}
+		if let Some(player_updates) = results.cross_results {
+			let id_check = format!("N/A");
+			for pu in player_updates {
+				if pu.cancel {
+					self.settle_cancel(&pu, &mut errors);
+					continue;
+				}
+				let volume = pu.volume;
+				let payment = pu.price * volume;
+
+				let settled = if pu.payer_id == id_check {
+					// This was an ask order, update asker: +bal, -inv
+					self.settle_trade(pu.vol_filler_id, pu.vol_filler_order_id, pu.asset, payment, -volume)
 				} else {
-					// No cross results, exit
-					return;
+					// This was a bid order, update bidder: -bal, +inv
+					self.settle_trade(pu.payer_id, pu.payer_order_id, pu.asset, -payment, volume)
+				};
+				if let Err(e) = settled {
+					errors.push(e);
+				}
+			}
+		}
+		errors
+	}
+
+	/// Settles a clear of the PortfolioMarket, where every trade has a buyer and a seller of
+	/// one asset. A portfolio order's quantity counts baskets rather than shares, so the legs
+	/// it trades don't reduce it, the decrement update for its baskets that follows them does.
+	pub fn portfolio_batch_update(&self, results: TradeResults) -> Vec<SimError> {
+		let mut errors = Vec::new();
+		let player_updates = match results.cross_results {
+			Some(player_updates) => player_updates,
+			None => return errors,
+		};
+		// The portfolio orders that traded in the batch
+		let portfolios: Vec<(String, u64)> = player_updates.iter()
+			.filter(|pu| pu.is_decrement())
+			.map(|pu| (pu.payer_id.clone(), pu.payer_order_id))
+			.collect();
+		for pu in player_updates {
+			if pu.cancel {
+				self.settle_cancel(&pu, &mut errors);
+				continue;
+			}
+			let payment = pu.price * pu.volume;
+
+			// Update bidder: -bal, +inv and asker: +bal, -inv
+			let sides = vec!((pu.payer_id, pu.payer_order_id, -payment, pu.volume),
+							 (pu.vol_filler_id, pu.vol_filler_order_id, payment, -pu.volume));
+			for (trader_id, order_id, bal_to_add, inv_to_add) in sides {
+				let is_leg = portfolios.iter().any(|(id, o_id)| *id == trader_id && *o_id == order_id);
+				let settled = match is_leg {
+					true => self.settle_position(trader_id, pu.asset, bal_to_add, inv_to_add),
+					false => self.settle_trade(trader_id, order_id, pu.asset, bal_to_add, inv_to_add),
+				};
+				if let Err(e) = settled {
+					errors.push(e);
 				}
 			}
 		}
+		errors
 	}
 
 	
-	/// Add a new order to the HashMap indexed by the player's id
-	pub fn new_order(&self, order: Order) -> Result<(), &'static str> {
+	/// Add a new order to the BTreeMap indexed by the player's id. Orders that can't
+	/// be matched are rejected before they reach the mempool.
+	pub fn new_order(&self, order: Order) -> Result<(), SimError> {
+		order.validate()?;
 		let mut players = self.players.lock().unwrap();
 		// Find the player by trader id and add their order
 		match players.get_mut(&order.trader_id) {
@@ -443,27 +507,28 @@ impl ClearingHouse {
 				player.add_order(order);
 				Ok(())
 			}
-			None => Err("Couldn't find trader to add order")
+			None => Err(SimError::PlayerNotFound { trader_id: order.trader_id })
 		}
 	}
 
-	/// Add a vector of new orders to the HashMap. This is preferable to new_order
+	/// Add a vector of new orders to the BTreeMap. This is preferable to new_order
 	/// as the mutex lock only has to be acquired once.
-	pub fn new_orders(&self, orders: Vec<Order>) -> Result<(), &'static str> {
+	pub fn new_orders(&self, orders: Vec<Order>) -> Result<(), SimError> {
 		let mut players = self.players.lock().unwrap();
 		for order in orders {
+			order.validate()?;
 			match players.get_mut(&order.trader_id) {
 				Some(player) => { 
 					player.add_order(order);
 				}
-				None => return Err("Couldn't find trader to add order"),
+				None => return Err(SimError::PlayerNotFound { trader_id: order.trader_id }),
 			}
 		}
 		Ok(())
 	}
 
-	/// Replaces a trader's order in the HashMap with the supplied 'order' 
-	pub fn update_player_order(&self, order: Order) -> Result<(), &'static str> {
+	/// Replaces a trader's order in the BTreeMap with the supplied 'order' 
+	pub fn update_player_order(&self, order: Order) -> Result<(), SimError> {
 		match self.cancel_player_order(order.trader_id.clone(), order.order_id) {
 			Ok(()) => {
 				self.new_order(order)
@@ -478,32 +543,30 @@ impl ClearingHouse {
 
 	/// Adds volume to a trader's order to reflect changes in the order book. 
 	/// If they updated volume <=0, the order is dropped from the player's list
-	pub fn update_player_order_vol(&self, trader_id: String, order_id: u64, vol_to_add: f64) -> Result<(), &'static str> {
+	pub fn update_player_order_vol(&self, trader_id: String, order_id: u64, vol_to_add: f64) -> Result<(), SimError> {
 		// println!("Updating {}'s order {} volume by {}", trader_id, order_id, vol_to_add);
 		// self.report_player(trader_id.clone());
 		let mut players = self.players.lock().unwrap();
 		if let Some(player) = players.get_mut(&trader_id) {
 			player.update_order_vol(order_id, vol_to_add)
+				.map_err(|_| SimError::PlayerOrderNotFound { trader_id, order_id })
 		} else {
-			return Err("Couldn't find trader to add order");
+			return Err(SimError::PlayerNotFound { trader_id });
 		}
 	}
 
-	/// Cancel's a trader's order in the HashMap with the supplied 'order'
-	pub fn cancel_player_order(&self, trader_id: String, order_id: u64) -> Result<(), &str> {
+	/// Cancel's a trader's order in the BTreeMap with the supplied 'order'
+	pub fn cancel_player_order(&self, trader_id: String, order_id: u64) -> Result<(), SimError> {
 		let mut players = self.players.lock().unwrap();
 		if let Some(player) = players.get_mut(&trader_id) {
-			let res = player.cancel_order(order_id);
-				match res {
-					Ok(_) => return Ok(()),
-					Err(e) => return Err(e),
-				}
+			player.cancel_order(order_id)
+				.map_err(|_| SimError::PlayerOrderNotFound { trader_id, order_id })
 		} else {
-			return Err("Couldn't find trader to cancel order");
+			return Err(SimError::PlayerNotFound { trader_id });
 		}
 	}
 
-	/// Removes the player from the ClearingHouse HashMap
+	/// Removes the player from the ClearingHouse BTreeMap
 	pub fn del_player(&self, trader_id: String) -> Option<()>{
 		match self.players.lock().unwrap().remove(&trader_id) {
 			Some(_p) => Some(()),
@@ -638,6 +701,8 @@ mod tests {
 	use super::*;
 	use std::sync::Arc;
 	use crate::players::maker::{Maker, MakerT};
+	use crate::exchange::exchange_logic::PlayerUpdate;
+	use crate::order::order::{OrderType, TradeType};
 
 	#[test]
 	fn test_ch() {
@@ -688,12 +753,23 @@ mod tests {
 		}
 	}
 
-	
+	#[test]
+	fn test_settle_skips_errors() {
+		let ch = ClearingHouse::new();
+		let mut inv = Investor::new(String::from("BUYER"));
+		let order = Order::new(String::from("BUYER"), OrderType::Enter, TradeType::Bid, ExchangeType::LimitOrder,
+							   0.0, 0.0, 100.0, 5.0, 5.0, 0.1);
+		let order_id = order.order_id;
+		inv.add_order(order);
+		ch.reg_investor(inv);
+
+		// The seller was never registered, but the buyer is still settled
+		let updates = vec!(PlayerUpdate::new(String::from("BUYER"), String::from("GHOST"), order_id, 7, 100.0, 2.0, false),
+						   PlayerUpdate::new(String::from("BUYER"), String::from("BUYER"), 99, 99, -9.99, -9.99, true));
+		let errors = ch.fba_batch_update(TradeResults::new(Some(100.0), 2.0, 2.0, Some(updates)));
+		assert_eq!(errors, vec!(SimError::PlayerNotFound { trader_id: String::from("GHOST") },
+								SimError::PlayerOrderNotFound { trader_id: String::from("BUYER"), order_id: 99 }));
+		assert_eq!(ch.get_bal_inv(String::from("BUYER")), Some((-200.0, 2.0)));
+		assert_eq!(ch.get_player_orders(&String::from("BUYER")).unwrap()[0].quantity, 3.0);
+	}
 }
-
-
-
-
-
-
-
@@ -1,15 +1,19 @@
 use crate::controller::{Task, State};
 use crate::order::order_book::Book;
-use crate::order::order::{Order};
-use crate::exchange::MarketType;
-use crate::utility::get_time;
+use crate::order::order::{Order, TradeType, TimeInForce, floor_to_grid};
+use crate::error::SimError;
+use crate::exchange::{SelfTradePrevention, AllocationRule, PriceRule};
+use crate::utility::{get_time, with_rng_stream, RngStream};
 use crate::log_order_book;
 
+use rand::seq::SliceRandom;
+
 use std::sync::{Mutex, Arc};
 use std::cmp::Ordering;
+use std::collections::HashMap;
 
+#[cfg(test)]
 use rayon::prelude::*;
-use log::{log, Level};
 
 
 
@@ -28,6 +32,7 @@ pub struct PlayerUpdate {
 	pub price: f64,
 	pub volume: f64,
 	pub cancel: bool,
+	pub asset: usize,		// The asset traded in a multi-asset market, 0 for the simulation's own books
 }
 
 impl PlayerUpdate {
@@ -41,58 +46,263 @@ impl PlayerUpdate {
 			price,
 			volume,
 			cancel,
+			asset: 0,
 		}
 	}
+
+	/// Sets the asset the update traded
+	pub fn with_asset(mut self, asset: usize) -> PlayerUpdate {
+		self.asset = asset;
+		self
+	}
+
+	/// The update that removes a cancelled, rejected or expired order from its player
+	pub fn cancel(trader_id: String, order_id: u64) -> PlayerUpdate {
+		PlayerUpdate::new(trader_id.clone(), trader_id, order_id, order_id, -9.99, -9.99, true)
+	}
+
+	/// The update that reduces an order by volume without trading, left by self-trade prevention.
+	/// It's a cancel of part of the order, so unlike a full cancel its volume is positive.
+	pub fn decrement(trader_id: String, order_id: u64, volume: f64) -> PlayerUpdate {
+		PlayerUpdate::new(trader_id.clone(), trader_id, order_id, order_id, -9.99, volume, true)
+	}
+
+	/// Whether this update only reduces its order rather than cancelling all of it
+	pub fn is_decrement(&self) -> bool {
+		self.cancel && self.volume > 0.0
+	}
 }
 
 #[derive(Debug, Clone)]
 pub struct TradeResults {
-	pub auction_type: MarketType,
 	pub uniform_price: Option<f64>,
 	pub agg_demand: f64,
 	pub agg_supply: f64,
 	pub cross_results: Option<Vec<PlayerUpdate>>,
+	pub self_trades: usize,		// The number of times a player's orders were stopped from trading together
+	pub price_interval: Option<(f64, f64)>,	// The lowest and highest prices the flow market clears at, if they differ
 }
 
 impl TradeResults {
-	pub fn new(a_t: MarketType, p: Option<f64>, agg_d: f64, agg_s: f64, player_updates: Option<Vec<PlayerUpdate>>) -> TradeResults {
+	pub fn new(p: Option<f64>, agg_d: f64, agg_s: f64, player_updates: Option<Vec<PlayerUpdate>>) -> TradeResults {
 		TradeResults {
-			auction_type: a_t,
 			uniform_price: p,
 			agg_demand: agg_d,
 			agg_supply: agg_s,
-			cross_results: player_updates
+			cross_results: player_updates,
+			self_trades: 0,
+			price_interval: None,
+		}
+	}
 
+	/// The price of the last trade in the results: the clearing price of an auction,
+	/// or the price of the last fill when a new CDA order crossed the book
+	pub fn last_trade_price(&self) -> Option<f64> {
+		if self.uniform_price.is_some() {
+			return self.uniform_price;
 		}
+		self.cross_results.as_ref()?.iter()
+			.rev()
+			.find(|pu| !pu.cancel && pu.volume > 0.0)
+			.map(|pu| pu.price)
 	}
 }
 
+/// The rules an FBA batch is crossed by, see frequent_batch_auction
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BatchRules {
+	pub stp: SelfTradePrevention,		// How a player's bid and ask that would trade together are handled
+	pub allocation: AllocationRule,		// How the volume at the marginal price level is shared out
+	pub price_rule: PriceRule,			// How the clearing price is picked
+	pub reference_price: Option<f64>,	// The last clearing price, for PriceRule::Reference
+	pub lot_size: f64,					// Pro-rata shares are whole multiples of lot_size, any size if 0.0
+}
+
+/// Where the flow market's aggregate demand meets its aggregate supply, see solve_flow_cross
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlowCross {
+	pub price: f64,		// The clearing price, the middle of the interval if a whole interval clears
+	pub demand: f64,	// The volume demanded at price
+	pub supply: f64,	// The volume supplied at price, equal to demand unless price is on a step
+	pub interval: Option<(f64, f64)>,	// The lowest and highest clearing prices if a whole interval clears
+}
+
+/// The price a call auction would uncross at if it ended now, published while it collects orders
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndicativePrice {
+	pub price: Option<f64>,	// The indicative clearing price, None while no orders cross
+	pub volume: f64,		// The volume that would trade at price
+	pub imbalance: f64,		// The demand less the supply at price, positive when buyers are left over
+}
+
 pub struct Auction {}
 
 // TODO replace prints with way to log tx's
 
 impl Auction {
+	// Compares the quantities of a new order and the best order of the opposite book
+	fn cmp_quantity(new_order: &Order, best: &Order) -> Result<Ordering, SimError> {
+		match new_order.quantity.partial_cmp(&best.quantity) {
+			Some(ordering) => Ok(ordering),
+			None => Err(SimError::InvalidOrder { order_id: best.order_id, reason: "quantity is NaN" }),
+		}
+	}
 
-	pub fn run_auction(bids: Arc<Book>, asks:Arc<Book>, m_t: MarketType) -> Option<TradeResults>{
-		match m_t {
-			MarketType::CDA => None,
-			MarketType::FBA => {
-				Auction::frequent_batch_auction(bids, asks)
+	/// Whether the new order is post-only and would trade with the best order of the opposite book
+	pub fn post_only_crosses(new_order: &Order, opposite: &Book) -> bool {
+		// An empty opposite book can't be crossed
+		let best = match opposite.peek_best_price() {
+			Some(best) if new_order.post_only => best,
+			_ => return false,
+		};
+		match new_order.trade_type {
+			TradeType::Bid => new_order.price >= best,
+			TradeType::Ask => new_order.price <= best,
+		}
+	}
+
+	// Whether a new CDA order must be rejected without trading: a post-only order that
+	// would cross the opposite book, or a FOK order the opposite book can't fill
+	fn reject_on_entry(new_order: &Order, opposite: &Book, stp: SelfTradePrevention) -> bool {
+		if new_order.post_only {
+			return Auction::post_only_crosses(new_order, opposite);
+		}
+		new_order.tif == TimeInForce::FOK && Auction::fillable_volume(new_order, opposite, stp) < new_order.quantity
+	}
+
+	// The volume a new CDA order can trade with the opposite book. Under self-trade prevention
+	// its player's own orders don't trade: CancelOldest skips them, while CancelNewest and
+	// DecrementBoth stop the new order filling once it meets the first of them.
+	fn fillable_volume(new_order: &Order, opposite: &Book, stp: SelfTradePrevention) -> f64 {
+		if stp == SelfTradePrevention::None {
+			return opposite.crossing_volume(new_order.price);
+		}
+		let crosses = |o: &Order| match new_order.trade_type {
+			TradeType::Bid => o.price <= new_order.price,
+			TradeType::Ask => o.price >= new_order.price,
+		};
+		let mut volume = 0.0;
+		for order in opposite.copy_orders().iter().take_while(|o| crosses(o)) {
+			if order.trader_id != new_order.trader_id {
+				volume += order.quantity;
+			} else if stp != SelfTradePrevention::CancelOldest {
+				break;
+			}
+		}
+		volume
+	}
+
+	// The unfilled part of a new CDA order rests in its book, unless it's IOC or FOK
+	// when it's cancelled instead
+	fn rest_or_cancel(book: &Book, new_order: Order, updates: &mut Vec<PlayerUpdate>) -> Result<(), SimError> {
+		if new_order.tif != TimeInForce::GTC {
+			updates.push(PlayerUpdate::cancel(new_order.trader_id, new_order.order_id));
+			return Ok(());
+		}
+		book.add_order(new_order)
+	}
+
+	// A resting order that was filled goes back in its book if it's an iceberg with more of its
+	// reserve to show. The replenished part queues at the back of its price level.
+	fn replenish_to_book(book: &Book, mut filled: Order) -> Result<(), SimError> {
+		if filled.replenish() {
+			book.add_order(filled)?;
+		}
+		Ok(())
+	}
+
+	// A resting order reduced by self-trade prevention goes back in its book keeping its priority.
+	// Once it's used up it's cancelled, unless it's an iceberg with more of its reserve to show.
+	fn decrement_resting(book: &Book, mut order: Order, volume: f64, updates: &mut Vec<PlayerUpdate>) -> Result<(), SimError> {
+		if order.quantity > 0.0 {
+			updates.push(PlayerUpdate::decrement(order.trader_id.clone(), order.order_id, volume));
+			return book.push_to_end(order);
+		}
+		if order.replenish() {
+			updates.push(PlayerUpdate::decrement(order.trader_id.clone(), order.order_id, volume));
+			return book.add_order(order);
+		}
+		updates.push(PlayerUpdate::cancel(order.trader_id, order.order_id));
+		Ok(())
+	}
+
+	// CDA helper: stops a new order trading with its player's best resting order, which was
+	// popped from book. The new order is the newest. Returns whether it can keep crossing.
+	fn prevent_self_trade(stp: SelfTradePrevention, new_order: &mut Order, mut resting: Order, book: &Book, 
+						  updates: &mut Vec<PlayerUpdate>) -> Result<bool, SimError> {
+		match stp {
+			SelfTradePrevention::None => {
+				book.push_to_end(resting)?;
+				Ok(true)
+			},
+			SelfTradePrevention::CancelNewest => {
+				book.push_to_end(resting)?;
+				updates.push(PlayerUpdate::cancel(new_order.trader_id.clone(), new_order.order_id));
+				Ok(false)
 			},
-			MarketType::KLF => {
-				Auction::bs_cross(bids, asks)
+			SelfTradePrevention::CancelOldest => {
+				updates.push(PlayerUpdate::cancel(resting.trader_id, resting.order_id));
+				Ok(true)
+			},
+			SelfTradePrevention::DecrementBoth => {
+				let volume = new_order.quantity.min(resting.quantity);
+				resting.quantity -= volume;
+				new_order.quantity -= volume;
+				Auction::decrement_resting(book, resting, volume, updates)?;
+				if new_order.quantity > 0.0 || new_order.replenish() {
+					updates.push(PlayerUpdate::decrement(new_order.trader_id.clone(), new_order.order_id, volume));
+					return Ok(true);
+				}
+				updates.push(PlayerUpdate::cancel(new_order.trader_id.clone(), new_order.order_id));
+				Ok(false)
 			},
 		}
 	}
 
-		
+	// FBA helper: stops a player's bid and ask trading with each other in the batch. The order
+	// that queued in its book last is the newest.
+	fn prevent_batch_self_trade(stp: SelfTradePrevention, mut bid: Order, mut ask: Order, bids: &Book, asks: &Book, 
+								updates: &mut Vec<PlayerUpdate>) -> Result<(), SimError> {
+		let bid_is_newest = bid.queued_at > ask.queued_at;
+		match stp {
+			SelfTradePrevention::None => {
+				bids.push_to_end(bid)?;
+				asks.push_to_end(ask)?;
+			},
+			SelfTradePrevention::CancelNewest|SelfTradePrevention::CancelOldest => {
+				let (cancelled, kept, book) = match bid_is_newest == (stp == SelfTradePrevention::CancelNewest) {
+					true => (bid, ask, asks),
+					false => (ask, bid, bids),
+				};
+				updates.push(PlayerUpdate::cancel(cancelled.trader_id, cancelled.order_id));
+				book.push_to_end(kept)?;
+			},
+			SelfTradePrevention::DecrementBoth => {
+				let volume = bid.quantity.min(ask.quantity);
+				bid.quantity -= volume;
+				ask.quantity -= volume;
+				Auction::decrement_resting(bids, bid, volume, updates)?;
+				Auction::decrement_resting(asks, ask, volume, updates)?;
+			},
+		}
+		Ok(())
+	}
+
 	/// ***CDA function***
 	/// Checks whether the new bid crosses the best ask. 
 	/// A new bid will cross at best ask.price iff best ask.price ≤ new bid.price
 	/// If the new order's quantity is not satisfied, the next best ask is checked.
-	pub fn calc_bid_crossing(bids: Arc<Book>, asks:Arc<Book>, mut new_bid: Order) -> Option<TradeResults> {
-		let mut results = TradeResults::new(MarketType::CDA, None, 0.0, 0.0, None);
+	/// An ask from the same player is handled by the self-trade prevention policy stp.
+	pub fn calc_bid_crossing(bids: Arc<Book>, asks:Arc<Book>, mut new_bid: Order, stp: SelfTradePrevention) -> Result<Option<TradeResults>, SimError> {
+		// Check the order can be matched before any book is modified
+		new_bid.validate()?;
+		let mut results = TradeResults::new(None, 0.0, 0.0, None);
 		let mut updates = Vec::<PlayerUpdate>::new();
+		if Auction::reject_on_entry(&new_bid, &asks, stp) {
+			updates.push(PlayerUpdate::cancel(new_bid.trader_id, new_bid.order_id));
+			results.cross_results = Some(updates);
+			return Ok(Some(results));
+		}
 		loop {
 			if new_bid.price >= asks.get_min_price() {
 				// buying for more than best ask is asking for -> tx @ ask price
@@ -100,14 +310,24 @@ impl Auction {
 				let mut best_ask = match asks.pop_from_end() {
 					Some(order) => order,
 					None => {
-						bids.add_order(new_bid).expect("Failed to add bid to book...");
+						Auction::rest_or_cancel(&bids, new_bid, &mut updates)?;
 						bids.find_new_max();
 						results.cross_results = Some(updates);
-						return Some(results);
+						return Ok(Some(results));
 					}
 				};
+				// The player's own ask is cancelled or reduced instead of trading
+				if stp != SelfTradePrevention::None && best_ask.trader_id == new_bid.trader_id {
+					results.self_trades += 1;
+					let keep_crossing = Auction::prevent_self_trade(stp, &mut new_bid, best_ask, &asks, &mut updates)?;
+					asks.find_new_min();
+					if keep_crossing {
+						continue;
+					}
+					break;
+				}
 				// Modify quantities of best ask and new bid
-				match new_bid.quantity.partial_cmp(&best_ask.quantity).expect("bad cmp") {
+				match Auction::cmp_quantity(&new_bid, &best_ask)? {
 					Ordering::Less => {
 						// This new bid will be satisfied and not be added to the book
 						best_ask.quantity -= new_bid.quantity;
@@ -126,9 +346,12 @@ impl Auction {
 							));
 
 						// Return the best ask to the book
-						asks.push_to_end(best_ask).expect("couldn't push");
+						asks.push_to_end(best_ask)?;
 
-						// This bid is done crossing, exit loop
+						// An iceberg bid shows more of its reserve and keeps crossing, else it's done
+						if new_bid.replenish() {
+							continue;
+						}
 						break;
 					},
 					Ordering::Greater => {
@@ -147,6 +370,8 @@ impl Auction {
 							best_ask.quantity,
 							false
 							));
+						// An iceberg ask shows more of its reserve at the back of its price level
+						Auction::replenish_to_book(&asks, best_ask)?;
 						
 						// Update the best ask price 
 						asks.find_new_min();
@@ -167,26 +392,30 @@ impl Auction {
 							new_bid.quantity,
 							false
 							));
+						Auction::replenish_to_book(&asks, best_ask)?;
 
 						// Update the best ask price 
 						asks.find_new_min();
-						// Don't return the bid to the book
+						// Don't return the bid to the book unless it's an iceberg with more to show
+						if new_bid.replenish() {
+							continue;
+						}
 						break;
 					}
 				}  
 			} else {
 				// New bid didn't cross, needs to be added to the book then exit
-				bids.add_order(new_bid.clone()).expect("Failed to add bid to book...");
+				Auction::rest_or_cancel(&bids, new_bid, &mut updates)?;
 				bids.find_new_max();
 				// log_order_book!(format!("{},{:?},{:?},",Order::order_to_csv(&new_bid),bids.orders,asks.orders));
 				results.cross_results = Some(updates);
-				return Some(results);
+				return Ok(Some(results));
 			}
 		}
 		// Done with loop, return the results
-		log_order_book!(format!("{},{:?},{:?},",Order::order_to_csv(&new_bid),bids.orders,asks.orders));
+		log_order_book!(format!("{},{:?},{:?},",Order::order_to_csv(&new_bid),bids.copy_orders(),asks.copy_orders()));
 		results.cross_results = Some(updates);
-		return Some(results);
+		return Ok(Some(results));
 	}
 
 
@@ -194,9 +423,17 @@ impl Auction {
 	/// Checks whether the new ask crosses the best bid. 
 	/// A new ask will cross at best bid.price iff best bid.price ≥ new ask.price
 	/// If the new order's quantity is not satisfied, the next best bid is checked.
-	pub fn calc_ask_crossing(bids: Arc<Book>, asks:Arc<Book>, mut new_ask: Order)  -> Option<TradeResults> {
-		let mut results = TradeResults::new(MarketType::CDA, None, 0.0, 0.0, None);
+	/// A bid from the same player is handled by the self-trade prevention policy stp.
+	pub fn calc_ask_crossing(bids: Arc<Book>, asks:Arc<Book>, mut new_ask: Order, stp: SelfTradePrevention)  -> Result<Option<TradeResults>, SimError> {
+		// Check the order can be matched before any book is modified
+		new_ask.validate()?;
+		let mut results = TradeResults::new(None, 0.0, 0.0, None);
 		let mut updates = Vec::<PlayerUpdate>::new();
+		if Auction::reject_on_entry(&new_ask, &bids, stp) {
+			updates.push(PlayerUpdate::cancel(new_ask.trader_id, new_ask.order_id));
+			results.cross_results = Some(updates);
+			return Ok(Some(results));
+		}
 		loop {
 			if new_ask.price <= bids.get_max_price() {
 				// asking for less than best bid willing to pay -> tx @ bid price
@@ -205,13 +442,23 @@ impl Auction {
 					Some(order) => order,
 					None => {
 						// There were no bids in the book, simply add this order to asks book
-						asks.add_order(new_ask).expect("Failed to add ask to book...");
+						Auction::rest_or_cancel(&asks, new_ask, &mut updates)?;
 						asks.find_new_min();
 						results.cross_results = Some(updates);
-						return Some(results);
+						return Ok(Some(results));
 					}
 				};
-				match new_ask.quantity.partial_cmp(&best_bid.quantity).expect("bad cmp") {
+				// The player's own bid is cancelled or reduced instead of trading
+				if stp != SelfTradePrevention::None && best_bid.trader_id == new_ask.trader_id {
+					results.self_trades += 1;
+					let keep_crossing = Auction::prevent_self_trade(stp, &mut new_ask, best_bid, &bids, &mut updates)?;
+					bids.find_new_max();
+					if keep_crossing {
+						continue;
+					}
+					break;
+				}
+				match Auction::cmp_quantity(&new_ask, &best_bid)? {
 					Ordering::Less => {
 						// This new ask will be satisfied and not be added to the book
 						best_bid.quantity -= new_ask.quantity;
@@ -230,9 +477,12 @@ impl Auction {
 							));
 
 						// Return the best bid to the book
-						bids.push_to_end(best_bid).expect("bad push");
+						bids.push_to_end(best_bid)?;
 
-						// This ask is done crossing, exit loop
+						// An iceberg ask shows more of its reserve and keeps crossing, else it's done
+						if new_ask.replenish() {
+							continue;
+						}
 						break;
 					},
 					Ordering::Greater => {
@@ -251,6 +501,8 @@ impl Auction {
 							best_bid.quantity,
 							false
 							));
+						// An iceberg bid shows more of its reserve at the back of its price level
+						Auction::replenish_to_book(&bids, best_bid)?;
 						
 						// Update the best bid price 
 						bids.find_new_max();
@@ -271,53 +523,339 @@ impl Auction {
 							new_ask.quantity,
 							false,
 							));
+						Auction::replenish_to_book(&bids, best_bid)?;
 						
 						// Update the best bid price 
 						bids.find_new_max();
-						// Don't return the ask to the book
+						// Don't return the ask to the book unless it's an iceberg with more to show
+						if new_ask.replenish() {
+							continue;
+						}
 						break;
 					}
 				}  
 			} else {
 				// New ask didn't cross, needs to be added to the book
-				asks.add_order(new_ask.clone()).expect("Failed to add ask to book...");
+				Auction::rest_or_cancel(&asks, new_ask, &mut updates)?;
 				asks.find_new_min();
 				// log_order_book!(format!("{},{:?},{:?},", Order::order_to_csv(&new_ask),bids.orders,asks.orders));
 
 				results.cross_results = Some(updates);
-				return Some(results);
+				return Ok(Some(results));
 			}
 		}
 		// Done with loop, return the results
-		log_order_book!(format!("{},{:?},{:?},", Order::order_to_csv(&new_ask),bids.orders,asks.orders));
+		log_order_book!(format!("{},{:?},{:?},", Order::order_to_csv(&new_ask),bids.copy_orders(),asks.copy_orders()));
 		results.cross_results = Some(updates);
-		return Some(results);
+		return Ok(Some(results));
 	}
 
 
 	
 
+	/// **FBA function**
+	/// Crosses the bids and asks books at a uniform clearing price, see batch_cross.
+	/// IOC and FOK orders, market orders among them, only take part in one batch, so what
+	/// is left of them in the books afterwards is cancelled and returned with the results.
+	pub fn frequent_batch_auction(bids: Arc<Book>, asks: Arc<Book>, rules: BatchRules) -> Result<Option<TradeResults>, SimError> {
+		let mut result = Auction::batch_cross(Arc::clone(&bids), Arc::clone(&asks), rules)?
+			.unwrap_or_else(|| TradeResults::new(None, 0.0, 0.0, None));
+
+		let mut cancels = Vec::<PlayerUpdate>::new();
+		for book in [&bids, &asks] {
+			for order in book.copy_orders().into_iter().filter(|o| o.tif != TimeInForce::GTC) {
+				book.cancel_order_by_id(order.order_id)?;
+				cancels.push(PlayerUpdate::cancel(order.trader_id, order.order_id));
+			}
+		}
+		if !cancels.is_empty() {
+			result.cross_results.get_or_insert_with(Vec::new).extend(cancels);
+		}
+		Ok(Some(result))
+	}
+
+	/// **FBA function**
+	/// The price frequent_batch_auction would clear the books at by the rules and the volume
+	/// it would trade, found by crossing copies of the books so they're left unchanged. The
+	/// imbalance is the volume bid at the price or above less the volume offered at or below
+	/// it, or all the bids less all the asks without a price. The allocation rule doesn't move
+	/// the price or volume, so the copies are always allocated by time priority.
+	pub fn indicative_batch(bids: &Book, asks: &Book, rules: BatchRules) -> Result<IndicativePrice, SimError> {
+		let rules = BatchRules { allocation: AllocationRule::TimePriority, ..rules };
+		let result = Auction::frequent_batch_auction(Arc::new(bids.snapshot()), Arc::new(asks.snapshot()), rules)?;
+		let (price, volume) = match result {
+			Some(result) => (result.uniform_price, result.agg_demand),
+			None => (None, 0.0),
+		};
+		let imbalance = match price {
+			Some(p) => bids.crossing_volume(p) - asks.crossing_volume(p),
+			None => bids.get_book_volume() - asks.get_book_volume(),
+		};
+		Ok(IndicativePrice { price, volume, imbalance })
+	}
+
+	// FBA helper: the FOK orders that wouldn't be completely filled at the clearing price.
+	// Orders fill in price-time priority up to the volume of the opposite book crossing cp.
+	fn unfillable_fok(bids: &Book, asks: &Book, cp: f64) -> Vec<Order> {
+		let mut killed = Vec::new();
+		for (book, opposite) in [(bids, asks), (asks, bids)] {
+			let available = opposite.crossing_volume(cp);
+			let mut filled_first = 0.0;
+			// Best order first
+			for order in book.copy_orders().into_iter().rev() {
+				let crosses = match order.trade_type {
+					TradeType::Bid => order.price >= cp,
+					TradeType::Ask => order.price <= cp,
+				};
+				if !crosses {
+					break;
+				}
+				filled_first += order.quantity;
+				if order.tif == TimeInForce::FOK && filled_first > available {
+					killed.push(order);
+				}
+			}
+		}
+		killed
+	}
+
+	// FBA helper: the clearing price picked by a price rule other than MergedBook, see PriceRule.
+	// None if no limit order prices a cross.
+	fn rule_price(bids: &Book, asks: &Book, rule: PriceRule, reference: Option<f64>) -> Option<f64> {
+		if rule == PriceRule::Midpoint {
+			return Auction::crossing_midpoint(bids, asks);
+		}
+		// The volume executed and the imbalance at each limit price that executes any volume
+		let mut prices: Vec<f64> = bids.copy_orders().iter().chain(asks.copy_orders().iter())
+			.filter(|o| !o.is_market())
+			.map(|o| o.price)
+			.collect();
+		prices.sort_by(|a, b| a.partial_cmp(b).expect("prices are validated"));
+		prices.dedup();
+		let candidates: Vec<(f64, f64, f64)> = prices.into_iter()
+			.map(|p| {
+				let (demand, supply) = (bids.crossing_volume(p), asks.crossing_volume(p));
+				(p, demand.min(supply), (demand - supply).abs())
+			})
+			.filter(|(_, volume, _)| *volume > 0.0)
+			.collect();
+
+		// Keeps the candidates with the best value of key, which are in ascending price order
+		let best = |candidates: Vec<(f64, f64, f64)>, key: &dyn Fn(&(f64, f64, f64)) -> f64| {
+			let top = candidates.iter().map(key).fold(f64::NEG_INFINITY, f64::max);
+			candidates.into_iter().filter(|c| key(c) == top).collect::<Vec<_>>()
+		};
+		let tied = match rule {
+			PriceRule::MinImbalance => best(best(candidates, &|c| -c.2), &|c| c.1),
+			PriceRule::Reference => best(best(candidates, &|c| c.1), &|c| -c.2),
+			_ => best(candidates, &|c| c.1),
+		};
+		let (low, high) = (tied.first()?.0, tied.last()?.0);
+		match (rule, reference) {
+			(PriceRule::Reference, Some(reference)) => Some(reference.max(low).min(high)),
+			_ => Some((low + high) / 2.0),
+		}
+	}
+
+	// FBA helper: pairs the best bids with the best asks like the batch does and returns the
+	// middle of the crossing interval, the prices that clear the paired volume. See PriceRule.
+	fn crossing_midpoint(bids: &Book, asks: &Book) -> Option<f64> {
+		let (bids, asks) = (bids.copy_orders(), asks.copy_orders());
+		let mut bids = bids.iter().rev().peekable();
+		let mut asks = asks.iter().rev().peekable();
+		let (mut bid_left, mut ask_left) = (0.0, 0.0);
+		let mut last_matched = None;
+		while let (Some(bid), Some(ask)) = (bids.peek(), asks.peek()) {
+			if bid.price < ask.price {
+				break;
+			}
+			if bid_left == 0.0 {
+				bid_left = bid.quantity;
+			}
+			if ask_left == 0.0 {
+				ask_left = ask.quantity;
+			}
+			let volume = Auction::min_float(&bid_left, &ask_left);
+			bid_left -= volume;
+			ask_left -= volume;
+			last_matched = Some((bid.price, ask.price));
+			if bid_left == 0.0 {
+				bids.next();
+			}
+			if ask_left == 0.0 {
+				asks.next();
+			}
+		}
+		let (last_bid, last_ask) = last_matched?;
+		// The next orders in line, which may be what's left of the last matched ones. What's
+		// left of a market order trades at any price so it leaves its end open.
+		let next_bid = bids.peek().filter(|o| !o.is_market()).map_or(f64::NEG_INFINITY, |o| o.price);
+		let next_ask = asks.peek().filter(|o| !o.is_market()).map_or(f64::INFINITY, |o| o.price);
+		let low = Auction::max_float(&last_ask, &next_bid);
+		let high = Auction::min_float(&last_bid, &next_ask);
+		match (low.is_finite(), high.is_finite()) {
+			(true, true) => Some((low + high) / 2.0),
+			(true, false) => Some(low),
+			(false, true) => Some(high),
+			(false, false) => None,
+		}
+	}
+
+	// FBA helper: shares the volume left for the marginal price level of the rationed side by
+	// the allocation rule. Each order there is swapped for a copy capped at its share, without
+	// a reserve to show, and returned with its share to be put back after the batch.
+	fn ration_marginal(bids: &Book, asks: &Book, cp: f64, allocation: AllocationRule, lot_size: f64) -> Result<Vec<(Order, f64)>, SimError> {
+		// Time priority is how the books already queue their orders
+		if allocation == AllocationRule::TimePriority {
+			return Ok(Vec::new());
+		}
+		let (demand, supply) = (bids.crossing_volume(cp), asks.crossing_volume(cp));
+		let (book, mut left) = match demand.partial_cmp(&supply) {
+			Some(Ordering::Greater) => (bids, supply),
+			Some(Ordering::Less) => (asks, demand),
+			_ => return Ok(Vec::new()),
+		};
+
+		// Whole levels fill in price priority until one holds more than the volume left
+		let mut orders = book.copy_orders();
+		orders.reverse();
+		let mut start = 0;
+		let marginal = loop {
+			let price = match orders.get(start) {
+				Some(order) => order.price,
+				None => return Ok(Vec::new()),
+			};
+			let end = orders[start..].iter().position(|o| o.price != price).map_or(orders.len(), |i| start + i);
+			let level_vol: f64 = orders[start..end].iter().map(|o| o.quantity).sum();
+			if level_vol > left {
+				break orders[start..end].to_vec();
+			}
+			left -= level_vol;
+			start = end;
+		};
+
+		let fills = Auction::allocate(&marginal, left, allocation, lot_size);
+		for order in marginal.iter() {
+			book.cancel_order_by_id(order.order_id)?;
+		}
+		// Queued back to front so the level keeps its order
+		for (order, fill) in marginal.iter().zip(fills.iter()).rev() {
+			if *fill > 0.0 {
+				let mut capped = order.clone();
+				capped.quantity = *fill;
+				capped.reserve = 0.0;
+				book.push_to_end(capped)?;
+			}
+		}
+		Ok(marginal.into_iter().zip(fills).collect())
+	}
+
+	// FBA helper: the share of volume of each order at the marginal level, in queue order.
+	// FOK orders must be filled completely so they're served first, if they fit. Pro-rata
+	// shares are rounded down to whole lots of lot_size.
+	fn allocate(marginal: &[Order], mut volume: f64, allocation: AllocationRule, lot_size: f64) -> Vec<f64> {
+		let mut fills = vec!(0.0; marginal.len());
+		for (fill, order) in fills.iter_mut().zip(marginal.iter()) {
+			if order.tif == TimeInForce::FOK && order.quantity <= volume {
+				*fill = order.quantity;
+				volume -= order.quantity;
+			}
+		}
+		let mut rest: Vec<usize> = (0..marginal.len()).filter(|i| marginal[*i].tif != TimeInForce::FOK).collect();
+		let total: f64 = rest.iter().map(|i| marginal[*i].quantity).sum();
+
+		if allocation == AllocationRule::ProRata && total > volume {
+			// Without lots the last order takes what's left so the shares add up to the volume
+			let mut shared = 0.0;
+			for (n, i) in rest.iter().enumerate() {
+				let share = match (lot_size > 0.0, n + 1 == rest.len()) {
+					(false, true) => volume - shared,
+					_ => floor_to_grid(volume * marginal[*i].quantity / total, lot_size),
+				};
+				fills[*i] = share.min(marginal[*i].quantity);
+				shared += fills[*i];
+			}
+			// Rounding down leaves fewer lots than orders, they go one each in time priority
+			let mut left = floor_to_grid(volume - shared, lot_size);
+			for i in rest.iter() {
+				if lot_size <= 0.0 || left < lot_size {
+					break;
+				}
+				if fills[*i] + lot_size <= marginal[*i].quantity {
+					fills[*i] += lot_size;
+					left -= lot_size;
+				}
+			}
+			return fills;
+		}
+		if allocation == AllocationRule::Random {
+			with_rng_stream(RngStream::Exchange, |rng| rest.shuffle(rng));
+		}
+		for i in rest {
+			fills[i] = marginal[i].quantity.min(volume);
+			volume -= fills[i];
+		}
+		fills
+	}
+
+	// FBA helper: puts the rationed orders back in their book in queue order, less what their
+	// capped copies traded. An iceberg that traded all it showed shows more of its reserve.
+	fn restore_marginal(book: &Book, rationed: Vec<(Order, f64)>) -> Result<(), SimError> {
+		let resting: HashMap<u64, f64> = book.copy_orders().iter().map(|o| (o.order_id, o.quantity)).collect();
+		for (mut order, fill) in rationed.into_iter().rev() {
+			let unfilled = match resting.get(&order.order_id) {
+				Some(quantity) => {
+					book.cancel_order_by_id(order.order_id)?;
+					*quantity
+				},
+				None => 0.0,
+			};
+			order.quantity -= fill - unfilled;
+			match order.quantity > 0.0 {
+				true => book.push_to_end(order)?,
+				false => Auction::replenish_to_book(book, order)?,
+			}
+		}
+		match book.book_type {
+			TradeType::Bid => book.find_new_max(),
+			TradeType::Ask => book.find_new_min(),
+		}
+		Ok(())
+	}
+
 	/// **FBA function**
 	/// Calculates the uniform clearing price for the orders in the bids and asks books.
-	/// Orders are sorted by price (descending for bids, ascending for asks).
-	/// Outputs the uniform clearing price if it exists and the total trade volume
-	pub fn frequent_batch_auction(bids: Arc<Book>, asks: Arc<Book>) -> Option<TradeResults> {
+	/// Orders are sorted by price (descending for bids, ascending for asks), market orders
+	/// first. Only limit orders set the price, with no limit orders there is no clearing.
+	/// The price is found by walking the merged books unless the rules pick another price rule.
+	/// Outputs the uniform clearing price if it exists and the total trade volume.
+	/// A player's bid and ask that would trade together are handled by the self-trade
+	/// prevention policy, after the clearing price is found. The last price level of the
+	/// side with more volume at the clearing price is shared out by the allocation rule.
+	pub fn batch_cross(bids: Arc<Book>, asks: Arc<Book>, rules: BatchRules) -> Result<Option<TradeResults>, SimError> {
+		let BatchRules { stp, allocation, .. } = rules;
 		// Check if auction necessary
 		if bids.len() == 0 || asks.len() == 0 {
-			let result = TradeResults::new(MarketType::FBA, None, 0.0, 0.0, None);
-			return Some(result);
+			let result = TradeResults::new(None, 0.0, 0.0, None);
+			return Ok(Some(result));
 		}
 
 		// There will be no crossings if best bid < best ask
 		if bids.get_max_price() < asks.get_min_price() {
-			let result = TradeResults::new(MarketType::FBA, None, 0.0, 0.0, None);
-			return Some(result);
+			let result = TradeResults::new(None, 0.0, 0.0, None);
+			return Ok(Some(result));
 		}
 
-		// Calc total ask volume 
+		// Calc total ask volume, market asks included
 		let ask_book_vol = asks.get_book_volume();
-		// Merge both books and sort in decreasing price order 
-		let merged_book = Book::merge_sort_books(Arc::clone(&bids), Arc::clone(&asks));
+		// Merge both books and sort in decreasing price order. Market orders are unpriced so
+		// they can't set the clearing price: market bids are demand at every price, counted
+		// before the first limit order, and market asks are supply at every price.
+		let merged_book: Vec<Order> = Book::merge_sort_books(Arc::clone(&bids), Arc::clone(&asks))
+			.into_iter()
+			.filter(|o| !o.is_market())
+			.collect();
+		let market_bid_vol = bids.sum_orders(|o| if o.is_market() { o.quantity } else { 0.0 });
 
 		// Initialize the min and max prices seen while traversing the merged book
 		let mut max_seen_price = MIN_PRICE;
@@ -325,16 +863,18 @@ impl Auction {
 		let mut clearing_price: Option<f64> = None;
 
 		// Initialize vars to track volume seen while traversing the merged book
-		let mut seen_vol = 0.0;
+		let mut seen_vol = market_bid_vol;
+		let mut crossed = false;
+		let mut seen_orders = 0;
 		// let mut prev_seen_vol = 0.0;
 		let mut prev_order_price = 0.0;	// is 0.0 acceptable?
 		let mut cur_order_price = 0.0;
 
 		// Iterate through descending orders. Sum volume of each order and track the min and max seen prices
-		let orders = merged_book.orders.lock().expect("ERROR: Couldn't lock book to sort");
 		println!("Calculating clearing price...");
-		for order in orders.iter() {
+		for order in merged_book.iter() {
 			cur_order_price = order.price;
+			seen_orders += 1;
 			// Process best prices
 			if cur_order_price > max_seen_price {
 				max_seen_price = cur_order_price;
@@ -349,6 +889,7 @@ impl Auction {
 			println!("Checking price:{}, seen_vol:{} / ask_vol:{}", cur_order_price, seen_vol, ask_book_vol);
 			if seen_vol >= ask_book_vol {
 				// NOTE: darrell's implementation didn't include <=, just <, but this fixed horizontal cross edge case
+				crossed = true;
 				break;
 			}
 			// Track the price of the last traversed order
@@ -357,7 +898,7 @@ impl Auction {
 
 		// If we have still not found a max or min seen price, loop until we do:
 		if max_seen_price == MIN_PRICE || min_seen_price == MAX_PRICE {
-			for order in orders.iter() {
+			for order in merged_book.iter() {
 				cur_order_price = order.price;
 				// Process best prices
 				if cur_order_price > max_seen_price {
@@ -380,7 +921,12 @@ impl Auction {
 		} 
 		// We perfectly matched volume
 		else if seen_vol == ask_book_vol {	
-			if prev_order_price == MAX_PRICE && MIN_PRICE < cur_order_price && cur_order_price < MAX_PRICE {
+			if seen_orders == 1 {
+				// The market bids and the first order matched the volume, there's no previous price
+				clearing_price = Some(cur_order_price);
+			}
+
+			else if prev_order_price == MAX_PRICE && MIN_PRICE < cur_order_price && cur_order_price < MAX_PRICE {
 				// The current order crossed, so use this price
 				clearing_price = Some(cur_order_price);
 			} 
@@ -403,23 +949,52 @@ impl Auction {
 		else if seen_vol > ask_book_vol {
 			clearing_price = Some(Auction::max_float(&cur_order_price, &min_seen_price));
 		}
+		// The market asks outweigh the bids at every limit price, so every bid trades at the lowest
+		else if !crossed {
+			clearing_price = Some(min_seen_price);
+		}
+
+		if rules.price_rule != PriceRule::MergedBook {
+			clearing_price = Auction::rule_price(&bids, &asks, rules.price_rule, rules.reference_price);
+		}
 
 		println!("Clearing price: {:?}", clearing_price);
 
+		// Kill the FOK orders that can't be completely filled, then find the clearing price without them
+		if let Some(cp) = clearing_price {
+			let killed = Auction::unfillable_fok(&bids, &asks, cp);
+			if !killed.is_empty() {
+				let mut updates = Vec::<PlayerUpdate>::new();
+				for order in killed {
+					match order.trade_type {
+						TradeType::Bid => bids.cancel_order_by_id(order.order_id)?,
+						TradeType::Ask => asks.cancel_order_by_id(order.order_id)?,
+					}
+					updates.push(PlayerUpdate::cancel(order.trader_id, order.order_id));
+				}
+				let mut result = Auction::batch_cross(bids, asks, rules)?.unwrap_or_else(|| TradeResults::new(None, 0.0, 0.0, None));
+				updates.extend(result.cross_results.take().unwrap_or_default());
+				result.cross_results = Some(updates);
+				return Ok(Some(result));
+			}
+		}
 		
 
 		// Initialize updates to send to ClearingHouse
 		let mut updates = Vec::<PlayerUpdate>::new();
 
-		let mut result = TradeResults::new(MarketType::FBA, clearing_price, 0.0, 0.0, None);
+		let mut result = TradeResults::new(clearing_price, 0.0, 0.0, None);
 
 		let mut cancel_bids = Vec::<u64>::new();
 		let mut _vol_filled = 0.0;
 
 		// If we have a clearing price, calculate which orders transact and at what volume, otherwise exit returning results
 		match clearing_price {
-			None => return Some(result),
+			None => return Ok(Some(result)),
 			Some(cp) => {
+				// Cap the orders sharing the marginal price level at their allocations
+				let rationed = Auction::ration_marginal(&bids, &asks, cp, allocation, rules.lot_size)?;
+
 				// Lock bids book 
 				// let mut bids_descending = bids.orders.lock().expect("ERROR: Couldn't lock book");
 				
@@ -437,7 +1012,7 @@ impl Auction {
 					let mut cur_ask = match asks.pop_from_end() {
 						Some(ask) => ask,
 						None => {
-							bids.push_to_end(cur_bid).expect("Couldn't push order");
+							bids.push_to_end(cur_bid)?;
 							break;
 						},
 					};
@@ -448,12 +1023,18 @@ impl Auction {
 						println!("breaking out of loop...cp={}, bp={}, ap={}", cp, bid_price, ask_price);
 						// A bid with price < cp will not tx, same with ask with price > cp
 						// Return the popped ask to the book before exiting
-						bids.push_to_end(cur_bid).expect("Couldn't push order");
-						asks.push_to_end(cur_ask).expect("Couldn't push order");
+						bids.push_to_end(cur_bid)?;
+						asks.push_to_end(cur_ask)?;
 						break;
 					}
+					// The player's own bid and ask are cancelled or reduced instead of trading
+					if stp != SelfTradePrevention::None && cur_bid.trader_id == cur_ask.trader_id {
+						result.self_trades += 1;
+						Auction::prevent_batch_self_trade(stp, cur_bid, cur_ask, &bids, &asks, &mut updates)?;
+						continue;
+					}
 					// The current bid will exchange at clearing price with current ask
-					match cur_bid.quantity.partial_cmp(&cur_ask.quantity).expect("bad cmp") {
+					match Auction::cmp_quantity(&cur_bid, &cur_ask)? {
 						Ordering::Less => {
 							println!("cur bid: {} volume < cur ask volume {}", cur_bid.order_id, cur_ask.order_id);
 							// cur_bid's interest is less than the cur_ask's volume
@@ -467,10 +1048,11 @@ impl Auction {
 											  cur_bid.order_id, 
 											  cur_ask.order_id.clone(), 
 											  cp, trade_amount, false));
-							// Cancel the bid from the book
+							// Cancel the bid from the book, an iceberg bid shows more of its reserve
 							cancel_bids.push(cur_bid.order_id);
+							Auction::replenish_to_book(&bids, cur_bid)?;
 							// Return the ask for next loop iteration
-							asks.push_to_end(cur_ask).expect("Couldn't push order");
+							asks.push_to_end(cur_ask)?;
 						},
 						Ordering::Greater => {
 							println!("cur bid: {} volume > cur ask volume {}", cur_bid.order_id, cur_ask.order_id);
@@ -486,8 +1068,10 @@ impl Auction {
 											  cur_ask.order_id, 
 											  cp, trade_amount, false));
 							// Cancel ask order since was filled (Simply don't add it back to the book...)
+							// unless it's an iceberg with more of its reserve to show
+							Auction::replenish_to_book(&asks, cur_ask)?;
 							// This bid's interest is not fully filled so return it to be used again:
-							bids.push_to_end(cur_bid).expect("Couldn't push order");
+							bids.push_to_end(cur_bid)?;
 						},
 						Ordering::Equal => {
 							println!("cur bid: {} volume = cur ask volume {}", cur_bid.order_id, cur_ask.order_id);
@@ -507,9 +1091,20 @@ impl Auction {
 							cancel_bids.push(cur_bid.order_id);
 
 							// Cancel ask order since was filled (Simply don't add it back to the book...)
+							// The icebergs show more of their reserves
+							Auction::replenish_to_book(&bids, cur_bid)?;
+							Auction::replenish_to_book(&asks, cur_ask)?;
 						}
 					}
 				}
+				// Put the rationed orders back with what they have left
+				if let Some((order, _)) = rationed.first() {
+					let book = match order.trade_type {
+						TradeType::Bid => &bids,
+						TradeType::Ask => &asks,
+					};
+					Auction::restore_marginal(book, rationed)?;
+				}
 			}
 		}
 		// Execute bid cleaning outside of scope where bids were borrwed so no deadlock.
@@ -523,29 +1118,21 @@ impl Auction {
 		result.agg_supply = _vol_filled;
 		// Add all of the PlayerUpdates to our TradeResults
 		result.cross_results = Some(updates);
-		return Some(result)
+		return Ok(Some(result))
 	}
 
 
 	/// Helper function for Flow Order clearing price calculation: bs_cross
-	/// Iterate over each order in parallel and compute the aggregate supply and
-	/// demand at a certain price.
-	pub fn calc_aggs(p: f64, bids: Arc<Book>, asks: Arc<Book>) -> (f64, f64) {
-		let bids = bids.orders.lock().expect("ERROR: No bids book");
-		let asks = asks.orders.lock().expect("ERROR: No asks book");
-
+	/// Iterate over each order and compute the aggregate supply and demand at a
+	/// certain price over a batch lasting seconds. Summed sequentially so the float
+	/// sums (and clearing prices) are identical every run; experiments already run
+	/// their trials in parallel.
+	pub fn calc_aggs(p: f64, bids: Arc<Book>, asks: Arc<Book>, seconds: f64) -> (f64, f64) {
 		// Calculate cummulative demand schedule trade volume
-		let agg_demand: f64 = bids.par_iter()
-		    .map(|order| {
-	    		order.calc_flow_demand(p)
-		    }).sum();
-
+		let agg_demand: f64 = bids.sum_orders(|order| order.calc_flow_demand(p, seconds));
 
 		// Calculate cummulative supply schedule trade volume
-		let agg_supply: f64 = asks.par_iter()
-		    .map(|order| {
-	    		order.calc_flow_supply(p)
-		    }).sum();
+		let agg_supply: f64 = asks.sum_orders(|order| order.calc_flow_supply(p, seconds));
 
 		(agg_demand, agg_supply)
 	}
@@ -554,8 +1141,9 @@ impl Auction {
 	/// **KLF function**
 	/// Calculates the market clearing price from the bids and asks books. Uses a 
 	/// binary search to find the intersection point between the aggregates supply and 
-	/// demand curves. 
-	pub fn bs_cross(bids: Arc<Book>, asks: Arc<Book>) -> Option<TradeResults> {
+	/// demand curves. The flow market clears with the exact flow_cross, this approximation
+	/// is kept to compare against.
+	pub fn bs_cross(bids: Arc<Book>, asks: Arc<Book>, seconds: f64) -> Result<Option<TradeResults>, SimError> {
 		// get_price_bounds obtains locks on the book's prices
 	    let (mut left, mut right) = Auction::get_price_bounds(Arc::clone(&bids), Arc::clone(&asks));
 	    let mut curr_iter = 0;
@@ -565,7 +1153,7 @@ impl Auction {
 	    	// Find a midpoint with the correct price tick precision
 	    	let index: f64 = (left + right) / 2.0;
 	    	// Calculate the aggregate supply and demand at this price
-	    	let (dem, sup) = Auction::calc_aggs(index, Arc::clone(&bids), Arc::clone(&asks));
+	    	let (dem, sup) = Auction::calc_aggs(index, Arc::clone(&bids), Arc::clone(&asks), seconds);
 	    	// println!("price_index: {}, dem: {}, sup: {}", index, dem, sup);
 
 	    	if Auction::greater_than_e(&dem, &sup) {  		// dev > sup
@@ -576,42 +1164,160 @@ impl Auction {
 	    		right = index;
 	    	} else {
 	    		println!("Found cross at: {}\n", index);
-	    		let mut result = TradeResults::new(MarketType::KLF, Some(index), dem, sup, None);
+	    		let mut result = TradeResults::new(Some(index), dem, sup, None);
 	    		// Push the player updates for updating the player's state in ClearingHouse
-	    		let player_updates = Auction::flow_player_updates(index, Arc::clone(&bids), Arc::clone(&asks));
+	    		let player_updates = Auction::flow_player_updates(index, Arc::clone(&bids), Arc::clone(&asks), seconds)?;
 	    		result.cross_results = Some(player_updates);
-	    		return Some(result);
+	    		return Ok(Some(result));
 	    	}
 
 	    	if curr_iter == MAX_ITERS {
 	    		println!("Trouble finding cross in max iterations, got: {}", index);
-	    		let mut result = TradeResults::new(MarketType::KLF, Some(index), dem, sup, None);
+	    		let mut result = TradeResults::new(Some(index), dem, sup, None);
 	    		// Push the player updates for updating the player's state in ClearingHouse
-	    		let player_updates = Auction::flow_player_updates(index, Arc::clone(&bids), Arc::clone(&asks));
+	    		let player_updates = Auction::flow_player_updates(index, Arc::clone(&bids), Arc::clone(&asks), seconds)?;
 	    		result.cross_results = Some(player_updates);
-	    		return Some(result);
+	    		return Ok(Some(result));
 	    	}
 	    }
-	    None
+	    Ok(None)
 	}
 
-	pub fn klf_clearing(bids: Arc<Book>, asks: Arc<Book>) -> Option<f64> {
-		let best_bid_p = bids.get_max_phigh();
-		let best_ask_p = asks.get_min_plow();
-
-		// Demand and supply at the best bid price
-		let (dem_bb, sup_bb) = Auction::calc_aggs(best_bid_p, Arc::clone(&bids), Arc::clone(&asks));
-
-		// Demand and supply at the best ask price
-		let (dem_ba, sup_ba) = Auction::calc_aggs(best_ask_p, Arc::clone(&bids), Arc::clone(&asks));
+	/// ***KLF function***
+	/// Clears the flow market at the exact cross of aggregate demand and supply, see
+	/// solve_flow_cross, and trades every flow order at the clearing price for a batch
+	/// lasting seconds. Orders keep trading in later batches until their quantity is used up.
+	pub fn flow_cross(bids: Arc<Book>, asks: Arc<Book>, seconds: f64) -> Result<Option<TradeResults>, SimError> {
+		let cross = match Auction::solve_flow_cross(&bids, &asks, seconds) {
+			Some(cross) => cross,
+			None => return Ok(None),
+		};
+		let mut result = TradeResults::new(Some(cross.price), cross.demand, cross.supply, None);
+		result.price_interval = cross.interval;
+		// Push the player updates for updating the player's state in ClearingHouse
+		result.cross_results = Some(Auction::flow_player_updates(cross.price, bids, asks, seconds)?);
+		Ok(Some(result))
+	}
 
-		let order_imbalance = (dem_bb - sup_bb) / (dem_bb - sup_bb + sup_ba - dem_ba);
+	/// ***KLF function***
+	/// The price flow_cross would clear the books at for a batch lasting seconds, with the
+	/// volume that would trade and the demand less the supply at it, which is only nonzero on
+	/// a step. Without a cross the imbalance is all the bids less all the asks.
+	pub fn indicative_flow(bids: &Book, asks: &Book, seconds: f64) -> IndicativePrice {
+		match Auction::solve_flow_cross(bids, asks, seconds) {
+			Some(cross) => IndicativePrice {
+				price: Some(cross.price),
+				volume: cross.demand.min(cross.supply),
+				imbalance: cross.demand - cross.supply,
+			},
+			None => IndicativePrice { price: None, volume: 0.0, imbalance: bids.get_book_volume() - asks.get_book_volume() },
+		}
+	}
 
-		let clearing_price = best_bid_p + order_imbalance * (best_ask_p - best_bid_p);
+	/// ***KLF function***
+	/// Solves for the price where the aggregate flow demand meets the aggregate flow supply
+	/// over a batch lasting seconds. Each flow order trades linearly between its p_low and
+	/// p_high, up to u_max shares per second, until it saturates at its quantity, so the
+	/// excess demand D(p) - S(p) is piecewise linear and non-increasing with breakpoints at
+	/// every p_low, p_high and saturation price. The breakpoints are sorted and swept once,
+	/// keeping the intercept and slope of the excess demand between them, and the segment
+	/// where it reaches zero is solved in closed form.
+	/// If it's zero over a whole interval, e.g. when no bid overlaps an ask and nothing trades,
+	/// the interval is returned and the price is its midpoint. An order with p_low = p_high is
+	/// a step, if the excess demand jumps past zero there the price is the step's and demand
+	/// and supply differ. Returns None if either book is empty.
+	pub fn solve_flow_cross(bids: &Book, asks: &Book, seconds: f64) -> Option<FlowCross> {
+		let (bids, asks) = (bids.copy_orders(), asks.copy_orders());
+		if bids.is_empty() || asks.is_empty() {
+			return None;
+		}
+		let zero = |z: f64| z.abs() < EPSILON;
+
+		let mut breakpoints: Vec<(f64, f64, f64, f64)> = bids.iter().chain(asks.iter()).flat_map(|o| Auction::flow_breakpoints(o, seconds)).collect();
+		breakpoints.sort_by(|a, b| a.0.partial_cmp(&b.0).expect("flow order prices are validated"));
+
+		// Below every breakpoint the bids trade all they can and the asks nothing, between
+		// two breakpoints the excess demand is intercept + slope * p
+		let mut intercept: f64 = bids.iter().map(|o| o.quantity.min(o.u_max * seconds)).sum();
+		let mut slope = 0.0;
+
+		// Walk up the breakpoints, checking the excess demand just below, at and just above
+		// each, until it turns negative, tracking the lowest and highest prices where it's zero
+		let (mut low, mut high) = (None, None);
+		let mut step = None;
+		let mut last: Option<(f64, f64)> = None;
+		let mut i = 0;
+		'walk: while i < breakpoints.len() {
+			let b = breakpoints[i].0;
+			let (mut d_slope, mut drop_at, mut drop_above) = (0.0, 0.0, 0.0);
+			while i < breakpoints.len() && breakpoints[i].0 == b {
+				d_slope += breakpoints[i].1;
+				drop_at += breakpoints[i].2;
+				drop_above += breakpoints[i].3;
+				i += 1;
+			}
+			let below = intercept + slope * b;
+			intercept -= drop_at;
+			let at = intercept + slope * b;
+			// The slope changes without moving the excess demand at b
+			slope += d_slope;
+			intercept -= d_slope * b + drop_above;
+			let above = intercept + slope * b;
+
+			for z in [below, at, above] {
+				if zero(z) {
+					low.get_or_insert(b);
+					high = Some(b);
+				} else if z < 0.0 {
+					// The excess demand fell through zero inside the segment up to b
+					if let Some((p, z_last)) = last {
+						if p < b && z_last > 0.0 && !zero(z_last) {
+							let root = p + z_last / (z_last - z) * (b - p);
+							low.get_or_insert(root);
+							high = Some(root);
+						}
+					}
+					if low.is_none() {
+						step = Some(b);
+					}
+					break 'walk;
+				}
+				last = Some((b, z));
+			}
+		}
 
-		println!("Clearing Price new way: {:?}, w:{}, pb:{}, pa:{}", clearing_price, order_imbalance, best_bid_p, best_ask_p);
+		let (price, interval) = match (low, high) {
+			(Some(low), Some(high)) if high - low > EPSILON => ((low + high) / 2.0, Some((low, high))),
+			(Some(low), Some(_)) => (low, None),
+			// The excess demand stepped past zero, or stays positive up to the last breakpoint
+			_ => (step.or_else(|| breakpoints.last().map(|b| b.0))?, None),
+		};
+		let demand = bids.iter().map(|o| o.calc_flow_demand(price, seconds)).sum::<f64>();
+		let supply = asks.iter().map(|o| o.calc_flow_supply(price, seconds)).sum::<f64>();
+		Some(FlowCross { price, demand, supply, interval })
+	}
 
-		Some(clearing_price)
+	// KLF helper: how a flow order moves the excess demand at its breakpoints in a batch lasting
+	// seconds, its p_low, p_high and the price where it saturates at its quantity. Each is the
+	// price, the change in slope there and the drop in excess demand at it and just above it.
+	// A step bid trades all it can up to its price and a step ask from its price.
+	fn flow_breakpoints(order: &Order, seconds: f64) -> Vec<(f64, f64, f64, f64)> {
+		let volume = order.quantity.min(order.u_max * seconds);
+		if order.p_high <= order.p_low {
+			return match order.trade_type {
+				TradeType::Bid => vec!((order.p_low, 0.0, 0.0, volume)),
+				TradeType::Ask => vec!((order.p_low, 0.0, volume, 0.0)),
+			};
+		}
+		let rate = order.u_max * seconds / (order.p_high - order.p_low);
+		let width = match volume < order.u_max * seconds {
+			true => volume / rate,
+			false => order.p_high - order.p_low,
+		};
+		match order.trade_type {
+			TradeType::Bid => vec!((order.p_high - width, -rate, 0.0, 0.0), (order.p_high, rate, 0.0, 0.0)),
+			TradeType::Ask => vec!((order.p_low, -rate, 0.0, 0.0), (order.p_low + width, rate, 0.0, 0.0)),
+		}
 	}
 
 
@@ -626,7 +1332,7 @@ impl Auction {
 	    		*state = State::Auction;
 	    	}
 	    	println!("Starting Auction @{:?}", get_time());
-	    	if let Some(result) = Auction::frequent_batch_auction(Arc::clone(&bids), Arc::clone(&asks)) {
+	    	if let Ok(Some(result)) = Auction::frequent_batch_auction(Arc::clone(&bids), Arc::clone(&asks), BatchRules::default()) {
 	    		println!("Found Cross at @{:?} \nP = {}\n", get_time(), result.uniform_price.unwrap());
 	    	} else {
 	    		println!("Error, Cross not found\n");
@@ -640,15 +1346,15 @@ impl Auction {
 		}, duration)
 	}
 
-	// helper function to calculate the changes to each player following the flow auction
-	pub fn flow_player_updates(clearing_price: f64, bids: Arc<Book>, asks: Arc<Book>) -> Vec<PlayerUpdate> {
+	// helper function to calculate the changes to each player following the flow auction,
+	// each order trades its rate at the clearing price for the seconds the batch lasted
+	pub fn flow_player_updates(clearing_price: f64, bids: Arc<Book>, asks: Arc<Book>, seconds: f64) -> Result<Vec<PlayerUpdate>, SimError> {
 		let mut updates = Vec::<PlayerUpdate>::new();
 		let mut cancel_bids = Vec::<u64>::new();
 		let mut cancel_asks = Vec::<u64>::new();
 		{
-			let mut bid_orders = bids.orders.lock().expect("couldn't lock");
-			for bid in bid_orders.iter_mut() {
-				let v = bid.calc_flow_demand(clearing_price);
+			bids.update_orders(|bid| {
+				let v = bid.calc_flow_demand(clearing_price, seconds);
 				// Generate the PlayerUpdate for the ClearingHouse to update the player if they transact at clearing price
 				if v > 0.0 {
 					updates.push(PlayerUpdate::new(
@@ -668,12 +1374,11 @@ impl Auction {
 						cancel_bids.push(bid.order_id);
 					}
 				}
-			}
+			});
 		}
 		{
-			let mut ask_orders = asks.orders.lock().expect("couldn't lock");
-			for ask in ask_orders.iter_mut() {
-				let v = ask.calc_flow_supply(clearing_price);
+			asks.update_orders(|ask| {
+				let v = ask.calc_flow_supply(clearing_price, seconds);
 				// Generate the PlayerUpdate for the ClearingHouse to update the player if they transact at clearing price
 				if v > 0.0 {
 					updates.push(PlayerUpdate::new(
@@ -693,20 +1398,20 @@ impl Auction {
 						cancel_asks.push(ask.order_id);
 					}
 				}
-			}
+			});
 		}
 
 		// println!("cancelling bids:{:?} and asks:{:?}", cancel_bids, cancel_asks);
 
 		// Cancel all of the orders that have been fully filled
 		for id in cancel_bids {
-			bids.cancel_order_by_id(id).expect("Error cancelling filled flow order");
+			bids.cancel_order_by_id(id)?;
 		}
 		for id in cancel_asks {
-			asks.cancel_order_by_id(id).expect("Error cancelling filled flow order");
+			asks.cancel_order_by_id(id)?;
 		}
 
-		updates
+		Ok(updates)
 	}
 
 	pub fn get_price_bounds(bids: Arc<Book>, asks: Arc<Book>) -> (f64, f64) {		
@@ -809,3 +1514,101 @@ fn test_float_helpers() {
 
 
 
+
+#[cfg(test)]
+fn flow_books(bids: &[(f64, f64, f64, f64)], asks: &[(f64, f64, f64, f64)]) -> (Book, Book) {
+	use crate::order::order::{OrderType, ExchangeType};
+	// Each flow order is (p_low, p_high, quantity, u_max)
+	let book = |trade_type: TradeType, orders: &[(f64, f64, f64, f64)]| {
+		let book = Book::new(trade_type.clone());
+		for (p_low, p_high, quantity, u_max) in orders {
+			book.add_order(Order::new(String::from("FLOW"), OrderType::Enter, trade_type.clone(), ExchangeType::FlowOrder,
+									  *p_low, *p_high, 0.0, *quantity, *u_max, 0.1)).unwrap();
+		}
+		book
+	};
+	(book(TradeType::Bid, bids), book(TradeType::Ask, asks))
+}
+
+#[test]
+fn test_solve_flow_cross_point() {
+	// D(p) = 100 - p meets S(p) = p
+	let (bids, asks) = flow_books(&[(0.0, 100.0, 100.0, 100.0)], &[(0.0, 100.0, 100.0, 100.0)]);
+	let cross = Auction::solve_flow_cross(&bids, &asks, 1.0).unwrap();
+	assert_eq!(cross, FlowCross { price: 50.0, demand: 50.0, supply: 50.0, interval: None });
+
+	// The bid saturates at 30 shares below 70, so S(p) = p meets it at 30
+	let (bids, asks) = flow_books(&[(0.0, 100.0, 30.0, 100.0)], &[(0.0, 100.0, 100.0, 100.0)]);
+	let cross = Auction::solve_flow_cross(&bids, &asks, 1.0).unwrap();
+	assert!(Auction::equal_e(&cross.price, &30.0) && Auction::equal_e(&cross.demand, &30.0));
+	assert_eq!(cross.interval, None);
+
+	// Several overlapping orders cross inside a segment
+	let (bids, asks) = flow_books(&[(90.0, 110.0, 50.0, 50.0), (95.0, 105.0, 20.0, 40.0)],
+								  &[(92.0, 108.0, 60.0, 60.0), (100.0, 120.0, 30.0, 30.0)]);
+	let cross = Auction::solve_flow_cross(&bids, &asks, 1.0).unwrap();
+	assert!(Auction::equal_e(&cross.demand, &cross.supply));
+	assert!(Auction::equal_e(&cross.demand, &Auction::calc_aggs(cross.price, Arc::new(bids), Arc::new(asks), 1.0).0));
+}
+
+#[test]
+fn test_solve_flow_cross_interval() {
+	// Both orders saturate at 10 shares, which trade at every price from 10 to 90
+	let (bids, asks) = flow_books(&[(0.0, 100.0, 10.0, 100.0)], &[(0.0, 100.0, 10.0, 100.0)]);
+	let cross = Auction::solve_flow_cross(&bids, &asks, 1.0).unwrap();
+	assert_eq!((cross.price, cross.demand, cross.supply), (50.0, 10.0, 10.0));
+	let (low, high) = cross.interval.unwrap();
+	assert!(Auction::equal_e(&low, &10.0) && Auction::equal_e(&high, &90.0));
+
+	// The bid and ask don't overlap so nothing trades anywhere between them
+	let (bids, asks) = flow_books(&[(10.0, 20.0, 5.0, 5.0)], &[(30.0, 40.0, 5.0, 5.0)]);
+	let cross = Auction::solve_flow_cross(&bids, &asks, 1.0).unwrap();
+	assert_eq!(cross, FlowCross { price: 25.0, demand: 0.0, supply: 0.0, interval: Some((20.0, 30.0)) });
+}
+
+#[test]
+fn test_solve_flow_cross_step() {
+	// A bid for 100 at exactly 50 steps over S(p) = p, so the price is the step's
+	let (bids, asks) = flow_books(&[(50.0, 50.0, 100.0, 100.0)], &[(0.0, 100.0, 100.0, 100.0)]);
+	let cross = Auction::solve_flow_cross(&bids, &asks, 1.0).unwrap();
+	assert_eq!(cross, FlowCross { price: 50.0, demand: 100.0, supply: 50.0, interval: None });
+
+	// Nothing to cross with an empty book
+	let (bids, asks) = flow_books(&[(50.0, 50.0, 100.0, 100.0)], &[]);
+	assert_eq!(Auction::solve_flow_cross(&bids, &asks, 1.0), None);
+}
+
+#[test]
+fn test_solve_flow_cross_many_orders() {
+	// Overlapping orders, some saturating inside their price range, cross where the summed
+	// demand and supply of every order meet
+	let bids: Vec<(f64, f64, f64, f64)> = (0..40).map(|i| (90.0 + (i % 7) as f64, 101.0 + (i % 5) as f64, 5.0 + (i % 3) as f64 * 10.0, 20.0)).collect();
+	let asks: Vec<(f64, f64, f64, f64)> = (0..40).map(|i| (95.0 + (i % 6) as f64, 103.0 + (i % 4) as f64, 8.0 + (i % 4) as f64 * 7.0, 25.0)).collect();
+	let (bids, asks) = flow_books(&bids, &asks);
+	let cross = Auction::solve_flow_cross(&bids, &asks, 1.0).unwrap();
+	let (demand, supply) = Auction::calc_aggs(cross.price, Arc::new(bids), Arc::new(asks), 1.0);
+	assert!(Auction::equal_e(&cross.demand, &demand) && Auction::equal_e(&cross.supply, &supply));
+	assert!((demand - supply).abs() < 1e-6, "demand {} supply {} at {}", demand, supply, cross.price);
+	assert_eq!(cross.interval, None);
+}
+
+#[test]
+fn test_allocate_pro_rata_lots() {
+	use crate::order::order::{OrderType, ExchangeType};
+	let ask = |quantity: f64| Order::new(String::from("SELLER"), OrderType::Enter, TradeType::Ask, ExchangeType::LimitOrder,
+										 10.0, 10.0, 10.0, quantity, quantity, 0.1);
+	let marginal = vec!(ask(1.0), ask(1.0), ask(1.0));
+
+	// Without lots each order gets a third of the volume
+	let fills = Auction::allocate(&marginal, 2.0, AllocationRule::ProRata, 0.0);
+	assert!(fills.iter().all(|fill| Auction::equal_e(fill, &(2.0 / 3.0))));
+	assert!(Auction::equal_e(&fills.iter().sum(), &2.0));
+
+	// The thirds round down to one lot each and the lot left over goes to the first order
+	assert_eq!(Auction::allocate(&marginal, 2.0, AllocationRule::ProRata, 0.5), vec!(1.0, 0.5, 0.5));
+	assert_eq!(Auction::allocate(&marginal, 2.5, AllocationRule::ProRata, 0.5), vec!(1.0, 1.0, 0.5));
+
+	// The shares stay on a decimal lot grid
+	let marginal = vec!(ask(0.3), ask(0.7));
+	assert_eq!(Auction::allocate(&marginal, 0.5, AllocationRule::ProRata, 0.1), vec!(0.2, 0.3));
+}
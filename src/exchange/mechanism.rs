@@ -0,0 +1,784 @@
+// The auction mechanisms that accept orders, clear the books and settle trades
+use crate::exchange::exchange_logic::{Auction, TradeResults, PlayerUpdate, BatchRules, IndicativePrice};
+use crate::exchange::clearing_house::ClearingHouse;
+use crate::order::order::{Order, TradeType, ExchangeType, TimeInForce};
+use crate::order::order_book::Book;
+use crate::error::SimError;
+use crate::exchange::{SelfTradePrevention, AllocationRule, PriceRule};
+use crate::controller::State;
+
+use std::sync::{Arc, Mutex};
+
+
+/// An auction mechanism for the exchange. The miner passes every order entered in a
+/// published block to accept_order, then calls clear once at the end of the block.
+/// All the TradeResults are settled with settle. New mechanisms implement this trait
+/// and are run with SimulationBuilder::mechanism. Errors are recorded by the simulation,
+/// which carries on with the next order.
+pub trait AuctionMechanism: Send + Sync {
+	/// The name of the mechanism, reported as the market_type of the performance results
+	fn name(&self) -> String;
+
+	/// The type of order players send to this mechanism
+	fn ex_type(&self) -> ExchangeType;
+
+	/// Accepts a new order into the books, returning the results of any trades it made
+	fn accept_order(&self, bids: Arc<Book>, asks: Arc<Book>, order: Order) -> Result<Option<TradeResults>, SimError>;
+
+	/// Clears the books at the end of a block. Mechanisms that only trade as orders
+	/// are accepted don't need to implement this.
+	fn clear(&self, _bids: Arc<Book>, _asks: Arc<Book>) -> Result<Option<TradeResults>, SimError> {
+		Ok(None)
+	}
+
+	/// Removes a cancelled order from the books. Mechanisms that keep orders outside the
+	/// simulation's books remove them from there.
+	fn cancel_order(&self, bids: Arc<Book>, asks: Arc<Book>, order: Order) -> Result<(), SimError> {
+		match order.trade_type {
+			TradeType::Ask => asks.cancel_order(order),
+			TradeType::Bid => bids.cancel_order(order),
+		}
+	}
+
+	/// The self-trade prevention policy used when a player's orders would trade together
+	fn self_trade_prevention(&self) -> SelfTradePrevention {
+		SelfTradePrevention::None
+	}
+
+	/// Whether the opening and closing call auctions collect and uncross orders through this
+	/// mechanism rather than an FBA or a KLF, needed by mechanisms that keep orders of their own
+	fn runs_call_auctions(&self) -> bool {
+		false
+	}
+
+	/// The price clear would set if it ran now, without changing the books. Published while
+	/// a call auction collects orders, mechanisms that only trade as orders are accepted
+	/// have none.
+	fn indicative_price(&self, _bids: Arc<Book>, _asks: Arc<Book>) -> Result<Option<IndicativePrice>, SimError> {
+		Ok(None)
+	}
+
+	/// Updates the players in the ClearingHouse with the results of accept_order or clear.
+	/// Returns an error for each update that couldn't be applied.
+	fn settle(&self, house: &ClearingHouse, results: TradeResults) -> Vec<SimError>;
+
+	/// The header of the order book csv log
+	fn order_book_header(&self) -> String {
+		String::from("time,block_num,book_type,clearing_price,book_before,book_after,")
+	}
+}
+
+
+// Adds the order to its book without checking for crosses
+fn add_to_book(bids: Arc<Book>, asks: Arc<Book>, order: Order) -> Result<Option<TradeResults>, SimError> {
+	match order.trade_type {
+		TradeType::Ask => asks.add_order(order)?,
+		TradeType::Bid => bids.add_order(order)?,
+	}
+	Ok(None)
+}
+
+
+/// Continuous double auction: orders trade with the opposite book as they are accepted
+#[derive(Default)]
+pub struct CdaMechanism {
+	pub stp: SelfTradePrevention,	// How a new order meeting its player's own resting order is handled
+}
+
+impl AuctionMechanism for CdaMechanism {
+	fn name(&self) -> String {
+		String::from("CDA")
+	}
+
+	fn ex_type(&self) -> ExchangeType {
+		ExchangeType::LimitOrder
+	}
+
+	fn accept_order(&self, bids: Arc<Book>, asks: Arc<Book>, order: Order) -> Result<Option<TradeResults>, SimError> {
+		// Since CDA we will check if the order transacts here, IOC and FOK orders
+		// are always checked since they can't rest in the book
+		let must_cross = order.tif != TimeInForce::GTC;
+		match order.trade_type {
+			TradeType::Ask => {
+				// Only check for cross if this ask price is lower than best ask
+				if order.price < asks.get_min_price() || must_cross {
+					// This will add the new ask to the book if it doesn't fully transact
+					Auction::calc_ask_crossing(bids, asks, order, self.stp)
+				} else {
+					// We need to add the ask to the book, best price will be updated in add_order
+					asks.add_order(order)?;
+					Ok(None)
+				}
+			},
+			TradeType::Bid => {
+				// Only check for cross if this bid price is higher than best bid
+				if order.price > bids.get_max_price() || must_cross {
+					// This will add the new bid to the book if it doesn't fully transact
+					Auction::calc_bid_crossing(bids, asks, order, self.stp)
+				} else {
+					// We need to add the bid to the book, best price will be updated in add_order
+					bids.add_order(order)?;
+					Ok(None)
+				}
+			}
+		}
+	}
+
+	fn self_trade_prevention(&self) -> SelfTradePrevention {
+		self.stp
+	}
+
+	fn settle(&self, house: &ClearingHouse, results: TradeResults) -> Vec<SimError> {
+		house.cda_cross_update(results)
+	}
+
+	fn order_book_header(&self) -> String {
+		String::from("time,new_order_trader_id,new_order_order_id,new_order_order_type,new_order_trade_type,new_order_ex_type,new_order_p_low,new_order_p_high,new_order_price,new_order_quantity,new_order_gas,bids_after,asks_after")
+	}
+}
+
+
+/// Frequent batch auction: limit orders rest in the books until they are crossed
+/// at a uniform clearing price at the end of each block
+#[derive(Default)]
+pub struct FbaMechanism {
+	pub stp: SelfTradePrevention,	// How a player's bid and ask that would trade together are handled
+	pub allocation: AllocationRule,	// How the volume at the marginal price level is shared out
+	pub price_rule: PriceRule,		// How the clearing price is picked
+	pub lot_size: f64,				// The size pro-rata fills are rounded down to, any size if 0.0
+	pub last_price: Mutex<Option<f64>>,	// The last clearing price, the reference for the next batch
+}
+
+impl AuctionMechanism for FbaMechanism {
+	fn name(&self) -> String {
+		String::from("FBA")
+	}
+
+	fn ex_type(&self) -> ExchangeType {
+		ExchangeType::LimitOrder
+	}
+
+	fn accept_order(&self, bids: Arc<Book>, asks: Arc<Book>, order: Order) -> Result<Option<TradeResults>, SimError> {
+		// Post-only orders that would trade with the opposite book are rejected
+		let opposite = match order.trade_type {
+			TradeType::Bid => &asks,
+			TradeType::Ask => &bids,
+		};
+		if Auction::post_only_crosses(&order, opposite) {
+			order.validate()?;
+			let rejected = vec!(PlayerUpdate::cancel(order.trader_id, order.order_id));
+			return Ok(Some(TradeResults::new(None, 0.0, 0.0, Some(rejected))));
+		}
+		add_to_book(bids, asks, order)
+	}
+
+	fn clear(&self, bids: Arc<Book>, asks: Arc<Book>) -> Result<Option<TradeResults>, SimError> {
+		// IOC and FOK orders are cancelled if they aren't filled in this batch
+		let mut last_price = self.last_price.lock().expect("couldn't lock last clearing price");
+		let rules = BatchRules {
+			stp: self.stp,
+			allocation: self.allocation,
+			price_rule: self.price_rule,
+			reference_price: *last_price,
+			lot_size: self.lot_size,
+		};
+		let results = Auction::frequent_batch_auction(bids, asks, rules)?;
+		if let Some(price) = results.as_ref().and_then(|r| r.uniform_price) {
+			*last_price = Some(price);
+		}
+		Ok(results)
+	}
+
+	fn self_trade_prevention(&self) -> SelfTradePrevention {
+		self.stp
+	}
+
+	fn indicative_price(&self, bids: Arc<Book>, asks: Arc<Book>) -> Result<Option<IndicativePrice>, SimError> {
+		let rules = BatchRules {
+			stp: self.stp,
+			allocation: self.allocation,
+			price_rule: self.price_rule,
+			reference_price: *self.last_price.lock().expect("couldn't lock last clearing price"),
+			lot_size: self.lot_size,
+		};
+		Auction::indicative_batch(&bids, &asks, rules).map(Some)
+	}
+
+	fn settle(&self, house: &ClearingHouse, results: TradeResults) -> Vec<SimError> {
+		house.fba_batch_update(results)
+	}
+}
+
+
+/// Flow market: flow orders rest in the books until the end of each block, when every
+/// order trades at the price where aggregate demand meets aggregate supply. An order's
+/// u_max is a rate in shares per second, so each batch it trades its rate for the
+/// batch_interval and it keeps trading in later batches until its quantity is used up.
+pub struct KlfMechanism {
+	pub batch_interval: u64,	// Milliseconds between clears, the time each batch's flow trades over
+}
+
+impl Default for KlfMechanism {
+	// One second batches, where an order trades up to u_max shares each batch
+	fn default() -> Self {
+		KlfMechanism { batch_interval: 1000 }
+	}
+}
+
+impl AuctionMechanism for KlfMechanism {
+	fn name(&self) -> String {
+		String::from("KLF")
+	}
+
+	fn ex_type(&self) -> ExchangeType {
+		ExchangeType::FlowOrder
+	}
+
+	fn accept_order(&self, bids: Arc<Book>, asks: Arc<Book>, order: Order) -> Result<Option<TradeResults>, SimError> {
+		// Every flow order trades at the clearing price it helps set, an unpriced order can't
+		if order.is_market() {
+			return Err(SimError::InvalidOrder { order_id: order.order_id, reason: "the flow market doesn't accept market orders" });
+		}
+		add_to_book(bids, asks, order)
+	}
+
+	fn clear(&self, bids: Arc<Book>, asks: Arc<Book>) -> Result<Option<TradeResults>, SimError> {
+		Auction::flow_cross(bids, asks, self.batch_interval as f64 / 1000.0)
+	}
+
+	fn indicative_price(&self, bids: Arc<Book>, asks: Arc<Book>) -> Result<Option<IndicativePrice>, SimError> {
+		Ok(Some(Auction::indicative_flow(&bids, &asks, self.batch_interval as f64 / 1000.0)))
+	}
+
+	fn settle(&self, house: &ClearingHouse, results: TradeResults) -> Vec<SimError> {
+		house.flow_batch_update(results)
+	}
+}
+
+
+
+/// A call auction: while it's in State::PreAuction orders collect in the books without
+/// trading, then in State::Auction the books are uncrossed at a single price by the
+/// auction mechanism, which also gives the indicative price in between. The simulation
+/// runs its opening and closing auctions with it, see Session.
+pub struct CallAuction {
+	pub auction: Arc<dyn AuctionMechanism>,	// Collects the orders and uncrosses the books, like the FBA or the KLF
+	pub state: Mutex<State>,
+}
+
+impl CallAuction {
+	pub fn new(auction: Arc<dyn AuctionMechanism>) -> CallAuction {
+		CallAuction {
+			auction,
+			state: Mutex::new(State::PreAuction),
+		}
+	}
+
+	/// Moves the call auction into state, it only uncrosses in State::Auction
+	pub fn set_state(&self, state: State) {
+		*self.state.lock().expect("couldn't lock call auction state") = state;
+	}
+}
+
+impl AuctionMechanism for CallAuction {
+	fn name(&self) -> String {
+		format!("{} call auction", self.auction.name())
+	}
+
+	fn ex_type(&self) -> ExchangeType {
+		self.auction.ex_type()
+	}
+
+	fn accept_order(&self, bids: Arc<Book>, asks: Arc<Book>, order: Order) -> Result<Option<TradeResults>, SimError> {
+		// Nothing trades while the auction collects orders, so IOC, FOK and market orders
+		// are rejected rather than left resting until the uncrossing
+		let collecting = *self.state.lock().expect("couldn't lock call auction state") == State::PreAuction;
+		if collecting && (order.tif != TimeInForce::GTC || order.is_market()) {
+			order.validate()?;
+			let rejected = vec!(PlayerUpdate::cancel(order.trader_id, order.order_id));
+			return Ok(Some(TradeResults::new(None, 0.0, 0.0, Some(rejected))));
+		}
+		self.auction.accept_order(bids, asks, order)
+	}
+
+	fn clear(&self, bids: Arc<Book>, asks: Arc<Book>) -> Result<Option<TradeResults>, SimError> {
+		match *self.state.lock().expect("couldn't lock call auction state") {
+			State::Auction => self.auction.clear(bids, asks),
+			State::Process | State::PreAuction => Ok(None),
+		}
+	}
+
+	fn cancel_order(&self, bids: Arc<Book>, asks: Arc<Book>, order: Order) -> Result<(), SimError> {
+		self.auction.cancel_order(bids, asks, order)
+	}
+
+	fn self_trade_prevention(&self) -> SelfTradePrevention {
+		self.auction.self_trade_prevention()
+	}
+
+	fn indicative_price(&self, bids: Arc<Book>, asks: Arc<Book>) -> Result<Option<IndicativePrice>, SimError> {
+		self.auction.indicative_price(bids, asks)
+	}
+
+	fn settle(&self, house: &ClearingHouse, results: TradeResults) -> Vec<SimError> {
+		self.auction.settle(house, results)
+	}
+
+	fn order_book_header(&self) -> String {
+		self.auction.order_book_header()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::order::order::OrderType;
+	use crate::players::investor::Investor;
+
+	fn limit_order(trader_id: &str, trade_type: TradeType, price: f64, quantity: f64) -> Order {
+		Order::new(String::from(trader_id), OrderType::Enter, trade_type, ExchangeType::LimitOrder,
+				   price, price, price, quantity, quantity, 0.1)
+	}
+
+	fn setup() -> (ClearingHouse, Arc<Book>, Arc<Book>) {
+		let house = ClearingHouse::new();
+		house.reg_n_investors(vec!(Investor::new(String::from("BUYER")), Investor::new(String::from("SELLER"))));
+		(house, Arc::new(Book::new(TradeType::Bid)), Arc::new(Book::new(TradeType::Ask)))
+	}
+
+	// Registers the order with its player then accepts it, settling any results
+	fn enter(mechanism: &dyn AuctionMechanism, house: &ClearingHouse, bids: &Arc<Book>, asks: &Arc<Book>, order: Order) -> Vec<PlayerUpdate> {
+		house.new_order(order.clone()).unwrap();
+		let results = mechanism.accept_order(Arc::clone(bids), Arc::clone(asks), order).unwrap();
+		settle(mechanism, house, results)
+	}
+
+	fn settle(mechanism: &dyn AuctionMechanism, house: &ClearingHouse, results: Option<TradeResults>) -> Vec<PlayerUpdate> {
+		match results {
+			Some(results) => {
+				let updates = results.cross_results.clone().unwrap_or_default();
+				assert_eq!(mechanism.settle(house, results), Vec::new());
+				updates
+			},
+			None => Vec::new(),
+		}
+	}
+
+	fn summary(updates: &[PlayerUpdate]) -> Vec<(bool, f64)> {
+		updates.iter().map(|pu| (pu.cancel, if pu.cancel { 0.0 } else { pu.volume })).collect()
+	}
+
+	#[test]
+	fn test_cda_ioc_and_fok() {
+		let (house, bids, asks) = setup();
+		enter(&CdaMechanism::default(), &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 5.0));
+
+		// A FOK bid for more than the book holds is killed without trading
+		let fok = limit_order("BUYER", TradeType::Bid, 101.0, 8.0).with_tif(TimeInForce::FOK);
+		assert_eq!(summary(&enter(&CdaMechanism::default(), &house, &bids, &asks, fok)), vec!((true, 0.0)));
+		assert_eq!(asks.len(), 1);
+
+		// An IOC bid trades what it can and its remainder is cancelled instead of resting
+		let ioc = limit_order("BUYER", TradeType::Bid, 101.0, 8.0).with_tif(TimeInForce::IOC);
+		assert_eq!(summary(&enter(&CdaMechanism::default(), &house, &bids, &asks, ioc)), vec!((false, 5.0), (true, 0.0)));
+		assert_eq!((bids.len(), asks.len()), (0, 0));
+
+		// An IOC ask that can't cross is cancelled
+		let ioc = limit_order("SELLER", TradeType::Ask, 100.0, 1.0).with_tif(TimeInForce::IOC);
+		assert_eq!(summary(&enter(&CdaMechanism::default(), &house, &bids, &asks, ioc)), vec!((true, 0.0)));
+		assert_eq!(asks.len(), 0);
+
+		// None of the orders are left with the players
+		assert_eq!(house.get_player_order_count(&String::from("BUYER")), Ok(0));
+		assert_eq!(house.get_player_order_count(&String::from("SELLER")), Ok(0));
+	}
+
+	#[test]
+	fn test_post_only() {
+		for mechanism in [&CdaMechanism::default() as &dyn AuctionMechanism, &FbaMechanism::default()] {
+			let (house, bids, asks) = setup();
+			enter(mechanism, &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 5.0));
+
+			// Rejected rather than trading with the resting ask
+			let crossing = limit_order("BUYER", TradeType::Bid, 100.0, 1.0).with_post_only();
+			assert_eq!(summary(&enter(mechanism, &house, &bids, &asks, crossing)), vec!((true, 0.0)));
+			assert_eq!(house.get_player_order_count(&String::from("BUYER")), Ok(0));
+
+			// Rests in the book if it doesn't cross
+			let resting = limit_order("BUYER", TradeType::Bid, 99.0, 1.0).with_post_only();
+			assert_eq!(enter(mechanism, &house, &bids, &asks, resting).len(), 0);
+			assert_eq!(bids.len(), 1);
+		}
+
+		let order = limit_order("BUYER", TradeType::Bid, 99.0, 1.0).with_post_only().with_tif(TimeInForce::IOC);
+		assert!(order.validate().is_err());
+	}
+
+	#[test]
+	fn test_post_only_after_book_empties() {
+		for mechanism in [&CdaMechanism::default() as &dyn AuctionMechanism, &FbaMechanism::default()] {
+			let (house, bids, asks) = setup();
+			let ask = limit_order("SELLER", TradeType::Ask, 100.0, 5.0);
+			enter(mechanism, &house, &bids, &asks, ask.clone());
+			mechanism.cancel_order(Arc::clone(&bids), Arc::clone(&asks), ask).unwrap();
+			assert_eq!(asks.len(), 0);
+
+			// Nothing is left to cross, so the post-only bid rests
+			let resting = limit_order("BUYER", TradeType::Bid, 50.0, 1.0).with_post_only();
+			assert_eq!(enter(mechanism, &house, &bids, &asks, resting).len(), 0);
+			assert_eq!(bids.len(), 1);
+
+			// As does a post-only ask once the bids are cancelled
+			let bid = bids.copy_orders().remove(0);
+			mechanism.cancel_order(Arc::clone(&bids), Arc::clone(&asks), bid).unwrap();
+			assert_eq!(bids.len(), 0);
+			let resting = limit_order("SELLER", TradeType::Ask, 200.0, 1.0).with_post_only();
+			assert_eq!(enter(mechanism, &house, &bids, &asks, resting).len(), 0);
+			assert_eq!(asks.len(), 1);
+		}
+	}
+
+	#[test]
+	fn test_fba_ioc_and_fok() {
+		let (house, bids, asks) = setup();
+		enter(&FbaMechanism::default(), &house, &bids, &asks, limit_order("BUYER", TradeType::Bid, 101.0, 5.0));
+		enter(&FbaMechanism::default(), &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 8.0).with_tif(TimeInForce::FOK));
+
+		// The FOK ask can't be completely filled so it's killed and nothing trades
+		let results = FbaMechanism::default().clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap();
+		assert_eq!(summary(&settle(&FbaMechanism::default(), &house, results)), vec!((true, 0.0)));
+		assert_eq!((bids.len(), asks.len()), (1, 0));
+
+		// The IOC ask fills the bid and what's left of it is cancelled after the batch
+		enter(&FbaMechanism::default(), &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 8.0).with_tif(TimeInForce::IOC));
+		let results = FbaMechanism::default().clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap();
+		assert_eq!(summary(&settle(&FbaMechanism::default(), &house, results)), vec!((false, 5.0), (true, 0.0)));
+		assert_eq!((bids.len(), asks.len()), (0, 0));
+		assert_eq!(house.get_player_order_count(&String::from("SELLER")), Ok(0));
+	}
+
+	#[test]
+	fn test_cda_market_orders() {
+		let (house, bids, asks) = setup();
+		enter(&CdaMechanism::default(), &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 2.0));
+		enter(&CdaMechanism::default(), &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 101.0, 2.0));
+
+		// A market bid sweeps the asks at their prices and the rest of it is cancelled
+		let updates = enter(&CdaMechanism::default(), &house, &bids, &asks, Order::market(String::from("BUYER"), TradeType::Bid, 5.0, 0.1));
+		assert_eq!(summary(&updates), vec!((false, 2.0), (false, 2.0), (true, 0.0)));
+		assert_eq!((updates[0].price, updates[1].price), (100.0, 101.0));
+		assert_eq!((bids.len(), asks.len()), (0, 0));
+
+		// A market ask with nothing to trade with is cancelled
+		let updates = enter(&CdaMechanism::default(), &house, &bids, &asks, Order::market(String::from("SELLER"), TradeType::Ask, 1.0, 0.1));
+		assert_eq!(summary(&updates), vec!((true, 0.0)));
+		assert_eq!(house.get_player_order_count(&String::from("BUYER")), Ok(0));
+		assert_eq!(house.get_player_order_count(&String::from("SELLER")), Ok(0));
+	}
+
+	#[test]
+	fn test_fba_market_orders() {
+		let (house, bids, asks) = setup();
+		let clear = |house: &ClearingHouse| {
+			let results = FbaMechanism::default().clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap();
+			let price = results.as_ref().and_then(|r| r.uniform_price);
+			(price, summary(&settle(&FbaMechanism::default(), house, results)))
+		};
+		enter(&FbaMechanism::default(), &house, &bids, &asks, limit_order("BUYER", TradeType::Bid, 99.0, 3.0));
+		enter(&FbaMechanism::default(), &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 5.0));
+
+		// The market bid trades first at the price set by the limit orders
+		enter(&FbaMechanism::default(), &house, &bids, &asks, Order::market(String::from("BUYER"), TradeType::Bid, 4.0, 0.1));
+		assert_eq!(clear(&house), (Some(100.0), vec!((false, 4.0))));
+		assert_eq!((bids.len(), asks.len()), (1, 1));
+
+		// A market ask larger than every bid sells at the lowest limit price, the rest is cancelled
+		enter(&FbaMechanism::default(), &house, &bids, &asks, Order::market(String::from("SELLER"), TradeType::Ask, 10.0, 0.1));
+		assert_eq!(clear(&house), (Some(99.0), vec!((false, 3.0), (true, 0.0))));
+		assert_eq!((bids.len(), asks.len()), (0, 1));
+
+		// Market orders alone can't set a price, they're cancelled
+		enter(&FbaMechanism::default(), &house, &bids, &asks, Order::market(String::from("BUYER"), TradeType::Bid, 1.0, 0.1));
+		enter(&FbaMechanism::default(), &house, &bids, &asks, Order::market(String::from("SELLER"), TradeType::Ask, 1.0, 0.1));
+		asks.cancel_order_by_id(asks.copy_orders()[0].order_id).unwrap();
+		assert_eq!(clear(&house), (None, vec!((true, 0.0), (true, 0.0))));
+		assert_eq!(house.get_player_order_count(&String::from("BUYER")), Ok(0));
+	}
+
+	#[test]
+	fn test_market_order_validation() {
+		let (_house, bids, asks) = setup();
+		let market = Order::market(String::from("BUYER"), TradeType::Bid, 1.0, 0.1);
+		assert_eq!(market.tif, TimeInForce::IOC);
+		assert!(market.clone().with_tif(TimeInForce::GTC).validate().is_err());
+		assert!(market.clone().with_tif(TimeInForce::FOK).validate().is_ok());
+		assert!(KlfMechanism::default().accept_order(bids, asks, market).is_err());
+	}
+
+	#[test]
+	fn test_cda_iceberg() {
+		let (house, bids, asks) = setup();
+		enter(&CdaMechanism::default(), &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 8.0).with_display(4.0));
+		enter(&CdaMechanism::default(), &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 3.0));
+		assert_eq!(asks.depth(1)[0].quantity, 7.0);
+
+		// The displayed part trades first, the replenished part queues behind the later ask
+		let updates = enter(&CdaMechanism::default(), &house, &bids, &asks, limit_order("BUYER", TradeType::Bid, 100.0, 5.0));
+		assert_eq!(summary(&updates), vec!((false, 4.0), (false, 1.0)));
+		assert_eq!(asks.depth(1)[0].quantity, 6.0);
+
+		// An iceberg bid keeps crossing as it shows its reserve
+		let updates = enter(&CdaMechanism::default(), &house, &bids, &asks, limit_order("BUYER", TradeType::Bid, 100.0, 6.0).with_display(3.0));
+		assert_eq!(summary(&updates), vec!((false, 2.0), (false, 1.0), (false, 3.0)));
+		assert_eq!((bids.len(), asks.len()), (0, 0));
+		assert_eq!(house.get_player_order_count(&String::from("BUYER")), Ok(0));
+		assert_eq!(house.get_player_order_count(&String::from("SELLER")), Ok(0));
+	}
+
+	#[test]
+	fn test_fba_iceberg() {
+		let (house, bids, asks) = setup();
+		enter(&FbaMechanism::default(), &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 6.0).with_display(2.0));
+		enter(&FbaMechanism::default(), &house, &bids, &asks, limit_order("BUYER", TradeType::Bid, 101.0, 5.0));
+
+		// Only the displayed ask sets the price, then the reserve trades as it's shown
+		let results = FbaMechanism::default().clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap();
+		assert_eq!(results.as_ref().unwrap().uniform_price, Some(101.0));
+		assert_eq!(summary(&settle(&FbaMechanism::default(), &house, results)), vec!((false, 2.0), (false, 2.0), (false, 1.0)));
+		assert_eq!((bids.len(), asks.len()), (0, 1));
+
+		// The book and the clearing house agree on what's left of the iceberg
+		let resting = asks.copy_orders().remove(0);
+		let held = house.get_player_orders(&String::from("SELLER")).unwrap().remove(0);
+		assert_eq!((resting.quantity, resting.reserve), (1.0, 0.0));
+		assert_eq!((held.quantity, held.reserve), (1.0, 0.0));
+	}
+
+	#[test]
+	fn test_cda_self_trade_prevention() {
+		let expected = [
+			(SelfTradePrevention::CancelNewest, vec!((true, 0.0)), (0, 2), 1),
+			(SelfTradePrevention::CancelOldest, vec!((true, 0.0), (false, 1.0)), (1, 0), 1),
+			(SelfTradePrevention::DecrementBoth, vec!((true, 0.0), (true, 0.0), (false, 1.0)), (0, 0), 0),
+		];
+		for (stp, updates, lens, seller_orders) in expected {
+			let (house, bids, asks) = setup();
+			let cda = CdaMechanism { stp };
+			enter(&cda, &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 2.0));
+			enter(&cda, &house, &bids, &asks, limit_order("BUYER", TradeType::Ask, 100.0, 1.0));
+
+			// The seller's bid meets its own ask before the buyer's
+			let bid = limit_order("SELLER", TradeType::Bid, 100.0, 3.0);
+			house.new_order(bid.clone()).unwrap();
+			let results = cda.accept_order(Arc::clone(&bids), Arc::clone(&asks), bid).unwrap();
+			assert_eq!(results.as_ref().unwrap().self_trades, 1);
+			assert_eq!(summary(&settle(&cda, &house, results)), updates);
+			assert_eq!((bids.len(), asks.len()), lens);
+			assert_eq!(house.get_player_order_count(&String::from("SELLER")), Ok(seller_orders));
+			if stp == SelfTradePrevention::CancelOldest {
+				assert_eq!(bids.copy_orders()[0].quantity, 2.0);
+			}
+		}
+	}
+
+	#[test]
+	fn test_cda_fok_self_trade_prevention() {
+		// The seller's own ask rests between the buyer's asks, enough to fill the FOK bid with it
+		let asks_before = [("BUYER", 1.0), ("SELLER", 2.0), ("BUYER", 2.0)];
+		let expected = [
+			(SelfTradePrevention::None, 3.0, false),
+			(SelfTradePrevention::CancelNewest, 3.0, true),
+			(SelfTradePrevention::CancelOldest, 3.0, false),
+			(SelfTradePrevention::CancelOldest, 4.0, true),
+			(SelfTradePrevention::DecrementBoth, 3.0, true),
+		];
+		for (stp, quantity, killed) in expected {
+			let (house, bids, asks) = setup();
+			let cda = CdaMechanism { stp };
+			for (id, volume) in asks_before.iter() {
+				enter(&cda, &house, &bids, &asks, limit_order(id, TradeType::Ask, 100.0, *volume));
+			}
+
+			// Killed whole without trading when its own volume would be needed to fill it
+			let fok = limit_order("SELLER", TradeType::Bid, 100.0, quantity).with_tif(TimeInForce::FOK);
+			let updates = enter(&cda, &house, &bids, &asks, fok);
+			match killed {
+				true => {
+					assert_eq!(summary(&updates), vec!((true, 0.0)));
+					assert_eq!(asks.len(), 3);
+				},
+				false => assert!(updates.iter().filter(|pu| !pu.cancel).map(|pu| pu.volume).sum::<f64>() == quantity),
+			}
+			assert_eq!(bids.len(), 0);
+		}
+	}
+
+	#[test]
+	fn test_fba_self_trade_prevention() {
+		let expected = [
+			(SelfTradePrevention::CancelNewest, vec!((true, 0.0)), (0, 2)),
+			(SelfTradePrevention::CancelOldest, vec!((true, 0.0), (false, 1.0)), (1, 0)),
+			(SelfTradePrevention::DecrementBoth, vec!((true, 0.0), (true, 0.0), (false, 1.0)), (0, 0)),
+		];
+		for (stp, updates, lens) in expected {
+			let (house, bids, asks) = setup();
+			let fba = FbaMechanism { stp, ..FbaMechanism::default() };
+			enter(&fba, &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 2.0));
+			enter(&fba, &house, &bids, &asks, limit_order("BUYER", TradeType::Ask, 100.0, 1.0));
+			enter(&fba, &house, &bids, &asks, limit_order("SELLER", TradeType::Bid, 101.0, 3.0));
+
+			// The seller's bid queued after its ask so it's the newest of the pair
+			let results = fba.clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap();
+			assert_eq!(results.as_ref().unwrap().self_trades, 1);
+			assert_eq!(summary(&settle(&fba, &house, results)), updates);
+			assert_eq!((bids.len(), asks.len()), lens);
+
+			// The clearing house agrees with the books on what the seller has left
+			let mut held: Vec<f64> = house.get_player_orders(&String::from("SELLER")).unwrap().iter().map(|o| o.quantity).collect();
+			let mut resting: Vec<f64> = bids.copy_orders().iter().chain(asks.copy_orders().iter())
+				.filter(|o| o.trader_id == "SELLER").map(|o| o.quantity).collect();
+			held.sort_by(|a, b| a.partial_cmp(b).unwrap());
+			resting.sort_by(|a, b| a.partial_cmp(b).unwrap());
+			assert_eq!(held, resting);
+		}
+	}
+
+	// Clears a vertical cross, where 61 bid at 12.30 meets 82 offered at or below it, or a
+	// horizontal cross, where 60 bid at 12.40 meets exactly 60 offered at 11.60 or below.
+	// Returns the clearing price and the volume traded.
+	fn clear_cross(price_rule: PriceRule, horizontal: bool, last_price: Option<f64>) -> (Option<f64>, f64) {
+		let (house, bids, asks) = setup();
+		let fba = FbaMechanism { price_rule, ..FbaMechanism::default() };
+		*fba.last_price.lock().unwrap() = last_price;
+		let (bid, next_ask) = match horizontal {
+			true => ((12.4, 60.0), 12.6),
+			false => ((12.3, 61.0), 12.3),
+		};
+		for (price, quantity) in [(11.2, 10.0), (11.6, 50.0), (next_ask, 22.0), (12.5, 30.0)] {
+			enter(&fba, &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, price, quantity));
+		}
+		enter(&fba, &house, &bids, &asks, limit_order("BUYER", TradeType::Bid, bid.0, bid.1));
+		enter(&fba, &house, &bids, &asks, limit_order("BUYER", TradeType::Bid, 11.0, 40.0));
+
+		let results = fba.clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap();
+		let price = results.as_ref().unwrap().uniform_price;
+		let volume = summary(&settle(&fba, &house, results)).iter().map(|(_, v)| v).sum();
+		// The price is kept as the reference for the next batch
+		assert_eq!(*fba.last_price.lock().unwrap(), price.or(last_price));
+		(price, volume)
+	}
+
+	#[test]
+	fn test_fba_price_rules_vertical() {
+		// The marginal ask level at 12.30 is rationed, only MinImbalance clears below it
+		assert_eq!(clear_cross(PriceRule::MergedBook, false, None), (Some(12.3), 61.0));
+		assert_eq!(clear_cross(PriceRule::Midpoint, false, None), (Some(12.3), 61.0));
+		assert_eq!(clear_cross(PriceRule::MaxVolume, false, None), (Some(12.3), 61.0));
+		assert_eq!(clear_cross(PriceRule::MinImbalance, false, None), (Some(11.6), 60.0));
+		assert_eq!(clear_cross(PriceRule::Reference, false, Some(11.0)), (Some(12.3), 61.0));
+	}
+
+	#[test]
+	fn test_fba_price_rules_horizontal() {
+		// Every price from 11.60 to 12.40 trades all 60 shares without an imbalance. MergedBook
+		// clears between the bid and the ask above it, where nothing trades.
+		assert_eq!(clear_cross(PriceRule::MergedBook, true, None), (Some(12.45), 0.0));
+		assert_eq!(clear_cross(PriceRule::Midpoint, true, None), (Some(12.0), 60.0));
+		assert_eq!(clear_cross(PriceRule::MaxVolume, true, None), (Some(12.0), 60.0));
+		assert_eq!(clear_cross(PriceRule::MinImbalance, true, None), (Some(12.0), 60.0));
+
+		// The last clearing price breaks the tie, moved into the range if it's outside it
+		assert_eq!(clear_cross(PriceRule::Reference, true, None), (Some(12.0), 60.0));
+		assert_eq!(clear_cross(PriceRule::Reference, true, Some(12.1)), (Some(12.1), 60.0));
+		assert_eq!(clear_cross(PriceRule::Reference, true, Some(13.0)), (Some(12.4), 60.0));
+	}
+
+	#[test]
+	fn test_fba_price_rules_no_cross() {
+		// Market orders alone don't price a cross under any rule
+		for price_rule in [PriceRule::MergedBook, PriceRule::Midpoint, PriceRule::MaxVolume, PriceRule::MinImbalance, PriceRule::Reference] {
+			let (house, bids, asks) = setup();
+			let fba = FbaMechanism { price_rule, ..FbaMechanism::default() };
+			enter(&fba, &house, &bids, &asks, Order::market(String::from("SELLER"), TradeType::Ask, 5.0, 0.1));
+			enter(&fba, &house, &bids, &asks, Order::market(String::from("BUYER"), TradeType::Bid, 5.0, 0.1));
+			let results = fba.clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap();
+			assert_eq!(results.unwrap().uniform_price, None);
+		}
+	}
+
+	#[test]
+	fn test_klf_flow_rates() {
+		// Both orders trade 5 shares per second at 100, so every batch interval trades
+		// the same 10 shares over two seconds and the orders persist until they're used up
+		for (batch_interval, batches) in vec!((500, 4), (1000, 2), (2000, 1)) {
+			let (house, bids, asks) = setup();
+			let klf = KlfMechanism { batch_interval };
+			let flow_order = |trader_id: &str, trade_type| Order::new(String::from(trader_id), OrderType::Enter, trade_type,
+																	 ExchangeType::FlowOrder, 99.0, 101.0, 100.0, 10.0, 10.0, 0.1);
+			enter(&klf, &house, &bids, &asks, flow_order("BUYER", TradeType::Bid));
+			enter(&klf, &house, &bids, &asks, flow_order("SELLER", TradeType::Ask));
+
+			let volume = 10.0 / batches as f64;
+			for batch in 0..batches {
+				assert_eq!((bids.len(), asks.len()), (1, 1), "the orders rest before batch {}", batch);
+				let results = klf.clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap();
+				assert_eq!(results.as_ref().unwrap().uniform_price, Some(100.0));
+				assert_eq!(summary(&settle(&klf, &house, results)), vec!((false, volume), (false, volume)));
+			}
+			assert_eq!((bids.len(), asks.len()), (0, 0));
+			assert_eq!(house.get_player_order_count(&String::from("BUYER")), Ok(0));
+		}
+	}
+
+	#[test]
+	fn test_call_auction() {
+		let (house, bids, asks) = setup();
+		let call = CallAuction::new(Arc::new(FbaMechanism::default()));
+		enter(&call, &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 5.0));
+		let indicative = call.indicative_price(Arc::clone(&bids), Arc::clone(&asks)).unwrap().unwrap();
+		assert_eq!(indicative, IndicativePrice { price: None, volume: 0.0, imbalance: -5.0 });
+
+		// Crossing orders rest in the books while the auction collects orders
+		enter(&call, &house, &bids, &asks, limit_order("BUYER", TradeType::Bid, 101.0, 3.0));
+		assert!(call.clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap().is_none());
+		assert_eq!((bids.len(), asks.len()), (1, 1));
+
+		// The indicative price doesn't touch the books and is where they uncross
+		let indicative = call.indicative_price(Arc::clone(&bids), Arc::clone(&asks)).unwrap().unwrap();
+		assert_eq!((indicative.volume, indicative.imbalance), (3.0, -2.0));
+		assert_eq!((bids.len(), asks.len()), (1, 1));
+		call.set_state(State::Auction);
+		let results = call.clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap();
+		assert_eq!(results.as_ref().unwrap().uniform_price, indicative.price);
+		assert_eq!(summary(&settle(&call, &house, results)), vec!((false, 3.0)));
+		assert_eq!((bids.len(), asks.len()), (0, 1));
+
+		// Continuous mechanisms don't publish one
+		assert_eq!(CdaMechanism::default().indicative_price(Arc::clone(&bids), Arc::clone(&asks)).unwrap(), None);
+	}
+
+	#[test]
+	fn test_call_auction_rejects_ioc_fok_and_market() {
+		let (house, bids, asks) = setup();
+		let call = CallAuction::new(Arc::new(FbaMechanism::default()));
+		enter(&call, &house, &bids, &asks, limit_order("SELLER", TradeType::Ask, 100.0, 5.0));
+
+		// While collecting, orders that can't rest until the uncrossing are cancelled on entry
+		let orders = vec!(limit_order("BUYER", TradeType::Bid, 101.0, 1.0).with_tif(TimeInForce::IOC),
+						  limit_order("BUYER", TradeType::Bid, 101.0, 1.0).with_tif(TimeInForce::FOK),
+						  Order::market(String::from("BUYER"), TradeType::Bid, 1.0, 0.1));
+		for order in orders {
+			assert_eq!(summary(&enter(&call, &house, &bids, &asks, order)), vec!((true, 0.0)));
+		}
+		assert!(call.clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap().is_none());
+		assert_eq!((bids.len(), asks.len()), (0, 1));
+		assert_eq!(house.get_player_order_count(&String::from("BUYER")), Ok(0));
+
+		// In the uncrossing block they trade in the batch like the FBA's
+		call.set_state(State::Auction);
+		enter(&call, &house, &bids, &asks, limit_order("BUYER", TradeType::Bid, 101.0, 2.0).with_tif(TimeInForce::IOC));
+		let results = call.clear(Arc::clone(&bids), Arc::clone(&asks)).unwrap();
+		assert_eq!(summary(&settle(&call, &house, results)), vec!((false, 2.0)));
+		assert_eq!(house.get_player_order_count(&String::from("BUYER")), Ok(0));
+	}
+}
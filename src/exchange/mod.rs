@@ -1,19 +1,105 @@
 pub mod exchange_logic;
 pub mod clearing_house;
+pub mod mechanism;
+pub mod portfolio;
 
-#[derive(Debug, Copy, Deserialize, PartialEq)]
+use self::mechanism::{AuctionMechanism, CdaMechanism, FbaMechanism, KlfMechanism};
+
+use std::sync::Arc;
+
+#[derive(Debug, Copy, Serialize, Deserialize, PartialEq)]
 pub enum MarketType {
 	CDA,
 	FBA,
 	KLF,
 }
 
+impl MarketType {
+	/// The built-in auction mechanism for this market type, the flow market clears
+	/// one second batches
+	pub fn mechanism(&self) -> Arc<dyn AuctionMechanism> {
+		self.mechanism_with(SelfTradePrevention::None, AllocationRule::TimePriority, PriceRule::MergedBook, 0.0, 1000)
+	}
+
+	/// The built-in auction mechanism for this market type, preventing self-trades with stp.
+	/// The FBA sets its clearing price by the price rule and shares out its marginal price
+	/// level by the allocation rule, in whole lots of lot_size. The flow market has no matching
+	/// between orders so it ignores all four, its orders trade at their rates for batch_interval
+	/// milliseconds each batch.
+	pub fn mechanism_with(&self, stp: SelfTradePrevention, allocation: AllocationRule, price_rule: PriceRule, lot_size: f64, batch_interval: u64) -> Arc<dyn AuctionMechanism> {
+		match self {
+			MarketType::CDA => Arc::new(CdaMechanism { stp }),
+			MarketType::FBA => Arc::new(FbaMechanism { stp, allocation, price_rule, lot_size, ..FbaMechanism::default() }),
+			MarketType::KLF => Arc::new(KlfMechanism { batch_interval }),
+		}
+	}
+}
+
+/// How the CDA and FBA matchers stop a player's bid trading with their own ask
+/// None: the orders trade with each other
+/// CancelNewest: the order that arrived last is cancelled, the other keeps matching
+/// CancelOldest: the order that arrived first is cancelled, the other keeps matching
+/// DecrementBoth: both orders are reduced by the smaller quantity without trading
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SelfTradePrevention {
+	#[default]
+	None,
+	CancelNewest,
+	CancelOldest,
+	DecrementBoth,
+}
+
+/// How the FBA shares the volume left at its marginal price level, the last level of the
+/// rationed side that only partly trades at the clearing price
+/// TimePriority: the orders that queued first are filled first
+/// ProRata: each order is filled in proportion to its size, rounded down to whole lots with the
+/// lots left over going one each to the orders in time priority
+/// Random: the orders are filled one after another in a uniformly random order
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AllocationRule {
+	#[default]
+	TimePriority,
+	ProRata,
+	Random,
+}
+
+/// How the FBA picks its clearing price. D(p) is the volume bid at p or above and S(p) the
+/// volume offered at p or below, market orders included, so a price p executes min(D(p), S(p)).
+/// Only limit order prices are candidates, without a limit order to price the cross there's no
+/// clearing. In a vertical cross one side's marginal level is rationed at a single price, in a
+/// horizontal cross demand and supply match over a range of prices.
+/// MergedBook: the default, so earlier FBA results reproduce. Walks the merged books in
+/// descending price until the volume seen covers the asks.
+/// A vertical cross clears at the marginal order's price, a horizontal one at the midpoint of
+/// the two prices either side of where the volume was covered. That midpoint can be outside
+/// the crossing interval, e.g. halfway from a bid up to an ask, where the bid no longer trades.
+/// Midpoint: matches the best bids with the best asks and clears at the middle of the crossing
+/// interval, from the higher of the last matched ask and the best unmatched bid to the lower of
+/// the last matched bid and the best unmatched ask. A vertical cross collapses the interval to
+/// the marginal price. An end left open, by market orders or no unmatched orders, takes the other.
+/// MaxVolume: the price that executes the most volume. A horizontal cross executes it over a
+/// range of prices and clears at the midpoint of the lowest and highest of them.
+/// MinImbalance: the price with the smallest |D(p) - S(p)| among those that execute any volume,
+/// ties go to the larger volume then the midpoint of the tied prices. A horizontal cross clears
+/// where D(p) = S(p), while a vertical cross may clear where less volume trades than MaxVolume.
+/// Reference: the MaxVolume prices narrowed to those with the smallest imbalance, then the last
+/// clearing price moved into their range. Without a last price it clears at the range's midpoint.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PriceRule {
+	#[default]
+	MergedBook,
+	Midpoint,
+	MaxVolume,
+	MinImbalance,
+	Reference,
+}
+
 impl Clone for MarketType {
-	fn clone(&self) -> MarketType { 
+	fn clone(&self) -> MarketType {
 		match self {
 			MarketType::CDA => MarketType::CDA,
 			MarketType::FBA => MarketType::FBA,
 			MarketType::KLF => MarketType::KLF,
 		}
 	}
-}
\ No newline at end of file
+}
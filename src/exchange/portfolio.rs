@@ -0,0 +1,767 @@
+// A flow market over several assets where portfolio flow orders trade weighted baskets
+use crate::exchange::exchange_logic::{Auction, TradeResults, PlayerUpdate};
+use crate::exchange::mechanism::AuctionMechanism;
+use crate::exchange::clearing_house::ClearingHouse;
+use crate::order::order::{Order, TradeType, ExchangeType};
+use crate::order::order_book::Book;
+use crate::error::SimError;
+
+use std::sync::{Arc, Mutex};
+
+
+const TOLERANCE: f64 = 0.000_001;
+const MAX_SWEEPS: usize = 1000;
+const BISECTIONS: usize = 200;
+
+/// One asset of a PortfolioMarket, with its own books of single-asset flow orders
+pub struct Asset {
+	pub name: String,
+	pub bids: Arc<Book>,
+	pub asks: Arc<Book>,
+}
+
+impl Asset {
+	pub fn new(name: String) -> Asset {
+		Asset {
+			name,
+			bids: Arc::new(Book::new(TradeType::Bid)),
+			asks: Arc::new(Book::new(TradeType::Ask)),
+		}
+	}
+}
+
+
+/// A flow order for a basket of assets. A basket holds weights[i] shares of asset i and a
+/// negative weight is a short leg, so a bid buys the basket's positive legs and sells its
+/// negative ones while an ask does the opposite. The order's flow schedule is over the
+/// basket price, the weighted sum of the asset prices: it trades baskets linearly between
+/// p_low and p_high at up to u_max baskets per second until quantity baskets have traded.
+#[derive(Debug, Clone)]
+pub struct PortfolioOrder {
+	pub order: Order,		// The flow order for the basket, its quantity and u_max count baskets
+	pub weights: Vec<f64>,	// The shares of each asset in one basket, indexed like the market's assets
+}
+
+impl PortfolioOrder {
+	pub fn new(order: Order, weights: Vec<f64>) -> PortfolioOrder {
+		PortfolioOrder { order, weights }
+	}
+
+	/// The value of one basket at the asset prices
+	pub fn basket_price(&self, prices: &[f64]) -> f64 {
+		self.weights.iter().zip(prices).map(|(w, p)| w * p).sum()
+	}
+
+	/// The baskets this order trades at a basket price in a batch lasting seconds
+	pub fn baskets(&self, basket_price: f64, seconds: f64) -> f64 {
+		match self.order.trade_type {
+			TradeType::Bid => self.order.calc_flow_demand(basket_price, seconds),
+			TradeType::Ask => self.order.calc_flow_supply(basket_price, seconds),
+		}
+	}
+
+	// The shares of asset i bought per basket traded, negative when they're sold
+	fn shares_per_basket(&self, i: usize) -> f64 {
+		match self.order.trade_type {
+			TradeType::Bid => self.weights[i],
+			TradeType::Ask => -self.weights[i],
+		}
+	}
+}
+
+
+/// The prices that clear every asset of a PortfolioMarket at once
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortfolioCross {
+	pub prices: Vec<Option<f64>>,	// The clearing price of each asset, None if no order trades it
+	pub demand: Vec<f64>,			// The shares of each asset bought at the prices, portfolio legs included
+	pub supply: Vec<f64>,			// The shares of each asset sold at the prices, portfolio legs included
+	pub sweeps: usize,				// The passes over the assets it took to solve the prices
+	pub converged: bool,			// Whether the prices stopped moving within the market's max_sweeps
+}
+
+
+/// A flow market over several assets. Each asset has its own books of single-asset flow
+/// orders and portfolio orders trade baskets across them, so a portfolio's demand for one
+/// asset depends on the prices of the others. Every clear solves for the vector of prices
+/// where each asset's demand meets its supply, then trades the flow orders and the
+/// portfolio legs at them. Like the KlfMechanism every order trades its rate for the
+/// batch_interval and keeps trading in later batches until its quantity is used up.
+/// Run as the simulation's AuctionMechanism, the simulation's books stand in for the
+/// books of asset 0 and orders reach the other assets through Order::with_asset and
+/// Order::with_basket.
+pub struct PortfolioMarket {
+	pub assets: Vec<Asset>,
+	pub portfolios: Mutex<Vec<PortfolioOrder>>,
+	pub batch_interval: u64,	// Milliseconds between clears, the time each batch's flow trades over
+	pub max_sweeps: usize,		// The passes over the assets solve makes before giving up on a cross
+	pub prices: Mutex<Vec<Option<f64>>>,	// The clearing price of each asset in the last batch that traded it
+}
+
+impl PortfolioMarket {
+	pub fn new(names: Vec<String>, batch_interval: u64) -> PortfolioMarket {
+		let prices = vec![None; names.len()];
+		PortfolioMarket {
+			assets: names.into_iter().map(Asset::new).collect(),
+			portfolios: Mutex::new(Vec::new()),
+			batch_interval,
+			max_sweeps: MAX_SWEEPS,
+			prices: Mutex::new(prices),
+		}
+	}
+
+	/// Adds a single-asset flow order to the books of the asset at index asset
+	pub fn add_order(&self, asset: usize, order: Order) -> Result<(), SimError> {
+		order.validate()?;
+		if order.ex_type != ExchangeType::FlowOrder {
+			return Err(SimError::InvalidOrder { order_id: order.order_id, reason: "the portfolio market only trades flow orders" });
+		}
+		let asset = match self.assets.get(asset) {
+			Some(asset) => asset,
+			None => return Err(SimError::InvalidOrder { order_id: order.order_id, reason: "the portfolio market has no such asset" }),
+		};
+		match order.trade_type {
+			TradeType::Bid => asset.bids.add_order(order),
+			TradeType::Ask => asset.asks.add_order(order),
+		}
+	}
+
+	/// Adds a portfolio order, its weights must give a finite share of every asset
+	/// in the market and hold at least one of them
+	pub fn add_portfolio(&self, portfolio: PortfolioOrder) -> Result<(), SimError> {
+		let order = &portfolio.order;
+		order.validate()?;
+		let reason = if order.ex_type != ExchangeType::FlowOrder {
+			"the portfolio market only trades flow orders"
+		} else if portfolio.weights.len() != self.assets.len() {
+			"a basket needs a weight for every asset in the market"
+		} else if portfolio.weights.iter().any(|w| !w.is_finite()) {
+			"a basket's weights must be finite numbers"
+		} else if portfolio.weights.iter().all(|w| *w == 0.0) {
+			"a basket must hold at least one asset"
+		} else {
+			self.portfolios.lock().expect("couldn't lock portfolios").push(portfolio);
+			return Ok(());
+		};
+		Err(SimError::InvalidOrder { order_id: order.order_id, reason })
+	}
+
+	/// Removes the portfolio order, returning it if it was still trading
+	pub fn cancel_portfolio(&self, order_id: u64) -> Option<PortfolioOrder> {
+		let mut portfolios = self.portfolios.lock().expect("couldn't lock portfolios");
+		let index = portfolios.iter().position(|p| p.order.order_id == order_id)?;
+		Some(portfolios.remove(index))
+	}
+
+	/// Solves for the prices that clear every asset in a batch lasting seconds. Each asset's
+	/// excess demand is non-increasing in its own price with the others held fixed, so the
+	/// assets are solved one at a time by bisection, each with the latest prices of the
+	/// others, and the passes are repeated until no price moves by more than TOLERANCE.
+	/// The first pass starts from each asset's own flow cross without the portfolios.
+	/// Where an asset clears over a range of prices it takes the midpoint, like
+	/// Auction::solve_flow_cross. The passes settle on the cross when the portfolios'
+	/// cross-asset demand is weaker than each asset's own, otherwise they may stop after
+	/// max_sweeps without converging. Returns None if there are no orders.
+	pub fn solve(&self, seconds: f64) -> Option<PortfolioCross> {
+		self.solve_books(&self.own_books(), seconds)
+	}
+
+	/// Clears every asset at the prices from solve. The single-asset flow orders trade as
+	/// in the KLF and each portfolio order trades one leg per asset in its basket, bought
+	/// or sold at that asset's price. Each asset's buyers are paired with its sellers in
+	/// book priority, portfolio legs last, so every trade has a real counterparty and is
+	/// tagged with its asset. Each portfolio's legs trade the same number of baskets on
+	/// every asset, only fewer than it wants where they'd outrun the other side, and a
+	/// decrement of each portfolio order by the baskets it traded follows the trades. The results carry the price, demand and supply of asset 0, the
+	/// prices of the rest are kept in prices. If the prices don't converge they don't
+	/// clear the market, so nothing trades and SimError::NoCross is returned.
+	pub fn clear(&self) -> Result<Option<TradeResults>, SimError> {
+		self.clear_books(&self.own_books())
+	}
+
+	// The books of every asset
+	fn own_books(&self) -> Vec<(Arc<Book>, Arc<Book>)> {
+		self.assets.iter().map(|asset| (Arc::clone(&asset.bids), Arc::clone(&asset.asks))).collect()
+	}
+
+	// The books of every asset, with the simulation's books for asset 0
+	fn sim_books(&self, bids: Arc<Book>, asks: Arc<Book>) -> Vec<(Arc<Book>, Arc<Book>)> {
+		let mut books = self.own_books();
+		books[0] = (bids, asks);
+		books
+	}
+
+	fn solve_books(&self, books: &[(Arc<Book>, Arc<Book>)], seconds: f64) -> Option<PortfolioCross> {
+		let orders: Vec<(Vec<Order>, Vec<Order>)> = books.iter()
+			.map(|(bids, asks)| (bids.copy_orders(), asks.copy_orders()))
+			.collect();
+		let portfolios = self.portfolios.lock().expect("couldn't lock portfolios").clone();
+
+		// The shares of asset i bought and sold at price p, given the value of the rest of each basket
+		let flows = |i: usize, p: f64, others: &[f64]| -> (f64, f64) {
+			let (bids, asks) = &orders[i];
+			let mut demand: f64 = bids.iter().map(|o| o.calc_flow_demand(p, seconds)).sum();
+			let mut supply: f64 = asks.iter().map(|o| o.calc_flow_supply(p, seconds)).sum();
+			for (portfolio, other) in portfolios.iter().zip(others) {
+				let per_basket = portfolio.shares_per_basket(i);
+				if per_basket == 0.0 {
+					continue;
+				}
+				let shares = per_basket * portfolio.baskets(other + portfolio.weights[i] * p, seconds);
+				if shares > 0.0 {
+					demand += shares;
+				} else {
+					supply -= shares;
+				}
+			}
+			(demand, supply)
+		};
+		// The value of each basket without its asset i leg
+		let others = |i: usize, prices: &[f64]| -> Vec<f64> {
+			portfolios.iter().map(|o| o.basket_price(prices) - o.weights[i] * prices[i]).collect()
+		};
+
+		// The prices where an order's flow schedule for asset i changes, an asset without any is untraded
+		let bounds = |i: usize, others: &[f64]| -> Option<(f64, f64)> {
+			let (bids, asks) = &orders[i];
+			let mut points: Vec<f64> = bids.iter().chain(asks.iter()).flat_map(|o| vec!(o.p_low, o.p_high)).collect();
+			for (portfolio, other) in portfolios.iter().zip(others) {
+				let w = portfolio.weights[i];
+				if w != 0.0 {
+					points.push((portfolio.order.p_low - other) / w);
+					points.push((portfolio.order.p_high - other) / w);
+				}
+			}
+			let low = points.iter().cloned().fold(None, |m: Option<f64>, p| Some(m.map_or(p, |m| m.min(p))))?;
+			let high = points.iter().cloned().fold(low, f64::max);
+			Some((low, high))
+		};
+
+		let n = books.len();
+		let traded: Vec<bool> = (0..n).map(|i| bounds(i, &others(i, &vec![0.0; n])).is_some()).collect();
+		if !traded.contains(&true) {
+			return None;
+		}
+		let mut prices: Vec<f64> = books.iter()
+			.map(|(bids, asks)| Auction::solve_flow_cross(bids, asks, seconds).map_or(0.0, |cross| cross.price))
+			.collect();
+
+		let mut sweeps = 0;
+		let mut converged = false;
+		while sweeps < self.max_sweeps && !converged {
+			sweeps += 1;
+			let mut moved: f64 = 0.0;
+			for i in (0..n).filter(|i| traded[*i]) {
+				let others = others(i, &prices);
+				let (low, high) = bounds(i, &others).expect("traded assets have bounds");
+				let price = bisect_price(|p| { let (d, s) = flows(i, p, &others); d - s }, low, high);
+				moved = moved.max((price - prices[i]).abs());
+				prices[i] = price;
+			}
+			converged = moved < TOLERANCE;
+		}
+
+		let (demand, supply) = (0..n).map(|i| flows(i, prices[i], &others(i, &prices))).unzip();
+		let prices = prices.into_iter().zip(traded).map(|(p, traded)| if traded { Some(p) } else { None }).collect();
+		Some(PortfolioCross { prices, demand, supply, sweeps, converged })
+	}
+
+	fn clear_books(&self, books: &[(Arc<Book>, Arc<Book>)]) -> Result<Option<TradeResults>, SimError> {
+		let seconds = self.batch_interval as f64 / 1000.0;
+		let cross = match self.solve_books(books, seconds) {
+			Some(cross) => cross,
+			None => return Ok(None),
+		};
+		if !cross.converged {
+			return Err(SimError::NoCross { sweeps: cross.sweeps });
+		}
+
+		// Every portfolio trades its baskets at the basket price of the clearing prices
+		let prices: Vec<f64> = cross.prices.iter().map(|p| p.unwrap_or(0.0)).collect();
+		let mut portfolios = self.portfolios.lock().expect("couldn't lock portfolios");
+		let baskets: Vec<f64> = portfolios.iter().map(|p| p.baskets(p.basket_price(&prices), seconds)).collect();
+
+		// The asset's flow orders at its price, the buyers then the sellers
+		let flow_fills: Vec<Option<(Vec<Fill>, Vec<Fill>)>> = books.iter().zip(&cross.prices).map(|((bids, asks), price)| {
+			price.map(|p| {
+				let buys = bids.copy_orders().iter().map(|o| Fill::new(o, o.calc_flow_demand(p, seconds))).filter(|f| f.volume > 0.0).collect();
+				let sells = asks.copy_orders().iter().map(|o| Fill::new(o, o.calc_flow_supply(p, seconds))).filter(|f| f.volume > 0.0).collect();
+				(buys, sells)
+			})
+		}).collect();
+		let flows: Vec<(f64, f64)> = flow_fills.iter().map(|fills| match fills {
+			Some((buys, sells)) => (buys.iter().map(|f| f.volume).sum(), sells.iter().map(|f| f.volume).sum()),
+			None => (0.0, 0.0),
+		}).collect();
+		let legs: Vec<Vec<f64>> = portfolios.iter().zip(&baskets)
+			.map(|(p, baskets)| (0..books.len()).map(|i| p.shares_per_basket(i) * baskets).collect())
+			.collect();
+		let baskets: Vec<f64> = fit_baskets(&flows, &legs).iter().zip(baskets).map(|(fit, baskets)| fit * baskets).collect();
+
+		let mut updates = Vec::<PlayerUpdate>::new();
+		for (i, ((bids, asks), fills)) in books.iter().zip(flow_fills).enumerate() {
+			let (price, (mut buys, mut sells)) = match (cross.prices[i], fills) {
+				(Some(price), Some(fills)) => (price, fills),
+				_ => continue,
+			};
+			let (flow_bought, flow_sold) = flows[i];
+			let (flow_buys, flow_sells) = (buys.len(), sells.len());
+			// The portfolio legs follow the flow orders, each its share of the baskets it trades
+			let (mut legs_bought, mut legs_sold) = (0.0, 0.0);
+			for (portfolio, baskets) in portfolios.iter().zip(&baskets) {
+				let shares = portfolio.shares_per_basket(i) * baskets;
+				if shares > 0.0 {
+					buys.push(Fill::new(&portfolio.order, shares));
+					legs_bought += shares;
+				} else if shares < 0.0 {
+					sells.push(Fill::new(&portfolio.order, -shares));
+					legs_sold -= shares;
+				}
+			}
+
+			// The prices are solved to a tolerance, so the flow orders on the larger side are
+			// scaled down to the other. The legs keep their baskets' proportions.
+			let traded = (flow_bought + legs_bought).min(flow_sold + legs_sold);
+			for (fills, n, flow, legs) in [(&mut buys, flow_buys, flow_bought, legs_bought), (&mut sells, flow_sells, flow_sold, legs_sold)] {
+				if flow > 0.0 && flow + legs > traded {
+					let scale = ((traded - legs) / flow).max(0.0);
+					for fill in fills.iter_mut().take(n) {
+						fill.volume *= scale;
+					}
+				}
+			}
+
+			trade_book(bids, &buys)?;
+			trade_book(asks, &sells)?;
+			updates.extend(pair_fills(price, &buys, &sells).into_iter().map(|pu| pu.with_asset(i)));
+		}
+
+		for (portfolio, baskets) in portfolios.iter_mut().zip(baskets) {
+			if baskets > 0.0 {
+				updates.push(PlayerUpdate::decrement(portfolio.order.trader_id.clone(), portfolio.order.order_id, baskets));
+				portfolio.order.quantity -= baskets;
+			}
+		}
+		// Portfolios that have traded their quantity are done
+		portfolios.retain(|p| p.order.quantity > 0.0);
+
+		let mut last_prices = self.prices.lock().expect("couldn't lock prices");
+		for (last, price) in last_prices.iter_mut().zip(&cross.prices) {
+			if price.is_some() {
+				*last = *price;
+			}
+		}
+		Ok(Some(TradeResults::new(cross.prices[0], cross.demand[0], cross.supply[0], Some(updates))))
+	}
+
+	// Checks the order can trade in the market, whichever books it's for. Only the simulation's
+	// books purge expired orders, so the other assets and the portfolios don't take them.
+	fn check_order(&self, order: &Order) -> Result<(), SimError> {
+		let in_sim_books = order.asset == 0 && !order.is_portfolio();
+		let reason = if order.ex_type != ExchangeType::FlowOrder {
+			"the portfolio market only trades flow orders"
+		} else if order.asset >= self.assets.len() {
+			"the portfolio market has no such asset"
+		} else if order.expires_at_block.is_some() && !in_sim_books {
+			"only orders for asset 0 can expire in the portfolio market"
+		} else {
+			return Ok(());
+		};
+		Err(SimError::InvalidOrder { order_id: order.order_id, reason })
+	}
+}
+
+impl AuctionMechanism for PortfolioMarket {
+	fn name(&self) -> String {
+		String::from("Portfolio KLF")
+	}
+
+	fn ex_type(&self) -> ExchangeType {
+		ExchangeType::FlowOrder
+	}
+
+	fn accept_order(&self, bids: Arc<Book>, asks: Arc<Book>, order: Order) -> Result<Option<TradeResults>, SimError> {
+		self.check_order(&order)?;
+		match (order.basket.clone(), order.asset) {
+			(Some(weights), _) => self.add_portfolio(PortfolioOrder::new(order, weights))?,
+			(None, 0) => match order.trade_type {
+				TradeType::Bid => bids.add_order(order)?,
+				TradeType::Ask => asks.add_order(order)?,
+			},
+			(None, asset) => self.add_order(asset, order)?,
+		}
+		Ok(None)
+	}
+
+	fn clear(&self, bids: Arc<Book>, asks: Arc<Book>) -> Result<Option<TradeResults>, SimError> {
+		self.clear_books(&self.sim_books(bids, asks))
+	}
+
+	fn cancel_order(&self, bids: Arc<Book>, asks: Arc<Book>, order: Order) -> Result<(), SimError> {
+		if order.is_portfolio() {
+			return match self.cancel_portfolio(order.order_id) {
+				Some(_) => Ok(()),
+				None => Err(SimError::OrderNotInBook { book: order.trade_type, order_id: order.order_id }),
+			};
+		}
+		let (bids, asks) = match self.assets.get(order.asset) {
+			Some(_) if order.asset == 0 => (bids, asks),
+			Some(asset) => (Arc::clone(&asset.bids), Arc::clone(&asset.asks)),
+			None => return Err(SimError::OrderNotInBook { book: order.trade_type, order_id: order.order_id }),
+		};
+		match order.trade_type {
+			TradeType::Ask => asks.cancel_order(order),
+			TradeType::Bid => bids.cancel_order(order),
+		}
+	}
+
+	fn runs_call_auctions(&self) -> bool {
+		true
+	}
+
+	fn settle(&self, house: &ClearingHouse, results: TradeResults) -> Vec<SimError> {
+		house.portfolio_batch_update(results)
+	}
+}
+
+
+// The shares one order buys or sells of an asset in a batch
+struct Fill {
+	trader_id: String,
+	order_id: u64,
+	volume: f64,
+}
+
+impl Fill {
+	fn new(order: &Order, volume: f64) -> Fill {
+		Fill { trader_id: order.trader_id.clone(), order_id: order.order_id, volume }
+	}
+}
+
+// The fraction of its baskets each portfolio trades, the same on every asset. flows[i] is
+// the shares of asset i the flow orders buy and sell and legs[p][i] the shares portfolio p
+// buys of it at its full baskets, negative when it sells. Where the legs on one side of an
+// asset come to more than the other side trades, the portfolios on that side are scaled
+// down until they fit, which can shrink their legs elsewhere, so it's repeated until
+// every asset fits.
+fn fit_baskets(flows: &[(f64, f64)], legs: &[Vec<f64>]) -> Vec<f64> {
+	let mut fit = vec![1.0; legs.len()];
+	for _ in 0..=legs.len() * flows.len() {
+		let mut fitted = true;
+		for (i, (flow_bought, flow_sold)) in flows.iter().enumerate() {
+			let side = |fit: &[f64], buys: bool| -> f64 {
+				legs.iter().zip(fit).map(|(l, f)| if (l[i] > 0.0) == buys { l[i].abs() * f } else { 0.0 }).sum()
+			};
+			for (buys, other_flow) in [(true, flow_sold), (false, flow_bought)] {
+				let (legs_side, other) = (side(&fit, buys), other_flow + side(&fit, !buys));
+				if legs_side > other + TOLERANCE {
+					for (l, f) in legs.iter().zip(fit.iter_mut()) {
+						if l[i] != 0.0 && (l[i] > 0.0) == buys {
+							*f *= other / legs_side;
+						}
+					}
+					fitted = false;
+				}
+			}
+		}
+		if fitted {
+			break;
+		}
+	}
+	fit
+}
+
+// Takes the shares each order in the book traded off it, removing the orders that are filled
+fn trade_book(book: &Book, fills: &[Fill]) -> Result<(), SimError> {
+	let mut filled = Vec::<u64>::new();
+	book.update_orders(|order| {
+		if let Some(fill) = fills.iter().find(|f| f.order_id == order.order_id) {
+			order.quantity -= fill.volume;
+			if order.quantity <= 0.0 {
+				filled.push(order.order_id);
+			}
+		}
+	});
+	for id in filled {
+		book.cancel_order_by_id(id)?;
+	}
+	Ok(())
+}
+
+// Pairs the buyers with the sellers in the order they're listed, each trade is between
+// one buyer and one seller at price. The sides must trade the same number of shares.
+fn pair_fills(price: f64, buys: &[Fill], sells: &[Fill]) -> Vec<PlayerUpdate> {
+	let mut updates = Vec::<PlayerUpdate>::new();
+	let (mut b, mut s) = (0, 0);
+	let (mut buy_left, mut sell_left) = (buys.first().map_or(0.0, |f| f.volume), sells.first().map_or(0.0, |f| f.volume));
+	while b < buys.len() && s < sells.len() {
+		let volume = buy_left.min(sell_left);
+		if volume > 0.0 {
+			updates.push(PlayerUpdate::new(buys[b].trader_id.clone(), sells[s].trader_id.clone(),
+										   buys[b].order_id, sells[s].order_id, price, volume, false));
+		}
+		buy_left -= volume;
+		sell_left -= volume;
+		if buy_left <= 0.0 {
+			b += 1;
+			buy_left = buys.get(b).map_or(0.0, |f| f.volume);
+		}
+		if sell_left <= 0.0 {
+			s += 1;
+			sell_left = sells.get(s).map_or(0.0, |f| f.volume);
+		}
+	}
+	updates
+}
+
+
+// Finds where a non-increasing excess demand crosses zero in [low, high]: bisects for the
+// last price where it's still positive and the last where it's not yet negative. Between
+// them it's zero, or it steps past zero if they meet, so the price is their midpoint.
+// If it never turns negative the price is high, if it's never positive it's low.
+fn bisect_price<F: Fn(f64) -> f64>(excess: F, low: f64, high: f64) -> f64 {
+	let last = |holds: &dyn Fn(f64) -> bool| -> f64 {
+		if holds(high) {
+			return high;
+		}
+		if !holds(low) {
+			return low;
+		}
+		let (mut left, mut right) = (low, high);
+		for _ in 0..BISECTIONS {
+			let mid = (left + right) / 2.0;
+			if mid <= left || mid >= right {
+				break;
+			}
+			if holds(mid) {
+				left = mid;
+			} else {
+				right = mid;
+			}
+		}
+		(left + right) / 2.0
+	};
+	let positive = last(&|p| excess(p) > TOLERANCE);
+	let not_negative = last(&|p| excess(p) >= -TOLERANCE);
+	(positive + not_negative) / 2.0
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::order::order::OrderType;
+
+	fn flow_order(trade_type: TradeType, p_low: f64, p_high: f64, quantity: f64, u_max: f64) -> Order {
+		Order::new(String::from("FLOW"), OrderType::Enter, trade_type, ExchangeType::FlowOrder,
+				   p_low, p_high, 0.0, quantity, u_max, 0.1)
+	}
+
+	fn close(a: f64, b: f64) -> bool {
+		(a - b).abs() < 0.000_1
+	}
+
+	// Two assets each offered at S(p) = p, one second batches
+	fn market() -> PortfolioMarket {
+		let market = PortfolioMarket::new(vec!(String::from("A"), String::from("B")), 1000);
+		for asset in 0..2 {
+			market.add_order(asset, flow_order(TradeType::Ask, 0.0, 100.0, 1000.0, 100.0)).unwrap();
+		}
+		market
+	}
+
+	#[test]
+	fn test_single_assets_match_flow_cross() {
+		// Without portfolios each asset clears alone at its own flow cross
+		let market = market();
+		market.add_order(0, flow_order(TradeType::Bid, 0.0, 100.0, 1000.0, 100.0)).unwrap();
+		market.add_order(1, flow_order(TradeType::Bid, 0.0, 100.0, 30.0, 100.0)).unwrap();
+		let cross = market.solve(1.0).unwrap();
+		assert!(cross.converged);
+		assert!(close(cross.prices[0].unwrap(), 50.0) && close(cross.prices[1].unwrap(), 30.0));
+		assert!(close(cross.demand[1], 30.0) && close(cross.supply[1], 30.0));
+	}
+
+	#[test]
+	fn test_basket_cross() {
+		// A bid for baskets of one A and two B, D(P) = 100 - P/2 baskets at basket price P,
+		// clears both assets together where p_A = D and p_B = 2D, so D = 100/3.5
+		let market = market();
+		let basket = flow_order(TradeType::Bid, 0.0, 200.0, 1000.0, 100.0);
+		market.add_portfolio(PortfolioOrder::new(basket, vec!(1.0, 2.0))).unwrap();
+		let cross = market.solve(1.0).unwrap();
+		let baskets = 100.0 / 3.5;
+		assert!(cross.converged);
+		assert!(close(cross.prices[0].unwrap(), baskets) && close(cross.prices[1].unwrap(), 2.0 * baskets));
+		assert!(close(cross.demand[0], baskets) && close(cross.demand[1], 2.0 * baskets));
+		assert!(close(cross.demand[0], cross.supply[0]) && close(cross.demand[1], cross.supply[1]));
+	}
+
+	#[test]
+	fn test_portfolio_legs() {
+		// A pairs trade: buy one A and sell one B, against bids for B at D(p) = 100 - p
+		let market = market();
+		market.add_order(1, flow_order(TradeType::Bid, 0.0, 100.0, 1000.0, 100.0)).unwrap();
+		let pair = flow_order(TradeType::Bid, -100.0, 100.0, 20.0, 10.0);
+		let pair_id = pair.order_id;
+		market.add_portfolio(PortfolioOrder::new(pair, vec!(1.0, -1.0))).unwrap();
+
+		// Halving the batch halves the baskets traded, 10 baskets a second at most
+		let market = PortfolioMarket { batch_interval: 500, ..market };
+		let mut traded = 0.0;
+		for _ in 0..10 {
+			let updates = match market.clear().unwrap() {
+				Some(results) => results.cross_results.unwrap(),
+				None => break,
+			};
+			let prices = market.prices.lock().unwrap().clone();
+			// Every trade is between a real buyer and seller at its asset's price
+			for pu in updates.iter().filter(|pu| !pu.cancel) {
+				assert!(pu.payer_order_id != 0 && pu.vol_filler_order_id != 0);
+				assert_eq!(Some(pu.price), prices[pu.asset]);
+			}
+			let leg = |asset: usize| -> f64 {
+				updates.iter()
+					.filter(|pu| !pu.cancel && pu.asset == asset && (pu.payer_order_id == pair_id || pu.vol_filler_order_id == pair_id))
+					.map(|pu| pu.volume)
+					.sum()
+			};
+			let baskets = match updates.iter().find(|pu| pu.is_decrement() && pu.payer_order_id == pair_id) {
+				Some(decrement) => decrement.volume,
+				None => break,
+			};
+			// The legs are the same number of baskets, A bought and B sold
+			assert!(close(leg(0), baskets) && close(leg(1), baskets) && baskets <= 5.0 + 0.000_1);
+			assert!(updates.iter().any(|pu| pu.asset == 1 && pu.vol_filler_order_id == pair_id));
+			traded += baskets;
+		}
+		// It kept trading until its 20 baskets were used up
+		assert!(close(traded, 20.0));
+		assert!(market.portfolios.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn test_legs_keep_proportions() {
+		// A basket ask of one A and one B, S(P) = P/5 baskets, against bids for A at
+		// D(p) = 100 - p and a bid for 20 B at any price up to 10. B clears at 10 where
+		// the bid wants more than the basket sells, so only the bid is scaled down.
+		let market = PortfolioMarket::new(vec!(String::from("A"), String::from("B")), 1000);
+		market.add_order(0, flow_order(TradeType::Bid, 0.0, 100.0, 1000.0, 100.0)).unwrap();
+		market.add_order(1, flow_order(TradeType::Bid, 10.0, 10.0, 1000.0, 20.0)).unwrap();
+		let basket = flow_order(TradeType::Ask, 0.0, 100.0, 1000.0, 20.0);
+		let basket_id = basket.order_id;
+		market.add_portfolio(PortfolioOrder::new(basket, vec!(1.0, 1.0))).unwrap();
+		let cross = market.solve(1.0).unwrap();
+		assert!(cross.demand[1] > cross.supply[1] + 1.0);
+
+		let updates = market.clear().unwrap().unwrap().cross_results.unwrap();
+		let decrement = updates.iter().find(|pu| pu.is_decrement() && pu.payer_order_id == basket_id).unwrap();
+		let baskets = 110.0 / 6.0;
+		assert!(close(decrement.volume, baskets));
+		for asset in 0..2 {
+			let leg: f64 = updates.iter().filter(|pu| !pu.cancel && pu.asset == asset).map(|pu| pu.volume).sum();
+			assert!(close(leg, baskets));
+		}
+		assert!(close(market.assets[1].bids.get_book_volume(), 1000.0 - baskets));
+		assert!(close(market.portfolios.lock().unwrap()[0].order.quantity, 1000.0 - baskets));
+	}
+
+	#[test]
+	fn test_fit_baskets() {
+		// Legs within what the other side trades keep all their baskets
+		assert_eq!(fit_baskets(&[(0.0, 100.0), (0.0, 50.0)], &[vec!(50.0, 50.0)]), vec!(1.0));
+		// A basket buying 50 of B when 5 are sold trades a tenth of its baskets on both assets
+		let fit = fit_baskets(&[(0.0, 100.0), (0.0, 5.0)], &[vec!(50.0, 50.0)]);
+		assert!(close(fit[0], 0.1));
+		// Scaling one portfolio down shrinks what another can sell to it
+		let fit = fit_baskets(&[(0.0, 5.0), (0.0, 0.0)], &[vec!(10.0, 10.0), vec!(0.0, -20.0)]);
+		assert!(close(fit[0], 0.5) && close(fit[1], 0.25));
+	}
+
+	#[test]
+	fn test_settles_per_asset_inventory() {
+		use crate::players::investor::Investor;
+
+		let house = ClearingHouse::new();
+		let ids = vec!("BASKET", "SELL_A", "SELL_B");
+		house.reg_n_investors(ids.iter().map(|id| Investor::new(String::from(*id))).collect());
+		let (bids, asks) = (Arc::new(Book::new(TradeType::Bid)), Arc::new(Book::new(TradeType::Ask)));
+
+		// Asset 0 trades in the simulation's books, asset 1 in the market's own
+		let market = PortfolioMarket::new(vec!(String::from("A"), String::from("B")), 1000);
+		let order = |id: &str, trade_type: TradeType, p_high: f64| Order::new(String::from(id), OrderType::Enter, trade_type,
+			ExchangeType::FlowOrder, 0.0, p_high, 0.0, 1000.0, 100.0, 0.1);
+		let orders = vec!(order("SELL_A", TradeType::Ask, 100.0),
+						  order("SELL_B", TradeType::Ask, 100.0).with_asset(1),
+						  order("BASKET", TradeType::Bid, 200.0).with_basket(vec!(1.0, 2.0)));
+		for o in orders {
+			house.new_order(o.clone()).unwrap();
+			assert!(market.accept_order(Arc::clone(&bids), Arc::clone(&asks), o).unwrap().is_none());
+		}
+		assert_eq!(asks.get_book_volume(), 1000.0);
+		assert_eq!(market.assets[1].asks.get_book_volume(), 1000.0);
+		assert!(market.assets[0].asks.get_book_volume() == 0.0 && market.portfolios.lock().unwrap().len() == 1);
+
+		let results = AuctionMechanism::clear(&market, Arc::clone(&bids), Arc::clone(&asks)).unwrap().unwrap();
+		assert!(market.settle(&house, results).is_empty());
+
+		// The basket bought D of A and 2D of B, each from its seller
+		let baskets = 100.0 / 3.5;
+		let basket_id = String::from("BASKET");
+		assert!(close(house.get_asset_inv(&basket_id, 0).unwrap(), baskets));
+		assert!(close(house.get_asset_inv(&basket_id, 1).unwrap(), 2.0 * baskets));
+		assert!(close(house.get_asset_inv(&String::from("SELL_A"), 0).unwrap(), -baskets));
+		assert!(close(house.get_asset_inv(&String::from("SELL_B"), 1).unwrap(), -2.0 * baskets));
+		assert_eq!(house.get_asset_inv(&String::from("SELL_A"), 1), Some(0.0));
+		let paid: f64 = ids.iter().map(|id| house.get_bal_inv(String::from(*id)).unwrap().0).sum();
+		assert!(close(paid, 0.0));
+
+		// The basket order counts baskets, the sellers' orders count shares
+		let quantity = |id: &str| house.get_player_orders(&String::from(id)).unwrap()[0].quantity;
+		assert!(close(quantity("BASKET"), 1000.0 - baskets));
+		assert!(close(quantity("SELL_B"), 1000.0 - 2.0 * baskets));
+		assert!(close(asks.get_book_volume(), 1000.0 - baskets));
+
+		// Cancelling the basket removes it from the market
+		let basket = house.get_player_orders(&basket_id).unwrap().remove(0);
+		assert!(market.cancel_order(Arc::clone(&bids), Arc::clone(&asks), basket.clone()).is_ok());
+		assert!(market.portfolios.lock().unwrap().is_empty());
+		assert!(market.cancel_order(bids, asks, basket).is_err());
+	}
+
+	#[test]
+	fn test_no_cross_trades_nothing() {
+		// One pass can't settle the basket's prices, so nothing is traded or used up
+		let market = market();
+		let basket = flow_order(TradeType::Bid, 0.0, 200.0, 1000.0, 100.0);
+		market.add_portfolio(PortfolioOrder::new(basket, vec!(1.0, 2.0))).unwrap();
+		let market = PortfolioMarket { max_sweeps: 1, ..market };
+		assert!(!market.solve(1.0).unwrap().converged);
+		assert_eq!(market.clear().err(), Some(SimError::NoCross { sweeps: 1 }));
+		assert_eq!(market.portfolios.lock().unwrap()[0].order.quantity, 1000.0);
+		assert_eq!(market.assets[0].asks.get_book_volume(), 1000.0);
+
+		// With enough passes it clears
+		let market = PortfolioMarket { max_sweeps: MAX_SWEEPS, ..market };
+		assert!(market.clear().unwrap().is_some());
+	}
+
+	#[test]
+	fn test_invalid_portfolios() {
+		let market = market();
+		let order = || flow_order(TradeType::Bid, 0.0, 100.0, 10.0, 10.0);
+		assert!(market.add_portfolio(PortfolioOrder::new(order(), vec!(1.0))).is_err());
+		assert!(market.add_portfolio(PortfolioOrder::new(order(), vec!(0.0, 0.0))).is_err());
+		assert!(market.add_portfolio(PortfolioOrder::new(order(), vec!(1.0, f64::NAN))).is_err());
+		assert!(market.add_order(2, order()).is_err());
+		let limit = Order::new(String::from("FLOW"), OrderType::Enter, TradeType::Bid, ExchangeType::LimitOrder,
+							   10.0, 10.0, 10.0, 1.0, 1.0, 0.1);
+		assert!(market.add_order(0, limit).is_err());
+
+		let basket = order();
+		let id = basket.order_id;
+		market.add_portfolio(PortfolioOrder::new(basket, vec!(1.0, 1.0))).unwrap();
+		assert!(market.cancel_portfolio(id).is_some());
+		assert!(market.cancel_portfolio(id).is_none());
+	}
+}
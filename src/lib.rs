@@ -14,6 +14,7 @@ extern crate log4rs;
 pub mod utility;
 
 pub mod io;
+pub mod error;
 pub mod exchange;
 pub mod simulation;
 pub mod order;
@@ -1,25 +1,20 @@
 extern crate flow_rs;
 extern crate tokio;
 
-use flow_rs::simulation::simulation_config::{DistReason};
-use flow_rs::simulation::simulation_history::UpdateReason;
-use flow_rs::controller::Controller;
-use flow_rs::simulation::simulation::{Simulation};
+use flow_rs::simulation::{SimulationBuilder, Scenario};
 use flow_rs::simulation::config_parser::*;
 
 
-use flow_rs::utility::{setup_logging, get_time, setup_log_headers};
-use flow_rs::{log_order_book, log_player_data, log_mempool_data, log_results};
+use flow_rs::utility::{setup_logging, gen_seed};
+use flow_rs::log_results;
 
 
 #[macro_use]
 extern crate log;
 extern crate log4rs;
 
-use std::collections::HashMap;
-use log::{log, Level};
-use std::sync::Arc;
 use std::env;
+use std::path::Path;
 
 fn main() {
 	// Get the log file names
@@ -37,17 +32,22 @@ fn main() {
 	let dists_name = match args.next() {
 		Some(arg) => arg,
 		None => {
-			println!("Supply distributions csv file!");
+			println!("Supply distributions csv file or a .toml/.json scenario file!");
 			std::process::exit(1);
 		}
 	};
 
-	let consts_name = match args.next() {
-		Some(arg) => arg,
-		None => {
-			println!("Supply consts csv file!");
-			std::process::exit(1);
-		}
+	// A scenario file replaces both csv files
+	let is_scenario = dists_name.ends_with(".toml") || dists_name.ends_with(".json");
+	let consts_name = match is_scenario {
+		true => String::new(),
+		false => match args.next() {
+			Some(arg) => arg,
+			None => {
+				println!("Supply consts csv file!");
+				std::process::exit(1);
+			}
+		},
 	};
 
 	let enable_log: bool = match args.next() {
@@ -63,111 +63,61 @@ fn main() {
 		},
 	};
 
-	// Initialize the logger
-	let _logger_handle = setup_logging(&filename, enable_log);
-
-	// Create a new Controller to dispatch our tasks
-	let mut controller = Controller::new();
-
-	// Create a vector to hold the handles to the threads
-	let mut thread_handles = Vec::new();
-
-	// Read the distribution parameters from the supplied csv file (arg2)
-	let distributions = parse_dist_config_csv(format!("configs/{}", dists_name)).expect("Couldn't parse dists config");
+	// An optional master seed overrides the seed in the consts config
+	let arg_seed: Option<u64> = match args.next() {
+		Some(arg) => match arg.parse() {
+			Ok(seed) => Some(seed),
+			Err(_) => {
+				println!("Seed must be an unsigned integer!");
+				std::process::exit(1);
+			}
+		},
+		None => None,
+	};
 
-	// Read the constant parameters from the supplied csv file (arg3)
-	let consts = parse_consts_config_csv(format!("configs/{}", consts_name)).expect(&format!("Couldn't parse consts config {}", consts_name));
+	// Read and validate the configs before setting up the logs, reporting every error at once
+	let builder = match is_scenario {
+		true => match Scenario::load(Path::new(&format!("configs/{}", dists_name))) {
+			Ok(scenario) => scenario.builder(),
+			Err(e) => {
+				println!("Couldn't load scenario {}: {}", dists_name, e);
+				std::process::exit(1);
+			}
+		},
+		false => {
+			// Read the distribution parameters from the supplied csv file (arg2)
+			let distributions = match parse_dist_config_csv(format!("configs/{}", dists_name)) {
+				Ok(dists) => dists,
+				Err(e) => {
+					println!("Couldn't parse dists config {}: {}", dists_name, e);
+					std::process::exit(1);
+				}
+			};
+
+			// Read the constant parameters from the supplied csv file (arg3)
+			let consts = match parse_consts_config_csv(format!("configs/{}", consts_name)) {
+				Ok(consts) => consts,
+				Err(e) => {
+					println!("Couldn't parse consts config {}: {}", consts_name, e);
+					std::process::exit(1);
+				}
+			};
+			SimulationBuilder::new(distributions, consts)
+		},
+	};
 
-	// Write the headers to all of the log files
-	setup_log_headers(consts.market_type.clone());    
+	// Initialize the logger
+	let _logger_handle = setup_logging(&filename, enable_log);
 
-	// Initial state of the sim
-	let (simulation, miner) = Simulation::init_simulation(distributions, consts.clone());
+	// Every RNG stream is seeded so the run can be reproduced from the seed logged in the results
+	let seed = arg_seed.or(builder.config_seed()).unwrap_or_else(gen_seed);
+	info!("Using seed {}", seed);
 
-	// Log and save the intial state of the players
-	simulation.house.log_all_players(UpdateReason::Initial);
-	// Save the initial balance and inventory of each player
-	let mut initial_player_state = HashMap::<String, (f64, f64)>::new(); 
-	{
-		for (id, player) in simulation.house.players.lock().unwrap().iter() {
-			initial_player_state.insert(id.clone(), (player.get_bal(), player.get_inv()));
-		}
-	}
-	
-	// Initialize an investor thread to repeat at intervals based on supplied distributions
-	let investor_task = Simulation::investor_task(simulation.dists.clone(), 
-												  Arc::clone(&simulation.house),
-												  Arc::clone(&simulation.mempool),
-												  Arc::clone(&simulation.history), 
-												  Arc::clone(&simulation.block_num), 
-												  consts.clone());
-
-	thread_handles.push(investor_task);
-
-
-	// Initialize an maker task to repeat to be repeated on a fixed interval
-	let maker_task = Simulation::maker_task(simulation.dists.clone(), 
-												  Arc::clone(&simulation.house),
-												  Arc::clone(&simulation.mempool), 
-												  Arc::clone(&simulation.history), 
-												  Arc::clone(&simulation.block_num), 
-												  consts.clone());
-
-	controller.start_task(maker_task);
-
-
-	// Initalize a miner task to be repeated on a fixed interval
-	let miner_task = Simulation::miner_task(miner, simulation.dists.clone(), 
-												   Arc::clone(&simulation.house), 
-												   Arc::clone(&simulation.mempool),
-												   Arc::clone(&simulation.bids_book),
-												   Arc::clone(&simulation.asks_book), 
-												   Arc::clone(&simulation.history),
-												   Arc::clone(&simulation.block_num), 
-												   consts.clone());
-	
-	controller.start_task(miner_task);
-
-	// Wait for investor task to finish
-	for h in thread_handles {
-		h.join().unwrap();
+	// Run the trial and log its pre and post liquidation performance results
+	let simulation = builder.seed(seed).build();
+	for report in simulation.run() {
+		log_results!(report.to_csv_row());
 	}
-
-	// End the tasks
-	controller.shutdown();
-
-
-	info!("Done running simulation. Saving data...");
-
-	println!("{:?}", simulation.house.gas_fees);
-
-	// Log the final state of the players
-	simulation.house.log_all_players(UpdateReason::Final);
-
-	// Calculate the fundamental value from the configs
-	let (mean_bids, _dev_bids) = simulation.dists.read_dist_params(DistReason::BidsCenter);
-	let (mean_asks, _dev_asks) = simulation.dists.read_dist_params(DistReason::AsksCenter);
-	let fund_val = (mean_bids + mean_asks) / 2.0;
-	println!("fund_val: {}", fund_val);
-
-	
-
-	let s = format!("Experiment ending at: {:?}", get_time());
-	log_order_book!(s);
-	log_mempool_data!(s);
-	log_player_data!(s);
-
-	// Calculate the pre liquidation performance results
-	let res = simulation.calc_performance_results(fund_val, initial_player_state.clone());
-	log_results!(format!("{:?},NO,{}", consts.market_type, res));
-
-	// Each player transacts all non-zero inventory at the fundamental value
-	simulation.house.liquidate(fund_val);
-
-	// Calculate the post liquidation performance results
-	let res = simulation.calc_performance_results(fund_val, initial_player_state);
-	log_results!(format!("{:?},YES,{}", consts.market_type, res));
-
 }
 
 
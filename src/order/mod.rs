@@ -1,2 +1,2 @@
 pub mod order;
-pub mod order_book;
\ No newline at end of file
+pub mod order_book;pub mod stop_book;
@@ -1,4 +1,5 @@
 use crate::utility::{gen_order_id, get_time};
+use crate::error::SimError;
 
 
 /// Enum for matching over order types
@@ -36,11 +37,13 @@ impl Clone for TradeType {
 	}
 }
 
-// Enum for matching over LimitOrders and FlowOrders
+// Enum for matching over LimitOrders, FlowOrders and MarketOrders. Market orders are
+// unpriced limit orders, so they are sent to the same CDA and FBA markets.
 #[derive(Debug, PartialEq)]
 pub enum ExchangeType {
     LimitOrder,
     FlowOrder,
+    MarketOrder,
 }
 
 impl Clone for ExchangeType {
@@ -48,21 +51,42 @@ impl Clone for ExchangeType {
 		match self {
 			ExchangeType::LimitOrder => ExchangeType::LimitOrder,
 			ExchangeType::FlowOrder => ExchangeType::FlowOrder,
+			ExchangeType::MarketOrder => ExchangeType::MarketOrder,
 		}
 	}
 }
 
+/// How long an order can rest in the book
+/// GTC: rests until it's filled or cancelled
+/// IOC: immediate-or-cancel, trades what it can when it's matched and the rest is cancelled
+/// FOK: fill-or-kill, trades its whole quantity when it's matched or is cancelled without trading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeInForce {
+	GTC,
+	IOC,
+	FOK,
+}
+
 /// The internal data structure that any exchange format will operate on. 
 /// trader_id: String -> identifier of the trader and their order
 /// order_id: u64 -> identifier for an order in case a trader has multiple orders
 /// order_type: OrderType{Enter, Update, Cancel} -> identifies how the order is used by the exchange
 /// trade_type: TradeType{Bid, Ask} -> decides which order book the order is placed in 
-///	ex_type: ExchangeType{LimitOrder, FlowOrder} -> identifies which exchange this order is compatible with
+///	ex_type: ExchangeType{LimitOrder, FlowOrder, MarketOrder} -> identifies which exchange this order is compatible with
 /// p_low: f64 -> trader's minimum willingness to buy or sell (FlowOrder)
 /// p_high: f64 -> trader's maximum willingness to buy or sell (FlowOrder)
-/// price: f64 -> trader's willing ness to buy or sell (LimitOrder)
-/// quantity: f64 -> amount of shares to buy/sell
+/// price: f64 -> trader's willing ness to buy or sell (LimitOrder), unbounded for a MarketOrder
+/// quantity: f64 -> amount of shares to buy/sell, only the displayed part for an iceberg order
 /// gas: f64 -> the gas/tx fee to post an order
+/// tif: TimeInForce{GTC, IOC, FOK} -> whether the order can rest in the book (LimitOrder)
+/// post_only: bool -> the order is rejected instead of trading on entry, so it only adds liquidity (LimitOrder)
+/// expires_at_block: Option<u64> -> the last block the order can trade in, None until filled or cancelled
+/// display_size: f64 -> the most an iceberg order shows at once, 0.0 to show the whole order (LimitOrder)
+/// reserve: f64 -> the hidden quantity of an iceberg order, shown display_size at a time (LimitOrder)
+/// stop_price: Option<f64> -> the trade price that triggers a stop order into the books, None once triggered
+/// queued_at: u64 -> the arrival number given when the order last queued at the back of a price level, 0 until then
+/// asset: usize -> the asset the order trades in a multi-asset market, 0 for the simulation's own books
+/// basket: Option<Vec<f64>> -> the shares of each asset in one basket of a portfolio flow order, None for a single asset (FlowOrder)
 #[derive(Debug)]
 pub struct Order {
 	pub trader_id: String,
@@ -76,6 +100,15 @@ pub struct Order {
 	pub quantity: f64,
 	pub u_max: f64,			
 	pub gas: f64,
+	pub tif: TimeInForce,
+	pub post_only: bool,
+	pub expires_at_block: Option<u64>,
+	pub display_size: f64,
+	pub reserve: f64,
+	pub stop_price: Option<f64>,
+	pub queued_at: u64,
+	pub asset: usize,
+	pub basket: Option<Vec<f64>>,
 }
 
 impl Clone for Order {
@@ -92,6 +125,15 @@ impl Clone for Order {
 			quantity: self.quantity.clone(),
 			u_max: self.u_max.clone(),
 			gas: self.gas.clone(),
+			tif: self.tif,
+			post_only: self.post_only,
+			expires_at_block: self.expires_at_block,
+			display_size: self.display_size,
+			reserve: self.reserve,
+			stop_price: self.stop_price,
+			queued_at: self.queued_at,
+			asset: self.asset,
+			basket: self.basket.clone(),
 		}
 	}
 }
@@ -100,6 +142,14 @@ impl Order {
     pub fn new(t_id: String, o_t: OrderType, t_t: TradeType, 
     		   e_t: ExchangeType, p_l: f64, p_h: f64, p: f64, q: f64, u: f64, gas: f64) -> Order
     {
+    	// Market orders are unpriced and can't rest in the book, the prices given are ignored
+    	let (p_l, p_h, p, tif) = match e_t {
+    		ExchangeType::MarketOrder => {
+    			let p = Order::market_price(&t_t);
+    			(p, p, p, TimeInForce::IOC)
+    		},
+    		_ => (p_l, p_h, p, TimeInForce::GTC),
+    	};
     	Order {
     		trader_id: t_id,	
     		order_id: gen_order_id(),	
@@ -112,6 +162,206 @@ impl Order {
 			quantity: q,	
 			u_max: u,
 			gas: gas,
+			tif,
+			post_only: false,
+			expires_at_block: None,
+			display_size: 0.0,
+			reserve: 0.0,
+			stop_price: None,
+			queued_at: 0,
+			asset: 0,
+			basket: None,
+    	}
+    }
+
+    /// A market order for quantity shares, immediate-or-cancel unless made FOK with with_tif
+    pub fn market(t_id: String, t_t: TradeType, q: f64, gas: f64) -> Order {
+    	Order::new(t_id, OrderType::Enter, t_t, ExchangeType::MarketOrder, 0.0, 0.0, 0.0, q, q, gas)
+    }
+
+    /// The price a market order is queued at: ahead of every limit order of its side.
+    /// It never sets a clearing price.
+    pub fn market_price(trade_type: &TradeType) -> f64 {
+    	match trade_type {
+    		TradeType::Bid => f64::INFINITY,
+    		TradeType::Ask => f64::NEG_INFINITY,
+    	}
+    }
+
+    pub fn is_market(&self) -> bool {
+    	self.ex_type == ExchangeType::MarketOrder
+    }
+
+    /// Sets the order's time in force
+    pub fn with_tif(mut self, tif: TimeInForce) -> Order {
+    	self.tif = tif;
+    	self
+    }
+
+    /// Makes the order post-only
+    pub fn with_post_only(mut self) -> Order {
+    	self.post_only = true;
+    	self
+    }
+
+    /// Makes the order good till block, it's purged from the book once the block is published
+    pub fn with_expiry(mut self, block_num: u64) -> Order {
+    	self.expires_at_block = Some(block_num);
+    	self
+    }
+
+    /// Makes the order an iceberg that shows at most display_size shares, the rest of its
+    /// quantity is held in a hidden reserve
+    pub fn with_display(mut self, display_size: f64) -> Order {
+    	let shown = display_size.min(self.quantity);
+    	self.reserve = self.quantity - shown;
+    	self.quantity = shown;
+    	self.display_size = display_size;
+    	self
+    }
+
+    /// The displayed and hidden quantity of the order
+    pub fn total_quantity(&self) -> f64 {
+    	self.quantity + self.reserve
+    }
+
+    /// Once the displayed quantity is filled, shows the next display_size shares from the
+    /// reserve. Returns false if there was no reserve left to show.
+    pub fn replenish(&mut self) -> bool {
+    	if self.reserve <= 0.0 {
+    		return false;
+    	}
+    	let shown = self.display_size.min(self.reserve);
+    	self.quantity = shown;
+    	self.reserve -= shown;
+    	true
+    }
+
+    /// The order as other players see it, without its hidden reserve
+    pub fn displayed(&self) -> Order {
+    	let mut order = self.clone();
+    	order.display_size = 0.0;
+    	order.reserve = 0.0;
+    	order
+    }
+
+    /// Makes the order a stop order that waits in the stop book until a trade at or through
+    /// stop_price, a bid at or above it and an ask at or below it. It then enters the books
+    /// as the market, limit or flow order it was made as.
+    pub fn with_stop(mut self, stop_price: f64) -> Order {
+    	self.stop_price = Some(stop_price);
+    	self
+    }
+
+    pub fn is_stop(&self) -> bool {
+    	self.stop_price.is_some()
+    }
+
+    /// Whether a trade at price triggers this stop order
+    pub fn stop_triggered(&self, price: f64) -> bool {
+    	match (self.stop_price, &self.trade_type) {
+    		(Some(stop_price), TradeType::Bid) => price >= stop_price,
+    		(Some(stop_price), TradeType::Ask) => price <= stop_price,
+    		(None, _) => false,
+    	}
+    }
+
+    /// Makes the order trade the asset at index asset of a multi-asset market
+    pub fn with_asset(mut self, asset: usize) -> Order {
+    	self.asset = asset;
+    	self
+    }
+
+    /// Makes a flow order a portfolio order for baskets holding weights[i] shares of asset i,
+    /// its prices, quantity and u_max are then per basket
+    pub fn with_basket(mut self, weights: Vec<f64>) -> Order {
+    	self.basket = Some(weights);
+    	self
+    }
+
+    pub fn is_portfolio(&self) -> bool {
+    	self.basket.is_some()
+    }
+
+    /// Whether the order has expired before it can trade in block_num
+    pub fn is_expired(&self, block_num: u64) -> bool {
+    	match self.expires_at_block {
+    		Some(last_block) => last_block < block_num,
+    		None => false,
+    	}
+    }
+
+    /// Checks the order's prices, quantity and gas are numbers so it can be sorted and matched,
+    /// and that its time in force and post-only flag can be honoured
+    pub fn validate(&self) -> Result<(), SimError> {
+    	let reason = if self.price.is_nan() || self.p_low.is_nan() || self.p_high.is_nan() {
+    		"price is NaN"
+    	} else if self.quantity.is_nan() || self.u_max.is_nan() || self.reserve.is_nan() || self.display_size.is_nan() {
+    		"quantity is NaN"
+    	} else if self.gas.is_nan() {
+    		"gas is NaN"
+    	} else if self.stop_price.is_some_and(|p| !p.is_finite()) {
+    		"stop price must be a finite number"
+    	} else if self.ex_type == ExchangeType::FlowOrder && (self.tif != TimeInForce::GTC || self.post_only) {
+    		"flow orders can't be IOC, FOK or post-only"
+    	} else if self.post_only && self.tif != TimeInForce::GTC {
+    		"post-only orders must rest in the book so can't be IOC or FOK"
+    	} else if self.is_market() && self.tif == TimeInForce::GTC {
+    		"market orders can't rest in the book so must be IOC or FOK"
+    	} else if self.is_market() && self.price != Order::market_price(&self.trade_type) {
+    		"market orders are unpriced"
+    	} else if self.reserve < 0.0 || (self.reserve > 0.0 && self.display_size <= 0.0) {
+    		"an iceberg order's reserve must be shown a display_size greater than 0 at a time"
+    	} else if self.reserve > 0.0 && (self.ex_type != ExchangeType::LimitOrder || self.tif != TimeInForce::GTC) {
+    		"only limit orders that rest in the book can be icebergs"
+    	} else {
+    		return Ok(());
+    	};
+    	Err(SimError::InvalidOrder { order_id: self.order_id, reason })
+    }
+
+    /// Checks the order's prices are multiples of tick_size and its quantity a multiple
+    /// of lot_size. A size of 0.0 allows any value, and market orders only have a stop price to check.
+    pub fn check_grid(&self, tick_size: f64, lot_size: f64) -> Result<(), SimError> {
+    	let price_tick = match self.is_market() {
+    		true => 0.0,
+    		false => tick_size,
+    	};
+    	let reason = if !on_grid(self.price, price_tick) {
+    		"price is not a multiple of tick_size"
+    	} else if !on_grid(self.p_low, price_tick) || !on_grid(self.p_high, price_tick) {
+    		"p_low or p_high is not a multiple of tick_size"
+    	} else if self.stop_price.is_some_and(|p| !on_grid(p, tick_size)) {
+    		"stop price is not a multiple of tick_size"
+    	} else if !on_grid(self.quantity, lot_size) || !on_grid(self.reserve, lot_size) || !on_grid(self.display_size, lot_size) {
+    		"quantity is not a multiple of lot_size"
+    	} else {
+    		return Ok(());
+    	};
+    	Err(SimError::OffGrid { order_id: self.order_id, reason })
+    }
+
+    /// Moves the order's prices onto the tick grid and its quantity to a whole number of lots.
+    /// Prices round away from the other side of the book, bids down and asks up, so rounding
+    /// never makes an order more aggressive. The quantity rounds to the nearest lot, at least one.
+    pub fn round_to_grid(&mut self, tick_size: f64, lot_size: f64) {
+    	if tick_size > 0.0 && !self.is_market() {
+    		let trade_type = self.trade_type.clone();
+    		let round = |p: f64| match trade_type {
+    			TradeType::Bid => grid_multiple((p / tick_size + GRID_TOLERANCE).floor(), tick_size),
+    			TradeType::Ask => grid_multiple((p / tick_size - GRID_TOLERANCE).ceil(), tick_size),
+    		};
+    		self.price = round(self.price);
+    		self.p_low = round(self.p_low);
+    		self.p_high = round(self.p_high);
+    	}
+    	if lot_size > 0.0 {
+    		self.quantity = grid_multiple((self.quantity / lot_size).round().max(1.0), lot_size);
+    		// An iceberg's reserve can round down to nothing, but it must show at least a lot
+    		if self.display_size > 0.0 {
+    			self.display_size = grid_multiple((self.display_size / lot_size).round().max(1.0), lot_size);
+    			self.reserve = grid_multiple((self.reserve / lot_size).round(), lot_size);
+    		}
     	}
     }
 
@@ -122,14 +372,15 @@ impl Order {
     		self.price, self.quantity);
     }
 
-    /// Given a price, calculates the quantity of shares
-    /// that this ask flow order is willing to sell.
-    pub fn calc_flow_supply(&self, price: f64) -> f64 {
+    /// Given a price, calculates the quantity of shares that this ask
+    /// flow order is willing to sell in a batch lasting seconds. u_max
+    /// is the order's maximum rate in shares per second.
+    pub fn calc_flow_supply(&self, price: f64, seconds: f64) -> f64 {
     	assert_eq!(self.ex_type, ExchangeType::FlowOrder);
     	assert_eq!(self.trade_type, TradeType::Ask);
     	let p_low = self.p_low;
     	let p_high = self.p_high;
-    	let u_max = self.u_max;
+    	let u_max = self.u_max * seconds;
     	let q_max = self.quantity;
     	if price < p_low {
 			// Don't trade if price is lower than min willingness to sell
@@ -152,14 +403,15 @@ impl Order {
     	}
     }
 
-    /// Given a price, calculates the quantity of shares
-    /// that this bid flow order is willing to buy.
-    pub fn calc_flow_demand(&self, price: f64) -> f64 {
+    /// Given a price, calculates the quantity of shares that this bid
+    /// flow order is willing to buy in a batch lasting seconds. u_max
+    /// is the order's maximum rate in shares per second.
+    pub fn calc_flow_demand(&self, price: f64, seconds: f64) -> f64 {
     	assert_eq!(self.ex_type, ExchangeType::FlowOrder);
     	assert_eq!(self.trade_type, TradeType::Bid);
     	let p_low = self.p_low;
     	let p_high = self.p_high;
-    	let u_max = self.u_max;
+    	let u_max = self.u_max * seconds;
     	let q_max = self.quantity;
     	if price <= p_low {
     		if q_max < u_max {
@@ -200,6 +452,37 @@ impl Order {
 }
 
 
+// Multiples of the grid size are only checked up to float error, e.g. 0.3 / 0.1 = 2.9999999999999996
+const GRID_TOLERANCE: f64 = 1e-9;
+
+// Whether value is a whole multiple of step. Every value is on a grid of size 0.0.
+fn on_grid(value: f64, step: f64) -> bool {
+	if step <= 0.0 {
+		return true;
+	}
+	let steps = value / step;
+	(steps - steps.round()).abs() < GRID_TOLERANCE
+}
+
+// steps * step, scaled by the decimal places of step so 2001 steps of 0.05 is 100.05
+// rather than 100.05000000000001
+fn grid_multiple(steps: f64, step: f64) -> f64 {
+	let mut scale = 1.0;
+	while scale < 1e12 && !on_grid(step * scale, 1.0) {
+		scale *= 10.0;
+	}
+	steps * (step * scale).round() / scale
+}
+
+/// The largest whole multiple of step no more than value. Every value is on a grid of size 0.0.
+pub fn floor_to_grid(value: f64, step: f64) -> f64 {
+	if step <= 0.0 {
+		return value;
+	}
+	grid_multiple((value / step + GRID_TOLERANCE).floor(), step)
+}
+
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -278,32 +561,67 @@ mod tests {
 		assert_eq!(order.quantity, 500.0);
 		assert_eq!(order.u_max, 500.0);
 		assert_eq!(order.gas, 0.05);
-		println!("{:?}", order.calc_flow_supply(81.09048166079447));
-		assert_eq!(order.calc_flow_supply(81.09048166079447), 162.33002965704407);
+		println!("{:?}", order.calc_flow_supply(81.09048166079447, 1.0));
+		assert_eq!(order.calc_flow_supply(81.09048166079447, 1.0), 162.33002965704407);
+		// u_max is a rate, so half a second trades half as much
+		assert!((order.calc_flow_supply(81.09048166079447, 0.5) - 162.33002965704407 / 2.0).abs() < 1e-9);
+		// and a long batch is capped by the quantity left
+		assert_eq!(order.calc_flow_supply(81.09048166079447, 10.0), 500.0);
 	}
-}
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
 
+	#[test]
+	fn test_grid() {
+		let mut bid = Order::new(String::from("trader_id"), OrderType::Enter, TradeType::Bid, ExchangeType::FlowOrder,
+								 99.74, 100.0, 100.0, 2.4, 2.4, 0.05);
+		assert_eq!(bid.check_grid(0.0, 0.0), Ok(()));
+		assert_eq!(bid.check_grid(0.01, 0.0), Ok(()));
+		assert_eq!(bid.check_grid(0.5, 0.0), Err(SimError::OffGrid { order_id: bid.order_id, reason: "p_low or p_high is not a multiple of tick_size" }));
+		assert_eq!(bid.check_grid(0.0, 1.0), Err(SimError::OffGrid { order_id: bid.order_id, reason: "quantity is not a multiple of lot_size" }));
+
+		// Bid prices round down, the quantity to the nearest lot
+		bid.round_to_grid(0.5, 1.0);
+		assert_eq!((bid.p_low, bid.p_high, bid.price, bid.quantity), (99.5, 100.0, 100.0, 2.0));
+		assert_eq!(bid.check_grid(0.5, 1.0), Ok(()));
+
+		// Ask prices round up, with at least one lot
+		let mut ask = Order::new(String::from("trader_id"), OrderType::Enter, TradeType::Ask, ExchangeType::LimitOrder,
+								 100.01, 100.01, 100.01, 0.2, 0.2, 0.05);
+		assert_eq!(ask.check_grid(0.01, 0.1), Ok(()));
+		ask.round_to_grid(0.05, 1.0);
+		assert_eq!(ask.price, 100.05);
+		assert_eq!(ask.quantity, 1.0);
+		assert_eq!(ask.check_grid(0.05, 1.0), Ok(()));
+	}
 
+	#[test]
+	fn test_iceberg() {
+		let mut ask = Order::new(String::from("trader_id"), OrderType::Enter, TradeType::Ask, ExchangeType::LimitOrder,
+								 100.0, 100.0, 100.0, 10.0, 10.0, 0.05).with_display(4.0);
+		assert_eq!((ask.quantity, ask.reserve, ask.total_quantity()), (4.0, 6.0, 10.0));
+		let shown = ask.displayed();
+		assert_eq!((shown.quantity, shown.reserve, shown.display_size), (4.0, 0.0, 0.0));
+
+		// The reserve is shown display_size at a time until it runs out
+		assert!(ask.replenish());
+		assert_eq!((ask.quantity, ask.reserve), (4.0, 2.0));
+		assert!(ask.replenish());
+		assert_eq!((ask.quantity, ask.reserve), (2.0, 0.0));
+		assert!(!ask.replenish());
+
+		assert!(ask.clone().with_tif(TimeInForce::IOC).with_display(1.0).validate().is_err());
+		let flow = Order::new(String::from("trader_id"), OrderType::Enter, TradeType::Ask, ExchangeType::FlowOrder,
+							  99.0, 101.0, 100.0, 10.0, 10.0, 0.05).with_display(4.0);
+		assert!(flow.validate().is_err());
+	}
 
+	#[test]
+	fn test_stop_price() {
+		let stop = Order::market(String::from("trader_id"), TradeType::Ask, 1.0, 0.05).with_stop(99.5);
+		assert!(stop.is_stop() && stop.stop_triggered(99.5) && !stop.stop_triggered(99.6));
+		// A stop market order has no price on the grid, but its stop price is checked
+		assert_eq!(stop.check_grid(0.5, 0.0), Ok(()));
+		assert!(stop.check_grid(1.0, 0.0).is_err());
+		assert!(stop.with_stop(f64::INFINITY).validate().is_err());
+	}
+}
 
@@ -1,20 +1,137 @@
 use std::sync::Arc;
 use core::f64::{MAX, MIN};
 use crate::order::order::{Order, TradeType};
+use crate::error::SimError;
+use crate::utility::next_queue_seq;
 
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::Mutex;
-use std::io;
+
+
+/// The price of a level in the Book. Orders with NaN prices are rejected
+/// by Order::validate, so every price is ordered.
+#[derive(Debug, Clone, Copy)]
+pub struct Price(pub f64);
+
+impl PartialEq for Price {
+	fn eq(&self, other: &Price) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl Eq for Price {}
+
+impl PartialOrd for Price {
+	fn partial_cmp(&self, other: &Price) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Price {
+	fn cmp(&self, other: &Price) -> Ordering {
+		self.0.total_cmp(&other.0)
+	}
+}
+
+
+/// The resting orders of a Book. Each price level is a FIFO queue where the front
+/// order arrived first and trades first. index finds the level of each order id.
+#[derive(Debug, Default, Clone)]
+pub struct Levels {
+	pub levels: BTreeMap<Price, VecDeque<Order>>,
+	pub index: HashMap<u64, Price>,
+}
+
+impl Levels {
+	// The price of the best level: the highest bid or the lowest ask
+	fn best_price(&self, book_type: &TradeType) -> Option<Price> {
+		match book_type {
+			TradeType::Bid => self.levels.keys().next_back().copied(),
+			TradeType::Ask => self.levels.keys().next().copied(),
+		}
+	}
+
+	// Queues the order at its price level, in front of the level if it is keeping its priority.
+	// An order queued at the back is given the next arrival number.
+	fn insert(&mut self, book_type: &TradeType, mut order: Order, front: bool) -> Result<(), SimError> {
+		if self.index.contains_key(&order.order_id) {
+			return Err(SimError::DuplicateOrder { book: book_type.clone(), order_id: order.order_id });
+		}
+		let price = Price(order.price);
+		self.index.insert(order.order_id, price);
+		let level = self.levels.entry(price).or_default();
+		match front {
+			true => level.push_front(order),
+			false => {
+				order.queued_at = next_queue_seq();
+				level.push_back(order);
+			},
+		}
+		Ok(())
+	}
+
+	// Removes the order from its price level, dropping the level once it's empty
+	fn remove(&mut self, order_id: u64) -> Option<Order> {
+		let price = self.index.remove(&order_id)?;
+		let level = self.levels.get_mut(&price)?;
+		let i = level.iter().position(|o| o.order_id == order_id)?;
+		let order = level.remove(i);
+		if level.is_empty() {
+			self.levels.remove(&price);
+		}
+		order
+	}
+
+	// Removes the order with the highest priority: the front of the best level
+	fn pop_best(&mut self, book_type: &TradeType) -> Option<Order> {
+		let price = self.best_price(book_type)?;
+		let level = self.levels.get_mut(&price)?;
+		let order = level.pop_front()?;
+		if level.is_empty() {
+			self.levels.remove(&price);
+		}
+		self.index.remove(&order.order_id);
+		Some(order)
+	}
+
+	// Iterates from the lowest to the highest priority order so the best order is last.
+	// Bids ascend and asks descend in price, each level from its newest to oldest order.
+	fn iter<'a>(&'a self, book_type: &TradeType) -> Box<dyn Iterator<Item = &'a Order> + 'a> {
+		match book_type {
+			TradeType::Bid => Box::new(self.levels.values().flat_map(|level| level.iter().rev())),
+			TradeType::Ask => Box::new(self.levels.values().rev().flat_map(|level| level.iter().rev())),
+		}
+	}
+
+	fn iter_mut<'a>(&'a mut self, book_type: &TradeType) -> Box<dyn Iterator<Item = &'a mut Order> + 'a> {
+		match book_type {
+			TradeType::Bid => Box::new(self.levels.values_mut().flat_map(|level| level.iter_mut().rev())),
+			TradeType::Ask => Box::new(self.levels.values_mut().rev().flat_map(|level| level.iter_mut().rev())),
+		}
+	}
+}
+
+
+/// An aggregated price level of a Book: the total quantity and number of orders resting at the price
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthLevel {
+	pub price: f64,
+	pub quantity: f64,
+	pub num_orders: usize,
+}
+
 
 /// The struct for the order books in the exchange. The purpose
 /// is to keep track of bids and asks for calculating order crossings.
 /// book_type: TradeType{Bid, Ask} -> To differentiate the two order books
-/// orders: Mutex<Vec<Order>> -> Threadsafe vector to keep track of orders
+/// orders: Mutex<Levels> -> Threadsafe price levels of orders with strict price-time priority
 /// min_price: Mutex<f64> -> Threadsafe minimum market price for computing clearing price
 /// max_price: Mutex<f64> -> Threadsafe maximum market price for computing clearing price
 #[derive(Debug)]
 pub struct Book {
 	pub book_type: TradeType,
-	pub orders: Mutex<Vec<Order>>,
+	pub orders: Mutex<Levels>,
 	pub min_price: Mutex<f64>,
 	pub max_price: Mutex<f64>,
 }
@@ -23,166 +140,130 @@ impl Book {
     pub fn new(book_type: TradeType) -> Book {
     	Book {
     		book_type,
-    		orders: Mutex::new(Vec::<Order>::new()),
+    		orders: Mutex::new(Levels::default()),
     		min_price: Mutex::new(MAX),
     		max_price: Mutex::new(MIN),
     	}
     }
 
-    /// Adds a new order to the Book after acquiring a lock, then sorts by price
-    pub fn add_order(&self, order: Order) -> io::Result<()> {
+    /// Queues a new order at the back of its price level after acquiring a lock.
+    /// Orders with a NaN price or quantity can't be sorted and are rejected.
+    pub fn add_order(&self, order: Order) -> Result<(), SimError> {
+    	order.validate()?;
     	let mut orders = self.orders.lock().expect("ERROR: Couldn't lock book to update order");
-    	match order.trade_type {
-			// Sort bids in descending order -> best bid (highest price) at end
-			TradeType::Bid => {
-				orders.push(order);
-				orders.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap());
-				// Update best price once book is sorted
-				let best_price = orders.last().unwrap().price;
-				self.update_best_price(best_price);
-			},
-			// Sort asks in ascending order -> best ask (lowest price) at end
-			TradeType::Ask => {
-				orders.push(order);
-				// Reverse a and b to get in ascending order
-    			orders.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap().reverse());
-				// Update best price once book is sorted
-				let best_price = orders.last().unwrap().price;
-				self.update_best_price(best_price);
-			}
+    	orders.insert(&self.book_type, order, false)?;
+		// Update best price once the order is queued
+		if let Some(best_price) = orders.best_price(&self.book_type) {
+			self.update_best_price(best_price.0);
 		}
-		
     	Ok(())
     }
 
-    /// Replaces the order in the order book with the supplied 'order' of the same trader_id
-    pub fn update_order(&self, order: Order) -> Result<(), &'static str> {
+    /// Replaces the order in the order book with the supplied 'order' of the same order_id.
+    /// The order keeps its priority unless its price changed.
+    pub fn update_order(&self, order: Order) -> Result<(), SimError> {
+    	order.validate()?;
     	// Acquire the lock
         let mut orders = self.orders.lock().expect("ERROR: Couldn't lock book to update order");
-        // Search for existing order's index
-        let order_index = orders.iter().position(|o| o.order_id == order.order_id);
-
-        if let Some(i) = order_index {
-        	// Add new order to end of the vector
-        	orders.push(order);
-    		// Swap orders then pop off the old order that is now at the end of vector
-        	let last = orders.len() - 1;
-        	orders.swap(i, last);
-        	orders.pop();
+        let price = match orders.index.get(&order.order_id) {
+        	Some(price) => *price,
+        	None => return Err(SimError::OrderNotInBook { book: self.book_type.clone(), order_id: order.order_id }),
+        };
+
+        if price == Price(order.price) {
+        	// Replace the order in place in its level
+        	let level = orders.levels.get_mut(&price).expect("indexed level");
+        	if let Some(old) = level.iter_mut().find(|o| o.order_id == order.order_id) {
+        		*old = order;
+        	}
         } else {
-        	println!("ERROR: order not found to update: {:?}", &order.order_id);
-        	return Err("ERROR: order not found to update");
+        	// Requeue the order at the back of its new price level
+        	orders.remove(order.order_id);
+        	orders.insert(&self.book_type, order, false)?;
         }
 
+        if let Some(best_price) = orders.best_price(&self.book_type) {
+        	self.update_best_price(best_price.0);
+        }
         Ok(())
     }
 
     /// Cancels the existing order in the order book if it exists
-    pub fn cancel_order(&self, order: Order) -> Result<(), &'static str> {
-    	// Acquire the lock
-        let mut orders = self.orders.lock().expect("couldn't acquire lock cancelling order");
-        // Search for existing order's index
-        let order_index: Option<usize> = orders.iter().position(|o| &o.order_id == &order.order_id);
+    pub fn cancel_order(&self, order: Order) -> Result<(), SimError> {
+    	self.cancel_order_by_id(order.order_id)
+    }
 
-        if let Some(i) = order_index {
-        	orders.remove(i);
-        } else {
-        	println!("ERROR: order not found to cancel: {:?}", &order.order_id);
-        	return Err("ERROR: order not found to cancel");
+	pub fn cancel_order_by_id(&self, id: u64) -> Result<(), SimError> {
+		// Acquire the lock
+        let mut orders = self.orders.lock().expect("couldn't acquire lock cancelling order");
+        if orders.remove(id).is_none() {
+        	return Err(SimError::OrderNotInBook { book: self.book_type.clone(), order_id: id });
         }
 
 		// Update the best price 
-        if let Some(last_order) = orders.last() { 
-            let best_price = last_order.price;
-            self.update_best_price(best_price);
+		if let Some(best_price) = orders.best_price(&self.book_type) {
+            self.update_best_price(best_price.0);
         } else {
             // No more orders in the book, reset best price
             self.reset_best_price();
         }
-
-
         Ok(())
-    }
-
-	pub fn cancel_order_by_id(&self, id: u64) -> Result<(), &'static str> {
-		// Acquire the lock
-        let mut orders = self.orders.lock().expect("couldn't acquire lock cancelling order");
-        // Search for existing order's index
-        let order_index: Option<usize> = orders.iter().position(|o| &o.order_id == &id);
+	}
 
-		if let Some(i) = order_index {
-        	orders.remove(i);
-        } else {
-        	println!("ERROR: order not found to cancel: {:?}", id);
-        	return Err("ERROR: order not found to cancel");
-        }
-		// Update the best price 
-		if let Some(last_order) = orders.last(){ 
-            let best_price = last_order.price;
-            self.update_best_price(best_price);
-        } else {
-            self.reset_best_price();
-        }
-		
+	/// Removes the orders that can't trade in block_num because they expired before it.
+	/// Returns them from the lowest to the highest priority.
+	pub fn purge_expired(&self, block_num: u64) -> Vec<Order> {
+		let mut orders = self.orders.lock().expect("couldn't acquire lock purging orders");
+		let expired: Vec<u64> = orders.iter(&self.book_type)
+			.filter(|o| o.is_expired(block_num))
+			.map(|o| o.order_id)
+			.collect();
+		if expired.is_empty() {
+			return Vec::new();
+		}
+		let purged = expired.into_iter().filter_map(|id| orders.remove(id)).collect();
 
-        Ok(())
+		// Update the best price as a cancel would
+		if let Some(best_price) = orders.best_price(&self.book_type) {
+			self.update_best_price(best_price.0);
+		} else {
+			self.reset_best_price();
+		}
+		purged
 	}
 
-	// Pushes best bid/ask to end of sorted book
-	pub fn push_to_end(&self, order: Order) -> io::Result<()> {
+	// Returns a popped best bid/ask to the front of its price level, keeping its priority
+	pub fn push_to_end(&self, order: Order) -> Result<(), SimError> {
 		let mut orders = self.orders.lock().expect("ERROR: Couldn't lock book to update order");
-    	orders.push(order);
-		Ok(())
+		orders.insert(&self.book_type, order, true)
 	}
 
-	// Pops best bid/ask from end of sorted book
+	// Pops the best bid/ask, the oldest order at the best price
 	pub fn pop_from_end(&self) -> Option<Order> {
 		let mut orders = self.orders.lock().expect("ERROR: Couldn't lock book to update order");
-    	if orders.len() > 0 {
-			let order = orders.pop();
-			return order;
-		} 
-		return None
+		orders.pop_best(&self.book_type)
 	}
 
-	pub fn merge_sort_books(book1: Arc<Book>, book2: Arc<Book>) -> Book {
-		let merged = Book::new(TradeType::Bid);
-		{
-			let mut m_orders = merged.orders.lock().expect("Error...");
-			let b1_orders = book1.orders.lock().expect("ERROR: Couldn't lock book to update order");
-			for o in b1_orders.iter() {
-				m_orders.push(o.clone());
-			}
-
-			let b2_orders = book2.orders.lock().expect("ERROR: Couldn't lock book to update order");
-			for o in b2_orders.iter() {
-				m_orders.push(o.clone());
-			}
-		}
-
-		merged.sort_desc_price();
-		return merged;
+	// Copies the orders of both books sorted in descending price order
+	pub fn merge_sort_books(book1: Arc<Book>, book2: Arc<Book>) -> Vec<Order> {
+		let mut merged = book1.copy_orders();
+		merged.extend(book2.copy_orders());
+		merged.sort_by(|a, b| a.price.partial_cmp(&b.price).expect("Failed to sorted").reverse());
+		merged
 	}
 
-    // Puts orders with lower prices at the end of array, so iterating is descending, popping is ascending.
-	pub fn sort_desc_price(&self) {
-    	// Acquire the lock
-        let mut orders = self.orders.lock().expect("ERROR: Couldn't lock book to sort");
-		// Sort orders in descending order
-		orders.sort_by(|a, b| a.price.partial_cmp(&b.price).expect("Failed to sorted").reverse());
-    }
-
     pub fn peek_id_pos(&self, trader_id: String) -> Option<usize> {
     	// Acquire the lock
         let orders = self.orders.lock().unwrap();
-        // Search for existing order's index
-        orders.iter().position(|o| o.trader_id == trader_id)
+        // Search for the trader's first order from the lowest priority end
+        let mut iter = orders.iter(&self.book_type);
+        iter.position(|o| o.trader_id == trader_id)
     }
 
     /// Utility to see depth of order book
     pub fn len(&self) -> usize {
     	let orders = self.orders.lock().unwrap();
-    	orders.len()
+    	orders.index.len()
     }
 
 	/// Atomically updates Book's best bid/ask
@@ -201,10 +282,7 @@ impl Book {
 
 	pub fn peek_best_price(&self) -> Option<f64> {
 		let orders = self.orders.lock().unwrap();
-		if orders.len() > 0 {
-			return Some(orders.last().expect("Couldn't peek best price").price);
-		}
-		None
+		orders.best_price(&self.book_type).map(|price| price.0)
 	}
 
     /// Atomically updates the Book's max price
@@ -237,15 +315,27 @@ impl Book {
 
     /// Returns sum of book's volume
     pub fn get_book_volume(&self) -> f64 {
+    	self.sum_orders(|o| o.quantity)
+    }
+
+    /// Sums f over every order, in priority order so the sum is repeatable
+    pub fn sum_orders<F: Fn(&Order) -> f64>(&self, f: F) -> f64 {
     	let orders = self.orders.lock().expect("couldn't acquire lock");
-    	orders.iter().map(|o| o.quantity).sum()
+    	orders.iter(&self.book_type).map(f).sum()
+    }
+
+    /// Calls f on every order in priority order. f must not change the order's
+    /// price or id, which would break the price levels.
+    pub fn update_orders<F: FnMut(&mut Order)>(&self, f: F) {
+    	let mut orders = self.orders.lock().expect("couldn't acquire lock");
+    	orders.iter_mut(&self.book_type).for_each(f);
     }
 
     /// Returns lowest p_low for the book
     pub fn get_min_plow(&self) -> f64 {
     	let orders = self.orders.lock().expect("couldn't acquire lock");
     	let mut p_low = MAX;
-    	for order in orders.iter() {
+    	for order in orders.iter(&self.book_type) {
     		if order.p_low < p_low {
     			p_low = order.p_low;
     		}
@@ -257,7 +347,7 @@ impl Book {
     pub fn get_max_phigh(&self) -> f64 {
     	let orders = self.orders.lock().expect("couldn't acquire lock");
     	let mut p_high = 0.0;
-    	for order in orders.iter() {
+    	for order in orders.iter(&self.book_type) {
     		if order.p_high > p_high {
     			p_high = order.p_high;
     		}
@@ -270,8 +360,8 @@ impl Book {
     pub fn find_new_max(&self) {
     	let orders = self.orders.lock().unwrap();
 
-    	// Iterates over all orders until a minimum is found
-        let new_max = orders.iter().fold(MIN, |max, order| if order.price > max {order.price} else {max});
+    	// The highest price level, or MIN if the book is empty
+        let new_max = orders.levels.keys().next_back().map_or(MIN, |price| price.0);
 
     	// Update the book with new max price
     	let mut max_price = self.max_price.lock().unwrap();
@@ -283,22 +373,54 @@ impl Book {
     pub fn find_new_min(&self) {
     	let orders = self.orders.lock().unwrap();
 
-    	// Iterates over all orders until a minimum is found
-    	let new_min = orders.iter().fold(MAX, |min, order| if order.price < min {order.price} else {min});
+    	// The lowest price level, or MAX if the book is empty
+    	let new_min = orders.levels.keys().next().map_or(MAX, |price| price.0);
 
     	// Update the book with new min price
     	let mut min_price = self.min_price.lock().unwrap();
     	*min_price = new_min;
     }
 
+    /// The volume of the orders that would trade with an opposite order at price:
+    /// bids priced at or above it, asks at or below it
+    pub fn crossing_volume(&self, price: f64) -> f64 {
+    	let orders = self.orders.lock().expect("couldn't acquire lock");
+    	let crossing = |(_, level): (&Price, &VecDeque<Order>)| level.iter().map(|o| o.quantity).sum::<f64>();
+    	match self.book_type {
+    		TradeType::Bid => orders.levels.range(Price(price)..).map(crossing).sum(),
+    		TradeType::Ask => orders.levels.range(..=Price(price)).map(crossing).sum(),
+    	}
+    }
+
+    /// Aggregates the best price levels of the book, at most levels of them, best price first
+    pub fn depth(&self, levels: usize) -> Vec<DepthLevel> {
+    	let orders = self.orders.lock().expect("couldn't acquire lock");
+    	let to_level = |(price, level): (&Price, &VecDeque<Order>)| DepthLevel {
+    		price: price.0,
+    		quantity: level.iter().map(|o| o.quantity).sum(),
+    		num_orders: level.len(),
+    	};
+    	match self.book_type {
+    		TradeType::Bid => orders.levels.iter().rev().take(levels).map(to_level).collect(),
+    		TradeType::Ask => orders.levels.iter().take(levels).map(to_level).collect(),
+    	}
+    }
+
+    /// Copies the orders from the lowest to the highest priority, so the best order is last
     pub fn copy_orders(&self) -> Vec<Order> {
         let orders = self.orders.lock().unwrap();
-        let mut v = Vec::new();
-        for o in orders.iter() {
-            v.push(o.clone());
-        }
-        v
+        orders.iter(&self.book_type).cloned().collect()
+    }
 
+    /// A copy of the book with the same orders in the same priority, to try out a cross
+    /// without changing the book
+    pub fn snapshot(&self) -> Book {
+        Book {
+            book_type: self.book_type.clone(),
+            orders: Mutex::new(self.orders.lock().unwrap().clone()),
+            min_price: Mutex::new(*self.min_price.lock().unwrap()),
+            max_price: Mutex::new(*self.max_price.lock().unwrap()),
+        }
     }
 
     pub fn reset_best_price(&self) {
@@ -331,7 +453,7 @@ impl Book {
 #[cfg(test)]
 mod tests {
 	use super::*;
-    use crate::order::order::{TradeType};
+    use crate::order::order::{TradeType, OrderType, ExchangeType};
     use std::sync::Arc;
     use std::thread;
 
@@ -372,27 +494,130 @@ mod tests {
 		assert_eq!(*book.max_price.lock().unwrap(), MIN + 50.0);
 
 	}
-}
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
 
+	#[test]
+	fn test_book_errors() {
+		let book = Book::new(TradeType::Bid);
+		let order = Order::new(String::from("BOB"), OrderType::Enter, TradeType::Bid, ExchangeType::LimitOrder,
+							   0.0, 0.0, 100.0, 5.0, 5.0, 0.1);
+		let order_id = order.order_id;
+		assert_eq!(book.cancel_order_by_id(order_id), Err(SimError::OrderNotInBook { book: TradeType::Bid, order_id }));
+
+		let mut nan_order = order.clone();
+		nan_order.price = f64::NAN;
+		assert_eq!(book.add_order(nan_order), Err(SimError::InvalidOrder { order_id, reason: "price is NaN" }));
+		assert_eq!(book.len(), 0);
+
+		book.add_order(order.clone()).unwrap();
+		assert_eq!(book.cancel_order(order), Ok(()));
+	}
 
+	fn limit_order(trader_id: &str, trade_type: TradeType, price: f64) -> Order {
+		Order::new(String::from(trader_id), OrderType::Enter, trade_type, ExchangeType::LimitOrder,
+				   0.0, 0.0, price, 5.0, 5.0, 0.1)
+	}
 
+	#[test]
+	fn test_price_time_priority() {
+		let book = Book::new(TradeType::Ask);
+		let first = limit_order("first", TradeType::Ask, 100.0);
+		let second = limit_order("second", TradeType::Ask, 100.0);
+		let worse = limit_order("worse", TradeType::Ask, 101.0);
+		let better = limit_order("better", TradeType::Ask, 99.0);
+		for order in [first.clone(), second.clone(), worse.clone(), better.clone()] {
+			book.add_order(order).unwrap();
+		}
+		assert_eq!(book.add_order(first.clone()), Err(SimError::DuplicateOrder { book: TradeType::Ask, order_id: first.order_id }));
+		assert_eq!(book.len(), 4);
+		assert_eq!(book.peek_best_price(), Some(99.0));
+		assert_eq!(book.get_min_price(), 99.0);
+
+		// Lowest priority first, best order last
+		let ids: Vec<u64> = book.copy_orders().iter().map(|o| o.order_id).collect();
+		assert_eq!(ids, vec![worse.order_id, second.order_id, first.order_id, better.order_id]);
+
+		// Orders at the same price pop oldest first
+		assert_eq!(book.pop_from_end().unwrap().order_id, better.order_id);
+		let popped = book.pop_from_end().unwrap();
+		assert_eq!(popped.order_id, first.order_id);
+
+		// A popped order pushed back keeps its place at the front of its level
+		book.push_to_end(popped).unwrap();
+		assert_eq!(book.pop_from_end().unwrap().order_id, first.order_id);
+		assert_eq!(book.pop_from_end().unwrap().order_id, second.order_id);
+		assert_eq!(book.pop_from_end().unwrap().order_id, worse.order_id);
+		assert!(book.pop_from_end().is_none());
+		assert_eq!(book.len(), 0);
+	}
 
+	#[test]
+	fn test_update_and_cancel_levels() {
+		let book = Book::new(TradeType::Bid);
+		let a = limit_order("a", TradeType::Bid, 100.0);
+		let b = limit_order("b", TradeType::Bid, 100.0);
+		let c = limit_order("c", TradeType::Bid, 100.0);
+		for order in [a.clone(), b.clone(), c.clone()] {
+			book.add_order(order).unwrap();
+		}
 
+		// Cancelling from the middle of a level keeps the others in time order
+		book.cancel_order_by_id(b.order_id).unwrap();
+		assert_eq!(book.copy_orders().iter().map(|o| o.order_id).collect::<Vec<u64>>(), vec![c.order_id, a.order_id]);
+
+		// A quantity update keeps its priority
+		let mut a_vol = a.clone();
+		a_vol.quantity = 1.0;
+		book.update_order(a_vol).unwrap();
+		assert_eq!(book.copy_orders().last().unwrap().order_id, a.order_id);
+		assert_eq!(book.get_book_volume(), 6.0);
+
+		// A price update requeues the order at the back of its new level
+		let mut a_price = a.clone();
+		a_price.price = 101.0;
+		book.update_order(a_price).unwrap();
+		assert_eq!(book.peek_best_price(), Some(101.0));
+		assert_eq!(book.get_max_price(), 101.0);
+		let mut a_back = a.clone();
+		a_back.price = 100.0;
+		book.update_order(a_back).unwrap();
+		assert_eq!(book.pop_from_end().unwrap().order_id, c.order_id);
+
+		// Emptying the book resets the best price
+		book.cancel_order_by_id(a.order_id).unwrap();
+		assert_eq!(book.peek_best_price(), None);
+		assert_eq!(book.get_max_price(), MIN);
+	}
 
+	#[test]
+	fn test_depth() {
+		let book = Book::new(TradeType::Bid);
+		for price in [99.0, 100.0, 98.0, 100.0] {
+			book.add_order(limit_order("a", TradeType::Bid, price)).unwrap();
+		}
+		assert_eq!(book.depth(2), vec!(
+			DepthLevel { price: 100.0, quantity: 10.0, num_orders: 2 },
+			DepthLevel { price: 99.0, quantity: 5.0, num_orders: 1 },
+		));
+		assert_eq!(book.depth(10).len(), 3);
+		assert_eq!(Book::new(TradeType::Ask).depth(10), Vec::new());
+	}
 
+	#[test]
+	fn test_purge_expired() {
+		let book = Book::new(TradeType::Ask);
+		let gtc = limit_order("gtc", TradeType::Ask, 101.0);
+		let expiring = limit_order("expiring", TradeType::Ask, 100.0).with_expiry(3);
+		let expiring_id = expiring.order_id;
+		book.add_order(gtc).unwrap();
+		book.add_order(expiring).unwrap();
+		assert_eq!(book.get_min_price(), 100.0);
+
+		// The order can still trade in its last block
+		assert!(book.purge_expired(3).is_empty());
+		let purged = book.purge_expired(4);
+		assert_eq!(purged.len(), 1);
+		assert_eq!(purged[0].order_id, expiring_id);
+		assert_eq!(book.len(), 1);
+		assert_eq!(book.get_min_price(), 101.0);
+	}
+}
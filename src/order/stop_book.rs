@@ -0,0 +1,116 @@
+use crate::order::order::{Order, OrderType};
+use crate::error::SimError;
+
+use std::sync::Mutex;
+
+
+/// The trigger book holding stop orders out of the Bids and Asks Books until a trade
+/// price crosses their stop price. Stop orders are kept in the order they arrived and
+/// are triggered oldest first.
+#[derive(Debug, Default)]
+pub struct StopBook {
+	pub orders: Mutex<Vec<Order>>,
+}
+
+impl StopBook {
+	pub fn new() -> StopBook {
+		StopBook {
+			orders: Mutex::new(Vec::<Order>::new()),
+		}
+	}
+
+	/// Queues a new stop order after acquiring a lock. Orders without a stop price
+	/// belong in the Bids or Asks Book and are rejected.
+	pub fn add_order(&self, order: Order) -> Result<(), SimError> {
+		order.validate()?;
+		if !order.is_stop() || order.order_type != OrderType::Enter {
+			return Err(SimError::InvalidOrder { order_id: order.order_id, reason: "only new stop orders can wait in the stop book" });
+		}
+		self.orders.lock().expect("couldn't lock stop book").push(order);
+		Ok(())
+	}
+
+	/// Removes the stop order with order_id if it's still waiting to be triggered
+	pub fn cancel_order_by_id(&self, id: u64) -> Option<Order> {
+		let mut orders = self.orders.lock().expect("couldn't lock stop book");
+		let pos = orders.iter().position(|o| o.order_id == id)?;
+		Some(orders.remove(pos))
+	}
+
+	/// Removes the oldest stop order triggered by a trade at price. It's returned without
+	/// its stop price, ready to enter the books.
+	pub fn trigger_next(&self, price: f64) -> Option<Order> {
+		let mut orders = self.orders.lock().expect("couldn't lock stop book");
+		let pos = orders.iter().position(|o| o.stop_triggered(price))?;
+		let mut order = orders.remove(pos);
+		order.stop_price = None;
+		Some(order)
+	}
+
+	/// Removes the stop orders that can't trade in block_num because they expired before it
+	pub fn purge_expired(&self, block_num: u64) -> Vec<Order> {
+		let mut orders = self.orders.lock().expect("couldn't lock stop book");
+		let (expired, waiting): (Vec<Order>, Vec<Order>) = orders.drain(..).partition(|o| o.is_expired(block_num));
+		*orders = waiting;
+		expired
+	}
+
+	pub fn copy_orders(&self) -> Vec<Order> {
+		self.orders.lock().expect("couldn't lock stop book").clone()
+	}
+
+	pub fn len(&self) -> usize {
+		self.orders.lock().expect("couldn't lock stop book").len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::order::order::{TradeType, ExchangeType};
+
+	fn stop_order(trade_type: TradeType, stop_price: f64) -> Order {
+		Order::market(String::from("BOB"), trade_type, 1.0, 0.05).with_stop(stop_price)
+	}
+
+	#[test]
+	fn test_trigger_next() {
+		let stops = StopBook::new();
+		let buy_stop = stop_order(TradeType::Bid, 105.0);
+		let sell_stop = stop_order(TradeType::Ask, 95.0);
+		let later_sell_stop = stop_order(TradeType::Ask, 97.0);
+		stops.add_order(buy_stop.clone()).unwrap();
+		stops.add_order(sell_stop.clone()).unwrap();
+		stops.add_order(later_sell_stop.clone()).unwrap();
+
+		// A buy stop triggers at or above its stop price, a sell stop at or below it
+		assert!(stops.trigger_next(100.0).is_none());
+		let triggered = stops.trigger_next(105.0).unwrap();
+		assert_eq!((triggered.order_id, triggered.stop_price), (buy_stop.order_id, None));
+		assert_eq!(stops.trigger_next(94.0).unwrap().order_id, sell_stop.order_id);
+		assert_eq!(stops.len(), 1);
+
+		assert_eq!(stops.cancel_order_by_id(later_sell_stop.order_id).unwrap().order_id, later_sell_stop.order_id);
+		assert!(stops.cancel_order_by_id(later_sell_stop.order_id).is_none());
+	}
+
+	#[test]
+	fn test_only_stop_orders() {
+		let stops = StopBook::new();
+		let limit = Order::new(String::from("BOB"), OrderType::Enter, TradeType::Bid, ExchangeType::LimitOrder,
+							   100.0, 100.0, 100.0, 1.0, 1.0, 0.05);
+		assert!(stops.add_order(limit.clone()).is_err());
+		assert!(stops.add_order(limit.with_stop(f64::NAN)).is_err());
+
+		let expiring = stop_order(TradeType::Bid, 105.0).with_expiry(3);
+		stops.add_order(expiring.clone()).unwrap();
+		assert!(stops.purge_expired(3).is_empty());
+		assert_eq!(stops.purge_expired(4)[0].order_id, expiring.order_id);
+		assert_eq!(stops.len(), 0);
+	}
+}
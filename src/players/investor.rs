@@ -138,7 +138,8 @@ impl Player for Investor {
         }
 	}
 
-	// Updates the order's volume and removes it if the vol <= 0
+	// Updates the order's volume and removes it if the vol <= 0, unless an iceberg
+	// order can show more of its reserve as the book does
 	fn update_order_vol(&mut self, o_id: u64, vol_to_add: f64) -> Result<(), &'static str> {
 		// Get the lock on the player's orders
 		let mut orders = self.orders.lock().expect("couldn't acquire lock on orders");
@@ -148,7 +149,7 @@ impl Player for Investor {
 		if let Some(i) = order_index {
         	orders[i].quantity += vol_to_add;
         	// println!("new quantity: {}", orders[i].quantity);
-        	if orders[i].quantity <= 0.0 {
+        	if orders[i].quantity <= 0.0 && !orders[i].replenish() {
         		orders.remove(i);
         	}
         	return Ok(());
@@ -1,8 +1,7 @@
 use crate::simulation::simulation_history::UpdateReason;
-use crate::utility::get_time;
+use crate::utility::{get_time, with_rng};
 use crate::simulation::simulation_config::{Distributions, Constants};
 use crate::simulation::simulation_history::{PriorData, LikelihoodStats};
-use crate::exchange::MarketType;
 use crate::players::{Player, TraderT};
 use crate::order::order::{Order, TradeType, ExchangeType, OrderType};
 use std::sync::Mutex;
@@ -58,8 +57,7 @@ impl Maker {
 	}
 
 	pub fn gen_rand_type() -> MakerT {
-		let mut rng = rand::thread_rng();
-		match rng.gen_range(0, NUM_TYPES){
+		match with_rng(|rng| rng.gen_range(0, NUM_TYPES)) {
 			0 => MakerT::Aggressive,
 			1 => MakerT::RiskAverse,
 			2 => MakerT::Random,
@@ -172,7 +170,9 @@ impl Maker {
 	}
 
 
-	pub fn new_orders(&self, data: &PriorData, inference: &LikelihoodStats, dists: &Distributions, consts: &Constants) -> Option<(Order, Order)> {
+	/// Generates a pair of bid and ask orders of ex_type, the type of order accepted by the auction mechanism
+	pub fn new_orders(&self, data: &PriorData, inference: &LikelihoodStats, dists: &Distributions, consts: &Constants,
+					  ex_type: ExchangeType) -> Option<(Order, Order)> {
 		// look at the weighted average price of the mempool, exit if no orders have been sent to pool
 		let wtd_pool_price = match inference.weighted_price {
 			Some(price) => price,
@@ -186,12 +186,6 @@ impl Maker {
 		let bid_vol = data.bids_volume;
 
 
-		// type of order (FlowOrder or LimitOrder)
-		let ex_type = match consts.market_type {
-			MarketType::CDA|MarketType::FBA => ExchangeType::LimitOrder,
-			MarketType::KLF => ExchangeType::FlowOrder,
-		};
-
 		// Calculate the bid and ask prices offset from weighted avg price of all seen orders based on maker type
 		// And the respective quantity for each order
 		let (bid_price, ask_price, bid_amt, ask_amt) = match self.calc_price_inv(Some(wtd_pool_price), dists, consts, ask_vol, bid_vol) {
@@ -208,7 +202,7 @@ impl Maker {
 		// gas
 		let gas = self.calc_gas(wtd_gas, dists, consts);
 
-		// u_max
+		// u_max, the maximum shares / second
 		let bid_u_max = Distributions::sample_uniform(0.0, bid_amt, None);
 		let ask_u_max = Distributions::sample_uniform(0.0, ask_amt, None);
 
@@ -343,7 +337,8 @@ impl Player for Maker {
 	}
 
 
-	// Updates the order's volume and removes it if the vol <= 0
+	// Updates the order's volume and removes it if the vol <= 0, unless an iceberg
+	// order can show more of its reserve as the book does
 	fn update_order_vol(&mut self, o_id: u64, vol_to_add: f64) -> Result<(), &'static str> {
 		// Get the lock on the player's orders
 		let mut orders = self.orders.lock().expect("couldn't acquire lock on orders");
@@ -353,7 +348,7 @@ impl Player for Maker {
 		if let Some(i) = order_index {
         	orders[i].quantity += vol_to_add;
         	// println!("new quantity: {}", orders[i].quantity);
-        	if orders[i].quantity <= 0.0 {
+        	if orders[i].quantity <= 0.0 && !orders[i].replenish() {
         		println!("bye bye: {}", o_id);
         		orders.remove(i);
         	}
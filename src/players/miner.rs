@@ -4,15 +4,20 @@ use crate::order::order::{Order, TradeType, OrderType};
 use crate::blockchain::mem_pool::MemPool;
 use crate::blockchain::mempool_processor::MemPoolProcessor;
 use crate::order::order_book::Book;
-use crate::exchange::MarketType;
-use crate::exchange::exchange_logic::{Auction, TradeResults};
-use crate::utility::{gen_order_id,get_time};
+use crate::order::stop_book::StopBook;
+use crate::exchange::mechanism::AuctionMechanism;
+use crate::exchange::exchange_logic::{TradeResults, PlayerUpdate};
+use crate::error::SimError;
+use crate::utility::{gen_order_id, get_time, with_rng};
 
 use std::any::Any;
 use std::sync::{Mutex, Arc};
-use rand::{thread_rng};
 use rand::seq::SliceRandom;
 
+/// The results of publishing a frame, the errors of the orders that were skipped, and the
+/// stop orders triggered with the price that triggered them
+pub type PublishedFrame = (Option<Vec<TradeResults>>, Vec<SimError>, Vec<(Order, f64)>);
+
 /// A struct for the Miner player. 
 pub struct Miner {
 	pub trader_id: String,
@@ -57,45 +62,103 @@ impl Miner {
 		}
 	}
 
-	pub fn publish_frame(&mut self, bids: Arc<Book>, asks: Arc<Book>, m_t: MarketType) -> Option<Vec<TradeResults>> {
+	/// Processes the frame into the books and runs the end-of-block auction. Stop orders wait
+	/// in the stop book until a trade crosses their stop price: each CDA trade, or the FBA and
+	/// KLF clearing price, triggers them into the books where they can trade and trigger more.
+	/// Returns the results with the errors of any orders that had to be skipped, and the
+	/// triggered stop orders with the price that triggered them.
+	pub fn publish_frame(&mut self, bids: Arc<Book>, asks: Arc<Book>, stops: Arc<StopBook>, mechanism: &dyn AuctionMechanism) -> PublishedFrame {
 		println!("Publishing Frame: {:?}", self.frame);
 		// The results from processing the orders in sequential order
 		// For CDA: Cancels, Transactions
 		// For FBA & KLF: Cancels,
-		let process_results: Option<Vec<TradeResults>> = MemPoolProcessor::seq_process_orders(&mut self.frame, 
-											Arc::clone(&bids), 
-											Arc::clone(&asks), 
-											m_t.clone());
+		let mut results = Vec::<TradeResults>::new();
+		let mut errors = Vec::<SimError>::new();
+		let mut triggered = Vec::<(Order, f64)>::new();
+		for order in self.frame.drain(..) {
+			// Stop orders wait in the stop book, which is also where they're cancelled from
+			if order.order_type == OrderType::Enter && order.is_stop() {
+				if let Err(e) = stops.add_order(order) {
+					errors.push(e);
+				}
+				continue;
+			}
+			if order.order_type == OrderType::Cancel && stops.cancel_order_by_id(order.order_id).is_some() {
+				let updates = vec![PlayerUpdate::cancel(order.trader_id, order.order_id)];
+				results.push(TradeResults::new(None, 0.0, 0.0, Some(updates)));
+				continue;
+			}
 
-		// Don't run end-of-batch auction
+			let processed = results.len();
+			MemPoolProcessor::seq_process_order(order, Arc::clone(&bids), Arc::clone(&asks), mechanism, &mut results, &mut errors);
+			// In the CDA every trade can trigger stop orders
+			let price = results[processed..].iter().rev().find_map(|r| r.last_trade_price());
+			triggered.extend(Miner::trigger_stops(price, &bids, &asks, &stops, mechanism, &mut results, &mut errors));
+		}
+
+		// Run the end-of-batch auction, CDA has none
+		match mechanism.clear(Arc::clone(&bids), Arc::clone(&asks)) {
+			Ok(Some(auction_result)) => {
+				// The clearing price triggers stop orders into the books for the next batch,
+				// the auction result stays last as the block's clearing
+				let mut trigger_results = Vec::<TradeResults>::new();
+				triggered.extend(Miner::trigger_stops(auction_result.uniform_price, &bids, &asks, &stops, mechanism, &mut trigger_results, &mut errors));
+				results.extend(trigger_results);
+				results.push(auction_result);
+			},
+			Ok(None) => {},
+			Err(e) => errors.push(e),
+		}
 
-		if m_t == MarketType::CDA {
-			return process_results;
+		if results.is_empty() {
+			return (None, errors, triggered);
 		}
-		if let Some(auction_result) = Auction::run_auction(bids, asks, m_t) {
-			// Received some results from FBA or KLF auction, merge with the process_results
-			// Option<TradeResults>
-			if let Some(mut unwrapped_process_results) = process_results {
-				unwrapped_process_results.push(auction_result);
-				Some(unwrapped_process_results)
-			} else {
-				// There were no process results so convert to proper output
-				let mut v = Vec::<TradeResults>::new();
-				v.push(auction_result);
-				return Some(v);
+		(Some(results), errors, triggered)
+	}
+
+	/// Enters the stop orders triggered by a trade at price into the books, oldest first. Each
+	/// triggered order that trades moves the price on, so a cascade runs until no stop order
+	/// is left to trigger. A triggered order the mechanism rejects is cancelled.
+	fn trigger_stops(price: Option<f64>, bids: &Arc<Book>, asks: &Arc<Book>, stops: &StopBook, mechanism: &dyn AuctionMechanism, 
+					 results: &mut Vec<TradeResults>, errors: &mut Vec<SimError>) -> Vec<(Order, f64)> {
+		let mut triggered = Vec::<(Order, f64)>::new();
+		let mut price = match price {
+			Some(price) => price,
+			None => return triggered,
+		};
+		while let Some(order) = stops.trigger_next(price) {
+			triggered.push((order.clone(), price));
+			let (trader_id, order_id) = (order.trader_id.clone(), order.order_id);
+			match mechanism.accept_order(Arc::clone(bids), Arc::clone(asks), order) {
+				Ok(Some(result)) => {
+					if let Some(p) = result.last_trade_price() {
+						price = p;
+					}
+					results.push(result);
+				},
+				Ok(None) => {},
+				Err(e) => {
+					errors.push(e);
+					let updates = vec![PlayerUpdate::cancel(trader_id, order_id)];
+					results.push(TradeResults::new(None, 0.0, 0.0, Some(updates)));
+				},
 			}
-			
-		} else {
-			return process_results;
 		}
+		triggered
+	}
+
+	/// Removes the new orders from the frame that expired before block_num, they never reach the books
+	pub fn drop_expired(&mut self, block_num: u64) -> Vec<Order> {
+		let (expired, frame): (Vec<Order>, Vec<Order>) = self.frame.drain(..).partition(|o| o.order_type == OrderType::Enter && o.is_expired(block_num));
+		self.frame = frame;
+		expired
 	}
 
 	// Selects a random order from the frame and appends an identical order with higher block priority
 	pub fn random_front_run(&mut self) -> Result<Order, &'static str> {
-		let mut rng = thread_rng();
-		if let Some(rand_order) = self.frame.choose(&mut rng) {
+		if let Some(rand_order) = with_rng(|rng| self.frame.choose(rng).cloned()) {
 			// Copy and update order 
-			let mut copied = rand_order.clone();
+			let mut copied = rand_order;
 			copied.trader_id = self.trader_id.clone();
 			copied.gas = 0.0;	// No gas needed since this is miner
 			copied.order_id = gen_order_id();
@@ -185,8 +248,8 @@ impl Miner {
 		let mut best_ask_p = std::f64::MAX;
 
 		for o in orders.iter() {
-			// Exclude the cancel orders in frame
-			if o.order_type == OrderType::Cancel {continue;}
+			// Exclude the cancel orders in frame, and stop orders which can't trade until triggered
+			if o.order_type == OrderType::Cancel || o.is_stop() {continue;}
 
 			match o.trade_type {
 				TradeType::Bid => {
@@ -330,7 +393,8 @@ impl Player for Miner {
 	}
 
 
-	// Updates the order's volume and removes it if the vol <= 0
+	// Updates the order's volume and removes it if the vol <= 0, unless an iceberg
+	// order can show more of its reserve as the book does
 	fn update_order_vol(&mut self, o_id: u64, vol_to_add: f64) -> Result<(), &'static str> {
 		// Get the lock on the player's orders
 		let mut orders = self.orders.lock().expect("couldn't acquire lock on orders");
@@ -340,7 +404,7 @@ impl Player for Miner {
 		if let Some(i) = order_index {
         	orders[i].quantity += vol_to_add;
         	// println!("new quantity: {}", orders[i].quantity);
-        	if orders[i].quantity <= 0.0 {
+        	if orders[i].quantity <= 0.0 && !orders[i].replenish() {
         		orders.remove(i);
         	}
         	return Ok(());
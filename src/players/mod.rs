@@ -6,6 +6,7 @@ use std::any::Any;
 pub mod investor;
 pub mod maker;
 pub mod miner;
+pub mod strategy;
 
 
 /// Enum for matching over trader types
@@ -0,0 +1,83 @@
+use crate::simulation::simulation_config::{Distributions, Constants};
+use crate::simulation::simulation_history::{PriorData, LikelihoodStats};
+use crate::exchange::exchange_logic::{PlayerUpdate, IndicativePrice};
+use crate::order::order::{Order, OrderType, TradeType, ExchangeType};
+use crate::players::TraderT;
+
+use std::collections::BTreeMap;
+
+
+/// The state of the player a Strategy is trading for, copied from the ClearingHouse
+/// before each callback.
+pub struct StrategyContext<'a> {
+	pub trader_id: String,
+	pub trader_type: TraderT,
+	pub balance: f64,
+	pub inventory: f64,
+	pub asset_inventories: BTreeMap<usize, f64>,	// The player's shares of each other asset of a multi-asset market
+	pub orders: Vec<Order>,		// The player's open orders
+	pub frame: &'a [Order],		// The miner's next frame, empty unless the player is the miner
+	pub block_num: u64,
+	pub ex_type: ExchangeType,	// The type of order accepted by the auction mechanism
+	pub indicative: Option<IndicativePrice>,	// The call auction's indicative price after the last block, None outside call auctions
+	pub dists: &'a Distributions,
+	pub consts: &'a Constants,
+}
+
+impl<'a> StrategyContext<'a> {
+	/// A new order from this player of the type accepted by the auction mechanism.
+	/// Flow orders trade between price and price +/- consts.flow_order_offset.
+	pub fn new_order(&self, trade_type: TradeType, price: f64, quantity: f64, gas: f64) -> Order {
+		let (p_low, p_high) = match (&self.ex_type, &trade_type) {
+			(ExchangeType::LimitOrder, _) | (ExchangeType::MarketOrder, _) => (price, price),
+			(ExchangeType::FlowOrder, TradeType::Bid) => (price - self.consts.flow_order_offset, price),
+			(ExchangeType::FlowOrder, TradeType::Ask) => (price, price + self.consts.flow_order_offset),
+		};
+		Order::new(self.trader_id.clone(), OrderType::Enter, trade_type, self.ex_type.clone(),
+				   p_low, p_high, price, quantity, quantity, gas)
+	}
+
+	/// A market order from this player, which trades at the best prices in a CDA or at the
+	/// clearing price of an FBA batch. The flow market doesn't accept them.
+	pub fn market_order(&self, trade_type: TradeType, quantity: f64, gas: f64) -> Order {
+		Order::market(self.trader_id.clone(), trade_type, quantity, gas)
+	}
+
+	/// A stop market order from this player that waits until a trade at or through stop_price.
+	/// Stop-limit orders are made with new_order(..).with_stop(stop_price), which are the
+	/// only stop orders the flow market accepts.
+	pub fn stop_order(&self, trade_type: TradeType, stop_price: f64, quantity: f64, gas: f64) -> Order {
+		self.market_order(trade_type, quantity, gas).with_stop(stop_price)
+	}
+
+	/// A cancel order for one of the player's open orders
+	pub fn cancel_order(&self, order_id: u64) -> Option<Order> {
+		let order = self.orders.iter().find(|o| o.order_id == order_id)?;
+		let mut cancel = order.clone();
+		cancel.order_type = OrderType::Cancel;
+		Some(cancel)
+	}
+}
+
+
+/// The decision logic of a player. A strategy is registered to a player with
+/// SimulationBuilder::strategy or SimulationBuilder::miner_strategy, which replaces
+/// the built-in behaviour of that player. Each callback returns the orders to submit.
+/// The orders the miner returns from on_block are inserted at the front of its next
+/// frame, every other order is sent to the mempool.
+pub trait Strategy: Send {
+	/// Called after each block is published for investors and makers, or once the
+	/// miner's next frame is formed for the miner.
+	fn on_block(&mut self, ctx: &StrategyContext, data: &PriorData, inference: &LikelihoodStats) -> Vec<Order>;
+
+	/// Called when one of the player's orders trades in a published block
+	fn on_fill(&mut self, _ctx: &StrategyContext, _fill: &PlayerUpdate) -> Vec<Order> {
+		Vec::new()
+	}
+
+	/// Called when one of the player's cancel orders is published in a block, or one of
+	/// its orders expires
+	fn on_cancel(&mut self, _ctx: &StrategyContext, _order_id: u64) -> Vec<Order> {
+		Vec::new()
+	}
+}
@@ -1,4 +1,5 @@
-use crate::simulation::simulation_config::{DistType, DistReason, Distributions, Constants};
+use crate::simulation::simulation_config::{DistType, DistReason, Distributions, Constants, REQUIRED_DISTS};
+use crate::simulation::scenario::{ConfigError, ConfigErrors};
 
 use std::error::Error;
 use serde::Deserialize;
@@ -21,24 +22,58 @@ impl TempDist {
 }
 
 
+// Reads the single row of constants and checks every field is in range
 pub fn parse_consts_config_csv(path: String) -> Result<Constants, Box<dyn Error>> {
-    let mut rdr = csv::Reader::from_path(path)?;
+    let mut rdr = csv::Reader::from_path(&path)?;
     println!("Reading in config file...");
-    let consts: Constants = rdr.deserialize().next().expect("unwrap iter item").expect("unwrap line");
-    return Ok(consts);
+    let mut rows = rdr.deserialize::<Constants>();
+    let consts = match rows.next() {
+        Some(row) => row?,
+        None => return Err(Box::new(ConfigErrors(vec!(ConfigError::new(&path, "row 1", String::from("missing the row of constants")))))),
+    };
+
+    let mut errors: Vec<ConfigError> = consts.validate().into_iter()
+        .map(|(name, message)| ConfigError::new(&path, &format!("row 1, {}", name), message))
+        .collect();
+    if rows.next().is_some() {
+        errors.push(ConfigError::new(&path, "row 2", String::from("only one row of constants is allowed")));
+    }
+    if !errors.is_empty() {
+        return Err(Box::new(ConfigErrors(errors)));
+    }
+    Ok(consts)
 }
 
+// Reads a distribution per row, checking their parameters and that every distribution
+// the simulation samples is configured
 pub fn parse_dist_config_csv(path: String) -> Result<Distributions, Box<dyn Error>> {
     let mut lines: Vec<(DistReason, f64, f64, f64, DistType)> = Vec::new();
-    let mut rdr = csv::Reader::from_path(path)?;
+    let mut errors = Vec::new();
+    let mut rdr = csv::Reader::from_path(&path)?;
     println!("Reading in config file...");
-    for result in rdr.deserialize() {
+    for (row, result) in rdr.deserialize().enumerate() {
         // Notice that we need to provide a type hint for automatic
         // deserialization.
         let mut csv_line: TempDist = result?;
         println!("{:?}", csv_line);
-        lines.push(csv_line.unpack());
+        let line = csv_line.unpack();
+        let location = format!("row {}, {:?}", row + 1, line.0);
+        if lines.iter().any(|l| l.0 == line.0) {
+            errors.push(ConfigError::new(&path, &location, String::from("duplicate distribution")));
+        }
+        if let Err(e) = Distributions::validate_dist(line.1, line.2, line.3, line.4) {
+            errors.push(ConfigError::new(&path, &location, e));
+        }
+        lines.push(line);
+    }
 
+    for reason in REQUIRED_DISTS.iter() {
+        if !lines.iter().any(|l| l.0 == *reason) {
+            errors.push(ConfigError::new(&path, &format!("{:?}", reason), String::from("missing distribution")));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Box::new(ConfigErrors(errors)));
     }
     Ok(Distributions::new(lines))
 }
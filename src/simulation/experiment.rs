@@ -0,0 +1,120 @@
+// Runs a single trial of the simulation or a whole experiment of trials in-process
+use crate::simulation::simulation_config::{Constants, Distributions};
+use crate::simulation::simulation_builder::SimulationBuilder;
+use crate::simulation::performance_report::PerformanceReport;
+use crate::simulation::config_parser::{parse_consts_config_csv, parse_dist_config_csv};
+use crate::exchange::MarketType;
+use crate::utility::{gen_seed, TrialLog};
+
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rayon::prelude::*;
+
+
+/// The market types run by every trial of an experiment, in the order they're reported
+pub const MARKET_TYPES: [MarketType; 3] = [MarketType::KLF, MarketType::FBA, MarketType::CDA];
+
+
+/// Runs one trial of the simulation with the market type in consts, seeding every RNG
+/// stream from seed. Returns the performance results before and after liquidation.
+pub fn run_trial(dists: Distributions, consts: Constants, seed: u64) -> Vec<PerformanceReport> {
+	SimulationBuilder::new(dists, consts).seed(seed).build().run()
+}
+
+
+/// A series of trials run with the same configs. Each trial runs every market type
+/// with the same seed so the market types are compared on identical order flow.
+pub struct Experiment {
+	pub name: String,
+	pub num_trials: u64,
+	pub dists_path: PathBuf,
+	pub consts_path: PathBuf,
+	pub dists: Distributions,
+	pub consts: Constants,
+	pub enable_log: bool,
+	pub seed: u64,		// Master seed that every trial seed is derived from
+}
+
+impl Experiment {
+	/// Loads the configs for a new experiment. The master seed is taken from seed if
+	/// supplied, then from the consts config, otherwise a random seed is generated.
+	pub fn new(name: String, num_trials: u64, dists_path: PathBuf, consts_path: PathBuf,
+			   enable_log: bool, seed: Option<u64>) -> Result<Experiment, Box<dyn Error>> {
+		let dists = parse_dist_config_csv(dists_path.to_string_lossy().to_string())?;
+		let consts = parse_consts_config_csv(consts_path.to_string_lossy().to_string())?;
+		let seed = seed.or(consts.seed).unwrap_or_else(gen_seed);
+		Ok(Experiment {
+			name,
+			num_trials,
+			dists_path,
+			consts_path,
+			dists,
+			consts,
+			enable_log,
+			seed,
+		})
+	}
+
+	/// Derives the seed of each trial from the master seed
+	pub fn trial_seeds(&self) -> Vec<u64> {
+		let mut rng = StdRng::seed_from_u64(self.seed);
+		(0..self.num_trials).map(|_| rng.gen()).collect()
+	}
+
+	/// Runs every trial in parallel and writes the experiment to <results_dir>/<name>/:
+	/// the aggregated results.csv, a copy of both configs and the trial logs in log/.
+	/// Returns the path of the experiment's directory.
+	pub fn run(&self, results_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+		let exp_dir = results_dir.join(&self.name);
+		let log_dir = exp_dir.join("log");
+		fs::create_dir_all(&log_dir)?;
+
+		// Copy the configs so the experiment can be re-run from its directory
+		for path in &[&self.dists_path, &self.consts_path] {
+			let file_name = path.file_name().ok_or("Config path isn't a file")?;
+			fs::copy(path, exp_dir.join(file_name))?;
+		}
+
+		let mut jobs = Vec::new();
+		for (trial, seed) in self.trial_seeds().into_iter().enumerate() {
+			for market_type in MARKET_TYPES.iter() {
+				jobs.push((trial, seed, *market_type));
+			}
+		}
+
+		// Reports are collected in job order, regardless of which trial finishes first
+		let reports: io::Result<Vec<Vec<PerformanceReport>>> = jobs.par_iter()
+			.map(|(trial, seed, market_type)| self.run_job(&log_dir, *trial, *seed, *market_type))
+			.collect();
+		let reports: Vec<PerformanceReport> = reports?.into_iter().flatten().collect();
+
+		PerformanceReport::write_csv(&reports, fs::File::create(exp_dir.join("results.csv"))?)?;
+
+		Ok(exp_dir)
+	}
+
+	// Runs a single trial of one market type with its logs sent to the trial's own files
+	fn run_job(&self, log_dir: &Path, trial: usize, seed: u64, market_type: MarketType) -> io::Result<Vec<PerformanceReport>> {
+		let file_name = format!("{}_{}_{:?}", trial, self.name, market_type);
+		let mut consts = self.consts;
+		consts.market_type = market_type;
+
+		let reports = run_logged_trial(log_dir, &file_name, self.enable_log, self.dists.clone(), consts, seed)?;
+		println!("Finished trial {} {:?}", trial, market_type);
+		Ok(reports)
+	}
+}
+
+
+/// Runs a trial with its logs sent to its own files, named file_name, inside log_dir.
+/// Safe to call from many threads at once.
+pub fn run_logged_trial(log_dir: &Path, file_name: &str, enable_log: bool, dists: Distributions, 
+						consts: Constants, seed: u64) -> io::Result<Vec<PerformanceReport>> {
+	let log = TrialLog::create(log_dir, file_name, enable_log)?;
+	Ok(SimulationBuilder::new(dists, consts).seed(seed).log(log).build().run())
+}
@@ -2,3 +2,12 @@ pub mod simulation_config;
 pub mod simulation;
 pub mod config_parser;
 pub mod simulation_history;
+pub mod experiment;
+pub mod sweep;
+pub mod performance_report;
+pub mod simulation_builder;
+pub mod scenario;
+pub mod session;
+
+pub use self::simulation_builder::SimulationBuilder;
+pub use self::scenario::Scenario;
@@ -0,0 +1,165 @@
+// The performance metrics of a single trial of the simulation
+use std::io::{Read, Write};
+
+use serde::{Serialize, Deserialize};
+
+
+/// The performance results of a trial, calculated by Simulation::calc_performance_results.
+/// The csv and json writers generate their headers from the field names.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PerformanceReport {
+	pub market_type: String,			// The name of the auction mechanism
+	pub liquidated: bool,				// Whether players have liquidated their inventory at the fundamental value
+	pub fund_val: f64,					// The fixed fundamental value of the simulation
+	pub total_gas: f64,					// Cummulative gas fees paid to the miner
+	pub avg_gas: f64,					// Average gas fees per block, ignoring empty blocks
+	pub total_tax: f64,					// Cummulative tax on maker inventory
+	pub maker_profit: f64,
+	pub investor_profit: f64,
+	pub miner_profit: f64,
+	pub dead_weight: f64,				// total_gas + maker_profit + miner_profit
+	pub volatility: f64,				// Std dev of transaction prices
+	pub rmsd: f64,						// Root mean squared deviation of transaction prices from fund_val
+	pub aggressive_maker_profit: f64,	// Cummulative profits of all Aggressive type makers
+	pub riskaverse_maker_profit: f64,	// Cummulative profits of all RiskAverse type makers
+	pub random_maker_profit: f64,		// Cummulative profits of all Random type makers
+	pub num_aggressive: i64,
+	pub num_riskaverse: i64,
+	pub num_random: i64,
+	pub investor_welfare: f64,
+	pub maker_welfare: f64,
+	pub miner_welfare: f64,
+	pub seed: u64,						// The master seed the trial was run with
+}
+
+impl PerformanceReport {
+	/// Writes the reports as csv with a header row
+	pub fn write_csv<W: Write>(reports: &[PerformanceReport], writer: W) -> Result<(), csv::Error> {
+		let mut wtr = csv::Writer::from_writer(writer);
+		for report in reports {
+			wtr.serialize(report)?;
+		}
+		wtr.flush()?;
+		Ok(())
+	}
+
+	/// Writes the reports as csv with a header row, prefixing every row with extra columns.
+	/// rows holds the values of the extra columns alongside each report.
+	pub fn write_csv_with_columns<W: Write>(columns: &[String], rows: &[(Vec<String>, PerformanceReport)],
+											writer: W) -> Result<(), csv::Error> {
+		let mut wtr = csv::WriterBuilder::new().has_headers(false).from_writer(writer);
+
+		// The report columns come from serializing a report with its header
+		let mut header = csv::StringRecord::from(columns.to_vec());
+		if let Some((_, report)) = rows.first() {
+			let mut buf = csv::Writer::from_writer(Vec::new());
+			buf.serialize(report)?;
+			let buf = buf.into_inner().expect("Couldn't flush csv buffer");
+			let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_reader(buf.as_slice());
+			if let Some(report_header) = rdr.records().next() {
+				header.extend(report_header?.iter());
+			}
+		}
+		wtr.write_record(&header)?;
+
+		for (values, report) in rows {
+			for value in values {
+				wtr.write_field(value)?;
+			}
+			// Continues the record started by the extra columns
+			wtr.serialize(report)?;
+		}
+		wtr.flush()?;
+		Ok(())
+	}
+
+	/// Reads reports written by write_csv
+	pub fn read_csv<R: Read>(reader: R) -> Result<Vec<PerformanceReport>, csv::Error> {
+		csv::Reader::from_reader(reader).deserialize().collect()
+	}
+
+	/// Writes the reports as a json array
+	pub fn write_json<W: Write>(reports: &[PerformanceReport], writer: W) -> Result<(), serde_json::Error> {
+		serde_json::to_writer_pretty(writer, reports)
+	}
+
+	/// Reads reports written by write_json
+	pub fn read_json<R: Read>(reader: R) -> Result<Vec<PerformanceReport>, serde_json::Error> {
+		serde_json::from_reader(reader)
+	}
+
+	/// The report as a single csv row without a header
+	pub fn to_csv_row(&self) -> String {
+		let mut wtr = csv::WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+		wtr.serialize(self).expect("Couldn't serialize report");
+		let buf = wtr.into_inner().expect("Couldn't flush csv buffer");
+		String::from_utf8(buf).expect("csv isn't utf8").trim_end().to_string()
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn setup_report(liquidated: bool) -> PerformanceReport {
+		PerformanceReport {
+			market_type: String::from("FBA"),
+			liquidated,
+			fund_val: 100.0,
+			total_gas: 20.5,
+			avg_gas: 1.025,
+			total_tax: 0.5,
+			maker_profit: -10.0,
+			investor_profit: 5.0,
+			miner_profit: 25.0,
+			dead_weight: 35.5,
+			volatility: 0.25,
+			rmsd: 1.5,
+			aggressive_maker_profit: -2.0,
+			riskaverse_maker_profit: -3.0,
+			random_maker_profit: -5.0,
+			num_aggressive: 3,
+			num_riskaverse: 4,
+			num_random: 5,
+			investor_welfare: 12.0,
+			maker_welfare: -1.0,
+			miner_welfare: 2.0,
+			seed: 42,
+		}
+	}
+
+	#[test]
+	fn test_csv_round_trip() {
+		let reports = vec!(setup_report(false), setup_report(true));
+		let mut buf = Vec::new();
+		PerformanceReport::write_csv(&reports, &mut buf).unwrap();
+
+		let text = String::from_utf8(buf.clone()).unwrap();
+		assert!(text.starts_with("market_type,liquidated,fund_val,"));
+		assert!(text.lines().next().unwrap().ends_with(",seed"));
+		assert_eq!(PerformanceReport::read_csv(buf.as_slice()).unwrap(), reports);
+		assert_eq!(text.lines().nth(1).unwrap(), reports[0].to_csv_row());
+	}
+
+	#[test]
+	fn test_json_round_trip() {
+		let reports = vec!(setup_report(false), setup_report(true));
+		let mut buf = Vec::new();
+		PerformanceReport::write_json(&reports, &mut buf).unwrap();
+		assert_eq!(PerformanceReport::read_json(buf.as_slice()).unwrap(), reports);
+	}
+
+	#[test]
+	fn test_csv_with_columns() {
+		let columns = vec!(String::from("point"), String::from("block_size"));
+		let rows = vec!((vec!(String::from("0"), String::from("50")), setup_report(false)));
+		let mut buf = Vec::new();
+		PerformanceReport::write_csv_with_columns(&columns, &rows, &mut buf).unwrap();
+
+		let text = String::from_utf8(buf).unwrap();
+		let lines: Vec<&str> = text.lines().collect();
+		assert!(lines[0].starts_with("point,block_size,market_type,liquidated,"));
+		assert_eq!(lines[1], format!("0,50,{}", rows[0].1.to_csv_row()));
+	}
+}
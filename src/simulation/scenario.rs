@@ -0,0 +1,546 @@
+// Loads a scenario: the constants, distributions and player populations of a run in one
+// TOML or JSON file, validated before the simulation starts
+use crate::simulation::simulation_config::{Constants, Distributions, DistReason, DistType, LiquidationPrice, REQUIRED_DISTS, INTEGER_PARAMS};
+use crate::simulation::simulation_builder::SimulationBuilder;
+use crate::exchange::{MarketType, SelfTradePrevention, AllocationRule, PriceRule};
+use crate::players::maker::MakerT;
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use serde_json::{Map, Value};
+
+
+/// A missing or bad field in a config file. The location names the file and the
+/// field, e.g. "scenario.toml (constants.front_run_perc)".
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+	pub location: String,
+	pub message: String,
+}
+
+impl ConfigError {
+	pub fn new(file: &str, field: &str, message: String) -> ConfigError {
+		ConfigError {
+			location: format!("{} ({})", file, field),
+			message,
+		}
+	}
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}: {}", self.location, self.message)
+	}
+}
+
+/// Every error found in a config file, so they can all be fixed at once
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Invalid config, found {} error(s):", self.0.len())?;
+		for error in &self.0 {
+			write!(f, "\n\t{}", error)?;
+		}
+		Ok(())
+	}
+}
+
+impl Error for ConfigErrors {}
+
+
+// The fields of the [constants] table. num_investors and num_makers are set in [players]
+// and the seed at the top of the file.
+const CONSTANT_FIELDS: [&str; 13] = ["batch_interval", "block_size", "num_blocks", "market_type", "front_run_perc",
+									 "flow_order_offset", "maker_prop_delay", "maker_base_spread", "maker_enter_prob",
+									 "max_held_inventory", "maker_inv_tax", "maker_cold_start", "maker_update_prob"];
+
+// The [constants] fields that may be left out, 0 or None if they are
+const OPTIONAL_CONSTANT_FIELDS: [&str; 11] = ["tick_size", "lot_size", "order_expiry_blocks", "market_order_prob",
+											  "maker_display_size", "self_trade_prevention", "allocation_rule", "price_rule",
+											  "opening_auction_blocks", "closing_auction_blocks", "liquidation_price"];
+
+const MAKER_TYPES: [(&str, MakerT); 3] = [("Aggressive", MakerT::Aggressive), ("RiskAverse", MakerT::RiskAverse),
+										  ("Random", MakerT::Random)];
+
+
+/// Everything needed to run a simulation, loaded from a single file:
+///
+/// ```toml
+/// seed = 42                   # optional master seed
+/// [constants]                 # every Constants field but num_investors and num_makers
+/// market_type = "KLF"
+/// tick_size = 0.01            # optional price and quantity increments, 0.0 for none
+/// lot_size = 1.0
+/// order_expiry_blocks = 5     # optional lifetime of investor orders in blocks, 0 until filled
+/// market_order_prob = 0.1     # optional probability of an investor market order, CDA and FBA only
+/// maker_display_size = 2.0    # optional size shown by the makers' iceberg orders, 0.0 for all of it
+/// self_trade_prevention = "CancelNewest"  # optional, or CancelOldest or DecrementBoth, None by default
+/// allocation_rule = "ProRata" # optional FBA marginal level allocation, or Random, TimePriority by default
+/// price_rule = "MaxVolume"    # optional FBA clearing price rule, or Midpoint, MinImbalance or Reference, MergedBook by default
+/// opening_auction_blocks = 3  # optional blocks of opening and closing call auctions, 0 for none
+/// closing_auction_blocks = 3
+/// liquidation_price = "ClosingAuction"    # optional, FundamentalValue by default
+/// # ...
+/// [players]
+/// num_investors = 250
+/// num_makers = 50             # random maker types, or instead the number of each type:
+/// # maker_types = { Aggressive = 10, RiskAverse = 20, Random = 19 }
+/// [distributions]             # v1, v2, scalar (default 1.0) and dist_type of each DistReason
+/// BidsCenter = { v1 = 90.0, v2 = 10.0, dist_type = "Normal" }
+/// # ...
+/// ```
+pub struct Scenario {
+	pub consts: Constants,
+	pub dists: Distributions,
+	pub maker_types: Option<Vec<(MakerT, u64)>>,	// The number of makers of each type, or random types if None
+}
+
+impl Scenario {
+	/// Loads a scenario from a .json file, or a .toml file otherwise, and validates every field
+	pub fn load(path: &Path) -> Result<Scenario, Box<dyn Error>> {
+		let text = fs::read_to_string(path)?;
+		let value: Value = match path.extension().and_then(|ext| ext.to_str()) {
+			Some("json") => serde_json::from_str(&text)?,
+			_ => serde_json::to_value(toml::from_str::<toml::Value>(&text)?)?,
+		};
+		Ok(Scenario::from_value(&value, &path.display().to_string())?)
+	}
+
+	/// Builds a scenario from the parsed file, reporting every missing or out of range
+	/// field. file is only used to locate the errors.
+	pub fn from_value(value: &Value, file: &str) -> Result<Scenario, ConfigErrors> {
+		let mut v = Validator { file, errors: Vec::new() };
+		let root = match value.as_object() {
+			Some(root) => root,
+			None => {
+				v.error("", String::from("expected a table of constants, players and distributions"));
+				return Err(ConfigErrors(v.errors));
+			}
+		};
+		v.check_keys(root, "", &["seed", "constants", "players", "distributions"]);
+
+		// Collect the constants with the player counts and seed to deserialize them together
+		let mut fields = Map::new();
+		let seed = match root.get("seed") {
+			Some(_) => v.uint(root, "", "seed"),
+			None => None,
+		};
+		fields.insert(String::from("seed"), seed.into());
+
+		if let Some(consts) = v.table(root, "", "constants") {
+			let allowed: Vec<&str> = CONSTANT_FIELDS.iter().chain(OPTIONAL_CONSTANT_FIELDS.iter()).cloned().collect();
+			v.check_keys(consts, "constants", &allowed);
+			for name in CONSTANT_FIELDS.iter() {
+				let field = match *name {
+					"market_type" => v.name::<MarketType>(consts, "constants", name).map(|_| consts[*name].clone()),
+					_ if INTEGER_PARAMS.contains(name) => v.uint(consts, "constants", name).map(Value::from),
+					_ => v.float(consts, "constants", name).map(Value::from),
+				};
+				if let Some(field) = field {
+					fields.insert(name.to_string(), field);
+				}
+			}
+			for name in OPTIONAL_CONSTANT_FIELDS.iter().filter(|name| consts.contains_key(**name)) {
+				let field = match *name {
+					"self_trade_prevention" => v.name::<SelfTradePrevention>(consts, "constants", name).map(|_| consts[*name].clone()),
+					"allocation_rule" => v.name::<AllocationRule>(consts, "constants", name).map(|_| consts[*name].clone()),
+					"price_rule" => v.name::<PriceRule>(consts, "constants", name).map(|_| consts[*name].clone()),
+					"liquidation_price" => v.name::<LiquidationPrice>(consts, "constants", name).map(|_| consts[*name].clone()),
+					_ if INTEGER_PARAMS.contains(name) => v.uint(consts, "constants", name).map(Value::from),
+					_ => v.float(consts, "constants", name).map(Value::from),
+				};
+				if let Some(field) = field {
+					fields.insert(name.to_string(), field);
+				}
+			}
+		}
+
+		let maker_types = v.players(root, &mut fields);
+		let dists = v.distributions(root);
+
+		// Range check the constants, standing in for the fields that are missing or of the
+		// wrong type, which have already been reported
+		let bad_fields: Vec<String> = v.errors.iter().map(|e| e.location.clone()).collect();
+		let mut checked = fields.clone();
+		for name in CONSTANT_FIELDS.iter().chain(["num_investors", "num_makers"].iter()) {
+			let stand_in = match *name {
+				"market_type" => Value::from("KLF"),
+				"num_investors" => Value::from(2),
+				_ if INTEGER_PARAMS.contains(name) => Value::from(1),
+				_ => Value::from(0.0),
+			};
+			checked.entry(name.to_string()).or_insert(stand_in);
+		}
+		let consts: Constants = serde_json::from_value(Value::Object(checked)).expect("Constants fields were validated");
+		for (name, message) in consts.validate() {
+			let table = match name {
+				"num_investors"|"num_makers" => "players",
+				_ => "constants",
+			};
+			let field = ConfigError::new(file, &format!("{}.{}", table, name), message);
+			if !bad_fields.contains(&field.location) {
+				v.errors.push(field);
+			}
+		}
+		if !v.errors.is_empty() {
+			return Err(ConfigErrors(v.errors));
+		}
+
+		Ok(Scenario {
+			consts,
+			dists: dists.expect("Distributions were validated"),
+			maker_types,
+		})
+	}
+
+	/// A SimulationBuilder for the scenario
+	pub fn builder(self) -> SimulationBuilder {
+		let builder = SimulationBuilder::new(self.dists, self.consts);
+		match self.maker_types {
+			Some(maker_types) => builder.maker_types(maker_types),
+			None => builder,
+		}
+	}
+}
+
+
+// Checks the fields of a scenario, collecting an error for every bad field
+struct Validator<'a> {
+	file: &'a str,
+	errors: Vec<ConfigError>,
+}
+
+impl<'a> Validator<'a> {
+	fn error(&mut self, field: &str, message: String) {
+		self.errors.push(ConfigError::new(self.file, field, message));
+	}
+
+	fn path(table: &str, key: &str) -> String {
+		match table {
+			"" => key.to_string(),
+			_ => format!("{}.{}", table, key),
+		}
+	}
+
+	// Reports every key that isn't one of the allowed keys
+	fn check_keys(&mut self, map: &Map<String, Value>, table: &str, allowed: &[&str]) {
+		for key in map.keys() {
+			if !allowed.contains(&key.as_str()) {
+				self.error(&Validator::path(table, key), String::from("unknown field"));
+			}
+		}
+	}
+
+	fn get<'v>(&mut self, map: &'v Map<String, Value>, table: &str, key: &str) -> Option<&'v Value> {
+		let value = map.get(key);
+		if value.is_none() {
+			self.error(&Validator::path(table, key), String::from("missing field"));
+		}
+		value
+	}
+
+	fn table<'v>(&mut self, map: &'v Map<String, Value>, table: &str, key: &str) -> Option<&'v Map<String, Value>> {
+		let value = self.get(map, table, key)?;
+		if value.is_object() {
+			value.as_object()
+		} else {
+			self.error(&Validator::path(table, key), format!("expected a table, got {}", value));
+			None
+		}
+	}
+
+	fn uint(&mut self, map: &Map<String, Value>, table: &str, key: &str) -> Option<u64> {
+		let value = self.get(map, table, key)?;
+		if value.is_u64() {
+			value.as_u64()
+		} else {
+			self.error(&Validator::path(table, key), format!("expected a non-negative integer, got {}", value));
+			None
+		}
+	}
+
+	fn float(&mut self, map: &Map<String, Value>, table: &str, key: &str) -> Option<f64> {
+		let value = self.get(map, table, key)?;
+		if value.is_number() {
+			value.as_f64()
+		} else {
+			self.error(&Validator::path(table, key), format!("expected a number, got {}", value));
+			None
+		}
+	}
+
+	// Parses the name of an enum variant such as a MarketType or DistType
+	fn name<T: for<'de> Deserialize<'de>>(&mut self, map: &Map<String, Value>, table: &str, key: &str) -> Option<T> {
+		let value = self.get(map, table, key)?;
+		let parsed = value.as_str().and_then(|name| {
+			let de: serde::de::value::StrDeserializer<serde::de::value::Error> = name.into_deserializer();
+			T::deserialize(de).ok()
+		});
+		if parsed.is_none() {
+			self.error(&Validator::path(table, key), format!("unknown {}", value));
+		}
+		parsed
+	}
+
+	// Adds num_investors and num_makers to the constants and returns the maker types if supplied
+	fn players(&mut self, root: &Map<String, Value>, fields: &mut Map<String, Value>) -> Option<Vec<(MakerT, u64)>> {
+		let players = self.table(root, "", "players")?;
+		self.check_keys(players, "players", &["num_investors", "num_makers", "maker_types"]);
+		if let Some(num_investors) = self.uint(players, "players", "num_investors") {
+			fields.insert(String::from("num_investors"), num_investors.into());
+		}
+
+		if !players.contains_key("maker_types") {
+			if let Some(num_makers) = self.uint(players, "players", "num_makers") {
+				fields.insert(String::from("num_makers"), num_makers.into());
+			}
+			return None;
+		}
+
+		if players.contains_key("num_makers") {
+			self.error("players.num_makers", String::from("can't be set with maker_types, which sets the number of each type"));
+		}
+		let types = self.table(players, "players", "maker_types")?;
+		let names: Vec<&str> = MAKER_TYPES.iter().map(|(name, _)| *name).collect();
+		self.check_keys(types, "players.maker_types", &names);
+
+		let mut maker_types = Vec::new();
+		for (name, maker_type) in MAKER_TYPES.iter() {
+			if types.contains_key(*name) {
+				let count = self.uint(types, "players.maker_types", name)?;
+				maker_types.push((maker_type.clone(), count));
+			}
+		}
+		let total: u64 = maker_types.iter().map(|(_, count)| count).sum();
+		fields.insert(String::from("num_makers"), total.into());
+		Some(maker_types)
+	}
+
+	fn distributions(&mut self, root: &Map<String, Value>) -> Option<Distributions> {
+		let dists = self.table(root, "", "distributions")?;
+		let mut config = Vec::new();
+		for (key, value) in dists.iter() {
+			let path = Validator::path("distributions", key);
+			let reason = {
+				let de: serde::de::value::StrDeserializer<serde::de::value::Error> = key.as_str().into_deserializer();
+				DistReason::deserialize(de)
+			};
+			let reason = match reason {
+				Ok(reason) => reason,
+				Err(_) => {
+					self.error(&path, String::from("unknown distribution"));
+					continue;
+				}
+			};
+			let dist = match value.as_object() {
+				Some(dist) => dist,
+				None => {
+					self.error(&path, format!("expected a table of v1, v2, scalar and dist_type, got {}", value));
+					continue;
+				}
+			};
+
+			self.check_keys(dist, &path, &["v1", "v2", "scalar", "dist_type"]);
+			let v1 = self.float(dist, &path, "v1");
+			let v2 = self.float(dist, &path, "v2");
+			let scalar = match dist.get("scalar") {
+				Some(_) => self.float(dist, &path, "scalar"),
+				None => Some(1.0),
+			};
+			let dist_type = self.name::<DistType>(dist, &path, "dist_type");
+			if let (Some(v1), Some(v2), Some(scalar), Some(dist_type)) = (v1, v2, scalar, dist_type) {
+				match Distributions::validate_dist(v1, v2, scalar, dist_type) {
+					Ok(()) => config.push((reason, v1, v2, scalar, dist_type)),
+					Err(e) => self.error(&path, e),
+				}
+			}
+		}
+
+		for reason in REQUIRED_DISTS.iter() {
+			if !dists.contains_key(&format!("{:?}", reason)) {
+				self.error(&Validator::path("distributions", &format!("{:?}", reason)), String::from("missing distribution"));
+			}
+		}
+		if config.is_empty() {
+			return None;
+		}
+		Some(Distributions::new(config))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SCENARIO: &str = r#"
+seed = 42
+
+[constants]
+batch_interval = 300
+block_size = 100
+num_blocks = 20
+market_type = "KLF"
+front_run_perc = 1.0
+flow_order_offset = 0.25
+maker_prop_delay = 1
+maker_base_spread = 0.25
+maker_enter_prob = 0.25
+max_held_inventory = 5.0
+maker_inv_tax = 0.01
+maker_cold_start = 10
+maker_update_prob = 0.5
+
+[players]
+num_investors = 250
+maker_types = { Aggressive = 10, RiskAverse = 20, Random = 19 }
+
+[distributions]
+AsksCenter = { v1 = 110.0, v2 = 10.0, dist_type = "Normal" }
+BidsCenter = { v1 = 90.0, v2 = 10.0, dist_type = "Normal" }
+MinerFrameForm = { v1 = 150.0, v2 = 151.0, dist_type = "Uniform" }
+InvestorVolume = { v1 = 0.99, v2 = 1.0, dist_type = "Uniform" }
+InvestorGas = { v1 = 0.0, v2 = 1.0, scalar = 2.0, dist_type = "Uniform" }
+InvestorEnter = { v1 = 10.0, v2 = 5.0, dist_type = "Normal" }
+"#;
+
+	fn parse(text: &str) -> Result<Scenario, ConfigErrors> {
+		let value = serde_json::to_value(toml::from_str::<toml::Value>(text).unwrap()).unwrap();
+		Scenario::from_value(&value, "test.toml")
+	}
+
+	fn locations(text: &str) -> Vec<String> {
+		match parse(text) {
+			Ok(_) => Vec::new(),
+			Err(errors) => errors.0.into_iter().map(|e| e.location).collect(),
+		}
+	}
+
+	#[test]
+	fn test_parse_scenario() {
+		let scenario = parse(SCENARIO).unwrap();
+		assert_eq!(scenario.consts.seed, Some(42));
+		assert_eq!(scenario.consts.market_type, MarketType::KLF);
+		assert_eq!(scenario.consts.num_investors, 250);
+		assert_eq!(scenario.consts.num_makers, 49);
+		assert_eq!(scenario.consts.front_run_perc, 1.0);
+		assert_eq!(scenario.maker_types.unwrap().len(), 3);
+		assert_eq!(scenario.dists.read_dist_params(DistReason::BidsCenter), (90.0, 10.0));
+		assert_eq!(scenario.dists.dists[DistReason::InvestorGas as usize].2, 2.0);
+	}
+
+	#[test]
+	fn test_reports_every_error() {
+		let text = SCENARIO
+			.replace("front_run_perc = 1.0", "front_run_perc = 1.5")
+			.replace("block_size = 100\n", "")
+			.replace("num_investors = 250", "num_investors = 1")
+			.replace("maker_cold_start = 10", "maker_cold_start = -1")
+			.replace("market_type = \"KLF\"", "market_type = \"XYZ\"")
+			.replace("v1 = 150.0, v2 = 151.0", "v1 = 151.0, v2 = 150.0")
+			.replace("InvestorEnter", "InvestorLeave");
+		let found = locations(&text);
+		for field in ["constants.front_run_perc", "players.num_investors", "constants.block_size",
+					  "constants.maker_cold_start", "constants.market_type", "distributions.MinerFrameForm", "distributions.InvestorLeave", "distributions.InvestorEnter"].iter() {
+			assert!(found.contains(&format!("test.toml ({})", field)), "{} not in {:?}", field, found);
+		}
+		assert_eq!(found.len(), 8);
+	}
+
+	#[test]
+	fn test_players() {
+		let text = SCENARIO.replace("maker_types = { Aggressive = 10, RiskAverse = 20, Random = 19 }", "num_makers = 50");
+		let scenario = parse(&text).unwrap();
+		assert_eq!(scenario.consts.num_makers, 50);
+		assert!(scenario.maker_types.is_none());
+
+		let text = SCENARIO.replace("num_investors = 250", "num_investors = 250\nnum_makers = 50");
+		assert_eq!(locations(&text), vec!(String::from("test.toml (players.num_makers)")));
+		let text = SCENARIO.replace("Random = 19", "Lazy = 19");
+		assert_eq!(locations(&text), vec!(String::from("test.toml (players.maker_types.Lazy)")));
+	}
+
+	#[test]
+	fn test_tick_and_lot_size() {
+		assert_eq!(parse(SCENARIO).unwrap().consts.tick_size, 0.0);
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\ntick_size = 0.01\nlot_size = 1");
+		let scenario = parse(&text).unwrap();
+		assert_eq!((scenario.consts.tick_size, scenario.consts.lot_size), (0.01, 1.0));
+
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\ntick_size = -0.01\nlot_size = \"one\"");
+		assert_eq!(locations(&text), vec!(String::from("test.toml (constants.lot_size)"), String::from("test.toml (constants.tick_size)")));
+	}
+
+	#[test]
+	fn test_order_expiry_blocks() {
+		assert_eq!(parse(SCENARIO).unwrap().consts.order_expiry_blocks, 0);
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\norder_expiry_blocks = 5");
+		assert_eq!(parse(&text).unwrap().consts.order_expiry_blocks, 5);
+
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\norder_expiry_blocks = 2.5");
+		assert_eq!(locations(&text), vec!(String::from("test.toml (constants.order_expiry_blocks)")));
+	}
+
+	#[test]
+	fn test_json_matches_toml() {
+		let toml_value = serde_json::to_value(toml::from_str::<toml::Value>(SCENARIO).unwrap()).unwrap();
+		let json = serde_json::to_string(&toml_value).unwrap();
+		let scenario = Scenario::from_value(&serde_json::from_str(&json).unwrap(), "test.json").unwrap();
+		assert_eq!(scenario.consts.num_blocks, 20);
+	}
+
+	#[test]
+	fn test_self_trade_prevention() {
+		assert_eq!(parse(SCENARIO).unwrap().consts.self_trade_prevention, SelfTradePrevention::None);
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\nself_trade_prevention = \"DecrementBoth\"");
+		assert_eq!(parse(&text).unwrap().consts.self_trade_prevention, SelfTradePrevention::DecrementBoth);
+
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\nself_trade_prevention = \"CancelBoth\"");
+		assert_eq!(locations(&text), vec!(String::from("test.toml (constants.self_trade_prevention)")));
+	}
+
+	#[test]
+	fn test_allocation_rule() {
+		assert_eq!(parse(SCENARIO).unwrap().consts.allocation_rule, AllocationRule::TimePriority);
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\nallocation_rule = \"ProRata\"");
+		assert_eq!(parse(&text).unwrap().consts.allocation_rule, AllocationRule::ProRata);
+
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\nallocation_rule = 3");
+		assert_eq!(locations(&text), vec!(String::from("test.toml (constants.allocation_rule)")));
+	}
+
+	#[test]
+	fn test_price_rule() {
+		assert_eq!(parse(SCENARIO).unwrap().consts.price_rule, PriceRule::MergedBook);
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\nprice_rule = \"Reference\"");
+		assert_eq!(parse(&text).unwrap().consts.price_rule, PriceRule::Reference);
+
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\nprice_rule = \"Median\"");
+		assert_eq!(locations(&text), vec!(String::from("test.toml (constants.price_rule)")));
+	}
+
+	#[test]
+	fn test_call_auctions() {
+		let consts = parse(SCENARIO).unwrap().consts;
+		assert_eq!((consts.opening_auction_blocks, consts.closing_auction_blocks), (0, 0));
+		assert_eq!(consts.liquidation_price, LiquidationPrice::FundamentalValue);
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\nopening_auction_blocks = 2\nclosing_auction_blocks = 3\nliquidation_price = \"ClosingAuction\"");
+		let consts = parse(&text).unwrap().consts;
+		assert_eq!((consts.opening_auction_blocks, consts.closing_auction_blocks), (2, 3));
+		assert_eq!(consts.liquidation_price, LiquidationPrice::ClosingAuction);
+
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\nliquidation_price = \"LastTrade\"");
+		assert_eq!(locations(&text), vec!(String::from("test.toml (constants.liquidation_price)")));
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\nliquidation_price = \"ClosingAuction\"");
+		assert_eq!(locations(&text), vec!(String::from("test.toml (constants.liquidation_price)")));
+		let text = SCENARIO.replace("maker_update_prob = 0.5", "maker_update_prob = 0.5\nopening_auction_blocks = 10\nclosing_auction_blocks = 11");
+		assert_eq!(locations(&text), vec!(String::from("test.toml (constants.closing_auction_blocks)")));
+	}
+}
@@ -0,0 +1,72 @@
+// The trading sessions of a simulation: an opening call auction, the main session and a closing call auction
+use crate::controller::State;
+use crate::simulation::simulation_config::Constants;
+
+
+/// The blocks of each trading session. Blocks 0 to num_blocks are published, the first
+/// opening_blocks of them are an opening call auction and the last closing_blocks a closing
+/// call auction, with the main session trading by the simulation's mechanism in between.
+/// A call auction is in State::PreAuction while it collects orders and in State::Auction
+/// for its last block, when the books are uncrossed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+	pub opening_blocks: u64,
+	pub closing_blocks: u64,
+	pub num_blocks: u64,
+}
+
+impl Session {
+	pub fn new(consts: &Constants) -> Session {
+		Session {
+			opening_blocks: consts.opening_auction_blocks,
+			closing_blocks: consts.closing_auction_blocks,
+			num_blocks: consts.num_blocks,
+		}
+	}
+
+	/// The state of the market while block is published
+	pub fn state(&self, block: u64) -> State {
+		if block < self.opening_blocks {
+			match block + 1 == self.opening_blocks {
+				true => State::Auction,
+				false => State::PreAuction,
+			}
+		} else if self.closing_blocks > 0 && block + self.closing_blocks > self.num_blocks {
+			match block >= self.num_blocks {
+				true => State::Auction,
+				false => State::PreAuction,
+			}
+		} else {
+			State::Process
+		}
+	}
+
+	/// Whether the closing auction uncrosses in block
+	pub fn is_closing_auction(&self, block: u64) -> bool {
+		self.closing_blocks > 0 && block == self.num_blocks
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_session_states() {
+		let session = Session { opening_blocks: 2, closing_blocks: 3, num_blocks: 10 };
+		let states: Vec<State> = (0..=10).map(|block| session.state(block)).collect();
+		let (pre, auction, process) = (State::PreAuction, State::Auction, State::Process);
+		assert_eq!(states, vec!(pre, auction, process, process, process, process, process, process, pre, pre, auction));
+		assert!(session.is_closing_auction(10) && !session.is_closing_auction(9));
+
+		// Without call auctions every block is in the main session
+		let session = Session { opening_blocks: 0, closing_blocks: 0, num_blocks: 10 };
+		assert!((0..=10).all(|block| session.state(block) == State::Process));
+		assert!(!session.is_closing_auction(10));
+
+		// A one block call auction uncrosses the orders sent for that block
+		let session = Session { opening_blocks: 1, closing_blocks: 1, num_blocks: 10 };
+		assert_eq!((session.state(0), session.state(1), session.state(9), session.state(10)), (auction, process, process, auction));
+	}
+}
@@ -1,25 +1,41 @@
-use crate::simulation::simulation_config::{Constants, Distributions, DistReason};
-use crate::controller::Task;
+use crate::simulation::simulation_config::{Constants, Distributions, DistReason, LiquidationPrice};
+use crate::simulation::session::Session;
+use crate::controller::State;
+use crate::controller::scheduler::{Scheduler, Event};
 use crate::exchange::clearing_house::ClearingHouse;
 use crate::order::order::{Order, TradeType, ExchangeType, OrderType};
 use crate::order::order_book::Book;
+use crate::order::stop_book::StopBook;
 use crate::blockchain::mem_pool::MemPool;
 use crate::players::{TraderT};
+use crate::players::strategy::{Strategy, StrategyContext};
 use crate::players::miner::Miner;
 use crate::players::investor::Investor;
 use crate::players::maker::{Maker, MakerT};
+use crate::exchange::mechanism::{AuctionMechanism, CallAuction};
 use crate::exchange::MarketType;
+use crate::exchange::exchange_logic::PlayerUpdate;
 use crate::blockchain::order_processor::OrderProcessor;
-use crate::utility::{gen_trader_id, get_time};
-use crate::simulation::simulation_history::History;
+use crate::utility::{gen_trader_id, get_time, get_seed, set_rng_stream, RngStream, SimRng, install_rng, 
+					 TrialLog, set_trial_log, setup_log_headers};
+use crate::simulation::simulation_history::UpdateReason;
+use crate::simulation::simulation_history::{History, DepthSnapshot};
+use crate::simulation::performance_report::PerformanceReport;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use std::sync::Mutex;
 use std::sync::Arc;
-use std::{time, thread};
-use std::thread::JoinHandle;
 
-use log::{Level};
+
+
+// The RNG stream a player's strategy draws from
+fn trader_stream(trader_type: TraderT) -> RngStream {
+	match trader_type {
+		TraderT::Investor => RngStream::Investor,
+		TraderT::Maker => RngStream::Maker,
+		TraderT::Miner => RngStream::Miner,
+	}
+}
 
 
 pub struct BlockNum {pub num: Mutex<u64>}
@@ -48,15 +64,36 @@ pub struct Simulation {
 	pub mempool: Arc<MemPool>,
 	pub bids_book: Arc<Book>,
 	pub asks_book: Arc<Book>,
+	pub stops_book: Arc<StopBook>,		// Stop orders waiting for a trade to trigger them into the books
 	pub history: Arc<History>,
+	pub mechanism: Arc<dyn AuctionMechanism>,	// Accepts, clears and settles the orders of each block
+	pub call_auction: CallAuction,		// Runs the opening and closing call auctions, see Session
+	pub strategies: Mutex<BTreeMap<String, Box<dyn Strategy>>>,	// Replaces the built-in behaviour of these players
 	pub block_num: Arc<BlockNum>,
+	pub miner: Mutex<Miner>,			// The miner's copy of its frame, driven by the scheduler
+	pub seed: u64,						// The master seed of every RNG stream
+	pub rng: Mutex<Option<SimRng>>,		// The RNG streams, continued from building the simulation
+	pub log: Mutex<Option<TrialLog>>,	// The csv logs, or the global logger if None
+	pub depth_levels: usize,			// The number of price levels of each book kept in the depth snapshots
 }
 
 
 
 impl Simulation {
 	pub fn new(dists: Distributions, consts: Constants, house: ClearingHouse, 
-			   mempool: MemPool, bids_book: Book, asks_book: Book, history: History) -> Simulation {
+			   mempool: MemPool, bids_book: Book, asks_book: Book, history: History,
+			   miner: Miner, mechanism: Arc<dyn AuctionMechanism>, seed: u64) -> Simulation {
+		// The call auctions uncross limit orders like the FBA and flow orders like the KLF,
+		// unless the mechanism keeps orders of its own
+		let call_market = match mechanism.ex_type() {
+			ExchangeType::FlowOrder => MarketType::KLF,
+			_ => MarketType::FBA,
+		};
+		let call_auction = match mechanism.runs_call_auctions() {
+			true => CallAuction::new(Arc::clone(&mechanism)),
+			false => CallAuction::new(call_market.mechanism_with(consts.self_trade_prevention, consts.allocation_rule,
+																 consts.price_rule, consts.lot_size, consts.batch_interval)),
+		};
 		Simulation {
 			dists: dists,
 			consts: consts,
@@ -64,39 +101,20 @@ impl Simulation {
 			mempool: Arc::new(mempool),
 			bids_book: Arc::new(bids_book),
 			asks_book: Arc::new(asks_book),
+			stops_book: Arc::new(StopBook::new()),
 			history: Arc::new(history),
+			mechanism,
+			call_auction,
+			strategies: Mutex::new(BTreeMap::new()),
 			block_num: Arc::new(BlockNum::new()),
+			miner: Mutex::new(miner),
+			seed,
+			rng: Mutex::new(None),
+			log: Mutex::new(None),
+			depth_levels: usize::MAX,
 		}
 	}
 
-	pub fn init_simulation(dists: Distributions, consts: Constants) -> (Simulation, Miner) {
-		// Initialize the state for the simulation
-		let house = ClearingHouse::new();
-		let bids_book = Book::new(TradeType::Bid);
-		let asks_book = Book::new(TradeType::Ask);
-		let mempool = MemPool::new();
-		let history = History::new(consts.market_type);
-
-		// Initialize and register the miner to CH
-		let ch_miner = Miner::new(gen_trader_id(TraderT::Miner));
-		let miner_id = ch_miner.trader_id.clone();
-		house.reg_miner(ch_miner);
-
-		// Initialize copy of miner for the miner task
-		let mut miner = Miner::new(gen_trader_id(TraderT::Miner));
-		miner.trader_id = miner_id;
-
-		// Initialize and register the Investors
-		let invs = Simulation::setup_investors(&dists, &consts);
-		house.reg_n_investors(invs);
-
-		// Initialize and register the Makers
-		let mkrs = Simulation::setup_makers(&dists, &consts);
-		house.reg_n_makers(mkrs);
-		
-		(Simulation::new(dists, consts, house, mempool, bids_book, asks_book, history), miner)
-	}
-
 	/// Initializes Investor players. Randomly samples the maker's initial balance and inventory
 	/// using the distribution configs. Number of makers saved in consts.
 	pub fn setup_investors(_dists: &Distributions, consts: &Constants) -> Vec<Investor> {
@@ -122,278 +140,617 @@ impl Simulation {
 		mkrs
 	}
 
-	/// A repeating task. Will randomly select an Investor from the ClearingHouse,
-	/// generate a bid/ask order priced via bid/ask distributions, send the order to 
-	/// the mempool, and then sleep until the next investor_arrival time.
-	pub fn investor_task(dists: Distributions, house: Arc<ClearingHouse>, mempool: Arc<MemPool>, history: Arc<History>, block_num: Arc<BlockNum>, consts: Constants) -> JoinHandle<()> {
-		thread::spawn(move || {       
-			loop {
-				// Check if the simulation is ending
-				if block_num.read_count() > consts.num_blocks {
-					// exit the thread
-					println!("Exiting investor_task");
-					break;
-				}
+	/// Generates the given number of makers of each type
+	pub fn setup_maker_types(maker_types: &[(MakerT, u64)]) -> Vec<Maker> {
+		let mut mkrs = Vec::new();
+		for (maker_type, count) in maker_types {
+			for _ in 0..*count {
+				let id = gen_trader_id(TraderT::Maker);
+				mkrs.push(Maker::new(id, maker_type.clone()));
+			}
+		}
+		mkrs
+	}
 
-				// Randomly select an investor
-				let trader_id = house.get_rand_player_id(TraderT::Investor).expect("Couldn't get rand investor");
+	/// Runs the simulation until num_blocks have been published, then liquidates every player's
+	/// inventory at the fundamental value, or the closing auction price. Returns the performance
+	/// results before and after liquidation. A simulation should only be run once.
+	pub fn run(&self) -> Vec<PerformanceReport> {
+		// Continue the RNG streams from where building the simulation left them
+		let rng = self.rng.lock().unwrap().take().unwrap_or_else(|| SimRng::new(self.seed));
+		let rng = install_rng(rng);
+		let _log = self.log.lock().unwrap().take().map(set_trial_log);
+
+		// Write the headers to all of the log files
+		setup_log_headers(self.mechanism.as_ref());
+
+		// Log and save the intial state of the players
+		self.house.log_all_players(UpdateReason::Initial);
+		// Save the initial balance and inventory of each player
+		let mut initial_player_state = HashMap::<String, (f64, f64)>::new();
+		{
+			for (id, player) in self.house.players.lock().unwrap().iter() {
+				initial_player_state.insert(id.clone(), (player.get_bal(), player.get_inv()));
+			}
+		}
 
-				// Only add a new order if they dont already have one in the book
-				if house.get_player_order_count(&trader_id).expect("get_player_order_count") == 0 {
-					// Decide bid or ask
-					let trade_type = match Distributions::fifty_fifty() {
-						true => TradeType::Ask,
-						false => TradeType::Bid,
-					};
+		// Drive the investors, makers and miner in simulated time until num_blocks are published
+		self.run_events();
 
-					// Sample order price from bid/ask distribution
-					let price = match trade_type {
-						TradeType::Ask => dists.sample_dist(DistReason::AsksCenter).expect("couldn't sample price"),
-						TradeType::Bid => dists.sample_dist(DistReason::BidsCenter).expect("couldn't sample price"),
-					};
+		info!("Done running simulation. Saving data...");
 
-					// Sample order volume from bid/ask distribution
-					let quantity = dists.sample_dist(DistReason::InvestorVolume).expect("couldn't sample vol");
+		// Log the final state of the players
+		self.house.log_all_players(UpdateReason::Final);
 
-					// Determine if were using flow or limit order
-					let ex_type = match consts.market_type {
-						MarketType::CDA|MarketType::FBA => ExchangeType::LimitOrder,
-						MarketType::KLF => ExchangeType::FlowOrder,
-					};
+		// Calculate the fundamental value from the configs
+		let (mean_bids, _dev_bids) = self.dists.read_dist_params(DistReason::BidsCenter);
+		let (mean_asks, _dev_asks) = self.dists.read_dist_params(DistReason::AsksCenter);
+		let fund_val = (mean_bids + mean_asks) / 2.0;
 
-					// Set the p_low and p_high to the price for limit orders
-					let (p_l, p_h) = match ex_type {								
-						ExchangeType::LimitOrder => (price, price),
-						ExchangeType::FlowOrder => {
-							// Flow order price has constant offset between p_low and p_high
-							match trade_type {
-								TradeType::Ask => (price, price + consts.flow_order_offset),
-								TradeType::Bid => (price - consts.flow_order_offset, price),
-							}
-						}
-					};
+		let s = format!("Experiment ending at: {:?}", get_time());
+		log_order_book!(s);
+		log_mempool_data!(s);
+		log_player_data!(s);
 
-					// Sample the u_max (maximum shares / batch) from (0, quantity)
-					let u_max = Distributions::sample_uniform(0.0, quantity, None);
-
-					// Generate the order
-					let order = Order::new(trader_id.clone(), 
-										   OrderType::Enter,
-								   	       trade_type,
-									       ex_type,
-									       p_l,
-									       p_h,
-									       price,
-									       quantity,
-									       u_max,
-									       dists.sample_dist(DistReason::InvestorGas).expect("Couldn't sample gas")
-					);
-
-					// Add the order to the ClearingHouse which will register to the correct investor
-					match house.new_order(order.clone()) {
-						Ok(()) => {
-							// Add the order to the simulation's history
-							history.mempool_order(order.clone());
-							// Send the order to the MemPool
-							OrderProcessor::conc_recv_order(order, Arc::clone(&mempool)).join().expect("Failed to send inv order");
-							
-						},
-						Err(e) => {
-							// If we failed to add the order to the player, don't send it to mempool
-							println!("{:?}", e);
-						},
-					}
-				}
+		// Calculate the pre liquidation performance results
+		let pre = self.calc_performance_results(fund_val, initial_player_state.clone(), false);
 
-				// Sample from InvestorEnter distribution how long to wait to send next investor
-				let sleep_time = dists.sample_dist(DistReason::InvestorEnter).expect("Couldn't get enter time sample").abs();	
-				let sleep_time = time::Duration::from_millis(sleep_time as u64);
-				thread::sleep(sleep_time);
-			}
-		})
+		// Each player transacts all non-zero inventory at the fundamental value, or the closing auction price
+		let liquidation_price = match self.consts.liquidation_price {
+			LiquidationPrice::FundamentalValue => fund_val,
+			LiquidationPrice::ClosingAuction => self.history.uncrossing_price(self.consts.num_blocks).unwrap_or(fund_val),
+		};
+		self.house.liquidate(liquidation_price);
+
+		// Calculate the post liquidation performance results
+		let post = self.calc_performance_results(fund_val, initial_player_state, true);
+
+		*self.rng.lock().unwrap() = Some(rng.take());
+		vec!(pre, post)
 	}
 
-	pub fn miner_task(mut miner: Miner, dists: Distributions, house: Arc<ClearingHouse>, 
-		mempool: Arc<MemPool>, bids: Arc<Book>, asks: Arc<Book>, history: Arc<History>, block_num: Arc<BlockNum>, consts: Constants) -> Task {
-		Task::rpt_task(move || {
-			// println!("in miner task, {:?}", block_num.read_count());
-			
+	/// Runs the simulation in simulated time until num_blocks have been published.
+	/// Investors, makers and the miner are driven by a discrete-event Scheduler rather
+	/// than OS threads and timers, so a run is repeatable and as fast as the CPU allows.
+	/// Each event draws from the RNG stream of the component it drives.
+	pub fn run_events(&self) {
+		let mut miner = self.miner.lock().unwrap();
+		let consts = &self.consts;
+		let mut scheduler = Scheduler::new();
+		scheduler.schedule(Event::InvestorArrival, 0.0);
+		scheduler.schedule(Event::MakerUpdate, (consts.batch_interval + consts.maker_prop_delay) as f64);
+		scheduler.schedule(Event::MinerPublish, consts.batch_interval as f64);
+
+		while let Some(event) = scheduler.next_event() {
 			// Check if the simulation is ending
-			if block_num.read_count() > consts.num_blocks {
-				// exit the thread
-				println!("Exiting miner_task");
-				// std::process::exit(1)
+			if self.block_num.read_count() > consts.num_blocks {
+				println!("Exiting simulation @{:?}", scheduler.now());
+				break;
+			}
+
+			match event {
+				Event::InvestorArrival => {
+					set_rng_stream(RngStream::Investor);
+					self.investor_arrival();
+					// Sample from InvestorEnter distribution how long to wait to send next investor
+					let wait = self.dists.sample_dist(DistReason::InvestorEnter).expect("Couldn't get enter time sample").abs();
+					scheduler.schedule(Event::InvestorArrival, wait);
+				},
+				Event::MakerUpdate => {
+					set_rng_stream(RngStream::Maker);
+					self.maker_update();
+					// Wait until the next batch + maker propagation delay to rerun the makers
+					scheduler.schedule(Event::MakerUpdate, (consts.batch_interval + consts.maker_prop_delay) as f64);
+				},
+				Event::MinerPublish => {
+					set_rng_stream(RngStream::Miner);
+					self.miner_publish(&mut miner);
+					// Form the next frame after the miner frame delay to simulate multiple miners
+					let delay = self.dists.sample_dist(DistReason::MinerFrameForm).expect("Couldn't get miner frame form delay").abs();
+					scheduler.schedule(Event::MinerFrameForm, delay);
+					// Wait until the next block publication time
+					scheduler.schedule(Event::MinerPublish, consts.batch_interval as f64);
+				},
+				Event::MinerFrameForm => {
+					set_rng_stream(RngStream::Miner);
+					self.miner_frame_form(&mut miner);
+				},
 			}
+		}
+	}
+
+	/// Randomly selects an Investor from the ClearingHouse, generates a bid/ask order 
+	/// priced via bid/ask distributions and sends the order to the mempool.
+	pub fn investor_arrival(&self) {
+		let (dists, house, consts) = (&self.dists, &self.house, &self.consts);
+
+		// Randomly select an investor
+		let trader_id = house.get_rand_player_id(TraderT::Investor).expect("Couldn't get rand investor");
+
+		// Investors with a strategy only trade through it
+		if self.strategies.lock().unwrap().contains_key(&trader_id) {
+			return;
+		}
+
+		// Only add a new order if they dont already have one in the book
+		if house.get_player_order_count(&trader_id).expect("get_player_order_count") != 0 {
+			return;
+		}
 
-			// Collect the gas from the frame
-			let (gas_changes, total_gas) = miner.collect_gas();
-			// Update the players' gas amounts
-			house.apply_gas_fees(gas_changes, total_gas);
-
-			// Publish the miner's current frame
-			if let Some(vec_results) = miner.publish_frame(Arc::clone(&bids), Arc::clone(&asks), consts.market_type) {
-				let copied_bids = bids.copy_orders();
-				let copied_asks = asks.copy_orders();
-
-				let clearing_price = vec_results.last().expect("vec_results").uniform_price;
-				log_order_book!(format!("{:?},{},{:?},{:?},{:?},",
-					get_time(),
-					block_num.read_count(),
-					clearing_price,
-					copied_bids,
-					copied_asks,
-					));
-
-				// Save new book state to the history
-				history.clone_book_state(copied_bids, TradeType::Bid, *block_num.num.lock().unwrap());
-				history.clone_book_state(copied_asks, TradeType::Ask, *block_num.num.lock().unwrap());
-
-				for res in vec_results {
-					// Update the clearing house and history
-					history.save_results(res.clone());
-					house.update_house(res);
+		// Decide bid or ask
+		let trade_type = match Distributions::fifty_fifty() {
+			true => TradeType::Ask,
+			false => TradeType::Bid,
+		};
+
+		// Sample order price from bid/ask distribution
+		let price = match trade_type {
+			TradeType::Ask => dists.sample_dist(DistReason::AsksCenter).expect("couldn't sample price"),
+			TradeType::Bid => dists.sample_dist(DistReason::BidsCenter).expect("couldn't sample price"),
+		};
+
+		// Sample order volume from bid/ask distribution
+		let quantity = dists.sample_dist(DistReason::InvestorVolume).expect("couldn't sample vol");
+
+		// Determine if were using flow or limit order, some limit orders are sent as market orders
+		let ex_type = match self.mechanism.ex_type() {
+			ExchangeType::LimitOrder if consts.market_order_prob > 0.0 && Distributions::do_with_prob(consts.market_order_prob) => ExchangeType::MarketOrder,
+			ex_type => ex_type,
+		};
+
+		// Set the p_low and p_high to the price for limit orders, market orders are unpriced
+		let (p_l, p_h) = match ex_type {								
+			ExchangeType::LimitOrder | ExchangeType::MarketOrder => (price, price),
+			ExchangeType::FlowOrder => {
+				// Flow order price has constant offset between p_low and p_high
+				match trade_type {
+					TradeType::Ask => (price, price + consts.flow_order_offset),
+					TradeType::Bid => (price - consts.flow_order_offset, price),
 				}
 			}
+		};
+
+		// Sample the u_max (maximum shares / second) from (0, quantity)
+		let u_max = Distributions::sample_uniform(0.0, quantity, None);
+
+		// Generate the order
+		let mut order = Order::new(trader_id.clone(), 
+							   OrderType::Enter,
+					   	       trade_type,
+						       ex_type,
+						       p_l,
+						       p_h,
+						       price,
+						       quantity,
+						       u_max,
+						       dists.sample_dist(DistReason::InvestorGas).expect("Couldn't sample gas")
+		);
+		// Investors trade on the market's tick and lot grid
+		order.round_to_grid(consts.tick_size, consts.lot_size);
+		// The order can trade in the next order_expiry_blocks blocks, starting with the one being mined
+		if consts.order_expiry_blocks > 0 {
+			order = order.with_expiry(self.block_num.read_count() + consts.order_expiry_blocks - 1);
+		}
+
+		// Add the order to the ClearingHouse which will register to the correct investor
+		match house.new_order(order.clone()) {
+			Ok(()) => {
+				// Add the order to the simulation's history
+				self.history.mempool_order(order.clone());
+				// Send the order to the MemPool
+				OrderProcessor::seq_recv_order(order, &self.mempool);
+			},
+			Err(e) => {
+				// If we failed to add the order to the player, don't send it to mempool
+				self.history.record_error(e);
+			},
+		}
+	}
+
+	/// Collects the gas from the miner's frame, publishes the frame as the next block and 
+	/// updates the ClearingHouse and History with the results. Blocks in a call auction are
+	/// published to the call auction instead of the mechanism, which publishes an indicative
+	/// price after each block until the block it uncrosses in.
+	pub fn miner_publish(&self, miner: &mut Miner) {
+		let (house, history, block_num, consts) = (&self.house, &self.history, &self.block_num, &self.consts);
+		let (bids, asks) = (&self.bids_book, &self.asks_book);
+
+		// Collect the gas from the frame
+		let (gas_changes, total_gas) = miner.collect_gas();
+		// Update the players' gas amounts
+		house.apply_gas_fees(gas_changes, total_gas);
+
+		// New orders that expired while waiting in the mempool never reach the books
+		let block = block_num.read_count();
+		let mut expired = miner.drop_expired(block);
+
+		let session = Session::new(consts);
+		let state = session.state(block);
+		let mechanism: &dyn AuctionMechanism = match state {
+			State::Process => self.mechanism.as_ref(),
+			State::PreAuction | State::Auction => {
+				self.call_auction.set_state(state);
+				&self.call_auction
+			},
+		};
+
+		// Publish the miner's current frame, skipping any orders that fail
+		let (frame_results, errors, triggered) = miner.publish_frame(Arc::clone(bids), Arc::clone(asks), Arc::clone(&self.stops_book), mechanism);
+		history.record_errors(errors);
+		history.save_triggered(triggered, block);
+		// The uncrossing of a call auction is the block's last result
+		let uncross_price = frame_results.as_ref().and_then(|results| results.last()).and_then(|r| r.uniform_price);
+		if let Some(vec_results) = frame_results {
+			let copied_bids = bids.copy_orders();
+			let copied_asks = asks.copy_orders();
+
+			let clearing_price = vec_results.last().expect("vec_results").uniform_price;
+			log_order_book!(format!("{:?},{},{:?},{:?},{:?},",
+				get_time(),
+				block_num.read_count(),
+				clearing_price,
+				copied_bids,
+				copied_asks,
+				));
+
+			// Save new book state to the history
+			history.clone_book_state(copied_bids, TradeType::Bid, block_num.read_count());
+			history.clone_book_state(copied_asks, TradeType::Ask, block_num.read_count());
+
+			for res in vec_results {
+				// Update the clearing house and history
+				history.save_results(res.clone());
+				let updates = res.cross_results.clone();
+				history.record_errors(mechanism.settle(house, res));
+				// Tell the strategies about their fills and cancels
+				if let Some(updates) = updates {
+					self.strategies_on_updates(&updates);
+				}
+			}
+		}
 
-			// Update the block num
-			block_num.inc_count();
-
-			// Tax the makers holding inventory
-			house.tax_makers(consts.maker_inv_tax);
-
-
-			// Sleep for miner frame delay to simulate multiple miners
-			let sleep_time = dists.sample_dist(DistReason::MinerFrameForm).expect("Couldn't get miner frame form delay").abs();	
-			let sleep_time = time::Duration::from_millis(sleep_time as u64);
-			thread::sleep(sleep_time);
-
-			// Make the next frame after simulated propagation delay expires
-			miner.make_frame(Arc::clone(&mempool), consts.block_size);
-
-			// Miner will front-run with some probability: 
-			match Distributions::do_with_prob(consts.front_run_perc) {
-				true => {
-					let (best_bid_price, best_ask_price) = history.get_best_prices();
-					match miner.strategic_front_run(best_bid_price, best_ask_price) {
-						Ok(order) => {
-							println!("Miner inserted a front-run order: {}", order.order_id);
-							// Log the order as if it were sent to the mempool
-							history.mempool_order(order.clone());
-
-							// Register the new order to the ClearingHouse
-							house.new_order(order).expect("Couldn't add front-run order to CH");
-							
-						},
-						Err(_e) => {
-							println!("asdfasdfsdf{:?}", _e);
+		// Purge the orders that can't trade in the next block
+		expired.extend(bids.purge_expired(block + 1));
+		expired.extend(asks.purge_expired(block + 1));
+		expired.extend(self.stops_book.purge_expired(block + 1));
+		self.expire_orders(expired, block);
+
+		match state {
+			// A call auction publishes where it would uncross after each block it collects orders in
+			State::PreAuction => match mechanism.indicative_price(Arc::clone(bids), Arc::clone(asks)) {
+				Ok(Some(indicative)) => history.save_indicative(indicative, block),
+				Ok(None) => {},
+				Err(e) => history.record_error(e),
+			},
+			State::Auction => history.save_uncrossing(uncross_price, block),
+			State::Process => {},
+		}
+
+		// Snapshot the depth of the books at the end of the block
+		let snapshot = DepthSnapshot {
+			block_num: block_num.read_count(),
+			bids: bids.depth(self.depth_levels),
+			asks: asks.depth(self.depth_levels),
+		};
+		for row in snapshot.to_csv() {
+			log_depth!(row);
+		}
+		history.save_depth(snapshot);
+
+		// Update the block num
+		block_num.inc_count();
+
+		// Tax the makers holding inventory
+		house.tax_makers(consts.maker_inv_tax);
+
+		// The investor and maker strategies react to the new block
+		self.strategies_on_block(&miner.trader_id);
+	}
+
+	/// The miner makes its next frame from the mempool once the simulated propagation
+	/// delay expires, then front-runs the frame with some probability.
+	pub fn miner_frame_form(&self, miner: &mut Miner) {
+		let (house, history, consts) = (&self.house, &self.history, &self.consts);
+
+		// Make the next frame after simulated propagation delay expires
+		miner.make_frame(Arc::clone(&self.mempool), consts.block_size);
+
+		// A miner with a strategy only front-runs through it
+		if self.strategies.lock().unwrap().contains_key(&miner.trader_id) {
+			self.miner_strategy_on_block(miner);
+			return;
+		}
+
+		// Miner will front-run with some probability: 
+		match Distributions::do_with_prob(consts.front_run_perc) {
+			true => {
+				let (best_bid_price, best_ask_price) = history.get_best_prices();
+				match miner.strategic_front_run(best_bid_price, best_ask_price) {
+					Ok(order) => {
+						println!("Miner inserted a front-run order: {}", order.order_id);
+						// Log the order as if it were sent to the mempool
+						history.mempool_order(order.clone());
+
+						// Register the new order to the ClearingHouse
+						if let Err(e) = house.new_order(order) {
+							history.record_error(e);
 						}
+						
+					},
+					Err(_e) => {
+						println!("asdfasdfsdf{:?}", _e);
 					}
 				}
-				false => {},
 			}
+			false => {},
+		}
+	}
 
-			// Wait until the next block publication time
 
-		}, consts.batch_interval)
-	}
+	/// Each maker cancels and re-enters a pair of orders with some probability, using the 
+	/// decision and inference data produced by the History.
+	pub fn maker_update(&self) {
+		let (dists, house, mempool, history, consts) = (&self.dists, &self.house, &self.mempool, &self.history, &self.consts);
 
+		// Wait until the maker_cold_start number of blocks has passed before entering orders to 
+		// allow more information to arrive from investors.
+		if self.block_num.read_count() <= consts.maker_cold_start {
+			return;
+		}
 
-	pub fn maker_task(dists: Distributions, house: Arc<ClearingHouse>, mempool: Arc<MemPool>, history: Arc<History>, block_num: Arc<BlockNum>, consts: Constants) -> Task {
-		Task::rpt_task(move || {
-			// Check if the simulation is ending
-			if block_num.read_count() > consts.num_blocks {
-				// exit the thread
-				println!("Exiting maker_task");
-				// std::process::exit(1)
+		// Select all Makers
+		let maker_ids = house.get_filtered_ids(TraderT::Maker);
+
+		// Copy the current mempool
+		let pool;
+		{
+			pool = mempool.items.lock().expect("maker task pool").clone();
+		}
+
+		// use History to produce inference and decision data
+		let (decision_data, inference_data) = history.produce_data(pool);
+
+		// iterate through each maker and produce an order using the decision and inference data
+		let strategies = self.strategies.lock().unwrap();
+		for id in maker_ids {
+			// Makers with a strategy only trade through it
+			if strategies.contains_key(&id) {
+				continue;
 			}
 
-			// Wait until the maker_cold_start number of blocks has passed before entering orders to 
-			// allow more information to arrive from investors.
-			if block_num.read_count() > consts.maker_cold_start {
-				// Select all Makers
-				let maker_ids = house.get_filtered_ids(TraderT::Maker);
+			// If the maker has orders in the book, cancel and re-enter with some probabilty
+			if house.get_player_order_count(&id).expect("get_player_order_count") != 0 {
+				// Randomly choose whether the maker should try cancel and re-enter
+				match Distributions::do_with_prob(consts.maker_update_prob) {
+					true => {},
+					false => continue,	// Don't trade this batch
+				}
 
-				// Copy the current mempool
-				let pool;
-				{
-					pool = mempool.items.lock().expect("maker task pool").clone();
+				// Cancel the maker's current orders
+				if let Ok(cancel_orders) = house.cancel_all_orders(id.clone()) {
+					for order in cancel_orders {
+						println!("Cancelling: {}:{},{}\n", id, order.order_id, order.price);
+						// Add the cancel order to the simulation's history
+						history.mempool_order(order.clone());
+						// Send the cancel order to the MemPool
+						OrderProcessor::seq_recv_order(order, mempool);
+					}
 				}
+			}
+			
+			// Randomly choose whether the maker should try and enter a pair of orders
+			match Distributions::do_with_prob(consts.maker_enter_prob) {
+				true => {},
+				false => continue,	// Don't trade this batch
+			}
 
-				// use History to produce inference and decision data
-				let (decision_data, inference_data) = history.produce_data(pool);
-
-				// iterate through each maker and produce an order using the decision and inference data
-				for id in maker_ids {
-					// If the maker has orders in the book, cancel and re-enter with some probabilty
-					if house.get_player_order_count(&id).expect("get_player_order_count") != 0 {
-						// Randomly choose whether the maker should try cancel and re-enter
-						match Distributions::do_with_prob(consts.maker_update_prob) {
-							true => {},
-							false => continue,	// Don't trade this batch
-						}
+			// Each maker interprets the data to produce their pair of new orders based on their type 
+			if let Some((mut bid_order, mut ask_order)) = house.maker_new_orders(id.clone(), &decision_data, &inference_data, dists, consts, self.mechanism.ex_type()) {
+				// Large limit order quotes only show maker_display_size shares at a time
+				if consts.maker_display_size > 0.0 && self.mechanism.ex_type() == ExchangeType::LimitOrder {
+					bid_order = bid_order.with_display(consts.maker_display_size);
+					ask_order = ask_order.with_display(consts.maker_display_size);
+				}
+				// Quotes are rounded onto the tick and lot grid
+				bid_order.round_to_grid(consts.tick_size, consts.lot_size);
+				ask_order.round_to_grid(consts.tick_size, consts.lot_size);
+
+				// Add the order to the ClearingHouse which will register to the correct maker
+				match house.new_order(bid_order.clone()) {
+					Ok(()) => {
+						println!("Entering: {}:{},{}\n", id, bid_order.order_id, bid_order.price);
+						// Add the bid_order to the simulation's history
+						history.mempool_order(bid_order.clone());
+						// Send the bid_order to the MemPool
+						OrderProcessor::seq_recv_order(bid_order, mempool);
+						
+					},
+					Err(e) => {
+						// If we failed to add the order to the player, don't send it to mempool
+						history.record_error(e);
+					},
+				}
 
-						// Cancel the maker's current orders
-						if let Ok(cancel_orders) = house.cancel_all_orders(id.clone()) {
-							for order in cancel_orders {
-								println!("Cancelling: {}:{},{}\n", id, order.order_id, order.price);
-								// Add the cancel order to the simulation's history
-								history.mempool_order(order.clone());
-								// Send the cancel order to the MemPool
-								OrderProcessor::conc_recv_order(order, Arc::clone(&mempool)).join().expect("Failed to send maker bid order");
-							}
-						}
-					}
-					
-					// Randomly choose whether the maker should try and enter a pair of orders
-					match Distributions::do_with_prob(consts.maker_enter_prob) {
-						true => {},
-						false => continue,	// Don't trade this batch
-					}
+				// Add the order to the ClearingHouse which will register to the correct maker
+				match house.new_order(ask_order.clone()) {
+					Ok(()) => {
+						println!("Entering: {}:{},{}\n", id, ask_order.order_id, ask_order.price);
+						// Add the ask_order to the simulation's history
+						history.mempool_order(ask_order.clone());
+						// Send the ask_order to the MemPool
+						OrderProcessor::seq_recv_order(ask_order, mempool);
+						
+					},
+					Err(e) => {
+						// If we failed to add the ask_order to the player, don't send it to mempool
+						history.record_error(e);
+					},
+				}
+			}	
+		}
+	}
 
-					// Each maker interprets the data to produce their pair of new orders based on their type 
-					if let Some((bid_order, ask_order)) = house.maker_new_orders(id.clone(), &decision_data, &inference_data, &dists, &consts) {
-						// Add the order to the ClearingHouse which will register to the correct maker
-						match house.new_order(bid_order.clone()) {
-							Ok(()) => {
-								println!("Entering: {}:{},{}\n", id, bid_order.order_id, bid_order.price);
-								// Add the bid_order to the simulation's history
-								history.mempool_order(bid_order.clone());
-								// Send the bid_order to the MemPool
-								OrderProcessor::conc_recv_order(bid_order, Arc::clone(&mempool)).join().expect("Failed to send maker bid order");
-								
-							},
-							Err(e) => {
-								// If we failed to add the order to the player, don't send it to mempool
-								println!("{:?}", e);
-							},
-						}
+	/// Calls on_block for every investor and maker strategy and sends their orders to the mempool
+	pub fn strategies_on_block(&self, miner_id: &String) {
+		let mut strategies = self.strategies.lock().unwrap();
+		if strategies.keys().all(|id| id == miner_id) {
+			return;
+		}
 
-						// Add the order to the ClearingHouse which will register to the correct maker
-						match house.new_order(ask_order.clone()) {
-							Ok(()) => {
-								println!("Entering: {}:{},{}\n", id, ask_order.order_id, ask_order.price);
-								// Add the ask_order to the simulation's history
-								history.mempool_order(ask_order.clone());
-								// Send the ask_order to the MemPool
-								OrderProcessor::conc_recv_order(ask_order, Arc::clone(&mempool)).join().expect("Failed to send maker ask order");
-								
-							},
-							Err(e) => {
-								// If we failed to add the ask_order to the player, don't send it to mempool
-								println!("{:?}", e);
-							},
-						}
-					}	
+		let pool = self.mempool.items.lock().expect("strategies pool").clone();
+		let (decision_data, inference_data) = self.history.produce_data(pool);
+		for (id, strategy) in strategies.iter_mut() {
+			if id == miner_id {
+				continue;
+			}
+			if let Some(ctx) = self.strategy_context(id, &[]) {
+				set_rng_stream(trader_stream(ctx.trader_type));
+				let orders = strategy.on_block(&ctx, &decision_data, &inference_data);
+				self.submit_strategy_orders(id, orders);
+			}
+		}
+		set_rng_stream(RngStream::Miner);
+	}
+
+	/// Calls on_block for the miner's strategy and inserts its orders at the front of the frame
+	pub fn miner_strategy_on_block(&self, miner: &mut Miner) {
+		let mut strategies = self.strategies.lock().unwrap();
+		let strategy = match strategies.get_mut(&miner.trader_id) {
+			Some(strategy) => strategy,
+			None => return,
+		};
+
+		let pool = self.mempool.items.lock().expect("miner strategy pool").clone();
+		let (decision_data, inference_data) = self.history.produce_data(pool);
+		let orders = match self.strategy_context(&miner.trader_id, &miner.frame) {
+			Some(ctx) => strategy.on_block(&ctx, &decision_data, &inference_data),
+			None => return,
+		};
+
+		// The first order returned gets the highest priority in the frame
+		for order in orders.into_iter().rev() {
+			if let Some(order) = self.register_strategy_order(&miner.trader_id, order) {
+				println!("Miner strategy inserted order: {}", order.order_id);
+				miner.frame.insert(0, order);
+			}
+		}
+	}
+
+	/// Reports the expired orders like cancels: they're removed from their players in the
+	/// ClearingHouse, saved to the History and the strategies can re-enter them.
+	fn expire_orders(&self, expired: Vec<Order>, block: u64) {
+		if expired.is_empty() {
+			return;
+		}
+		let updates: Vec<PlayerUpdate> = expired.iter()
+			.map(|o| PlayerUpdate::cancel(o.trader_id.clone(), o.order_id))
+			.collect();
+		self.history.record_errors(self.house.settle_cancels(&updates));
+		self.history.save_expired(expired, &updates, block);
+		self.strategies_on_updates(&updates);
+	}
+
+	// Calls on_fill and on_cancel for the strategies of the players in the updates
+	fn strategies_on_updates(&self, updates: &[PlayerUpdate]) {
+		let mut strategies = self.strategies.lock().unwrap();
+		if strategies.is_empty() {
+			return;
+		}
+
+		for pu in updates {
+			// A self-trade decrement only reduces the order, the strategy sees it in ctx.orders
+			if (!pu.cancel && pu.volume == 0.0) || pu.is_decrement() {
+				continue;
+			}
+			// Fills update the bidder and asker, cancels only the player who cancelled
+			let mut ids = vec!(&pu.payer_id);
+			if !pu.cancel && pu.vol_filler_id != pu.payer_id {
+				ids.push(&pu.vol_filler_id);
+			}
+
+			for id in ids {
+				let strategy = match strategies.get_mut(id) {
+					Some(strategy) => strategy,
+					None => continue,
+				};
+				if let Some(ctx) = self.strategy_context(id, &[]) {
+					set_rng_stream(trader_stream(ctx.trader_type));
+					let orders = match pu.cancel {
+						true => strategy.on_cancel(&ctx, pu.payer_order_id),
+						false => strategy.on_fill(&ctx, pu),
+					};
+					self.submit_strategy_orders(id, orders);
 				}
 			}
-			// Wait until the next batch + maker propagation delay to rerun the maker task
-		}, consts.batch_interval + consts.maker_prop_delay)
+		}
+		set_rng_stream(RngStream::Miner);
+	}
+
+	// Copies the state of a strategy's player from the ClearingHouse
+	fn strategy_context<'a>(&'a self, trader_id: &String, frame: &'a [Order]) -> Option<StrategyContext<'a>> {
+		let (balance, inventory) = self.house.get_bal_inv(trader_id.clone())?;
+		let trader_type = self.house.get_type(trader_id).ok()?;
+		let orders = self.house.get_player_orders(trader_id)?;
+		Some(StrategyContext {
+			trader_id: trader_id.clone(),
+			trader_type,
+			balance,
+			inventory,
+			asset_inventories: self.house.get_asset_invs(trader_id),
+			orders,
+			frame,
+			block_num: self.block_num.read_count(),
+			ex_type: self.mechanism.ex_type(),
+			indicative: self.history.last_indicative(self.block_num.read_count()),
+			dists: &self.dists,
+			consts: &self.consts,
+		})
+	}
+
+	// Sends the orders from a strategy to the mempool
+	fn submit_strategy_orders(&self, trader_id: &String, orders: Vec<Order>) {
+		for order in orders {
+			if let Some(order) = self.register_strategy_order(trader_id, order) {
+				OrderProcessor::seq_recv_order(order, &self.mempool);
+			}
+		}
+	}
+
+	// Registers an order from a strategy with the ClearingHouse and History. Returns the
+	// order to send, or None if it isn't a valid order for the strategy's player.
+	fn register_strategy_order(&self, trader_id: &String, order: Order) -> Option<Order> {
+		if &order.trader_id != trader_id {
+			println!("Strategy for {} can't send orders for {}", trader_id, order.trader_id);
+			return None;
+		}
+
+		// Orders from strategies aren't rounded, those off the tick and lot grid are rejected
+		let registered = match order.order_type {
+			OrderType::Enter => order.check_grid(self.consts.tick_size, self.consts.lot_size)
+				.and_then(|()| self.house.new_order(order.clone())).map(|()| order),
+			OrderType::Update => order.check_grid(self.consts.tick_size, self.consts.lot_size)
+				.and_then(|()| self.house.update_player_order(order.clone())).map(|()| order),
+			OrderType::Cancel => self.house.cancel_player_order_by_id(trader_id, order.order_id),
+		};
+
+		match registered {
+			Ok(order) => {
+				// Add the order to the simulation's history
+				self.history.mempool_order(order.clone());
+				Some(order)
+			},
+			Err(e) => {
+				self.history.record_error(e);
+				None
+			},
+		}
 	}
 
-	// Calculates performance metrics for the simulation and returns a CSV formatted string of the results
+	// Calculates performance metrics for the simulation and returns them as a PerformanceReport
 	// init_player_s = a hashmap of the initial player balances and inventories
 	// fund_val: the fixed fundamental value for the simulation
-	pub fn calc_performance_results(&self, fund_val: f64, init_player_s: HashMap<String, (f64, f64)>) -> String {
+	// liquidated: whether the players have liquidated their inventory
+	pub fn calc_performance_results(&self, fund_val: f64, init_player_s: HashMap<String, (f64, f64)>, liquidated: bool) -> PerformanceReport {
 		let volatility = self.calc_price_volatility();
 		let rmsd = self.calc_rmsd(fund_val);
 		let (maker_profit, investor_profit, miner_profit) = self.calc_total_profit(init_player_s);
@@ -412,7 +769,30 @@ impl Simulation {
 
 		let (inv_welf, mkr_welf, min_welf) = self.calc_welfare();
 
-		format!("{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},", fund_val, total_gas, avg_gas, total_tax, maker_profit, investor_profit, miner_profit, dead_weight, volatility, rmsd, agg_profit, riskav_profit, rand_profit, num_agg, num_riska, num_rand, inv_welf, mkr_welf, min_welf)
+		PerformanceReport {
+			market_type: self.mechanism.name(),
+			liquidated,
+			fund_val,
+			total_gas,
+			avg_gas,
+			total_tax,
+			maker_profit,
+			investor_profit,
+			miner_profit,
+			dead_weight,
+			volatility,
+			rmsd,
+			aggressive_maker_profit: agg_profit,
+			riskaverse_maker_profit: riskav_profit,
+			random_maker_profit: rand_profit,
+			num_aggressive: num_agg,
+			num_riskaverse: num_riska,
+			num_random: num_rand,
+			investor_welfare: inv_welf,
+			maker_welfare: mkr_welf,
+			miner_welfare: min_welf,
+			seed: get_seed(),
+		}
 	}
 
 	// standard deviation of transaction price differences relative to the fundamental value
@@ -615,27 +995,28 @@ impl Simulation {
 
 			let (mut bid_price, mut bid_plow) = (0.0, 0.0);
 			// Get the price parameters from the original bid order
+			// Market orders have no price to gain welfare against
 			match pool.get(&buyer_oid) {
-				Some((order, _time)) => {	
+				Some((order, _time)) if !order.is_market() => {	
 					bid_price = order.price;
 					bid_plow = order.p_low;
 				},
-				None => {bidder = false},
+				_ => {bidder = false},
 			}
 
 			let (mut ask_price, mut ask_phigh) = (0.0, 0.0);
 			// Get the price parameters from the original ask order
 			match pool.get(&seller_oid) {
-				Some((order, _time)) => {	
+				Some((order, _time)) if !order.is_market() => {	
 					ask_price = order.price;
 					ask_phigh = order.p_high;
 				},
-				None => {asker = false},
+				_ => {asker = false},
 			}
 
 			// Determine the amount of welfare gained from order
-			match self.consts.market_type {
-				MarketType::KLF => {
+			match self.mechanism.ex_type() {
+				ExchangeType::FlowOrder => {
 					if bidder {
 						// Positive welfare if they bought at a lower price than they bid
 						let welfare = (bid_plow - tx.price) * tx.volume;
@@ -670,7 +1051,7 @@ impl Simulation {
 						}
 					}
 				},
-				MarketType::FBA|MarketType::CDA => {
+				ExchangeType::LimitOrder | ExchangeType::MarketOrder => {
 					if bidder {
 						// Positive welfare if they bought at a lower price than they bid
 						let welfare = (bid_price - tx.price) * tx.volume;
@@ -0,0 +1,736 @@
+// Builds a Simulation from its configs so it can be embedded and run from any program
+use crate::simulation::simulation_config::{Constants, Distributions};
+use crate::simulation::simulation::Simulation;
+use crate::simulation::simulation_history::History;
+use crate::exchange::clearing_house::ClearingHouse;
+use crate::exchange::mechanism::AuctionMechanism;
+use crate::order::order::TradeType;
+use crate::order::order_book::Book;
+use crate::blockchain::mem_pool::MemPool;
+use crate::players::TraderT;
+use crate::players::miner::Miner;
+use crate::players::investor::Investor;
+use crate::players::maker::{Maker, MakerT};
+use crate::players::strategy::Strategy;
+use crate::utility::{gen_trader_id, gen_seed, seed_rng, set_rng_stream, RngStream, TrialLog};
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+
+/// Builds a Simulation from the Constants and Distributions. Investors and makers are
+/// generated from the configs unless custom players are supplied. Nothing is logged
+/// unless a log is supplied or a global logger has been set up with setup_logging.
+///
+/// let reports = SimulationBuilder::new(dists, consts).seed(42).build().run();
+pub struct SimulationBuilder {
+	dists: Distributions,
+	consts: Constants,
+	seed: Option<u64>,
+	investors: Option<Vec<Investor>>,
+	makers: Option<Vec<Maker>>,
+	maker_types: Option<Vec<(MakerT, u64)>>,
+	mechanism: Option<Arc<dyn AuctionMechanism>>,
+	strategies: Vec<(String, Box<dyn Strategy>)>,
+	miner_strategy: Option<Box<dyn Strategy>>,
+	log: Option<TrialLog>,
+	depth_levels: usize,
+}
+
+impl SimulationBuilder {
+	pub fn new(dists: Distributions, consts: Constants) -> SimulationBuilder {
+		SimulationBuilder {
+			dists,
+			consts,
+			seed: None,
+			investors: None,
+			makers: None,
+			maker_types: None,
+			mechanism: None,
+			strategies: Vec::new(),
+			miner_strategy: None,
+			log: None,
+			depth_levels: usize::MAX,
+		}
+	}
+
+	/// Seeds every RNG stream, overriding the seed in the consts. If neither is set
+	/// a random seed is generated, which is saved in Simulation::seed.
+	pub fn seed(mut self, seed: u64) -> SimulationBuilder {
+		self.seed = Some(seed);
+		self
+	}
+
+	/// The seed set in the consts, if any
+	pub fn config_seed(&self) -> Option<u64> {
+		self.consts.seed
+	}
+
+	/// Registers these investors instead of generating them from the configs
+	pub fn investors(mut self, investors: Vec<Investor>) -> SimulationBuilder {
+		self.investors = Some(investors);
+		self
+	}
+
+	/// Registers these makers instead of generating them from the configs
+	pub fn makers(mut self, makers: Vec<Maker>) -> SimulationBuilder {
+		self.makers = Some(makers);
+		self
+	}
+
+	/// Generates this many makers of each type instead of num_makers makers of random types
+	pub fn maker_types(mut self, maker_types: Vec<(MakerT, u64)>) -> SimulationBuilder {
+		self.maker_types = Some(maker_types);
+		self
+	}
+
+	/// Runs the simulation with this auction mechanism instead of the built-in mechanism
+	/// for consts.market_type
+	pub fn mechanism(mut self, mechanism: Arc<dyn AuctionMechanism>) -> SimulationBuilder {
+		self.mechanism = Some(mechanism);
+		self
+	}
+
+	/// Drives the registered investor or maker with this id by the strategy instead of its
+	/// built-in behaviour. The player must be supplied with investors() or makers().
+	pub fn strategy(mut self, trader_id: String, strategy: Box<dyn Strategy>) -> SimulationBuilder {
+		self.strategies.push((trader_id, strategy));
+		self
+	}
+
+	/// Front-runs with this strategy instead of the miner's built-in strategic front-running
+	pub fn miner_strategy(mut self, strategy: Box<dyn Strategy>) -> SimulationBuilder {
+		self.miner_strategy = Some(strategy);
+		self
+	}
+
+	/// Writes the simulation's csv logs to log instead of the global logger
+	pub fn log(mut self, log: TrialLog) -> SimulationBuilder {
+		self.log = Some(log);
+		self
+	}
+
+	/// Keeps only the best levels price levels of each book in the per-block depth snapshots
+	/// instead of every level
+	pub fn depth_levels(mut self, levels: usize) -> SimulationBuilder {
+		self.depth_levels = levels;
+		self
+	}
+
+	/// Initializes the state of the simulation and registers its players to the ClearingHouse
+	pub fn build(self) -> Simulation {
+		let (dists, consts) = (self.dists, self.consts);
+		let seed = self.seed.or(consts.seed).unwrap_or_else(gen_seed);
+		let rng = seed_rng(seed);
+		set_rng_stream(RngStream::Setup);
+
+		let mechanism = self.mechanism.unwrap_or_else(|| consts.market_type.mechanism_with(consts.self_trade_prevention, consts.allocation_rule, consts.price_rule, consts.lot_size, consts.batch_interval));
+
+		// Initialize the state for the simulation
+		let house = ClearingHouse::new();
+		let bids_book = Book::new(TradeType::Bid);
+		let asks_book = Book::new(TradeType::Ask);
+		let mempool = MemPool::new();
+		let history = History::new(mechanism.ex_type());
+
+		// Initialize and register the miner to CH
+		let ch_miner = Miner::new(gen_trader_id(TraderT::Miner));
+		let miner_id = ch_miner.trader_id.clone();
+		house.reg_miner(ch_miner);
+
+		// Initialize copy of miner for the miner's events
+		let mut miner = Miner::new(gen_trader_id(TraderT::Miner));
+		miner.trader_id = miner_id;
+
+		// Initialize and register the Investors
+		let invs = match self.investors {
+			Some(invs) => invs,
+			None => Simulation::setup_investors(&dists, &consts),
+		};
+		house.reg_n_investors(invs);
+
+		// Initialize and register the Makers
+		let mkrs = match (self.makers, self.maker_types) {
+			(Some(mkrs), _) => mkrs,
+			(None, Some(maker_types)) => Simulation::setup_maker_types(&maker_types),
+			(None, None) => Simulation::setup_makers(&dists, &consts),
+		};
+		house.reg_n_makers(mkrs);
+
+		// Strategies can only drive registered players
+		let mut strategies = BTreeMap::new();
+		for (trader_id, strategy) in self.strategies {
+			if house.get_type(&trader_id).is_err() {
+				panic!("No player {} registered for its strategy", trader_id);
+			}
+			strategies.insert(trader_id, strategy);
+		}
+		if let Some(strategy) = self.miner_strategy {
+			strategies.insert(miner.trader_id.clone(), strategy);
+		}
+
+		let mut simulation = Simulation::new(dists, consts, house, mempool, bids_book, asks_book, history, miner, mechanism, seed);
+		simulation.depth_levels = self.depth_levels;
+		// Hand the RNG streams to the simulation so run() continues where setup left off
+		*simulation.rng.lock().unwrap() = Some(rng.take());
+		*simulation.log.lock().unwrap() = self.log;
+		*simulation.strategies.lock().unwrap() = strategies;
+		simulation
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::simulation::simulation_config::{DistReason, DistType};
+	use crate::exchange::{MarketType, SelfTradePrevention};
+	use crate::exchange::mechanism::FbaMechanism;
+	use crate::exchange::portfolio::PortfolioMarket;
+	use crate::exchange::exchange_logic::TradeResults;
+	use crate::error::SimError;
+	use crate::exchange::clearing_house::ClearingHouse;
+	use crate::order::order::{Order, OrderType, ExchangeType};
+	use crate::players::maker::MakerT;
+	use crate::players::strategy::StrategyContext;
+	use crate::simulation::simulation_history::{PriorData, LikelihoodStats};
+	use crate::exchange::exchange_logic::PlayerUpdate;
+	use crate::order::order::TradeType as Side;
+	use std::sync::Mutex;
+
+	// Quotes a large bid and ask every block and counts the callbacks
+	struct Quoter {
+		calls: Arc<Mutex<(usize, usize, usize)>>,	// on_block, on_fill, on_cancel
+	}
+
+	impl Strategy for Quoter {
+		fn on_block(&mut self, ctx: &StrategyContext, _data: &PriorData, _inference: &LikelihoodStats) -> Vec<Order> {
+			self.calls.lock().unwrap().0 += 1;
+			// Replace the open orders with a new pair of quotes
+			let mut orders: Vec<Order> = ctx.orders.iter().filter_map(|o| ctx.cancel_order(o.order_id)).collect();
+			orders.push(ctx.new_order(Side::Bid, 100.0, 50.0, 0.5));
+			orders.push(ctx.new_order(Side::Ask, 100.0, 50.0, 0.5));
+			orders
+		}
+
+		fn on_fill(&mut self, _ctx: &StrategyContext, fill: &PlayerUpdate) -> Vec<Order> {
+			assert!(fill.volume > 0.0);
+			self.calls.lock().unwrap().1 += 1;
+			Vec::new()
+		}
+
+		fn on_cancel(&mut self, _ctx: &StrategyContext, _order_id: u64) -> Vec<Order> {
+			self.calls.lock().unwrap().2 += 1;
+			Vec::new()
+		}
+	}
+
+	// Front-runs every frame with a bid for one share
+	struct FrontRunner {
+		orders: Arc<Mutex<Vec<u64>>>,
+	}
+
+	impl Strategy for FrontRunner {
+		fn on_block(&mut self, ctx: &StrategyContext, _data: &PriorData, _inference: &LikelihoodStats) -> Vec<Order> {
+			assert_eq!(ctx.trader_type, TraderT::Miner);
+			let order = ctx.new_order(Side::Bid, 100.0, 1.0, 0.0);
+			self.orders.lock().unwrap().push(order.order_id);
+			vec!(order)
+		}
+	}
+
+	// Sends a bid with a NaN price every block
+	struct BadQuoter;
+
+	impl Strategy for BadQuoter {
+		fn on_block(&mut self, ctx: &StrategyContext, _data: &PriorData, _inference: &LikelihoodStats) -> Vec<Order> {
+			vec!(ctx.new_order(Side::Bid, f64::NAN, 1.0, 0.5))
+		}
+	}
+
+	// Quotes a price between ticks and a quantity between lots
+	struct OffGridQuoter;
+
+	impl Strategy for OffGridQuoter {
+		fn on_block(&mut self, ctx: &StrategyContext, _data: &PriorData, _inference: &LikelihoodStats) -> Vec<Order> {
+			vec!(ctx.new_order(Side::Bid, 100.1, 1.0, 0.5), ctx.new_order(Side::Ask, 100.0, 1.5, 0.5))
+		}
+	}
+
+	// Sends a bid too low to trade that expires after the first block, then re-enters it until filled
+	struct ExpiringQuoter {
+		expired: Arc<Mutex<Vec<u64>>>,
+	}
+
+	impl Strategy for ExpiringQuoter {
+		fn on_block(&mut self, ctx: &StrategyContext, _data: &PriorData, _inference: &LikelihoodStats) -> Vec<Order> {
+			match ctx.orders.is_empty() && self.expired.lock().unwrap().is_empty() {
+				true => vec!(ctx.new_order(Side::Bid, 1.0, 1.0, 0.5).with_expiry(0)),
+				false => Vec::new(),
+			}
+		}
+
+		fn on_cancel(&mut self, ctx: &StrategyContext, order_id: u64) -> Vec<Order> {
+			self.expired.lock().unwrap().push(order_id);
+			vec!(ctx.new_order(Side::Bid, 1.0, 1.0, 0.5))
+		}
+	}
+
+	// Places a sell stop the first trade triggers and a buy stop no trade reaches
+	struct StopTrader {
+		placed: bool,
+	}
+
+	impl Strategy for StopTrader {
+		fn on_block(&mut self, ctx: &StrategyContext, _data: &PriorData, _inference: &LikelihoodStats) -> Vec<Order> {
+			if self.placed {
+				return Vec::new();
+			}
+			self.placed = true;
+			vec!(ctx.stop_order(Side::Ask, 1e6, 1.0, 0.5), ctx.stop_order(Side::Bid, 1e6, 1.0, 0.5))
+		}
+	}
+
+	// Sends a cheap ask then a bid that would buy it back, once
+	struct SelfCrosser {
+		placed: bool,
+	}
+
+	impl Strategy for SelfCrosser {
+		fn on_block(&mut self, ctx: &StrategyContext, _data: &PriorData, _inference: &LikelihoodStats) -> Vec<Order> {
+			if self.placed {
+				return Vec::new();
+			}
+			self.placed = true;
+			vec!(ctx.new_order(Side::Ask, 1.0, 1000.0, 0.9), ctx.new_order(Side::Bid, 1e4, 1.0, 0.1))
+		}
+	}
+
+	// Offers one share too high to trade, then updates it to a price that crosses the bids
+	struct Repricer {
+		placed: bool,
+		fills: Arc<Mutex<Vec<f64>>>,
+	}
+
+	impl Strategy for Repricer {
+		fn on_block(&mut self, ctx: &StrategyContext, _data: &PriorData, _inference: &LikelihoodStats) -> Vec<Order> {
+			match (self.placed, ctx.orders.first()) {
+				(false, _) => {
+					self.placed = true;
+					vec!(ctx.new_order(Side::Ask, 1e4, 1.0, 0.5))
+				},
+				(true, Some(ask)) if ask.price == 1e4 => {
+					let mut update = ask.clone();
+					update.order_type = OrderType::Update;
+					update.price = 1.0;
+					vec!(update)
+				},
+				_ => Vec::new(),
+			}
+		}
+
+		fn on_fill(&mut self, _ctx: &StrategyContext, fill: &PlayerUpdate) -> Vec<Order> {
+			self.fills.lock().unwrap().push(fill.volume);
+			Vec::new()
+		}
+	}
+
+	// Sells asset 1 of a portfolio market once
+	struct AssetSeller {
+		placed: bool,
+	}
+
+	impl Strategy for AssetSeller {
+		fn on_block(&mut self, ctx: &StrategyContext, _data: &PriorData, _inference: &LikelihoodStats) -> Vec<Order> {
+			if self.placed {
+				return Vec::new();
+			}
+			self.placed = true;
+			vec!(ctx.new_order(Side::Ask, 100.0, 50.0, 0.5).with_asset(1))
+		}
+	}
+
+	// Bids once for baskets of one share of each asset, recording its inventory of asset 1 after each fill
+	struct BasketBuyer {
+		placed: bool,
+		inventories: Arc<Mutex<Vec<f64>>>,
+	}
+
+	impl Strategy for BasketBuyer {
+		fn on_block(&mut self, ctx: &StrategyContext, _data: &PriorData, _inference: &LikelihoodStats) -> Vec<Order> {
+			if self.placed {
+				return Vec::new();
+			}
+			self.placed = true;
+			vec!(ctx.new_order(Side::Bid, 400.0, 10.0, 0.5).with_basket(vec!(1.0, 1.0)))
+		}
+
+		fn on_fill(&mut self, ctx: &StrategyContext, _fill: &PlayerUpdate) -> Vec<Order> {
+			let inventory = ctx.asset_inventories.get(&1).cloned().unwrap_or(0.0);
+			self.inventories.lock().unwrap().push(inventory);
+			Vec::new()
+		}
+	}
+
+	// A mechanism registered from outside the built-in market types
+	struct RenamedFba;
+
+	impl AuctionMechanism for RenamedFba {
+		fn name(&self) -> String {
+			String::from("MyFBA")
+		}
+
+		fn ex_type(&self) -> ExchangeType {
+			FbaMechanism::default().ex_type()
+		}
+
+		fn accept_order(&self, bids: Arc<Book>, asks: Arc<Book>, order: Order) -> Result<Option<TradeResults>, SimError> {
+			FbaMechanism::default().accept_order(bids, asks, order)
+		}
+
+		fn clear(&self, bids: Arc<Book>, asks: Arc<Book>) -> Result<Option<TradeResults>, SimError> {
+			FbaMechanism::default().clear(bids, asks)
+		}
+
+		fn settle(&self, house: &ClearingHouse, results: TradeResults) -> Vec<SimError> {
+			FbaMechanism::default().settle(house, results)
+		}
+	}
+
+	fn setup_builder() -> SimulationBuilder {
+		let dists = Distributions::new(vec!(
+			(DistReason::AsksCenter, 110.0, 10.0, 1.0, DistType::Normal),
+			(DistReason::BidsCenter, 90.0, 10.0, 1.0, DistType::Normal),
+			(DistReason::MinerFrameForm, 150.0, 151.0, 1.0, DistType::Uniform),
+			(DistReason::InvestorVolume, 0.99, 1.0, 1.0, DistType::Uniform),
+			(DistReason::InvestorGas, 0.0, 1.0, 1.0, DistType::Uniform),
+			(DistReason::InvestorEnter, 10.0, 5.0, 1.0, DistType::Normal),
+			(DistReason::MakerOrderVolume, 1.0, 3.0, 1.0, DistType::Uniform),
+		));
+		let consts = Constants::new(300, 50, 10, 100, 5, MarketType::FBA, 1.0, 0.25, 1, 0.25, 0.25, 5.0, 0.01, 2, 0.5);
+		SimulationBuilder::new(dists, consts)
+	}
+
+	#[test]
+	fn test_build_and_run() {
+		let simulation = setup_builder().seed(42).build();
+		assert_eq!(simulation.seed, 42);
+		// The investors and makers (minus one, as in setup_investors/makers) plus the miner
+		assert_eq!(simulation.house.num_players(), 49 + 9 + 1);
+
+		let reports = simulation.run();
+		assert_eq!(reports.len(), 2);
+		assert_eq!(reports[0].market_type, "FBA");
+		assert!(!reports[0].liquidated);
+		assert!(reports[1].liquidated);
+		assert_eq!(reports[0].seed, 42);
+		assert!(simulation.block_num.read_count() > 5);
+
+		// The same seed reproduces the run
+		assert_eq!(setup_builder().seed(42).build().run(), reports);
+	}
+
+	#[test]
+	fn test_custom_players() {
+		let investors = vec!(Investor::new(String::from("INV1")), Investor::new(String::from("INV2")));
+		let makers = vec!(Maker::new(String::from("MKR1"), MakerT::Aggressive));
+		let simulation = setup_builder().investors(investors).makers(makers).build();
+		assert_eq!(simulation.house.num_players(), 4);
+		assert_eq!(simulation.house.get_maker_counts(), (1, 0, 0));
+	}
+
+	#[test]
+	fn test_custom_mechanism() {
+		let reports = setup_builder().seed(7).mechanism(Arc::new(RenamedFba)).build().run();
+		assert_eq!(reports[0].market_type, "MyFBA");
+
+		// The same trades are made as the built-in FBA
+		let mut fba_reports = setup_builder().seed(7).build().run();
+		for report in fba_reports.iter_mut() {
+			report.market_type = String::from("MyFBA");
+		}
+		assert_eq!(reports, fba_reports);
+	}
+
+	#[test]
+	fn test_strategy_updates() {
+		let fills = Arc::new(Mutex::new(Vec::new()));
+		let makers = vec!(Maker::new(String::from("REPRICER"), MakerT::Aggressive));
+		let simulation = setup_builder().seed(6)
+			.makers(makers)
+			.strategy(String::from("REPRICER"), Box::new(Repricer { placed: false, fills: Arc::clone(&fills) }))
+			.build();
+		simulation.run();
+
+		// The updated ask traded in the FBA and was settled with the player, using up its order
+		assert_eq!(fills.lock().unwrap().iter().sum::<f64>(), 1.0);
+		assert_eq!(simulation.house.get_player_order_count(&String::from("REPRICER")), Ok(0));
+		let errors = simulation.history.errors.lock().unwrap();
+		assert!(!errors.iter().any(|(e, _)| match e {
+			SimError::PlayerOrderNotFound { trader_id, .. } | SimError::PlayerNotFound { trader_id } => trader_id == "REPRICER",
+			_ => false,
+		}));
+	}
+
+	#[test]
+	fn test_portfolio_market() {
+		let inventories = Arc::new(Mutex::new(Vec::new()));
+		let makers = vec!(Maker::new(String::from("BASKET"), MakerT::Aggressive), Maker::new(String::from("SELL_B"), MakerT::Aggressive));
+		let market = Arc::new(PortfolioMarket::new(vec!(String::from("A"), String::from("B")), 1000));
+		let mut builder = setup_builder().seed(4)
+			.makers(makers)
+			.mechanism(Arc::clone(&market) as Arc<dyn AuctionMechanism>)
+			.strategy(String::from("SELL_B"), Box::new(AssetSeller { placed: false }))
+			.strategy(String::from("BASKET"), Box::new(BasketBuyer { placed: false, inventories: Arc::clone(&inventories) }));
+		builder.consts.market_type = MarketType::KLF;
+		let simulation = builder.build();
+		let reports = simulation.run();
+		assert_eq!(reports[0].market_type, "Portfolio KLF");
+
+		// The basket bought asset 1 from its only seller, who the house settled against
+		let house = &simulation.house;
+		let bought = house.get_asset_inv(&String::from("BASKET"), 1).unwrap();
+		assert!(bought > 0.0);
+		assert!((bought + house.get_asset_inv(&String::from("SELL_B"), 1).unwrap()).abs() < 0.000_001);
+		assert!(market.prices.lock().unwrap()[1].is_some());
+
+		// Its strategy saw its inventory of asset 1 grow with each fill
+		let inventories = inventories.lock().unwrap();
+		assert!(inventories.len() > 0);
+		assert!(inventories.windows(2).all(|w| w[0] <= w[1]));
+	}
+
+	#[test]
+	fn test_strategies() {
+		let calls = Arc::new(Mutex::new((0, 0, 0)));
+		let front_runs = Arc::new(Mutex::new(Vec::new()));
+		let makers = vec!(Maker::new(String::from("QUOTER"), MakerT::Aggressive));
+		let simulation = setup_builder().seed(3)
+			.makers(makers)
+			.strategy(String::from("QUOTER"), Box::new(Quoter { calls: Arc::clone(&calls) }))
+			.miner_strategy(Box::new(FrontRunner { orders: Arc::clone(&front_runs) }))
+			.build();
+		simulation.run();
+
+		// Called after every block, and its quotes partly traded before being cancelled
+		let (on_block, on_fill, on_cancel) = *calls.lock().unwrap();
+		assert_eq!(on_block as u64, simulation.block_num.read_count());
+		assert!(on_fill > 0);
+		assert!(on_cancel > 0);
+
+		// The miner's orders were sent in its frames
+		let front_runs = front_runs.lock().unwrap();
+		assert!(front_runs.len() > 0);
+		let (order, _) = simulation.history.find_orig_order(front_runs[0]).unwrap();
+		assert_eq!(order.trader_id, simulation.miner.lock().unwrap().trader_id);
+	}
+
+	#[test]
+	fn test_bad_orders_are_skipped() {
+		let makers = vec!(Maker::new(String::from("BAD"), MakerT::Aggressive));
+		let simulation = setup_builder().seed(5)
+			.makers(makers)
+			.strategy(String::from("BAD"), Box::new(BadQuoter))
+			.build();
+		let reports = simulation.run();
+		assert_eq!(reports.len(), 2);
+
+		// Every bad order was rejected by the book and recorded, the trial kept running
+		let errors = simulation.history.errors.lock().unwrap();
+		let rejected = errors.iter().filter(|(e, _)| match e {
+			SimError::InvalidOrder { reason, .. } => *reason == "price is NaN",
+			_ => false,
+		}).count();
+		assert!(rejected > 0);
+		assert!(simulation.block_num.read_count() > 5);
+	}
+
+	#[test]
+	fn test_tick_and_lot_size() {
+		let dists = setup_builder().dists;
+		let mut consts = Constants::new(300, 50, 10, 100, 5, MarketType::CDA, 1.0, 0.25, 1, 0.25, 0.25, 5.0, 0.01, 2, 0.5);
+		consts.tick_size = 0.5;
+		consts.lot_size = 1.0;
+		let makers = vec!(Maker::new(String::from("OFFGRID"), MakerT::Aggressive));
+		let simulation = SimulationBuilder::new(dists, consts).seed(5)
+			.makers(makers)
+			.strategy(String::from("OFFGRID"), Box::new(OffGridQuoter))
+			.build();
+		simulation.run();
+
+		// The investors' and makers' orders were rounded onto the grid
+		let pool = simulation.history.mempool_data.lock().unwrap();
+		assert!(pool.len() > 0);
+		for (order, _) in pool.values() {
+			assert_eq!(order.check_grid(0.5, 1.0), Ok(()));
+		}
+
+		// The strategy's orders were rejected with the reason
+		let errors = simulation.history.errors.lock().unwrap();
+		let reasons: Vec<&str> = errors.iter().filter_map(|(e, _)| match e {
+			SimError::OffGrid { reason, .. } => Some(*reason),
+			_ => None,
+		}).collect();
+		assert!(reasons.contains(&"price is not a multiple of tick_size"));
+		assert!(reasons.contains(&"quantity is not a multiple of lot_size"));
+	}
+
+	#[test]
+	fn test_order_expiry() {
+		let expired = Arc::new(Mutex::new(Vec::new()));
+		let makers = vec!(Maker::new(String::from("EXPIRING"), MakerT::Aggressive));
+		let mut builder = setup_builder().seed(8)
+			.makers(makers)
+			.strategy(String::from("EXPIRING"), Box::new(ExpiringQuoter { expired: Arc::clone(&expired) }));
+		builder.consts.order_expiry_blocks = 2;
+		let simulation = builder.build();
+		simulation.run();
+
+		// The strategy's order was purged and reported like a cancel, then re-entered
+		let expired = expired.lock().unwrap();
+		assert_eq!(expired.len(), 1);
+		let orders = simulation.house.get_player_orders(&String::from("EXPIRING")).unwrap();
+		assert_eq!(orders.len(), 1);
+		assert!(orders[0].order_id != expired[0] && orders[0].expires_at_block.is_none());
+
+		// Every expired order left the players and was purged after its last block
+		let history = simulation.history.expired.lock().unwrap();
+		assert!(history.iter().any(|(order, _)| order.order_id == expired[0]));
+		assert!(history.iter().any(|(order, _)| order.trader_id != "EXPIRING"));
+		for (order, block) in history.iter() {
+			assert!(order.expires_at_block.unwrap() <= *block);
+			let held = simulation.house.get_player_orders(&order.trader_id).unwrap();
+			assert!(held.iter().all(|o| o.order_id != order.order_id));
+		}
+
+		// The books only hold orders that can trade in the next block
+		let next_block = simulation.block_num.read_count();
+		for order in simulation.bids_book.copy_orders().iter().chain(simulation.asks_book.copy_orders().iter()) {
+			assert!(!order.is_expired(next_block));
+		}
+	}
+
+	#[test]
+	fn test_investor_market_orders() {
+		for market_type in [MarketType::CDA, MarketType::FBA] {
+			let mut builder = setup_builder().seed(3);
+			builder.consts.market_type = market_type;
+			builder.consts.market_order_prob = 0.5;
+			let simulation = builder.build();
+			let reports = simulation.run();
+			assert!(reports[0].investor_welfare.is_finite() && reports[0].rmsd.is_finite());
+
+			// Some investors sent market orders, none of them were left resting in the books
+			{
+				let pool = simulation.history.mempool_data.lock().unwrap();
+				assert!(pool.values().any(|(order, _)| order.is_market()));
+				assert!(pool.values().any(|(order, _)| !order.is_market()));
+			}
+			for order in simulation.bids_book.copy_orders().iter().chain(simulation.asks_book.copy_orders().iter()) {
+				assert!(!order.is_market());
+			}
+			let (avg_bids, avg_asks, _, _) = simulation.history.average_seen_prices();
+			assert!(avg_bids.unwrap().is_finite() && avg_asks.unwrap().is_finite());
+		}
+	}
+
+	#[test]
+	fn test_maker_icebergs() {
+		for market_type in [MarketType::CDA, MarketType::FBA] {
+			let mut builder = setup_builder().seed(5);
+			builder.consts.market_type = market_type;
+			builder.consts.maker_display_size = 0.25;
+			builder.consts.num_blocks = 20;
+			builder.consts.maker_update_prob = 0.0;
+			let simulation = builder.build();
+			let reports = simulation.run();
+			assert!(reports[0].investor_welfare.is_finite() && reports[0].rmsd.is_finite());
+
+			// Resting icebergs only show display_size and the clearing house holds the same remainder
+			let resting: Vec<Order> = simulation.bids_book.copy_orders().into_iter().chain(simulation.asks_book.copy_orders()).collect();
+			assert!(resting.iter().any(|o| o.reserve > 0.0));
+			for order in resting.iter().filter(|o| o.display_size > 0.0) {
+				assert!(order.quantity <= order.display_size);
+				let held = simulation.house.get_player_orders(&order.trader_id).unwrap();
+				let held = held.iter().find(|o| o.order_id == order.order_id).unwrap();
+				assert_eq!((held.quantity, held.reserve), (order.quantity, order.reserve));
+			}
+
+			// The history other players see never reveals a reserve
+			let (bids, asks, _, _) = simulation.history.get_current_orders();
+			assert!(bids.iter().chain(asks.iter()).all(|o| o.reserve == 0.0));
+		}
+	}
+
+	#[test]
+	fn test_stop_orders() {
+		for market_type in [MarketType::CDA, MarketType::FBA] {
+			let makers = vec!(Maker::new(String::from("STOPS"), MakerT::Aggressive));
+			let mut builder = setup_builder().seed(8)
+				.makers(makers)
+				.strategy(String::from("STOPS"), Box::new(StopTrader { placed: false }));
+			builder.consts.market_type = market_type;
+			let simulation = builder.build();
+			simulation.run();
+
+			// The sell stop was triggered once by a trade and saved as its own event
+			let triggered = simulation.history.triggered.lock().unwrap();
+			let events: Vec<_> = triggered.iter().filter(|t| t.order.trader_id == "STOPS").collect();
+			assert_eq!(events.len(), 1);
+			assert_eq!(events[0].order.trade_type, Side::Ask);
+			assert!(events[0].order.stop_price.is_none() && events[0].trigger_price <= 1e6);
+			assert!(events[0].block_num < simulation.block_num.read_count());
+
+			// The buy stop is still waiting in the stop book and held by its player
+			let waiting = simulation.stops_book.copy_orders();
+			assert_eq!(waiting.len(), 1);
+			assert_eq!(waiting[0].trade_type, Side::Bid);
+			let held = simulation.house.get_player_orders(&String::from("STOPS")).unwrap();
+			assert!(held.iter().any(|o| o.order_id == waiting[0].order_id));
+		}
+	}
+
+	#[test]
+	fn test_self_trade_prevention() {
+		for market_type in [MarketType::CDA, MarketType::FBA] {
+			let makers = vec!(Maker::new(String::from("CROSSER"), MakerT::Aggressive));
+			let mut builder = setup_builder().seed(8)
+				.makers(makers)
+				.strategy(String::from("CROSSER"), Box::new(SelfCrosser { placed: false }));
+			builder.consts.market_type = market_type;
+			builder.consts.self_trade_prevention = SelfTradePrevention::DecrementBoth;
+			let simulation = builder.build();
+			simulation.run();
+
+			// The crossing bid and ask were reduced instead of trading with each other
+			let clearings = simulation.history.clearings.lock().unwrap();
+			assert!(clearings.iter().map(|(r, _)| r.self_trades).sum::<usize>() > 0);
+			let txs = simulation.history.transactions.lock().unwrap();
+			assert!(txs.iter().filter(|pu| !pu.cancel).all(|pu| pu.payer_id != pu.vol_filler_id));
+		}
+	}
+
+	#[test]
+	fn test_depth_snapshots() {
+		let simulation = setup_builder().seed(11).depth_levels(3).build();
+		simulation.run();
+
+		// A snapshot of at most 3 levels per side at the end of every block, best price first
+		let depth = simulation.history.depth.lock().unwrap();
+		assert_eq!(depth.len() as u64, simulation.block_num.read_count());
+		assert!(depth.iter().any(|s| s.bids.len() == 3));
+		for (i, snapshot) in depth.iter().enumerate() {
+			assert_eq!(snapshot.block_num, i as u64);
+			assert!(snapshot.bids.len() <= 3 && snapshot.asks.len() <= 3);
+			assert!(snapshot.bids.windows(2).all(|l| l[0].price > l[1].price));
+			assert!(snapshot.asks.windows(2).all(|l| l[0].price < l[1].price));
+			assert_eq!(snapshot.to_csv().len(), snapshot.bids.len() + snapshot.asks.len());
+		}
+	}
+
+	#[test]
+	#[should_panic]
+	fn test_strategy_needs_player() {
+		setup_builder().strategy(String::from("NOBODY"), Box::new(Quoter { calls: Arc::new(Mutex::new((0, 0, 0))) })).build();
+	}
+}
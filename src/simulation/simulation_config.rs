@@ -1,8 +1,9 @@
 // File for loading in all the parameters for the simulation and then
 // setting up the appropriate constants and distributions.
-use crate::exchange::MarketType;
+use crate::exchange::{MarketType, SelfTradePrevention, AllocationRule, PriceRule};
+
+use crate::utility::with_rng;
 
-use rand::thread_rng;
 use rand::distributions::{Distribution};
 
 #[derive(Clone, Copy, Debug, Deserialize)]
@@ -22,6 +23,45 @@ pub struct Constants {
 	pub maker_inv_tax: f64,
 	pub maker_cold_start: u64,	// Amount of blocks to wait before makers start submitting orders
 	pub maker_update_prob: f64,
+	#[serde(default)]
+	pub tick_size: f64,			// Orders are priced in multiples of tick_size, any price if 0.0
+	#[serde(default)]
+	pub lot_size: f64,			// Orders are for multiples of lot_size shares, any quantity if 0.0
+	#[serde(default)]
+	pub order_expiry_blocks: u64,	// Investor orders can trade in this many blocks, until filled if 0
+	#[serde(default)]
+	pub market_order_prob: f64,	// Probability an investor sends a market order in the CDA or FBA
+	#[serde(default)]
+	pub maker_display_size: f64,	// Makers' limit orders show at most this many shares, all of them if 0.0
+	#[serde(default)]
+	pub self_trade_prevention: SelfTradePrevention,	// How the CDA and FBA stop a player's orders trading together
+	#[serde(default)]
+	pub allocation_rule: AllocationRule,	// How the FBA shares out the volume at its marginal price level
+	#[serde(default)]
+	pub price_rule: PriceRule,	// How the FBA picks its clearing price
+	#[serde(default)]
+	pub opening_auction_blocks: u64,	// Blocks at the start collected into an opening call auction, none if 0
+	#[serde(default)]
+	pub closing_auction_blocks: u64,	// Blocks at the end collected into a closing call auction, none if 0
+	#[serde(default)]
+	pub liquidation_price: LiquidationPrice,	// The price inventory is liquidated at after the last block
+	#[serde(default)]
+	pub seed: Option<u64>,		// Master seed for the RNG, a random seed is generated if omitted
+}
+
+/// The Constants fields that hold integers
+pub const INTEGER_PARAMS: [&str; 10] = ["batch_interval", "num_investors", "num_makers", "block_size", 
+									   "num_blocks", "maker_prop_delay", "maker_cold_start", "order_expiry_blocks",
+									   "opening_auction_blocks", "closing_auction_blocks"];
+
+/// The price every player's inventory is liquidated at once the simulation ends
+/// FundamentalValue: the midpoint of the BidsCenter and AsksCenter means
+/// ClosingAuction: the closing call auction's price, the fundamental value if it didn't cross
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LiquidationPrice {
+	#[default]
+	FundamentalValue,
+	ClosingAuction,
 }
 
 impl Constants {
@@ -44,12 +84,28 @@ impl Constants {
 			maker_inv_tax: mit,
 			maker_cold_start: mcs,
 			maker_update_prob: mup,
+			tick_size: 0.0,
+			lot_size: 0.0,
+			order_expiry_blocks: 0,
+			market_order_prob: 0.0,
+			maker_display_size: 0.0,
+			self_trade_prevention: SelfTradePrevention::None,
+			allocation_rule: AllocationRule::TimePriority,
+			price_rule: PriceRule::MergedBook,
+			opening_auction_blocks: 0,
+			closing_auction_blocks: 0,
+			liquidation_price: LiquidationPrice::FundamentalValue,
+			seed: None,
 		}
 	}
 
 	pub fn log(&self) -> String {
-		let h = format!("\nbatch_interval,num_investors,num_makers,block_size,num_blocks,market_type,front_run_perc,flow_order_offset,maker_prop_delay,maker_base_spread,maker_enter_prob,max_held_inventory,maker_inv_tax,maker_cold_start,maker_update_prob,");
-		let d = format!("{},{},{},{},{},{:?},{},{},{},{},{},{},{},{},{},",
+		let h = format!("\nbatch_interval,num_investors,num_makers,block_size,num_blocks,market_type,front_run_perc,flow_order_offset,maker_prop_delay,maker_base_spread,maker_enter_prob,max_held_inventory,maker_inv_tax,maker_cold_start,maker_update_prob,tick_size,lot_size,order_expiry_blocks,market_order_prob,maker_display_size,self_trade_prevention,allocation_rule,price_rule,opening_auction_blocks,closing_auction_blocks,liquidation_price,seed,");
+		let seed = match self.seed {
+			Some(seed) => seed.to_string(),
+			None => String::new(),
+		};
+		let d = format!("{},{},{},{},{},{:?},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{:?},{:?},{:?},{},{},{:?},{},",
 			self.batch_interval,
 			self.num_investors,
 			self.num_makers,
@@ -64,42 +120,105 @@ impl Constants {
 			self.max_held_inventory,
 			self.maker_inv_tax,
 			self.maker_cold_start,
-			self.maker_update_prob);
+			self.maker_update_prob,
+			self.tick_size,
+			self.lot_size,
+			self.order_expiry_blocks,
+			self.market_order_prob,
+			self.maker_display_size,
+			self.self_trade_prevention,
+			self.allocation_rule,
+			self.price_rule,
+			self.opening_auction_blocks,
+			self.closing_auction_blocks,
+			self.liquidation_price,
+			seed);
 		format!("{}\n{}", h, d)
 	}
 
-	// Given a consts, make 2 more with differing market type
-	// returned as (CDA, FBA, KLF)
-	pub fn copy3(&self) -> (String, String, String) {
-		match self.market_type {
-			MarketType::CDA => {
-				// Make an FBA and KLF csv
-				let mut fba = self.clone();
-				fba.market_type = MarketType::FBA;
-				let mut klf = self.clone();
-				klf.market_type = MarketType::KLF;
-				return (self.log(), fba.log(), klf.log());
-
-			},
-			MarketType::FBA => {
-				// Make a CDA and KLF csv
-				let mut cda = self.clone();
-				cda.market_type = MarketType::CDA;
-				let mut klf = self.clone();
-				klf.market_type = MarketType::KLF;
-				return (cda.log(), self.log(), klf.log());
-			},
-			MarketType::KLF => {
-				// Make an FBA and CDA csv
-				let mut fba = self.clone();
-				fba.market_type = MarketType::FBA;
-				let mut cda = self.clone();
-				cda.market_type = MarketType::CDA;
-				return (cda.log(), fba.log(), self.log());
-			},
+	/// Sets a field by name, used to sweep over the constants. Integer fields are
+	/// rounded to the nearest integer. The market_type, self_trade_prevention, allocation_rule,
+	/// price_rule, liquidation_price and seed can't be set this way.
+	pub fn set_param(&mut self, name: &str, value: f64) -> Result<(), &'static str> {
+		if !value.is_finite() {
+			return Err("Parameter value must be finite");
 		}
+		let int = value.round();
+		if int < 0.0 && INTEGER_PARAMS.contains(&name) {
+			return Err("Integer parameter can't be negative");
+		}
+		match name {
+			"batch_interval" => self.batch_interval = int as u64,
+			"num_investors" => self.num_investors = int as u64,
+			"num_makers" => self.num_makers = int as u64,
+			"block_size" => self.block_size = int as usize,
+			"num_blocks" => self.num_blocks = int as u64,
+			"front_run_perc" => self.front_run_perc = value,
+			"flow_order_offset" => self.flow_order_offset = value,
+			"maker_prop_delay" => self.maker_prop_delay = int as u64,
+			"maker_base_spread" => self.maker_base_spread = value,
+			"maker_enter_prob" => self.maker_enter_prob = value,
+			"max_held_inventory" => self.max_held_inventory = value,
+			"maker_inv_tax" => self.maker_inv_tax = value,
+			"maker_cold_start" => self.maker_cold_start = int as u64,
+			"maker_update_prob" => self.maker_update_prob = value,
+			"tick_size" => self.tick_size = value,
+			"lot_size" => self.lot_size = value,
+			"order_expiry_blocks" => self.order_expiry_blocks = int as u64,
+			"market_order_prob" => self.market_order_prob = value,
+			"maker_display_size" => self.maker_display_size = value,
+			"opening_auction_blocks" => self.opening_auction_blocks = int as u64,
+			"closing_auction_blocks" => self.closing_auction_blocks = int as u64,
+			_ => return Err("Unknown Constants parameter"),
+		}
+		Ok(())
 	}
 
+	/// Checks every field is in range so bad configs are caught before the simulation
+	/// starts. Returns the name of each bad field with the reason.
+	pub fn validate(&self) -> Vec<(&'static str, String)> {
+		let mut errors = Vec::new();
+		let positive = [("batch_interval", self.batch_interval), ("block_size", self.block_size as u64),
+						("num_blocks", self.num_blocks)];
+		for (name, value) in positive.iter() {
+			if *value == 0 {
+				errors.push((*name, String::from("must be at least 1")));
+			}
+		}
+		// num_investors - 1 investors are created and at least one is needed to send orders
+		if self.num_investors < 2 {
+			errors.push(("num_investors", format!("{} must be at least 2", self.num_investors)));
+		}
+
+		let probs = [("front_run_perc", self.front_run_perc), ("maker_enter_prob", self.maker_enter_prob),
+					 ("maker_update_prob", self.maker_update_prob), ("market_order_prob", self.market_order_prob)];
+		for (name, value) in probs.iter() {
+			if !(0.0..=1.0).contains(value) {
+				errors.push((*name, format!("{} must be a probability between 0 and 1", value)));
+			}
+		}
+
+		let non_negative = [("flow_order_offset", self.flow_order_offset), ("maker_base_spread", self.maker_base_spread),
+							("maker_inv_tax", self.maker_inv_tax), ("tick_size", self.tick_size), ("lot_size", self.lot_size),
+							("maker_display_size", self.maker_display_size)];
+		for (name, value) in non_negative.iter() {
+			if !(value.is_finite() && *value >= 0.0) {
+				errors.push((*name, format!("{} must not be negative", value)));
+			}
+		}
+		if !(self.max_held_inventory.is_finite() && self.max_held_inventory > 0.0) {
+			errors.push(("max_held_inventory", format!("{} must be greater than 0", self.max_held_inventory)));
+		}
+		// The call auctions must leave at least one block of the main session
+		if self.opening_auction_blocks + self.closing_auction_blocks > self.num_blocks {
+			errors.push(("closing_auction_blocks", format!("{} opening and {} closing auction blocks leave no main session in {} blocks",
+							self.opening_auction_blocks, self.closing_auction_blocks, self.num_blocks)));
+		}
+		if self.liquidation_price == LiquidationPrice::ClosingAuction && self.closing_auction_blocks == 0 {
+			errors.push(("liquidation_price", String::from("ClosingAuction needs at least 1 closing_auction_blocks")));
+		}
+		errors
+	}
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
@@ -110,7 +229,7 @@ pub enum DistType {
 	Exponential,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
 pub enum DistReason {
 	AsksCenter,
 	BidsCenter,
@@ -130,6 +249,10 @@ pub enum DistReason {
 
 const NUM_DISTS: usize = DistReason::InvestorInventory as usize + 1;
 
+/// The distributions sampled by the simulation, which every config must supply
+pub const REQUIRED_DISTS: [DistReason; 6] = [DistReason::AsksCenter, DistReason::BidsCenter, DistReason::InvestorVolume,
+											 DistReason::InvestorGas, DistReason::InvestorEnter, DistReason::MinerFrameForm];
+
 // Each distribution is in the form (µ: f64, std_dev: f64, scalar: f64, DistType)
 #[derive(Debug, Deserialize, Clone)]
 pub struct Distributions {
@@ -199,8 +322,36 @@ impl Distributions {
 		(v1, v2)
 	}
 
+	/// Sets v1, v2 or scalar of the distribution for which_dist, used to sweep over the distributions
+	pub fn set_param(&mut self, which_dist: DistReason, field: &str, value: f64) -> Result<(), &'static str> {
+		if !value.is_finite() {
+			return Err("Parameter value must be finite");
+		}
+		let dist_entry = self.dists.get_mut(which_dist as usize).ok_or("Unknown DistReason")?;
+		match field {
+			"v1" => dist_entry.0 = value,
+			"v2" => dist_entry.1 = value,
+			"scalar" => dist_entry.2 = value,
+			_ => return Err("Distribution parameter must be v1, v2 or scalar"),
+		}
+		Ok(())
+	}
+
+	/// Checks the parameters can be sampled from, see Distributions::sample
+	pub fn validate_dist(v1: f64, v2: f64, scalar: f64, dtype: DistType) -> Result<(), String> {
+		if !(v1.is_finite() && v2.is_finite() && scalar.is_finite()) {
+			return Err(String::from("v1, v2 and scalar must be finite numbers"));
+		}
+		match dtype {
+			DistType::Uniform if v1 >= v2 => Err(format!("Uniform low v1={} must be less than high v2={}", v1, v2)),
+			DistType::Normal if v2 < 0.0 => Err(format!("Normal std dev v2={} must not be negative", v2)),
+			DistType::Poisson|DistType::Exponential if v1 <= 0.0 => Err(format!("{:?} lambda v1={} must be greater than 0", dtype, v1)),
+			_ => Ok(()),
+		}
+	}
+
 	pub fn fifty_fifty() -> bool {
-		let val = rand::distributions::Uniform::new(0.0, 1.0).sample(&mut thread_rng());
+		let val = with_rng(|rng| rand::distributions::Uniform::new(0.0, 1.0).sample(rng));
 		if val > 0.50 {
 			return true;
 		} else {
@@ -213,7 +364,7 @@ impl Distributions {
 		assert!(prob <= 1.0);
 		assert!(prob >= 0.0);
 
-		let val = rand::distributions::Uniform::new(0.0, 1.0).sample(&mut thread_rng());
+		let val = with_rng(|rng| rand::distributions::Uniform::new(0.0, 1.0).sample(rng));
 		if val <= prob {
 			return true;
 		} else {
@@ -226,19 +377,20 @@ impl Distributions {
 	// Poisson: v1 = lambda, v2 = lambda
 	// Exp:		v1 = lambda, v2 = lambda
 	pub fn sample(v1: f64, v2: f64, scalar: f64, dtype: DistType) -> f64 {
-		match dtype {
-			DistType::Uniform => 	 scalar * rand::distributions::Uniform::new(v1, v2).sample(&mut thread_rng()),
-			DistType::Normal =>  	 scalar * rand::distributions::Normal::new(v1, v2).sample(&mut thread_rng()),
-			DistType::Poisson => 	 scalar * rand::distributions::Poisson::new(v1).sample(&mut thread_rng()) as f64,
-			DistType::Exponential => scalar * rand::distributions::Exp::new(v1).sample(&mut thread_rng()),
-		}
+		with_rng(|rng| match dtype {
+			DistType::Uniform => 	 scalar * rand::distributions::Uniform::new(v1, v2).sample(rng),
+			DistType::Normal =>  	 scalar * rand::distributions::Normal::new(v1, v2).sample(rng),
+			DistType::Poisson => 	 scalar * rand::distributions::Poisson::new(v1).sample(rng) as f64,
+			DistType::Exponential => scalar * rand::distributions::Exp::new(v1).sample(rng),
+		})
 	}
 }
 
 
 #[cfg(test)]
 mod tests {
-	use crate::simulation::simulation_config::{DistReason, DistType, Distributions};
+	use crate::simulation::simulation_config::{DistReason, DistType, Distributions, Constants};
+	use crate::exchange::MarketType;
 
 	#[test]
 	fn test_index_by_enum() {
@@ -327,19 +479,21 @@ mod tests {
 		assert_eq!(d_conf.3, DistType::Uniform);
 
 	}
-}
-
-
-
-
-
-
-
-
-
-
-
-
-
-
 
+	#[test]
+	fn test_set_param() {
+		let mut c = Constants::new(300, 250, 50, 100, 20, MarketType::KLF, 1.0, 0.25, 1, 0.25, 0.25, 5.0, 0.01, 10, 0.5);
+		c.set_param("block_size", 49.6).unwrap();
+		assert_eq!(c.block_size, 50);
+		c.set_param("maker_inv_tax", 0.05).unwrap();
+		assert_eq!(c.maker_inv_tax, 0.05);
+		assert!(c.set_param("market_type", 1.0).is_err());
+		assert!(c.set_param("num_blocks", -1.0).is_err());
+
+		let mut d = Distributions::new(vec!((DistReason::AsksCenter, 110.0, 20.0, 1.0, DistType::Normal)));
+		d.set_param(DistReason::AsksCenter, "v2", 5.0).unwrap();
+		d.set_param(DistReason::AsksCenter, "scalar", 2.0).unwrap();
+		assert_eq!(d.dists[DistReason::AsksCenter as usize], (110.0, 5.0, 2.0, DistType::Normal));
+		assert!(d.set_param(DistReason::AsksCenter, "mean", 1.0).is_err());
+	}
+}
@@ -1,8 +1,9 @@
-use crate::exchange::exchange_logic::{TradeResults, PlayerUpdate};
-use crate::exchange::MarketType;
-use crate::order::order::{Order, TradeType};
+use crate::exchange::exchange_logic::{TradeResults, PlayerUpdate, IndicativePrice};
+use crate::order::order::{Order, TradeType, ExchangeType};
+use crate::order::order_book::DepthLevel;
+use crate::error::SimError;
 use crate::utility::get_time;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::sync::Mutex;
 use std::time::Duration;
 
@@ -72,6 +73,34 @@ impl ShallowBook {
 	}
 }
 
+/// The aggregated price levels of both books at the end of a block, best price first
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepthSnapshot {
+	pub block_num: u64,
+	pub bids: Vec<DepthLevel>,
+	pub asks: Vec<DepthLevel>,
+}
+
+impl DepthSnapshot {
+	/// A csv row per level: block,side,level,price,quantity,orders with level 1 the best price
+	pub fn to_csv(&self) -> Vec<String> {
+		let sides = [(TradeType::Bid, &self.bids), (TradeType::Ask, &self.asks)];
+		sides.iter().flat_map(|(side, levels)| {
+			levels.iter().enumerate().map(move |(i, l)| {
+				format!("{},{:?},{},{},{},{},", self.block_num, side, i + 1, l.price, l.quantity, l.num_orders)
+			})
+		}).collect()
+	}
+}
+
+/// A stop order triggered into the books by a trade at trigger_price while block_num was published
+#[derive(Debug, Clone)]
+pub struct StopTrigger {
+	pub order: Order,
+	pub trigger_price: f64,
+	pub block_num: u64,
+}
+
 // Likelihood
 // A struct to hold statistical data from the history. Used to infer a true value for a price
 #[derive(Debug)]
@@ -112,30 +141,64 @@ pub struct PriorData {
 
 
 /// A struct to track the state of the simulation for logging and player strategies. 
-/// mempool_data: a hashmap containing every order sent to the mempool, indexed by order id
+/// mempool_data: a btreemap containing every order sent to the mempool, indexed by order id
 /// order_books: a vector of shallowbooks which contain the minimum information to recreate state.
 /// 			 Each index in the vector will correspond to mutation of state
 /// clearings: A vector of TradeResults 
+/// errors: every order or update that was skipped because of an inconsistency, with the time it occurred
+/// depth: the aggregated price levels of both books at the end of every block
+/// expired: every order purged after its last block, with the block it was purged in
+/// triggered: every stop order triggered into the books, in the order they were triggered
 pub struct History {
-	pub mempool_data: Mutex<HashMap<u64, (Order, Duration)>>,
+	pub mempool_data: Mutex<BTreeMap<u64, (Order, Duration)>>,
 	pub order_books: Mutex<Vec<ShallowBook>>,
 	pub clearings: Mutex<Vec<(TradeResults, Duration)>>,
-	pub market_type: MarketType,
+	pub ex_type: ExchangeType,			// The type of order the auction mechanism accepts
 	pub transactions: Mutex<Vec<PlayerUpdate>>,
+	pub errors: Mutex<Vec<(SimError, Duration)>>,
+	pub depth: Mutex<Vec<DepthSnapshot>>,
+	pub expired: Mutex<Vec<(Order, u64)>>,
+	pub triggered: Mutex<Vec<StopTrigger>>,
+	pub indicative: Mutex<Vec<(IndicativePrice, u64)>>,	// The indicative price published after each call auction block
+	pub uncrossings: Mutex<Vec<(Option<f64>, u64)>>,	// The price each call auction uncrossed at and its block
 }
 
 
 impl History {
-	pub fn new(m: MarketType) -> History {
+	pub fn new(ex_type: ExchangeType) -> History {
 		History {
-			mempool_data: Mutex::new(HashMap::new()),
+			mempool_data: Mutex::new(BTreeMap::new()),
 			order_books: Mutex::new(Vec::new()),
 			clearings: Mutex::new(Vec::new()),
-			market_type: m,
+			ex_type,
 			transactions: Mutex::new(Vec::new()),
+			errors: Mutex::new(Vec::new()),
+			depth: Mutex::new(Vec::new()),
+			expired: Mutex::new(Vec::new()),
+			triggered: Mutex::new(Vec::new()),
+			indicative: Mutex::new(Vec::new()),
+			uncrossings: Mutex::new(Vec::new()),
 		}
 	}
 
+	// Records an inconsistency that the simulation skipped over
+	pub fn record_error(&self, error: SimError) {
+		println!("Skipped: {}", error);
+		self.errors.lock().expect("History errors lock").push((error, get_time()));
+	}
+
+	// Records every error returned from publishing or settling a block
+	pub fn record_errors(&self, errors: Vec<SimError>) {
+		for error in errors {
+			self.record_error(error);
+		}
+	}
+
+	// Saves the depth of the books at the end of a block
+	pub fn save_depth(&self, snapshot: DepthSnapshot) {
+		self.depth.lock().expect("History depth lock").push(snapshot);
+	}
+
 	// Adds an order indexed by its order id to a history of all orders to mempool 
 	pub fn mempool_order(&self, order: Order) {
 		let mut pool = self.mempool_data.lock().expect("History mempool lock");
@@ -145,10 +208,11 @@ impl History {
 	// Parses through the orders and creates a shallow clone of the book
 	pub fn clone_book_state(&self, new_book: Vec<Order>, book_type: TradeType, block_num: u64) {
 		// Calculate average bid/ask prices from this book
-		let (avg_bids, avg_asks, num_bids, num_asks, wtd_avg_price) = History::average_order_prices(&new_book, self.market_type);
+		let (avg_bids, avg_asks, num_bids, num_asks, wtd_avg_price) = History::average_order_prices(&new_book, self.ex_type.clone());
 
+		// Other players only see the displayed part of an iceberg
 		let best_order = match new_book.last() {
-			Some(order) => Some(order.clone()),
+			Some(order) => Some(order.displayed()),
 			None => None,
 		};	
 
@@ -178,6 +242,47 @@ impl History {
 		clearings.push((results, get_time()));
 	}
 
+	// Saves the orders purged at the end of a block with their cancel updates. They aren't
+	// a clearing so the last clearing price is left alone.
+	pub fn save_expired(&self, orders: Vec<Order>, updates: &[PlayerUpdate], block_num: u64) {
+		let mut txs = self.transactions.lock().expect("save_expired");
+		txs.extend(updates.iter().cloned());
+		let mut expired = self.expired.lock().expect("save_expired");
+		expired.extend(orders.into_iter().map(|order| (order, block_num)));
+	}
+
+	// Saves each stop order triggered while publishing block_num with the price that triggered it
+	pub fn save_triggered(&self, triggered: Vec<(Order, f64)>, block_num: u64) {
+		let mut events = self.triggered.lock().expect("save_triggered");
+		events.extend(triggered.into_iter().map(|(order, trigger_price)| StopTrigger { order, trigger_price, block_num }));
+	}
+
+	// Publishes the indicative price of the call auction after block_num
+	pub fn save_indicative(&self, indicative: IndicativePrice, block_num: u64) {
+		self.indicative.lock().expect("save_indicative").push((indicative, block_num));
+	}
+
+	// The indicative price published after the block before block_num, None if that block
+	// wasn't collecting orders for a call auction
+	pub fn last_indicative(&self, block_num: u64) -> Option<IndicativePrice> {
+		let indicative = self.indicative.lock().expect("last_indicative");
+		match indicative.last() {
+			Some((price, block)) if block + 1 == block_num => Some(*price),
+			_ => None,
+		}
+	}
+
+	// Saves the price a call auction uncrossed at in block_num, None if its books didn't cross
+	pub fn save_uncrossing(&self, price: Option<f64>, block_num: u64) {
+		self.uncrossings.lock().expect("save_uncrossing").push((price, block_num));
+	}
+
+	// The price the call auction uncrossed at in block_num, if it crossed
+	pub fn uncrossing_price(&self, block_num: u64) -> Option<f64> {
+		let uncrossings = self.uncrossings.lock().expect("uncrossing_price");
+		uncrossings.iter().find(|(_, block)| *block == block_num).and_then(|(price, _)| *price)
+	}
+
 	// Searches the hashmap of mempool orders
 	// Returns a copy of the order and the timestamp it was sent
 	pub fn find_orig_order(&self, order_id: u64) -> Option<(Order, Duration)> {
@@ -191,13 +296,13 @@ impl History {
 	}
 
 
-	pub fn average_order_prices(orders: &Vec<Order>, market_type: MarketType) -> (Option<f64>, Option<f64>, usize, usize, Option<f64>) {
+	pub fn average_order_prices(orders: &Vec<Order>, ex_type: ExchangeType) -> (Option<f64>, Option<f64>, usize, usize, Option<f64>) {
 		let (mut asks_sum, mut bids_sum) = (0.0, 0.0);
 		let (mut num_asks, mut num_bids) = (0.0, 0.0);
-		match market_type {
-			MarketType::CDA|MarketType::FBA => {
-				// For each order in the mempool sum 
-				for order in orders {
+		match ex_type {
+			ExchangeType::LimitOrder | ExchangeType::MarketOrder => {
+				// For each order in the mempool sum, market orders are unpriced
+				for order in orders.iter().filter(|o| !o.is_market()) {
 					match order.trade_type {
 						TradeType::Bid => {
 							num_bids += 1.0;
@@ -210,7 +315,7 @@ impl History {
 					}
 				}
 			},
-			MarketType::KLF => {
+			ExchangeType::FlowOrder => {
 				for order in orders {
 					match order.trade_type {
 						TradeType::Bid => {
@@ -252,10 +357,10 @@ impl History {
 		let (mut asks_sum, mut bids_sum) = (0.0, 0.0);
 		let (mut num_asks, mut num_bids) = (0.0, 0.0);
 		let all_orders = self.mempool_data.lock().expect("average_prices");
-		match self.market_type {
-			MarketType::CDA|MarketType::FBA => {
-				// For each order in the mempool sum 
-				for (_key, (order, _timestamp)) in all_orders.iter() {
+		match self.ex_type {
+			ExchangeType::LimitOrder | ExchangeType::MarketOrder => {
+				// For each order in the mempool sum, market orders are unpriced
+				for (_key, (order, _timestamp)) in all_orders.iter().filter(|(_key, (o, _))| !o.is_market()) {
 					match order.trade_type {
 						TradeType::Bid => {
 							num_bids += 1.0;
@@ -268,7 +373,7 @@ impl History {
 					}
 				}
 			},
-			MarketType::KLF => {
+			ExchangeType::FlowOrder => {
 				for (_key, (order, _timestamp))in all_orders.iter() {
 					match order.trade_type {
 						TradeType::Bid => {
@@ -413,18 +518,19 @@ impl History {
 			}
 		}
 		let (mut bids_vol, mut asks_vol) = (0.0, 0.0);
-		// Drop lock on the order_books, get the original orders from the entries
+		// Drop lock on the order_books, get the original orders from the entries without
+		// the hidden reserves of icebergs
 		for entry in bids_entries {
 			bids_vol += entry.quantity;
 			if let Some((order, _time)) = self.find_orig_order(entry.order_id) {
-				bids_out.push(order);
+				bids_out.push(order.displayed());
 			}
 		}
 
 		for entry in asks_entries {
 			asks_vol += entry.quantity;
 			if let Some((order, _time)) = self.find_orig_order(entry.order_id) {
-				asks_out.push(order);
+				asks_out.push(order.displayed());
 			}
 		}
 		return (bids_out, asks_out, bids_vol, asks_vol);
@@ -549,6 +655,7 @@ impl History {
 	}
 
 
+	// The makers' view of the market, in which icebergs only show their displayed quantity
 	pub fn decision_data(&self, current_pool: Vec<Order>) -> PriorData {
 		let clearing_price = self.get_last_clearing_price();
 		let (best_bid, best_ask) = self.get_best_orders();
@@ -559,6 +666,7 @@ impl History {
 
 		// Get the current average gas price in the mmepool 
 		let mean_pool_gas = History::get_mean_gas(&current_pool);
+		let current_pool = current_pool.iter().map(|o| o.displayed()).collect();
 
 		PriorData {
 			clearing_price, 
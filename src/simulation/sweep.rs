@@ -0,0 +1,393 @@
+// Sweeps the simulation over a grid or Latin hypercube of Constants and Distributions values
+use crate::simulation::simulation_config::{Constants, Distributions, DistReason, INTEGER_PARAMS};
+use crate::simulation::experiment::{Experiment, run_logged_trial, MARKET_TYPES};
+use crate::simulation::performance_report::PerformanceReport;
+
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rayon::prelude::*;
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+
+
+/// How the points of the sweep are chosen from the parameter values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SweepMode {
+	Grid,					// Every combination of the parameter values
+	LatinHypercube(usize),	// A Latin hypercube sample with this many points
+}
+
+impl SweepMode {
+	/// Parses "grid" or "lhs:<num_points>"
+	pub fn parse(mode: &str) -> Result<SweepMode, &'static str> {
+		let mode = mode.to_lowercase();
+		if mode == "grid" {
+			return Ok(SweepMode::Grid);
+		}
+		match mode.strip_prefix("lhs:").map(|n| n.parse::<usize>()) {
+			Some(Ok(n)) if n > 0 => Ok(SweepMode::LatinHypercube(n)),
+			_ => Err("Sweep mode must be grid or lhs:<num_points>"),
+		}
+	}
+}
+
+
+// A line of the sweep spec csv
+#[derive(Debug, Deserialize)]
+struct SpecLine {
+	parameter: String,
+	values: String,
+}
+
+
+/// A swept parameter: either a Constants field (e.g. block_size) or a field of a
+/// distribution keyed by its DistReason (e.g. AsksCenter.v1, InvestorGas.scalar).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepParam {
+	pub name: String,
+	pub values: Vec<f64>,			// The values swept over in a grid
+	pub range: Option<(f64, f64)>,	// Ranges are sampled continuously by a Latin hypercube
+	pub integer: bool,				// Integer Constants fields are rounded
+}
+
+impl SweepParam {
+	/// Parses the values as a ';' separated list (e.g. 50;100;200) or
+	/// an inclusive low:high:steps range (e.g. 0.0:1.0:5)
+	pub fn parse(name: &str, values: &str) -> Result<SweepParam, &'static str> {
+		let name = name.trim();
+		let integer = INTEGER_PARAMS.contains(&name);
+		let parts: Vec<&str> = values.split(':').map(|v| v.trim()).collect();
+
+		let (values, range) = match parts.len() {
+			1 => {
+				let values = parts[0].split(';')
+					.map(|v| v.trim().parse::<f64>())
+					.collect::<Result<Vec<f64>, _>>()
+					.map_err(|_| "Sweep values must be numbers")?;
+				(values, None)
+			},
+			3 => {
+				let low: f64 = parts[0].parse().map_err(|_| "Sweep range low must be a number")?;
+				let high: f64 = parts[1].parse().map_err(|_| "Sweep range high must be a number")?;
+				let steps: usize = parts[2].parse().map_err(|_| "Sweep range steps must be a positive integer")?;
+				if steps == 0 {
+					return Err("Sweep range steps must be a positive integer");
+				}
+				let values = match steps {
+					1 => vec!(low),
+					_ => (0..steps).map(|i| low + (high - low) * i as f64 / (steps - 1) as f64).collect(),
+				};
+				(values, Some((low, high)))
+			},
+			_ => return Err("Sweep values must be a;b;c or low:high:steps"),
+		};
+
+		let mut param = SweepParam {
+			name: name.to_string(),
+			values,
+			range,
+			integer,
+		};
+		param.values = param.values.iter().map(|v| param.round(*v)).collect();
+		Ok(param)
+	}
+
+	// Rounds values of integer parameters so the results show the value that was used
+	fn round(&self, value: f64) -> f64 {
+		match self.integer {
+			true => value.round(),
+			false => value,
+		}
+	}
+
+	/// Sets this parameter to value in the configs
+	pub fn apply(&self, value: f64, dists: &mut Distributions, consts: &mut Constants) -> Result<(), &'static str> {
+		match self.name.find('.') {
+			Some(i) => {
+				let (reason, field) = (&self.name[..i], &self.name[i + 1..]);
+				let reason: Result<DistReason, serde::de::value::Error> = DistReason::deserialize(reason.into_deserializer());
+				let reason = reason.map_err(|_| "Unknown DistReason")?;
+				dists.set_param(reason, field, value)
+			},
+			None => consts.set_param(&self.name, value),
+		}
+	}
+}
+
+
+/// A parameter sweep. Every point runs the base experiment's trials across every market type.
+/// All points share the same trial seeds, so differences between points come from the
+/// swept parameters rather than from the random order flow.
+pub struct Sweep {
+	pub base: Experiment,
+	pub spec_path: PathBuf,
+	pub params: Vec<SweepParam>,
+	pub mode: SweepMode,
+}
+
+impl Sweep {
+	/// Loads the sweep spec and checks every parameter can be applied to the base configs
+	pub fn new(base: Experiment, spec_path: PathBuf, mode: SweepMode) -> Result<Sweep, Box<dyn Error>> {
+		let params = Sweep::parse_spec(&spec_path)?;
+		let sweep = Sweep {
+			base,
+			spec_path,
+			params,
+			mode,
+		};
+		sweep.validate()?;
+		Ok(sweep)
+	}
+
+	/// Reads a csv spec with a header of parameter,values and one swept parameter per line
+	pub fn parse_spec(path: &Path) -> Result<Vec<SweepParam>, Box<dyn Error>> {
+		let mut rdr = csv::Reader::from_path(path)?;
+		let mut params = Vec::new();
+		for result in rdr.deserialize() {
+			let line: SpecLine = result?;
+			params.push(SweepParam::parse(&line.parameter, &line.values)?);
+		}
+		Ok(params)
+	}
+
+	// Checks the spec isn't empty, has no duplicates and that every value applies
+	fn validate(&self) -> Result<(), &'static str> {
+		if self.params.is_empty() {
+			return Err("Sweep spec has no parameters");
+		}
+		for (i, param) in self.params.iter().enumerate() {
+			if param.values.is_empty() {
+				return Err("Sweep parameter has no values");
+			}
+			if self.params[..i].iter().any(|p| p.name == param.name) {
+				return Err("Sweep parameter is listed twice");
+			}
+			let (mut dists, mut consts) = (self.base.dists.clone(), self.base.consts);
+			for value in param.values.iter() {
+				param.apply(*value, &mut dists, &mut consts)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// The parameter values of every point in the sweep, in the order of self.params
+	pub fn points(&self) -> Vec<Vec<f64>> {
+		match self.mode {
+			SweepMode::Grid => {
+				// Cartesian product of all the parameter values
+				let mut points: Vec<Vec<f64>> = vec!(Vec::new());
+				for param in self.params.iter() {
+					points = points.iter()
+						.flat_map(|point| param.values.iter().map(move |v| {
+							let mut p = point.clone();
+							p.push(*v);
+							p
+						}))
+						.collect();
+				}
+				points
+			},
+			SweepMode::LatinHypercube(n) => {
+				// Seeded apart from the trial seeds, which are also derived from the master seed
+				let mut rng = StdRng::seed_from_u64(!self.base.seed);
+				let mut points = vec!(Vec::new(); n);
+				for param in self.params.iter() {
+					// Each of the n strata of [0, 1) is sampled exactly once
+					let mut strata: Vec<usize> = (0..n).collect();
+					strata.shuffle(&mut rng);
+					for (point, stratum) in points.iter_mut().zip(strata) {
+						let x = (stratum as f64 + rng.gen::<f64>()) / n as f64;
+						let value = match param.range {
+							Some((low, high)) => low + x * (high - low),
+							None => {
+								let i = (x * param.values.len() as f64) as usize;
+								param.values[i.min(param.values.len() - 1)]
+							},
+						};
+						point.push(param.round(value));
+					}
+				}
+				points
+			},
+		}
+	}
+
+	/// The base configs with the point's parameter values applied
+	pub fn configs(&self, point: &[f64]) -> Result<(Distributions, Constants), &'static str> {
+		let (mut dists, mut consts) = (self.base.dists.clone(), self.base.consts);
+		for (param, value) in self.params.iter().zip(point) {
+			param.apply(*value, &mut dists, &mut consts)?;
+		}
+		Ok((dists, consts))
+	}
+
+	/// Runs every point of the sweep in parallel and writes it to <results_dir>/<name>/: a
+	/// results.csv with a row per point, trial, market type and liquidation, a copy of the
+	/// spec and configs and the trial logs in log/. Returns the path of the sweep's directory.
+	pub fn run(&self, results_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+		let name = &self.base.name;
+		let exp_dir = results_dir.join(name);
+		let log_dir = exp_dir.join("log");
+		fs::create_dir_all(&log_dir)?;
+
+		// Copy the spec and configs so the sweep can be re-run from its directory
+		for path in &[&self.spec_path, &self.base.dists_path, &self.base.consts_path] {
+			let file_name = path.file_name().ok_or("Config path isn't a file")?;
+			fs::copy(path, exp_dir.join(file_name))?;
+		}
+
+		let points = self.points();
+		let mut configs = Vec::new();
+		for point in points.iter() {
+			configs.push(self.configs(point)?);
+		}
+
+		let mut jobs = Vec::new();
+		for point in 0..points.len() {
+			for (trial, seed) in self.base.trial_seeds().into_iter().enumerate() {
+				for market_type in MARKET_TYPES.iter() {
+					jobs.push((point, trial, seed, *market_type));
+				}
+			}
+		}
+
+		// Rows are collected in job order, regardless of which trial finishes first
+		let reports: io::Result<Vec<(usize, usize, Vec<PerformanceReport>)>> = jobs.par_iter()
+			.map(|(point, trial, seed, market_type)| {
+				let (dists, mut consts) = configs[*point].clone();
+				consts.market_type = *market_type;
+				let file_name = format!("{}_{}_{}_{:?}", point, trial, name, market_type);
+				let reports = run_logged_trial(&log_dir, &file_name, self.base.enable_log, dists, consts, *seed)?;
+				println!("Finished point {} trial {} {:?}", point, trial, market_type);
+				Ok((*point, *trial, reports))
+			})
+			.collect();
+
+		// Each row carries the point, trial and swept parameter values
+		let mut columns = vec!(String::from("point"), String::from("trial"));
+		columns.extend(self.params.iter().map(|p| p.name.clone()));
+		let mut rows = Vec::new();
+		for (point, trial, trial_reports) in reports? {
+			let mut values = vec!(point.to_string(), trial.to_string());
+			values.extend(points[point].iter().map(|v| v.to_string()));
+			for report in trial_reports {
+				rows.push((values.clone(), report));
+			}
+		}
+		PerformanceReport::write_csv_with_columns(&columns, &rows, fs::File::create(exp_dir.join("results.csv"))?)?;
+
+		Ok(exp_dir)
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::simulation::simulation_config::DistType;
+	use crate::exchange::MarketType;
+
+	fn setup_sweep(params: Vec<SweepParam>, mode: SweepMode) -> Sweep {
+		let base = Experiment {
+			name: String::from("test"),
+			num_trials: 1,
+			dists_path: PathBuf::new(),
+			consts_path: PathBuf::new(),
+			dists: Distributions::new(vec!((DistReason::AsksCenter, 110.0, 20.0, 1.0, DistType::Normal))),
+			consts: Constants::new(300, 250, 50, 100, 20, MarketType::KLF, 1.0, 0.25, 1, 0.25, 0.25, 5.0, 0.01, 10, 0.5),
+			enable_log: false,
+			seed: 42,
+		};
+		Sweep {
+			base,
+			spec_path: PathBuf::new(),
+			params,
+			mode,
+		}
+	}
+
+	#[test]
+	fn test_parse_values() {
+		let p = SweepParam::parse("front_run_perc", "0.0:1.0:5").unwrap();
+		assert_eq!(p.values, vec!(0.0, 0.25, 0.5, 0.75, 1.0));
+		assert_eq!(p.range, Some((0.0, 1.0)));
+
+		let p = SweepParam::parse("block_size", "50; 100;200.4").unwrap();
+		assert_eq!(p.values, vec!(50.0, 100.0, 200.0));
+		assert_eq!(p.range, None);
+
+		assert!(SweepParam::parse("block_size", "1:2").is_err());
+		assert!(SweepParam::parse("block_size", "1:2:0").is_err());
+		assert!(SweepParam::parse("block_size", "a;b").is_err());
+	}
+
+	#[test]
+	fn test_mode() {
+		assert_eq!(SweepMode::parse("grid"), Ok(SweepMode::Grid));
+		assert_eq!(SweepMode::parse("LHS:20"), Ok(SweepMode::LatinHypercube(20)));
+		assert!(SweepMode::parse("lhs:0").is_err());
+		assert!(SweepMode::parse("random").is_err());
+	}
+
+	#[test]
+	fn test_grid() {
+		let sweep = setup_sweep(vec!(
+			SweepParam::parse("block_size", "50;100").unwrap(),
+			SweepParam::parse("AsksCenter.v1", "100:120:3").unwrap(),
+		), SweepMode::Grid);
+		assert!(sweep.validate().is_ok());
+
+		let points = sweep.points();
+		assert_eq!(points.len(), 6);
+		assert_eq!(points[0], vec!(50.0, 100.0));
+		assert_eq!(points[5], vec!(100.0, 120.0));
+
+		let (dists, consts) = sweep.configs(&points[5]).unwrap();
+		assert_eq!(consts.block_size, 100);
+		assert_eq!(dists.read_dist_params(DistReason::AsksCenter), (120.0, 20.0));
+	}
+
+	#[test]
+	fn test_latin_hypercube() {
+		let n = 10;
+		let sweep = setup_sweep(vec!(
+			SweepParam::parse("maker_inv_tax", "0.0:1.0:2").unwrap(),
+			SweepParam::parse("num_makers", "10:20:2").unwrap(),
+		), SweepMode::LatinHypercube(n));
+
+		let points = sweep.points();
+		assert_eq!(points.len(), n);
+		assert_eq!(points, sweep.points());
+
+		// Every stratum of the continuous parameter is sampled exactly once
+		let mut strata: Vec<usize> = points.iter().map(|p| (p[0] * n as f64) as usize).collect();
+		strata.sort();
+		assert_eq!(strata, (0..n).collect::<Vec<usize>>());
+
+		// Integer parameters are rounded
+		for p in points.iter() {
+			assert_eq!(p[1], p[1].round());
+		}
+	}
+
+	#[test]
+	fn test_validate() {
+		let sweep = setup_sweep(vec!(SweepParam::parse("not_a_param", "1;2").unwrap()), SweepMode::Grid);
+		assert!(sweep.validate().is_err());
+
+		let sweep = setup_sweep(vec!(SweepParam::parse("NotAReason.v1", "1;2").unwrap()), SweepMode::Grid);
+		assert!(sweep.validate().is_err());
+
+		let sweep = setup_sweep(vec!(
+			SweepParam::parse("block_size", "1;2").unwrap(),
+			SweepParam::parse("block_size", "3").unwrap(),
+		), SweepMode::Grid);
+		assert!(sweep.validate().is_err());
+	}
+}
@@ -1,9 +1,14 @@
-use crate::exchange::MarketType;
+use crate::exchange::mechanism::AuctionMechanism;
 use crate::players::TraderT;
-use std::time::{Duration, SystemTime};
-use rand::{Rng, thread_rng};
+use std::time::Duration;
+use std::cell::{Cell, RefCell};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use rand::distributions::Alphanumeric;
 use std::iter;
+use std::io::{self, BufWriter, Write};
+use std::fs::File;
+use std::path::Path;
 
 use log::{LevelFilter, Level};
 use log4rs::append::console::ConsoleAppender;
@@ -15,59 +20,304 @@ use log4rs::config::{Appender, Config, Root, Logger};
 #[macro_export]
 macro_rules! log_order_book {
     ($message:expr) => {
-        log!(target: "app::order_books", Level::Warn, "{}", $message);
+        $crate::utility::write_log($crate::utility::LogTarget::OrderBooks, &$message)
     }   
 }
 
 #[macro_export]
 macro_rules! log_player_data {
     ($message:expr) => {
-        log!(target: "app::player_data", Level::Warn, "{}", $message);
+        $crate::utility::write_log($crate::utility::LogTarget::PlayerData, &$message)
     }   
 }
 
 #[macro_export]
 macro_rules! log_mempool_data {
     ($message:expr) => {
-        log!(target: "app::mempool_data", Level::Warn, "{}", $message);
+        $crate::utility::write_log($crate::utility::LogTarget::MempoolData, &$message)
+    }   
+}
+
+#[macro_export]
+macro_rules! log_depth {
+    ($message:expr) => {
+        $crate::utility::write_log($crate::utility::LogTarget::Depth, &$message)
     }   
 }
 
 #[macro_export]
 macro_rules! log_results {
     ($message:expr) => {
-        log!(target: "app::results", Level::Warn, "{}", $message);
+        $crate::utility::write_log($crate::utility::LogTarget::Results, &$message)
     }   
 }
 
 
+/// The csv logs written by a simulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTarget {
+    OrderBooks,
+    PlayerData,
+    MempoolData,
+    Results,
+    Depth,
+}
+
+const NUM_LOG_TARGETS: usize = LogTarget::Depth as usize + 1;
+
+/// Per-trial log files, used instead of the global logger so trials running in
+/// parallel each write to their own files. A target without a file is discarded.
+pub struct TrialLog {
+    files: Vec<Option<BufWriter<File>>>,
+}
+
+impl TrialLog {
+    /// Creates the order book, player, mempool and depth logs for the trial named file_name
+    /// inside dir. Results aren't written to a file, they're returned by the trial.
+    /// If logging is disabled everything is discarded.
+    pub fn create(dir: &Path, file_name: &str, enable_log: bool) -> io::Result<TrialLog> {
+        let mut files = Vec::new();
+        for target in &[LogTarget::OrderBooks, LogTarget::PlayerData, LogTarget::MempoolData, LogTarget::Results, LogTarget::Depth] {
+            let name = match target {
+                LogTarget::OrderBooks => format!("order_books_{}.csv", file_name),
+                LogTarget::PlayerData => format!("player_data_{}.csv", file_name),
+                LogTarget::MempoolData => format!("mempool_data_{}.csv", file_name),
+                LogTarget::Depth => format!("depth_{}.csv", file_name),
+                LogTarget::Results => {
+                    files.push(None);
+                    continue;
+                },
+            };
+            match enable_log {
+                true => files.push(Some(BufWriter::new(File::create(dir.join(name))?))),
+                false => files.push(None),
+            }
+        }
+        Ok(TrialLog {
+            files,
+        })
+    }
+
+    /// A log that discards everything
+    pub fn discard() -> TrialLog {
+        TrialLog {
+            files: (0..NUM_LOG_TARGETS).map(|_| None).collect(),
+        }
+    }
+}
+
+/// Restores the log that was active before set_trial_log was called once dropped
+pub struct TrialLogGuard {
+    prev: Option<TrialLog>,
+}
+
+impl Drop for TrialLogGuard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        TRIAL_LOG.with(|l| l.replace(prev));
+    }
+}
+
+/// Sends this thread's logs to the trial's files until the returned guard is dropped
+pub fn set_trial_log(log: TrialLog) -> TrialLogGuard {
+    let prev = TRIAL_LOG.with(|l| l.replace(Some(log)));
+    TrialLogGuard {
+        prev,
+    }
+}
+
+/// Writes a line to the log target. Goes to the trial log of this thread if one is
+/// set, otherwise to the global logger configured by setup_logging.
+pub fn write_log(target: LogTarget, message: &str) {
+    let logged = TRIAL_LOG.with(|l| {
+        match l.borrow_mut().as_mut() {
+            Some(trial_log) => {
+                if let Some(file) = trial_log.files[target as usize].as_mut() {
+                    writeln!(file, "{}", message).expect("Couldn't write to trial log");
+                }
+                true
+            },
+            None => false,
+        }
+    });
+
+    if !logged {
+        match target {
+            LogTarget::OrderBooks => log!(target: "app::order_books", Level::Warn, "{}", message),
+            LogTarget::PlayerData => log!(target: "app::player_data", Level::Warn, "{}", message),
+            LogTarget::MempoolData => log!(target: "app::mempool_data", Level::Warn, "{}", message),
+            LogTarget::Results => log!(target: "app::results", Level::Warn, "{}", message),
+            LogTarget::Depth => log!(target: "app::depth", Level::Warn, "{}", message),
+        }
+    }
+}
+
+
+thread_local! {
+    // The log files of the trial running on this thread, if any
+    static TRIAL_LOG: RefCell<Option<TrialLog>> = const { RefCell::new(None) };
+}
+
+thread_local! {
+    // The simulated time of the simulation running on this thread. Only the
+    // discrete-event Scheduler advances it, so timestamps never depend on machine load.
+    static SIM_TIME: Cell<Duration> = const { Cell::new(Duration::from_millis(0)) };
+}
+
+/// Returns the current simulated time, measured from the start of the simulation
 pub fn get_time() -> Duration {
-    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
-                         .expect("SystemTime::duration_since failed")
+    SIM_TIME.with(|t| t.get())
+}
+
+/// Sets the simulated time and returns the previous time
+pub fn set_time(time: Duration) -> Duration {
+    SIM_TIME.with(|t| t.replace(time))
+}
+
+thread_local! {
+    // The number of orders queued in a book on this thread, so orders in the bids and
+    // asks books can be compared by arrival
+    static QUEUE_SEQ: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The arrival number of the next order queued in a book, counting up from 1
+pub fn next_queue_seq() -> u64 {
+    QUEUE_SEQ.with(|s| {
+        s.set(s.get() + 1);
+        s.get()
+    })
+}
+
+
+/// The independent random number streams of a simulation. Each component draws from
+/// its own stream so changing how often one component draws doesn't shift the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RngStream {
+    Setup,      // Initializing the players
+    Investor,   // Investor arrivals and orders
+    Maker,      // Maker updates and orders
+    Miner,      // Frame forming and front-running
+    Ids,        // Order and trader ids
+    Exchange,   // Random allocation in the batch auctions
+}
+
+const NUM_STREAMS: usize = RngStream::Exchange as usize + 1;
+
+/// The seeded state of every stream plus the stream currently being drawn from
+pub struct SimRng {
+    pub seed: u64,
+    streams: Vec<StdRng>,
+    current: RngStream,
+}
+
+impl SimRng {
+    /// Derives every stream from the master seed
+    pub fn new(seed: u64) -> SimRng {
+        let streams = (0..NUM_STREAMS as u64)
+            .map(|i| StdRng::seed_from_u64(seed ^ (i + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15)))
+            .collect();
+        SimRng {
+            seed,
+            streams,
+            current: RngStream::Setup,
+        }
+    }
+}
+
+thread_local! {
+    // The RNG of the simulation running on this thread. Falls back to a random
+    // seed so code running outside of a seeded simulation (e.g. tests) still works.
+    static SIM_RNG: RefCell<SimRng> = RefCell::new(SimRng::new(gen_seed()));
+}
+
+/// Restores the RNG that was active before seed_rng was called once dropped
+pub struct RngGuard {
+    prev: Option<SimRng>,
+}
+
+impl Drop for RngGuard {
+    fn drop(&mut self) {
+        // Simulations can run nested on the same thread (e.g. rayon work stealing),
+        // so hand the outer simulation its RNG back.
+        if let Some(prev) = self.prev.take() {
+            SIM_RNG.with(|r| r.replace(prev));
+        }
+    }
+}
+
+/// Generates a fresh master seed from entropy
+pub fn gen_seed() -> u64 {
+    rand::random::<u64>()
+}
+
+impl RngGuard {
+    /// Restores the previous RNG now and returns the state of the RNG that was installed
+    pub fn take(mut self) -> SimRng {
+        let prev = self.prev.take().expect("RngGuard already taken");
+        SIM_RNG.with(|r| r.replace(prev))
+    }
+}
+
+/// Seeds this thread's RNG streams from the master seed. The previous RNG
+/// is restored when the returned guard is dropped.
+pub fn seed_rng(seed: u64) -> RngGuard {
+    install_rng(SimRng::new(seed))
+}
+
+/// Installs rng as this thread's RNG, e.g. to continue a simulation's streams where
+/// they left off. The previous RNG is restored when the returned guard is dropped.
+pub fn install_rng(rng: SimRng) -> RngGuard {
+    let prev = SIM_RNG.with(|r| r.replace(rng));
+    RngGuard {
+        prev: Some(prev),
+    }
+}
+
+/// Returns the master seed of this thread's RNG
+pub fn get_seed() -> u64 {
+    SIM_RNG.with(|r| r.borrow().seed)
+}
+
+/// Selects the stream that with_rng draws from and returns the previous stream
+pub fn set_rng_stream(stream: RngStream) -> RngStream {
+    SIM_RNG.with(|r| {
+        let mut rng = r.borrow_mut();
+        let prev = rng.current;
+        rng.current = stream;
+        prev
+    })
+}
+
+/// Runs f with the currently selected stream of this thread's RNG
+pub fn with_rng<T, F: FnOnce(&mut StdRng) -> T>(f: F) -> T {
+    SIM_RNG.with(|r| {
+        let mut rng = r.borrow_mut();
+        let current = rng.current as usize;
+        f(&mut rng.streams[current])
+    })
+}
+
+/// Runs f with a specific stream of this thread's RNG, regardless of the selected stream
+pub fn with_rng_stream<T, F: FnOnce(&mut StdRng) -> T>(stream: RngStream, f: F) -> T {
+    SIM_RNG.with(|r| f(&mut r.borrow_mut().streams[stream as usize]))
 }
 
 
 // Generate a random 64b order id
 pub fn gen_order_id() -> u64 {
-    let mut rng = thread_rng();
-    let p: u64 = rng.gen();
-    p
-
+    with_rng_stream(RngStream::Ids, |rng| rng.gen())
 }
 
 pub fn gen_rand_f64() -> f64 {
-     let mut rng = thread_rng();
-    let p: f64 = rng.gen();
-    p
+    with_rng(|rng| rng.gen())
 }
 
 /// Generate a trader id for a specific type of trader
 pub fn gen_trader_id(tt: TraderT) -> String {
-	let mut rng = thread_rng();
-	let id: String = iter::repeat(())
+	let id: String = with_rng_stream(RngStream::Ids, |rng| iter::repeat(())
         .map(|()| rng.sample(Alphanumeric))
         .take(10)
-        .collect();
+        .collect());
 
     match tt {
     	TraderT::Maker => format!("MKR{}", id),
@@ -79,14 +329,15 @@ pub fn gen_trader_id(tt: TraderT) -> String {
 
 /// Generate a trader id for a specific type of trader
 pub fn gen_rand_trader_id() -> String {
-    let mut rng = thread_rng();
-    let id: String = iter::repeat(())
-        .map(|()| rng.sample(Alphanumeric))
-        .take(10)
-        .collect();
-
+    let (id, tt): (String, u8) = with_rng_stream(RngStream::Ids, |rng| {
+        let id = iter::repeat(())
+            .map(|()| rng.sample(Alphanumeric))
+            .take(10)
+            .collect();
+        (id, rng.gen_range(0, 3))
+    });
 
-    match rng.gen_range(0, 3) {
+    match tt {
         0 => format!("MKR{}", id),
         1 => format!("INV{}", id),
         _ => format!("MIN{}", id),
@@ -101,18 +352,21 @@ pub fn setup_logging(file_name: &str, enable_log: bool) -> log4rs::Handle {
     let order_books_name;
     let player_data_name;
     let mempool_data_name;
+    let depth_name;
 
     match enable_log {
         true => {
             order_books_name = format!("log/order_books_{}.csv", file_name);
             player_data_name = format!("log/player_data_{}.csv", file_name);
             mempool_data_name = format!("log/mempool_data_{}.csv", file_name);
+            depth_name = format!("log/depth_{}.csv", file_name);
         },
         false => {
             // Write logs to /dev/null if logging is disabled
             order_books_name = format!("/dev/null");
             player_data_name = format!("/dev/null");
             mempool_data_name = format!("/dev/null");
+            depth_name = String::from("/dev/null");
         },
     }
     
@@ -130,6 +384,10 @@ pub fn setup_logging(file_name: &str, enable_log: bool) -> log4rs::Handle {
         .encoder(Box::new(PatternEncoder::new("{m}\n")))
         .build(mempool_data_name).expect("Couldn't set up appender");
 
+    let depth_file = FileAppender::builder()
+        .encoder(Box::new(PatternEncoder::new("{m}\n")))
+        .build(depth_name).expect("Couldn't set up appender");
+
     let results_file = FileAppender::builder()
         .encoder(Box::new(PatternEncoder::new("{m}\n")))
         .build(results_name).expect("Couldn't set up appender");
@@ -141,6 +399,7 @@ pub fn setup_logging(file_name: &str, enable_log: bool) -> log4rs::Handle {
         .appender(Appender::builder().build("order_books", Box::new(order_books_file)))
         .appender(Appender::builder().build("player_data", Box::new(player_data_file)))
         .appender(Appender::builder().build("mempool_data", Box::new(mempool_data_file)))
+        .appender(Appender::builder().build("depth", Box::new(depth_file)))
         .appender(Appender::builder().build("results", Box::new(results_file)))
         // the logger for the order book data. use log!(target: "app::order_books", Level::Warn, "message here");
         .logger(Logger::builder()       
@@ -157,6 +416,11 @@ pub fn setup_logging(file_name: &str, enable_log: bool) -> log4rs::Handle {
             .appender("mempool_data")
             .additive(false)
             .build("app::mempool_data", LevelFilter::Info))
+         // the logger for the L2 depth snapshots. use log!(target: "app::depth", Level::Warn, "message here");
+        .logger(Logger::builder()
+            .appender("depth")
+            .additive(false)
+            .build("app::depth", LevelFilter::Info))
         .logger(Logger::builder()
             .appender("results")
             .additive(false)
@@ -175,30 +439,78 @@ pub fn setup_logging(file_name: &str, enable_log: bool) -> log4rs::Handle {
 
 
 // Write the headers to the csv logs
-pub fn setup_log_headers(market_type: MarketType) {
+pub fn setup_log_headers(mechanism: &dyn AuctionMechanism) {
     // Setup the logfile headers
     log_player_data!(format!("time,reason,trader_id,player_type,balance,inventory,orders,"));
     log_mempool_data!(format!("time,trader_id,order_id,order_type,trade_type,ex_type,p_low,p_high,price,quantity,gas,"));
+    log_depth!(String::from("block,side,level,price,quantity,orders,"));
 
-    match market_type {
-        MarketType::CDA => {
-            log_order_book!("time,new_order_trader_id,new_order_order_id,new_order_order_type,new_order_trade_type,new_order_ex_type,new_order_p_low,new_order_p_high,new_order_price,new_order_quantity,new_order_gas,bids_after,asks_after");
-        },
-        _ => log_order_book!(format!("time,block_num,book_type,clearing_price,book_before,book_after,")),
-    }
+    log_order_book!(mechanism.order_book_header());
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn draw(stream: RngStream, n: usize) -> Vec<u64> {
+        (0..n).map(|_| with_rng_stream(stream, |rng| rng.gen())).collect()
+    }
 
+    #[test]
+    fn test_seed_repeats() {
+        let first = {
+            let _rng = seed_rng(42);
+            (draw(RngStream::Investor, 10), gen_order_id(), gen_trader_id(TraderT::Maker))
+        };
+        let second = {
+            let _rng = seed_rng(42);
+            (draw(RngStream::Investor, 10), gen_order_id(), gen_trader_id(TraderT::Maker))
+        };
+        assert_eq!(first, second);
+
+        let _rng = seed_rng(43);
+        assert_ne!(first.0, draw(RngStream::Investor, 10));
+    }
 
+    #[test]
+    fn test_streams_independent() {
+        // Extra draws from the maker stream shouldn't shift the investor stream
+        let expected = {
+            let _rng = seed_rng(7);
+            draw(RngStream::Investor, 5)
+        };
+        let _rng = seed_rng(7);
+        draw(RngStream::Maker, 100);
+        set_rng_stream(RngStream::Investor);
+        let drawn: Vec<u64> = (0..5).map(|_| with_rng(|rng| rng.gen())).collect();
+        assert_eq!(expected, drawn);
+    }
 
+    #[test]
+    fn test_rng_take() {
+        // Taking the state and installing it again continues the streams where they left off
+        let expected = {
+            let _rng = seed_rng(9);
+            draw(RngStream::Miner, 4)
+        };
+        let _outer = seed_rng(1);
+        let rng = seed_rng(9);
+        let mut drawn = draw(RngStream::Miner, 2);
+        let state = rng.take();
+        assert_eq!(get_seed(), 1);
+        let _rng = install_rng(state);
+        drawn.extend(draw(RngStream::Miner, 2));
+        assert_eq!(expected, drawn);
+    }
 
-
-
-
-
-
-
-
-
+    #[test]
+    fn test_rng_restored() {
+        let _outer = seed_rng(1);
+        {
+            let _inner = seed_rng(2);
+            assert_eq!(get_seed(), 2);
+        }
+        assert_eq!(get_seed(), 1);
+    }
+}
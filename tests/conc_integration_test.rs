@@ -30,7 +30,7 @@ fn test_add_order_to_book() {
 
 	assert_eq!(book.len(), 1);
 
-	let order = book.orders.lock().unwrap().pop().unwrap();
+	let order = book.pop_from_end().unwrap();
 
 }
 
@@ -119,7 +119,7 @@ fn test_ask_transaction() {
 							Arc::clone(&asks_book));
 
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}						
 
 	// There should be num_bids bids in the book, with max price num_bids and quantity 5.0
@@ -143,7 +143,7 @@ fn test_ask_transaction() {
 							Arc::clone(&asks_book));
 	
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	// Only one ask should cross and fill, other will remain
@@ -190,7 +190,7 @@ fn test_bid_transaction() {
 							Arc::clone(&asks_book));
 
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}						
 
 	// There should be num_asks asks in the book, with min price 50 -> 50 + num_asks and quantity 5.0
@@ -214,7 +214,7 @@ fn test_bid_transaction() {
 							Arc::clone(&asks_book));
 	
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	// Only one bid should cross and fill, other will remain
@@ -258,7 +258,7 @@ pub fn test_update_bid() {
 							Arc::clone(&asks_book));
 
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	assert_eq!(bids_book.len(), 100);
@@ -278,7 +278,7 @@ pub fn test_update_bid() {
 							Arc::clone(&bids_book),
 							Arc::clone(&asks_book));
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	// Books should be same length
@@ -289,7 +289,7 @@ pub fn test_update_bid() {
 
 	// Unwrap the index and check order has been updating
 	if let Some(i) = index {
-		let order = &bids_book.orders.lock().unwrap()[i];
+		let order = &bids_book.copy_orders()[i];
 		assert_eq!(order.trader_id, format!("jason"));
 		assert_eq!(order.price, 99.9);
 		assert_eq!(order.quantity, 555.5);
@@ -328,7 +328,7 @@ pub fn test_update_ask() {
 							Arc::clone(&asks_book));
 
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	assert_eq!(asks_book.len(), 100);
@@ -348,7 +348,7 @@ pub fn test_update_ask() {
 							Arc::clone(&bids_book),
 							Arc::clone(&asks_book));
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	// Books should be same length
@@ -359,7 +359,7 @@ pub fn test_update_ask() {
 
 	// Unwrap the index and check order has been updating
 	if let Some(i) = index {
-		let order = &asks_book.orders.lock().unwrap()[i];
+		let order = &asks_book.copy_orders()[i];
 		assert_eq!(order.trader_id, format!("jason"));
 		assert_eq!(order.price, 99.9);
 		assert_eq!(order.quantity, 555.5);
@@ -399,7 +399,7 @@ pub fn test_cancel_bid() {
 							Arc::clone(&asks_book));
 
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	assert_eq!(bids_book.len(), 100);
@@ -422,7 +422,7 @@ pub fn test_cancel_bid() {
 							Arc::clone(&bids_book),
 							Arc::clone(&asks_book));
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	// Book should shorter by 1
@@ -471,7 +471,7 @@ pub fn test_cancel_ask() {
 							Arc::clone(&asks_book));
 
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	assert_eq!(asks_book.len(), 100);
@@ -494,7 +494,7 @@ pub fn test_cancel_ask() {
 							Arc::clone(&bids_book),
 							Arc::clone(&asks_book));
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	// Books should be same length
@@ -543,7 +543,7 @@ pub fn test_update_ask_to_cross() {
 							Arc::clone(&asks_book));
 
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}						
 
 	// There should be num_bids bids in the book, with max price num_bids and quantity 5.0
@@ -573,7 +573,7 @@ pub fn test_update_ask_to_cross() {
 							Arc::clone(&asks_book));
 	
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	// No asks should have cross
@@ -594,7 +594,7 @@ pub fn test_update_ask_to_cross() {
 							Arc::clone(&bids_book),
 							Arc::clone(&asks_book));
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	// The filled ask had 10x quantity as the bids so should have filled 10 bids
@@ -640,7 +640,7 @@ pub fn test_update_bid_to_cross() {
 							Arc::clone(&asks_book));
 
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}						
 
 	// There should be num_asks bids in the book, with max price num_asks and quantity 5.0
@@ -670,7 +670,7 @@ pub fn test_update_bid_to_cross() {
 							Arc::clone(&asks_book));
 	
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	// No bids should have cross
@@ -691,7 +691,7 @@ pub fn test_update_bid_to_cross() {
 							Arc::clone(&bids_book),
 							Arc::clone(&asks_book));
 	for h in handles {
-		h.join().unwrap();
+		assert!(h.join().unwrap().is_empty());
 	}
 
 	// The filled bid had 10x quantity as the asks so should have filled 10 asks
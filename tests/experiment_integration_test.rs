@@ -0,0 +1,80 @@
+extern crate flow_rs;
+use flow_rs::simulation::experiment::{Experiment, MARKET_TYPES};
+use flow_rs::simulation::performance_report::PerformanceReport;
+use flow_rs::simulation::sweep::{Sweep, SweepMode};
+use std::fs;
+use std::path::PathBuf;
+
+
+fn setup_experiment(name: &str, num_trials: u64) -> Experiment {
+	let mut exp = Experiment::new(String::from(name), num_trials, 
+		PathBuf::from("configs/dists.csv"), PathBuf::from("configs/consts.csv"), false, Some(42))
+		.expect("Couldn't load configs");
+	// Keep the trials short
+	exp.consts.num_investors = 50;
+	exp.consts.num_makers = 10;
+	exp.consts.maker_cold_start = 2;
+	exp.consts.num_blocks = 5;
+	exp
+}
+
+#[test]
+fn test_experiment_layout() {
+	let results_dir = std::env::temp_dir().join("flow_rs_test_experiment_layout");
+	let exp = setup_experiment("layout", 2);
+	let exp_dir = exp.run(&results_dir).expect("Couldn't run experiment");
+
+	assert!(exp_dir.join("log").is_dir());
+	assert!(exp_dir.join("dists.csv").is_file());
+	assert!(exp_dir.join("consts.csv").is_file());
+
+	let reports = PerformanceReport::read_csv(fs::File::open(exp_dir.join("results.csv")).unwrap()).unwrap();
+	// A pre and post liquidation report for every market type of every trial
+	assert_eq!(reports.len(), 2 * 2 * MARKET_TYPES.len());
+
+	// Every market type in a trial shares the trial's seed
+	let seeds = exp.trial_seeds();
+	for (i, report) in reports.iter().enumerate() {
+		let trial = i / (2 * MARKET_TYPES.len());
+		assert_eq!(report.seed, seeds[trial]);
+		assert_eq!(report.market_type, format!("{:?}", MARKET_TYPES[(i / 2) % MARKET_TYPES.len()]));
+		assert_eq!(report.liquidated, i % 2 == 1);
+	}
+
+	fs::remove_dir_all(results_dir).unwrap();
+}
+
+#[test]
+fn test_experiment_repeatable() {
+	let results_dir = std::env::temp_dir().join("flow_rs_test_experiment_repeatable");
+	let exp = setup_experiment("repeat", 2);
+
+	let first = fs::read_to_string(exp.run(&results_dir).unwrap().join("results.csv")).unwrap();
+	let second = fs::read_to_string(exp.run(&results_dir).unwrap().join("results.csv")).unwrap();
+	assert_eq!(first, second);
+
+	fs::remove_dir_all(results_dir).unwrap();
+}
+
+#[test]
+fn test_sweep_grid() {
+	let results_dir = std::env::temp_dir().join("flow_rs_test_sweep_grid");
+	let sweep = Sweep::new(setup_experiment("grid", 1), PathBuf::from("configs/sweep.csv"), SweepMode::Grid)
+		.expect("Couldn't load sweep spec");
+	let num_points = sweep.points().len();
+	assert_eq!(num_points, 2 * 3 * 2);
+
+	let exp_dir = sweep.run(&results_dir).expect("Couldn't run sweep");
+	assert!(exp_dir.join("sweep.csv").is_file());
+
+	let results = fs::read_to_string(exp_dir.join("results.csv")).unwrap();
+	let lines: Vec<&str> = results.lines().collect();
+	assert!(lines[0].starts_with("point,trial,block_size,front_run_perc,AsksCenter.v1,market_type,liquidated,"));
+	assert_eq!(lines.len(), 1 + num_points * 2 * MARKET_TYPES.len());
+
+	// Each row carries the swept values of its point
+	assert!(lines[1].starts_with("0,0,50,0,105,KLF,false,"));
+	assert!(lines[lines.len() - 1].starts_with(&format!("{},0,100,1,110,CDA,true,", num_points - 1)));
+
+	fs::remove_dir_all(results_dir).unwrap();
+}
@@ -1,9 +1,12 @@
 extern crate more_asserts;
 use flow_rs::players::Player;
 use flow_rs::blockchain::order_processor::*;
-use flow_rs::exchange::exchange_logic::Auction;
-use flow_rs::exchange::MarketType;
+use flow_rs::exchange::exchange_logic::{Auction, TradeResults};
+use flow_rs::exchange::{MarketType, AllocationRule, PriceRule};
+use flow_rs::exchange::mechanism::FbaMechanism;
 use flow_rs::players::investor::Investor;
+use flow_rs::order::stop_book::StopBook;
+use flow_rs::order::order::{Order, OrderType, TradeType, ExchangeType};
 
 use std::sync::Arc;
 use more_asserts::{assert_le};
@@ -13,6 +16,7 @@ mod common;
 
 const EPSILON: f64 =  0.000_000_001;	
 const BLOCK_SIZE: usize = 99999;
+const ALLOCATION_RULES: [AllocationRule; 3] = [AllocationRule::TimePriority, AllocationRule::ProRata, AllocationRule::Random];
 
 
 #[test]
@@ -31,7 +35,7 @@ fn test_add_order_to_book() {
 
 	assert_eq!(book.len(), 1);
 
-	let order = book.orders.lock().unwrap().pop().unwrap();
+	let order = book.pop_from_end().unwrap();
 
 	assert_eq!(order.trader_id, String::from("bid_id"));
 
@@ -113,7 +117,7 @@ fn test_cda_cancel() {
 	let asks_book = Arc::new(common::setup_asks_book());
 
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::CDA;
+	let mechanism = MarketType::CDA.mechanism();
 	
 	// investor
 	let mut bid1 = common::setup_bid_limit_order();
@@ -158,7 +162,7 @@ fn test_cda_cancel() {
 	// Create frame from the orders in mempool
 	miner.make_frame(Arc::clone(&pool), BLOCK_SIZE);
 
-	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type);
+	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref());
 
 	// Only one ask should cross and fill, other will remain
 	assert_eq!(asks_book.len(), 0);
@@ -186,7 +190,7 @@ fn test_cda_cancel() {
 	// Create frame from the orders in mempool
 	miner.make_frame(Arc::clone(&pool), BLOCK_SIZE);
 
-	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type);
+	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref());
 
 	// Only one ask should cross and fill, other will remain
 	assert_eq!(asks_book.len(), 0);
@@ -201,7 +205,7 @@ fn test_klf_cancel() {
 	let asks_book = Arc::new(common::setup_asks_book());
 
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::KLF;
+	let mechanism = MarketType::KLF.mechanism();
 	
 	// investor
 	let mut bid1 = common::setup_bid_limit_order();
@@ -246,7 +250,7 @@ fn test_klf_cancel() {
 	// Create frame from the orders in mempool
 	miner.make_frame(Arc::clone(&pool), BLOCK_SIZE);
 
-	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type);
+	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref());
 
 	// Only one ask should cross and fill, other will remain
 	assert_eq!(asks_book.len(), 0);
@@ -274,7 +278,7 @@ fn test_klf_cancel() {
 	// Create frame from the orders in mempool
 	miner.make_frame(Arc::clone(&pool), BLOCK_SIZE);
 
-	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type);
+	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref());
 
 	// Only one ask should cross and fill, other will remain
 	assert_eq!(asks_book.len(), 0);
@@ -289,7 +293,7 @@ fn test_fba_cancel() {
 	let asks_book = Arc::new(common::setup_asks_book());
 
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::FBA;
+	let mechanism = MarketType::FBA.mechanism();
 	
 	// investor
 	let mut bid1 = common::setup_bid_limit_order();
@@ -334,7 +338,7 @@ fn test_fba_cancel() {
 	// Create frame from the orders in mempool
 	miner.make_frame(Arc::clone(&pool), BLOCK_SIZE);
 
-	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type);
+	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref());
 
 	// Only one ask should cross and fill, other will remain
 	assert_eq!(asks_book.len(), 0);
@@ -362,7 +366,7 @@ fn test_fba_cancel() {
 	// Create frame from the orders in mempool
 	miner.make_frame(Arc::clone(&pool), BLOCK_SIZE);
 
-	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type);
+	miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref());
 
 	// Only one ask should cross and fill, other will remain
 	assert_eq!(asks_book.len(), 0);
@@ -381,7 +385,7 @@ fn test_cda_ask_transaction() {
 	let asks_book = Arc::new(common::setup_asks_book());
 
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::CDA;
+	let mechanism = MarketType::CDA.mechanism();
 	
 	// Setup 1 bid and 2 asks
 	let mut bid = common::setup_bid_limit_order();
@@ -437,11 +441,11 @@ fn test_cda_ask_transaction() {
 		last_gas = order.gas;
 	}
 
-	let vec_results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).expect("shouldn't be none");
+	let vec_results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref()).0.expect("shouldn't be none");
 
 	// update the players with CDA results
 	for res in vec_results {
-		house.update_house(res);
+		mechanism.settle(&house, res);
 	}
 
 	// Only one ask should cross and fill, other will remain
@@ -476,7 +480,7 @@ fn test_cda_bid_transaction() {
 	let asks_book = Arc::new(common::setup_asks_book());
 
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::CDA;
+	let mechanism = MarketType::CDA.mechanism();
 	
 	// Setup 1 ask and 2 bids
 	let mut ask = common::setup_ask_limit_order();
@@ -537,11 +541,11 @@ fn test_cda_bid_transaction() {
 	}
 
 	// Process the bid order
-	let vec_results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).expect("shouldn't be none");
+	let vec_results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref()).0.expect("shouldn't be none");
 
 	// update the players with CDA results
 	for res in vec_results {
-		house.update_house(res);
+		mechanism.settle(&house, res);
 	}
 
 	// Only one bid should cross and fill, other will remain
@@ -579,7 +583,7 @@ pub fn test_klf_crossing_price() {
 	let mut handles = Vec::new();
 
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::KLF;
+	let mechanism = MarketType::KLF.mechanism();
 
 	// Send all the orders in parallel 
 	for bid in bids {
@@ -599,7 +603,7 @@ pub fn test_klf_crossing_price() {
 
 	// Process the bid order
 	let _house = Arc::new(common::setup_clearing_house());
-	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).unwrap();
+	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref()).0.unwrap();
 
 	assert_eq!(bids_book.len(), 82);
 	assert_eq!(asks_book.len(), 100);
@@ -618,7 +622,7 @@ pub fn test_klf_update_chouse() {
 	let mut handles = Vec::new();
 
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::KLF;
+	let mechanism = MarketType::KLF.mechanism();
 
 	let house = Arc::new(common::setup_clearing_house());
 	let mut investors = common::setup_n_investors(100);
@@ -628,14 +632,14 @@ pub fn test_klf_update_chouse() {
 	// Calculate what each player's end vol should be before so we can check later
 	let mut bids_vol = Vec::<(String, f64, f64)>::new();
 	for bid in bids.iter() {
-		let vol = bid.calc_flow_demand(81.09048166081236);
+		let vol = bid.calc_flow_demand(81.09048166081236, 1.0);
 		let bal = vol * 81.09048166081236;
 		bids_vol.push((bid.trader_id.clone(), bal, vol));
 	}
 
 	let mut asks_vol = Vec::<(String, f64, f64)>::new();
 	for ask in asks.iter() {
-		let vol = ask.calc_flow_supply(81.09048166081236);
+		let vol = ask.calc_flow_supply(81.09048166081236, 1.0);
 		let bal = vol * 81.09048166081236;
 		asks_vol.push((ask.trader_id.clone(), bal, vol));
 	}
@@ -672,7 +676,7 @@ pub fn test_klf_update_chouse() {
 	miner.make_frame(Arc::clone(&pool), BLOCK_SIZE);
 
 	// Process the orders
-	let mut results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).unwrap();
+	let mut results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref()).0.unwrap();
 	let results = results.pop().unwrap();
 
 	// clearing price is < asks p_high, so none will be fully filled
@@ -706,7 +710,7 @@ pub fn test_fba_update_chouse() {
 	let mut handles = Vec::new();
 
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::FBA;
+	let mechanism = MarketType::FBA.mechanism();
 
 	let house = Arc::new(common::setup_clearing_house());
 	let i1 = Investor::new(format!("ask1"));
@@ -761,7 +765,7 @@ pub fn test_fba_update_chouse() {
 	miner.make_frame(Arc::clone(&pool), BLOCK_SIZE);
 
 	// Process the bid order
-	let mut results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).unwrap();
+	let mut results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref()).0.unwrap();
 	let results = results.pop().unwrap();
 
 	// The bid1's volume was filled so it should have been removed from the book
@@ -825,7 +829,7 @@ pub fn test_fba_uniform_price1() {
 	// Setup Miner
 	let mut handles = Vec::new();
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::FBA;
+	let mechanism = MarketType::FBA.mechanism();
 
 	// Send all the orders in parallel 
 	handles.push(OrderProcessor::conc_recv_order(bid1, Arc::clone(&pool)));
@@ -843,7 +847,7 @@ pub fn test_fba_uniform_price1() {
 
 	// Process the orders order
 	let _house = Arc::new(common::setup_clearing_house());
-	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).unwrap();
+	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref()).0.unwrap();
 	let results = results.get(results.len() - 1).unwrap();
 
 	// The bid1's volume was filled so it should have been removed from the book
@@ -900,7 +904,7 @@ pub fn test_fba_uniform_price2() {
 	// Setup Miner
 	let mut handles = Vec::new();
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::FBA;
+	let mechanism = MarketType::FBA.mechanism();
 
 	// Send all the orders in parallel 
 	handles.push(OrderProcessor::conc_recv_order(bid1, Arc::clone(&pool)));
@@ -918,7 +922,7 @@ pub fn test_fba_uniform_price2() {
 
 	// Process the orders order
 	let _house = Arc::new(common::setup_clearing_house());
-	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).unwrap();
+	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref()).0.unwrap();
 	let results = results.get(results.len() - 1).unwrap();
 
 	// The bid that was filled is removed
@@ -982,7 +986,7 @@ pub fn test_fba_uniform_price3() {
 	// Setup Miner
 	let mut handles = Vec::new();
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::FBA;
+	let mechanism = MarketType::FBA.mechanism();
 
 	// Send all the orders in parallel 
 	handles.push(OrderProcessor::conc_recv_order(bid1, Arc::clone(&pool)));
@@ -1002,7 +1006,7 @@ pub fn test_fba_uniform_price3() {
 
 	// Process the orders order
 	let _house = Arc::new(common::setup_clearing_house());
-	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).unwrap();
+	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref()).0.unwrap();
 	let results = results.get(results.len() - 1).unwrap();
 
 	assert_eq!(bids_book.len(), 2);
@@ -1048,7 +1052,7 @@ pub fn test_fba_no_cross() {
 	// Setup Miner
 	let mut handles = Vec::new();
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::FBA;
+	let mechanism = MarketType::FBA.mechanism();
 
 	// Send all the orders in parallel 
 	handles.push(OrderProcessor::conc_recv_order(ask1, Arc::clone(&pool)));
@@ -1064,7 +1068,7 @@ pub fn test_fba_no_cross() {
 
 	// Process the orders order
 	let _house = Arc::new(common::setup_clearing_house());
-	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).unwrap();
+	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref()).0.unwrap();
 	let results = results.get(results.len() - 1).unwrap();
 
 	assert_eq!(bids_book.len(), 0);
@@ -1077,6 +1081,13 @@ pub fn test_fba_no_cross() {
 
 #[test]
 pub fn test_fba_vertical_cross() {
+	// Only one order is left at the marginal price level, so every allocation rule agrees
+	for allocation in ALLOCATION_RULES {
+		fba_vertical_cross(allocation);
+	}
+}
+
+fn fba_vertical_cross(allocation: AllocationRule) {
     let pool = Arc::new(common::setup_mem_pool());
 	let bids_book = Arc::new(common::setup_bids_book());
 	let asks_book = Arc::new(common::setup_asks_book());
@@ -1103,7 +1114,7 @@ pub fn test_fba_vertical_cross() {
 	// Setup Miner
 	let mut handles = Vec::new();
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::FBA;
+	let mechanism = FbaMechanism { allocation, ..FbaMechanism::default() };
 
 	// Send all the orders in parallel 
 	handles.push(OrderProcessor::conc_recv_order(bid1, Arc::clone(&pool)));
@@ -1121,7 +1132,7 @@ pub fn test_fba_vertical_cross() {
 
 	// Process the orders order
 	let _house = Arc::new(common::setup_clearing_house());
-	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).unwrap();
+	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), &mechanism).0.unwrap();
 	let results = results.get(results.len() - 1).unwrap();
 
 	assert_eq!(bids_book.len(), 1);
@@ -1142,6 +1153,13 @@ pub fn test_fba_vertical_cross() {
 
 #[test]
 pub fn test_fba_vertical_cross2() {
+	// Only one order is left at the marginal price level, so every allocation rule agrees
+	for allocation in ALLOCATION_RULES {
+		fba_vertical_cross2(allocation);
+	}
+}
+
+fn fba_vertical_cross2(allocation: AllocationRule) {
     let pool = Arc::new(common::setup_mem_pool());
 	let bids_book = Arc::new(common::setup_bids_book());
 	let asks_book = Arc::new(common::setup_asks_book());
@@ -1178,7 +1196,7 @@ pub fn test_fba_vertical_cross2() {
 	// Setup Miner
 	let mut handles = Vec::new();
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::FBA;
+	let mechanism = FbaMechanism { allocation, ..FbaMechanism::default() };
 
 	// Send all the orders in parallel 
 	handles.push(OrderProcessor::conc_recv_order(bid1, Arc::clone(&pool)));
@@ -1198,7 +1216,7 @@ pub fn test_fba_vertical_cross2() {
 
 	// Process the orders order
 	let _house = Arc::new(common::setup_clearing_house());
-	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).unwrap();
+	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), &mechanism).0.unwrap();
 	let results = results.get(results.len() - 1).unwrap();
 
 	assert_eq!(bids_book.len(), 1);
@@ -1231,8 +1249,16 @@ pub fn test_fba_vertical_cross2() {
 }
 
 
-// #[test]		// Need to confirm what the price is for this...
+#[test]
 pub fn test_fba_horizontal_cross() {
+	// Supply and demand match at the clearing price so no level is rationed and every
+	// allocation rule agrees
+	for allocation in ALLOCATION_RULES {
+		fba_horizontal_cross(allocation);
+	}
+}
+
+fn fba_horizontal_cross(allocation: AllocationRule) {
     let pool = Arc::new(common::setup_mem_pool());
 	let bids_book = Arc::new(common::setup_bids_book());
 	let asks_book = Arc::new(common::setup_asks_book());
@@ -1259,7 +1285,8 @@ pub fn test_fba_horizontal_cross() {
 	// Setup Miner
 	let mut handles = Vec::new();
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::FBA;
+	// The default merged-book walk clears at 12.25, above the bid, where nothing trades
+	let mechanism = FbaMechanism { allocation, price_rule: PriceRule::Midpoint, ..FbaMechanism::default() };
 
 	// Send all the orders in parallel 
 	handles.push(OrderProcessor::conc_recv_order(bid1, Arc::clone(&pool)));
@@ -1277,26 +1304,36 @@ pub fn test_fba_horizontal_cross() {
 
 	// Process the orders order
 	let _house = Arc::new(common::setup_clearing_house());
-	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).unwrap();
+	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), &mechanism).0.unwrap();
 	let results = results.get(results.len() - 1).unwrap();
 
 	assert_eq!(bids_book.len(), 1);
 	assert_eq!(asks_book.len(), 1);
 
 	println!("{:?}", results);
-	assert!(Auction::equal_e(&results.uniform_price.unwrap(), &12.25));
-
-	if let Some(player_updates) = &results.cross_results {
-		assert_eq!(player_updates[0].payer_order_id, bid1_id);
-		assert_eq!(player_updates[0].vol_filler_order_id, ask1_id);
-		assert_eq!(player_updates[0].volume, 50.0);
-		assert!(Auction::equal_e(&player_updates[0].price, &12.25));
-	}
+	// Every price from the 11.30 ask to the 12.00 bid trades all 50 shares, the midpoint clears
+	assert!(Auction::equal_e(&results.uniform_price.unwrap(), &11.65));
+	assert_eq!(results.agg_supply, 50.0);
+
+	let player_updates = results.cross_results.as_ref().expect("no fills!!");
+	assert_eq!(player_updates.len(), 1, "{:?} fills", allocation);
+	assert_eq!(player_updates[0].payer_order_id, bid1_id);
+	assert_eq!(player_updates[0].vol_filler_order_id, ask1_id);
+	assert_eq!(player_updates[0].volume, 50.0);
+	assert!(Auction::equal_e(&player_updates[0].price, &11.65));
 }
 
 
 #[test]
 pub fn test_fba_horizontal_cross2() {
+	// Supply and demand match at the clearing price so no level is rationed and every
+	// allocation rule agrees
+	for allocation in ALLOCATION_RULES {
+		fba_horizontal_cross2(allocation);
+	}
+}
+
+fn fba_horizontal_cross2(allocation: AllocationRule) {
     let pool = Arc::new(common::setup_mem_pool());
 	let bids_book = Arc::new(common::setup_bids_book());
 	let asks_book = Arc::new(common::setup_asks_book());
@@ -1332,7 +1369,7 @@ pub fn test_fba_horizontal_cross2() {
 	// Setup Miner
 	let mut handles = Vec::new();
 	let mut miner = common::setup_miner();
-	let market_type = MarketType::FBA;
+	let mechanism = FbaMechanism { allocation, ..FbaMechanism::default() };
 
 	// Send all the orders in parallel 
 	handles.push(OrderProcessor::conc_recv_order(bid1, Arc::clone(&pool)));
@@ -1352,7 +1389,7 @@ pub fn test_fba_horizontal_cross2() {
 
 	// Process the orders order
 	let _house = Arc::new(common::setup_clearing_house());
-	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), market_type).unwrap();
+	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), &mechanism).0.unwrap();
 	let results = results.get(results.len() - 1).unwrap();
 
 	assert_eq!(bids_book.len(), 1);
@@ -1377,4 +1414,271 @@ pub fn test_fba_horizontal_cross2() {
 		assert!(Auction::equal_e(&player_updates[1].price, &12.35));
 
 	}
-}
\ No newline at end of file
+}
+// The volume an order traded in the batch
+fn traded(results: &TradeResults, order_id: u64) -> f64 {
+	results.cross_results.iter().flatten()
+		.filter(|pu| !pu.cancel && (pu.payer_order_id == order_id || pu.vol_filler_order_id == order_id))
+		.map(|pu| pu.volume)
+		.sum()
+}
+
+#[test]
+pub fn test_fba_vertical_cross_rationed_bids() {
+	// The fixture of test_fba_vertical_cross with two bids sharing the marginal price level
+	for allocation in ALLOCATION_RULES {
+		let pool = Arc::new(common::setup_mem_pool());
+		let bids_book = Arc::new(common::setup_bids_book());
+		let asks_book = Arc::new(common::setup_asks_book());
+
+		let mut ask1 = common::setup_ask_limit_order();
+		ask1.quantity = 50.0;
+		ask1.price = 11.30;
+
+		let mut ask2 = common::setup_ask_limit_order();
+		ask2.quantity = 50.0;
+		ask2.price = 12.50;
+
+		// The higher gas bid is processed, and queued at 11.30, first
+		let mut bid1 = common::setup_bid_limit_order();
+		bid1.quantity = 30.0;
+		bid1.price = 11.30;
+		bid1.gas = 0.2;
+		let bid1_id = bid1.order_id;
+
+		let mut bid2 = common::setup_bid_limit_order();
+		bid2.quantity = 45.0;
+		bid2.price = 11.30;
+		let bid2_id = bid2.order_id;
+
+		let mut bid3 = common::setup_bid_limit_order();
+		bid3.quantity = 23.0;
+		bid3.price = 11.20;
+
+		for order in vec!(bid1, bid2, bid3, ask1, ask2) {
+			pool.add(order);
+		}
+		let mut miner = common::setup_miner();
+		miner.make_frame(Arc::clone(&pool), BLOCK_SIZE);
+		let mechanism = FbaMechanism { allocation, lot_size: 1.0, ..FbaMechanism::default() };
+		let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), &mechanism).0.unwrap();
+		let results = results.get(results.len() - 1).unwrap();
+		assert!(Auction::equal_e(&results.uniform_price.unwrap(), &11.30));
+
+		// 75 shares are bid at 11.30 for the 50 offered
+		let fills = (traded(results, bid1_id), traded(results, bid2_id));
+		match allocation {
+			AllocationRule::TimePriority => assert_eq!(fills, (30.0, 20.0)),
+			AllocationRule::ProRata => assert_eq!(fills, (20.0, 30.0)),
+			AllocationRule::Random => assert!(fills == (30.0, 20.0) || fills == (5.0, 45.0)),
+		}
+
+		// What's left of the bids rests in the book in its queue order
+		let resting: Vec<(u64, f64)> = bids_book.copy_orders().iter().rev()
+			.filter(|o| o.price == 11.30)
+			.map(|o| (o.order_id, o.quantity))
+			.collect();
+		let left = [(bid1_id, 30.0 - fills.0), (bid2_id, 45.0 - fills.1)];
+		assert_eq!(resting, left.iter().cloned().filter(|(_, q)| *q > 0.0).collect::<Vec<_>>());
+		assert_eq!(asks_book.len(), 1);
+	}
+}
+
+#[test]
+pub fn test_fba_vertical_cross_rationed_asks() {
+	// The fixture of test_fba_vertical_cross2 with its marginal ask split in two
+	for allocation in ALLOCATION_RULES {
+		let pool = Arc::new(common::setup_mem_pool());
+		let bids_book = Arc::new(common::setup_bids_book());
+		let asks_book = Arc::new(common::setup_asks_book());
+
+		let mut ask1 = common::setup_ask_limit_order();
+		ask1.quantity = 10.0;
+		ask1.price = 11.20;
+
+		let mut ask2 = common::setup_ask_limit_order();
+		ask2.quantity = 50.0;
+		ask2.price = 11.60;
+
+		// The higher gas ask is processed, and queued at 12.30, first
+		let mut ask3 = common::setup_ask_limit_order();
+		ask3.quantity = 11.0;
+		ask3.price = 12.30;
+		ask3.gas = 0.2;
+		let ask3_id = ask3.order_id;
+
+		let mut ask4 = common::setup_ask_limit_order();
+		ask4.quantity = 11.0;
+		ask4.price = 12.30;
+		let ask4_id = ask4.order_id;
+
+		let mut ask5 = common::setup_ask_limit_order();
+		ask5.quantity = 30.0;
+		ask5.price = 12.50;
+
+		let mut bid1 = common::setup_bid_limit_order();
+		bid1.quantity = 61.0;
+		bid1.price = 12.3;
+
+		let mut bid2 = common::setup_bid_limit_order();
+		bid2.quantity = 40.0;
+		bid2.price = 11.0;
+
+		for order in vec!(bid1, bid2, ask1, ask2, ask3, ask4, ask5) {
+			pool.add(order);
+		}
+		let mut miner = common::setup_miner();
+		miner.make_frame(Arc::clone(&pool), BLOCK_SIZE);
+		let mechanism = FbaMechanism { allocation, lot_size: 1.0, ..FbaMechanism::default() };
+		let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), &mechanism).0.unwrap();
+		let results = results.get(results.len() - 1).unwrap();
+		assert!(Auction::equal_e(&results.uniform_price.expect("no price!!"), &12.3));
+		assert_eq!(results.agg_supply, 61.0);
+
+		// One share is left for the 22 offered at 12.30, pro-rata halves round down to no
+		// lots and the leftover lot goes to the first ask in the queue
+		let fills = (traded(results, ask3_id), traded(results, ask4_id));
+		match allocation {
+			AllocationRule::TimePriority => assert_eq!(fills, (1.0, 0.0)),
+			AllocationRule::ProRata => assert_eq!(fills, (1.0, 0.0)),
+			AllocationRule::Random => assert!(fills == (1.0, 0.0) || fills == (0.0, 1.0)),
+		}
+		let resting: Vec<(u64, f64)> = asks_book.copy_orders().iter().rev()
+			.filter(|o| o.price == 12.30)
+			.map(|o| (o.order_id, o.quantity))
+			.collect();
+		assert_eq!(resting, vec!((ask3_id, 11.0 - fills.0), (ask4_id, 11.0 - fills.1)));
+		assert_eq!(bids_book.len(), 1);
+	}
+}
+
+fn limit_order(trader_id: &str, trade_type: TradeType, price: f64, quantity: f64) -> Order {
+	Order::new(String::from(trader_id), OrderType::Enter, trade_type, ExchangeType::LimitOrder,
+			   price, price, price, quantity, quantity, 0.1)
+}
+
+fn stop_order(trade_type: TradeType, stop_price: f64) -> Order {
+	Order::market(String::from("stop_id"), trade_type, 1.0, 0.1).with_stop(stop_price)
+}
+
+#[test]
+pub fn test_cda_stop_cascade() {
+	let bids_book = Arc::new(common::setup_bids_book());
+	let asks_book = Arc::new(common::setup_asks_book());
+	let stops = Arc::new(StopBook::new());
+	let mut miner = common::setup_miner();
+	let mechanism = MarketType::CDA.mechanism();
+	for price in [100.0, 102.0, 105.0] {
+		asks_book.add_order(limit_order("ask_id", TradeType::Ask, price, 1.0)).unwrap();
+	}
+
+	// The stop orders wait until the bid trades at 100, then each one's trade triggers the next
+	let (first, second, third) = (stop_order(TradeType::Bid, 100.0), stop_order(TradeType::Bid, 102.0), stop_order(TradeType::Bid, 110.0));
+	miner.frame = vec![first.clone(), second.clone(), third.clone(), limit_order("bid_id", TradeType::Bid, 100.0, 1.0)];
+	let (results, errors, triggered) = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::clone(&stops), mechanism.as_ref());
+	assert!(errors.is_empty());
+	let triggered: Vec<(u64, f64)> = triggered.iter().map(|(o, p)| (o.order_id, *p)).collect();
+	assert_eq!(triggered, vec![(first.order_id, 100.0), (second.order_id, 102.0)]);
+	let prices: Vec<f64> = results.unwrap().iter().filter_map(|r| r.last_trade_price()).collect();
+	assert_eq!(prices, vec![100.0, 102.0, 105.0]);
+	assert_eq!((asks_book.len(), stops.len()), (0, 1));
+
+	// A stop order is cancelled from the stop book
+	let mut cancel = third.clone();
+	cancel.order_type = OrderType::Cancel;
+	miner.frame = vec![cancel];
+	let results = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::clone(&stops), mechanism.as_ref()).0.unwrap();
+	let updates = results[0].cross_results.as_ref().unwrap();
+	assert!(updates[0].cancel && updates[0].payer_order_id == third.order_id);
+	assert_eq!(stops.len(), 0);
+}
+
+#[test]
+pub fn test_fba_stop_trigger() {
+	let bids_book = Arc::new(common::setup_bids_book());
+	let asks_book = Arc::new(common::setup_asks_book());
+	let stops = Arc::new(StopBook::new());
+	let mut miner = common::setup_miner();
+	let mechanism = MarketType::FBA.mechanism();
+
+	// The clearing price triggers the sell stop into the books for the next batch
+	let sell_stop = stop_order(TradeType::Ask, 99.0);
+	miner.frame = vec![sell_stop.clone(), limit_order("bid_id", TradeType::Bid, 99.0, 2.0), limit_order("ask_id", TradeType::Ask, 99.0, 2.0)];
+	let (results, _, triggered) = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::clone(&stops), mechanism.as_ref());
+	assert_eq!(results.unwrap().last().unwrap().uniform_price, Some(99.0));
+	assert_eq!(triggered.len(), 1);
+	assert_eq!((triggered[0].0.order_id, triggered[0].1), (sell_stop.order_id, 99.0));
+	assert_eq!(asks_book.copy_orders()[0].order_id, sell_stop.order_id);
+	assert_eq!(stops.len(), 0);
+}
+
+#[test]
+pub fn test_klf_rejects_triggered_market_order() {
+	let pool = Arc::new(common::setup_mem_pool());
+	let bids_book = Arc::new(common::setup_bids_book());
+	let asks_book = Arc::new(common::setup_asks_book());
+	let stops = Arc::new(StopBook::new());
+	let mut miner = common::setup_miner();
+	let mechanism = MarketType::KLF.mechanism();
+
+	let (bids, asks) = common::setup_flow_orders();
+	for order in bids.into_iter().chain(asks) {
+		OrderProcessor::seq_recv_order(order, &pool);
+	}
+	miner.make_frame(Arc::clone(&pool), BLOCK_SIZE);
+	let buy_stop = stop_order(TradeType::Bid, 80.0);
+	miner.frame.insert(0, buy_stop.clone());
+
+	// The flow market can't take the triggered market order so it's cancelled after the auction
+	let (results, errors, triggered) = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::clone(&stops), mechanism.as_ref());
+	assert_eq!(triggered.len(), 1);
+	assert_eq!(errors.len(), 1);
+	let results = results.unwrap();
+	assert!(results.last().unwrap().uniform_price.is_some());
+	let cancel = &results[results.len() - 2].cross_results.as_ref().unwrap()[0];
+	assert!(cancel.cancel && cancel.payer_order_id == buy_stop.order_id);
+}
+
+
+// An update that moves a bid through the best ask trades through the mechanism and is settled
+#[test]
+pub fn test_update_settles_through_mechanism() {
+	for market_type in [MarketType::CDA, MarketType::FBA] {
+		let bids_book = Arc::new(common::setup_bids_book());
+		let asks_book = Arc::new(common::setup_asks_book());
+		let mut miner = common::setup_miner();
+		let mechanism = market_type.mechanism();
+		let house = common::setup_clearing_house();
+		house.reg_investor(common::setup_investor(format!("bid")));
+		house.reg_investor(common::setup_investor(format!("ask")));
+
+		// A bid at 90.0 rests below an ask at 100.0
+		let mut bid = common::setup_bid_limit_order();
+		bid.trader_id = format!("bid");
+		bid.price = 90.0;
+		let mut ask = common::setup_ask_limit_order();
+		ask.trader_id = format!("ask");
+		for order in vec!(bid.clone(), ask) {
+			house.new_order(order.clone()).unwrap();
+			mechanism.accept_order(Arc::clone(&bids_book), Arc::clone(&asks_book), order).unwrap();
+		}
+
+		// Raising the bid to 100.0 crosses the ask
+		let mut update = bid.clone();
+		update.order_type = OrderType::Update;
+		update.price = 100.0;
+		house.update_player_order(update.clone()).unwrap();
+		miner.frame.push(update);
+		let (results, errors, _) = miner.publish_frame(Arc::clone(&bids_book), Arc::clone(&asks_book), Arc::new(StopBook::new()), mechanism.as_ref());
+		assert!(errors.is_empty());
+		for res in results.expect("the update should trade") {
+			assert!(mechanism.settle(&house, res).is_empty());
+		}
+
+		assert_eq!((bids_book.len(), asks_book.len()), (0, 0));
+		assert_eq!(house.get_bal_inv(format!("bid")), Some((-500.0, 5.0)));
+		assert_eq!(house.get_bal_inv(format!("ask")), Some((500.0, -5.0)));
+		assert_eq!(house.get_player_order_count(&format!("bid")), Ok(0));
+		assert_eq!(house.get_player_order_count(&format!("ask")), Ok(0));
+	}
+}